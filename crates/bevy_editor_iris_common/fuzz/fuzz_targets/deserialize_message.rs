@@ -0,0 +1,15 @@
+//! Deserializes arbitrary bytes as a message, as the remote thread does with the payload of every message header.
+#![no_main]
+
+use bevy_editor_iris_common::fuzzing;
+use bevy_editor_iris_common::message;
+use bevy_editor_iris_common::serde::RegistryContext;
+use libfuzzer_sys::fuzz_target;
+
+thread_local!(static REGISTRY: RegistryContext = fuzzing::registry());
+
+fuzz_target!(|data: &[u8]| {
+    REGISTRY.with(|registry| {
+        _ = message::deserialize_message(data, registry);
+    });
+});
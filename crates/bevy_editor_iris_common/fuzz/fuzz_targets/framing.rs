@@ -0,0 +1,14 @@
+//! Receives arbitrary bytes as a transaction's stream of message and chunk headers, each followed by its payload.
+#![no_main]
+
+use bevy_editor_iris_common::fuzzing;
+use bevy_editor_iris_common::serde::RegistryContext;
+use libfuzzer_sys::fuzz_target;
+
+thread_local!(static REGISTRY: RegistryContext = fuzzing::registry());
+
+fuzz_target!(|data: &[u8]| {
+    REGISTRY.with(|registry| {
+        _ = fuzzing::receive_stream(data, registry);
+    });
+});
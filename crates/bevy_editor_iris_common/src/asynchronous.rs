@@ -1,67 +1,215 @@
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::mem;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
-use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
+use bevy::log::{info_span, warn};
 use bevy::prelude::World;
 use bevy::reflect::TypeRegistry;
+use bevy::tasks::IoTaskPool;
+use bevy::utils::tracing::{field, Instrument, Span};
 use futures::stream::FuturesUnordered;
-use futures_lite::{Future, StreamExt};
-use quinn::{ConnectionError, NewConnection, RecvStream, SendStream};
+use futures::FutureExt;
+use futures_lite::{future, Future, StreamExt};
+use quinn::{
+    ConnectionError, NewConnection, ReadError, ReadExactError, RecvStream, SendStream, VarInt,
+    WriteError,
+};
+use tokio::runtime::Handle;
 use tokio::select;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
 
+use crate::config::{ConnectionConfig, RemoteRuntime};
 use crate::error::{
     ProcessChannelError, ProcessConnectionError, ProcessStreamError, RecvError, RemoteThreadError,
     SendError,
 };
-use crate::interface::{CloseTransaction, Interface};
-use crate::message;
-use crate::serde;
-use crate::Message;
+use crate::interface::{
+    AbortTransaction, CloseTransaction, Interface, Transaction, TransactionState,
+};
+use crate::message::{self, Message};
+use crate::serde::RegistryContext;
+use crate::session::Session;
+use crate::transfer::{
+    TransferChunk, TransferCounter, TransferDirection, TransferProgressReceiver,
+    TransferProgressSender,
+};
+
+mod multiplex;
 
 const MAGIC: &[u8; 4] = b"OBRS";
+/// Precedes the raw bytes of a [`TransferChunk`] instead of a serialized message
+const CHUNK_MAGIC: &[u8; 4] = b"OBRC";
+/// A magic followed by the length of the payload as a little-endian `u64`
+const HEADER_SIZE: usize = MAGIC.len() + mem::size_of::<u64>();
+
+/// The longest payload a header may announce. Longer payloads are rejected before a buffer is allocated for them,
+/// since the length comes from the remote application. Anything larger should be sent as a [transfer](crate::transfer).
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
 
 /// A type-erased [Boxed](Box) [message](Message)
 pub type MessageBox = Box<dyn Message>;
-/// A channel for sending [messages](MessageBox)
-pub type MessageTx = UnboundedSender<MessageBox>;
-/// A channel for receiving [messages](MessageBox)
-pub type MessageRx = UnboundedReceiver<MessageBox>;
-/// A channel for sending parts of a bi-directional channel of [messages](MessageBox) between
+pub use crate::queue::{MessageRx, MessageTx};
+/// A channel for sending one end of a [transaction](Transaction) between
 /// two threads.
-pub type OpeningSender = UnboundedSender<(MessageTx, MessageRx)>;
-/// A channel for receiving parts of a bi-directional channel of [messages](MessageBox) between
+pub type OpeningSender = UnboundedSender<Transaction>;
+/// A channel for receiving one end of a [transaction](Transaction) between
 /// two threads.
-pub type OpeningReceiver = UnboundedReceiver<(MessageTx, MessageRx)>;
+pub type OpeningReceiver = UnboundedReceiver<Transaction>;
 
-struct ReceiveState {
-    recv: RecvStream,
+/// The receiving half of a stream, which transactions and multiplexed frames are read from.
+///
+/// Implemented for quinn's [`RecvStream`], and for byte slices so what the remote thread does with a stream can
+/// be tested and fuzzed without a connection.
+pub(crate) trait ReadStream: Send {
+    /// Fills `buf`, failing with [`ReadExactError::FinishedEarly`] if the stream ends first.
+    fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExact<'a>;
+    /// Tells the remote application to stop sending, with an error code.
+    fn stop(&mut self, code: VarInt);
+}
+
+pub(crate) type ReadExact<'a> =
+    Pin<Box<dyn Future<Output = Result<(), ReadExactError>> + Send + 'a>>;
+
+impl ReadStream for RecvStream {
+    fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExact<'a> {
+        Box::pin(RecvStream::read_exact(self, buf))
+    }
+
+    fn stop(&mut self, code: VarInt) {
+        _ = RecvStream::stop(self, code);
+    }
+}
+
+impl ReadStream for &[u8] {
+    fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExact<'a> {
+        let bytes = *self;
+        let read = if bytes.len() < buf.len() {
+            *self = &[];
+            Err(ReadExactError::FinishedEarly)
+        } else {
+            let (read, rest) = bytes.split_at(buf.len());
+            buf.copy_from_slice(read);
+            *self = rest;
+            Ok(())
+        };
+        Box::pin(future::ready(read))
+    }
+
+    fn stop(&mut self, _code: VarInt) {}
+}
+
+struct ReceiveState<R = RecvStream> {
+    recv: R,
     tx: MessageTx,
+    state: Arc<TransactionState>,
+    transfer: TransferCounter,
     buffer: Vec<u8>,
+    span: Span,
+    registry: RegistryContext,
 }
 struct SendState {
     send: SendStream,
     rx: MessageRx,
+    state: Arc<TransactionState>,
+    transfer: TransferCounter,
     buffer: Vec<u8>,
+    span: Span,
+    registry: RegistryContext,
 }
 // TODO: Type-Alias-Impl-Trait might make the Pin<Box<Future>> unnecessary
 type ReceivedMessages =
-    FuturesUnordered<Pin<Box<dyn Future<Output = Result<ReceiveState, RecvError>>>>>;
-type PendingMessages =
-    FuturesUnordered<Pin<Box<dyn Future<Output = Result<SendState, SendError>>>>>;
+    FuturesUnordered<Pin<Box<dyn Future<Output = Result<ReceiveState, RecvError>> + Send>>>;
+type NextMessage = Pin<Box<dyn Future<Output = Result<(SendState, MessageBox), SendError>> + Send>>;
+type PendingSend = Pin<Box<dyn Future<Output = Result<SendState, SendError>> + Send>>;
+
+/// Transactions waiting to send. Each message is written at its own [priority](TransactionState::priority_of),
+/// so messages with a higher priority are polled, and therefore serialized and written, first.
+#[derive(Default)]
+struct PendingMessages {
+    /// Transactions waiting for the local threads to send a message
+    waiting: FuturesUnordered<NextMessage>,
+    /// Messages being written, grouped by priority
+    writing: BTreeMap<Reverse<i32>, FuturesUnordered<PendingSend>>,
+}
 
-/// A [`JoinHandle`] to the remote thread. Used by [`monitor_remote_thread`][crate::systems::monitor_remote_thread] to
-/// detect and recover from panics.
-pub struct RemoteThread(pub(crate) JoinHandle<Result<(), RemoteThreadError>>);
+impl PendingMessages {
+    fn is_empty(&self) -> bool {
+        self.waiting.is_empty() && self.writing.is_empty()
+    }
+
+    /// Completes with the first transaction to finish sending a message, checking higher priorities first.
+    async fn next(&mut self) -> Option<Result<SendState, SendError>> {
+        future::poll_fn(|cx| {
+            while let Poll::Ready(Some(next)) = self.waiting.poll_next(cx) {
+                match next {
+                    Ok((state, msg)) => self.push_write(state, msg),
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                }
+            }
+
+            let mut sent = None;
+            for pending in self.writing.values_mut() {
+                if let Poll::Ready(Some(result)) = pending.poll_next(cx) {
+                    sent = Some(result);
+                    break;
+                }
+            }
+            self.writing.retain(|_, pending| !pending.is_empty());
+
+            match sent {
+                Some(result) => Poll::Ready(Some(result)),
+                None if self.is_empty() => Poll::Ready(None),
+                None => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    fn push_write(&mut self, state: SendState, msg: MessageBox) {
+        let priority = state.state.priority_of(&*msg);
+        let span = state.span.clone();
+        self.writing
+            .entry(Reverse(priority))
+            .or_default()
+            .push(Box::pin(
+                async move { log_sent(send_message(state, msg).await) }.instrument(span),
+            ));
+    }
+}
+
+/// Receives the result of the remote thread once it ends. Used by [`monitor_remote_thread`][crate::systems::monitor_remote_thread] to
+/// detect and recover from errors and panics as soon as they happen.
+pub struct RemoteThread(pub(crate) oneshot::Receiver<Result<(), RemoteThreadError>>);
 
 // TODO: Connect to multiple clients?
 /// Opens the remote thread using the given run function.
 ///
 /// The remote thread handles transactions between the local threads and the remote
 /// application.
-pub fn open_remote_thread<F: 'static + Future<Output = Result<(), RemoteThreadError>>>(
-    // run_fn: impl 'static + Fn(OpeningSender, OpeningReceiver, StreamCounter) -> F + Send + Sync + Copy,
-    run_fn: impl 'static + Fn(OpeningSender, OpeningReceiver) -> F + Send + Sync + Copy,
+///
+/// The run function is given the channel to report the [progress](crate::transfer::TransferProgress) of transfers to,
+/// the [`ConnectionConfig`] resource, or the default config if there is none, and the [`RegistryContext`] messages
+/// are (de)serialized with.
+/// [`ConnectionConfig::runtime`] decides whether it runs on a dedicated thread, or as a task on a shared tokio runtime
+/// or bevy's [`IoTaskPool`].
+pub fn open_remote_thread<F: 'static + Future<Output = Result<(), RemoteThreadError>> + Send>(
+    run_fn: impl 'static
+        + Fn(
+            OpeningSender,
+            OpeningReceiver,
+            TransferProgressSender,
+            ConnectionConfig,
+            RegistryContext,
+        ) -> F
+        + Send
+        + Sync
+        + Copy,
 ) -> impl 'static + Fn(&mut World) {
     move |world| {
         let run_fn = run_fn;
@@ -72,34 +220,115 @@ pub fn open_remote_thread<F: 'static + Future<Output = Result<(), RemoteThreadEr
         let interface = Interface::new(local_tx, local_rx);
         world.insert_resource(interface);
 
-        let registry = world.remove_resource::<TypeRegistry>().expect("failed to get TypeRegistry while starting remote thread. Ensure a TypeRegistry is added to the world at startup");
-        let client_registry = registry.clone();
-        world.insert_resource(registry);
+        // Transactions opened before this point are dead, so subscriptions must be re-established
+        if let Some(mut session) = world.get_resource_mut::<Session>() {
+            session.reconnect();
+        }
 
-        let client_thread = std::thread::spawn(move || {
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        world.insert_resource(TransferProgressReceiver(Mutex::new(progress_rx)));
+
+        let registry = match world.get_resource::<RegistryContext>() {
+            Some(registry) => registry.clone(),
+            None => world
+                .get_resource::<TypeRegistry>()
+                .expect("failed to get TypeRegistry while starting remote thread. Ensure a TypeRegistry is added to the world at startup")
+                .clone()
+                .into(),
+        };
+
+        let config = world
+            .get_resource::<ConnectionConfig>()
+            .cloned()
+            .unwrap_or_default();
+
+        let runtime = config.runtime.clone();
+        let (result_tx, result_rx) = oneshot::channel();
+
+        let run = async move {
+            let result = AssertUnwindSafe(async move {
+                run_fn(remote_tx, remote_rx, progress_tx, config, registry).await
+            })
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|payload| Err(RemoteThreadError::Panicked(panic_message(payload))));
+
+            _ = result_tx.send(result);
+        };
+
+        match runtime {
+            RemoteRuntime::Dedicated => {
+                std::thread::spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .unwrap();
+
+                    runtime.block_on(run)
+                });
+            }
+            RemoteRuntime::Shared(handle) => {
+                handle.spawn(run);
+            }
+            RemoteRuntime::IoTaskPool(handle) => {
+                world
+                    .get_resource::<IoTaskPool>()
+                    .expect("failed to get IoTaskPool while starting remote thread. Ensure the TaskPoolPlugin is added")
+                    .spawn(WithRuntime {
+                        handle,
+                        future: Box::pin(run),
+                    })
+                    .detach();
+            }
+        }
 
-            // TODO: Should the type registry be deep cloned instead of arc cloned?
-            _ = serde::replace_type_registry(client_registry);
+        world.insert_resource(RemoteThread(result_rx));
+    }
+}
 
-            runtime.block_on(run_fn(remote_tx, remote_rx))
-        });
+/// Polls a future with the IO and timer drivers of a tokio runtime available, so it can run on another executor.
+struct WithRuntime<F> {
+    handle: Handle,
+    future: Pin<Box<F>>,
+}
 
-        world.insert_resource(RemoteThread(client_thread));
+impl<F: Future> Future for WithRuntime<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let _runtime = this.handle.enter();
+        this.future.as_mut().poll(cx)
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
     }
 }
 
 /// Processes incoming transactions and messages to send, sending messages
 /// between the two given channels.
+///
+/// Each transaction is sent over its own stream, unless [`ConnectionConfig::multiplex`] is set.
 pub async fn process_connection(
     mut new: NewConnection,
     tx: &OpeningSender,
     rx: &mut OpeningReceiver,
+    progress: &TransferProgressSender,
+    config: &ConnectionConfig,
+    registry: &RegistryContext,
 ) -> Result<(), ProcessConnectionError> {
-    let mut pending_messages = FuturesUnordered::new();
+    if let Some(streams) = config.multiplex {
+        return multiplex::process_connection(new, tx, rx, progress, streams, registry).await;
+    }
+
+    let mut pending_messages = PendingMessages::default();
     let mut received_messages = FuturesUnordered::new();
 
     loop {
@@ -107,33 +336,23 @@ pub async fn process_connection(
             // The remote application opened a new stream
             stream = new.bi_streams.next() => {
                 // process_incoming_bi(stream, tx, &mut pool, stream_counter, &mut received_messages, &mut pending_messages).await?
-                process_incoming_bi(stream, tx, &mut received_messages, &mut pending_messages).await?
+                process_incoming_bi(stream, tx, progress, registry, &mut received_messages, &mut pending_messages).await?
             },
             // The local thread(s) opened a new channel
             channel = rx.recv() => {
-                process_incoming_channel(channel, &new, &mut received_messages, &mut pending_messages).await?
+                process_incoming_channel(channel, &new, progress, registry, &mut received_messages, &mut pending_messages).await?
             }
             // The local thread(s) sent a new message
-            pending = pending_messages.next() => {
-                if let Some(pending) = pending {
-                    match pending {
-                        Ok(SendState { send, rx, buffer }) => {
-                            setup_pending(send, rx, buffer, &mut pending_messages);
-                        },
-                        Err(SendError::TransactionClosed) => (),
-                        Err(err) => eprintln!("Send stream closed with error {:?}", err),
-                    }
+            pending = pending_messages.next(), if !pending_messages.is_empty() => {
+                // Streams which closed were logged by the transaction
+                if let Some(Ok(state)) = pending {
+                    push_send(&mut pending_messages, state);
                 }
             }
             // The remote application sent us a message
-            received = received_messages.next() => {
-                if let Some(received) = received {
-                    match received {
-                        Ok(ReceiveState { recv, tx, buffer }) => {
-                            setup_received(recv, tx, buffer, &mut received_messages);
-                        },
-                        Err(err) => eprintln!("Recv stream closed with error {:?}", err),
-                    }
+            received = received_messages.next(), if !received_messages.is_empty() => {
+                if let Some(Ok(state)) = received {
+                    push_receive(&mut received_messages, state);
                 }
             }
         }
@@ -143,6 +362,8 @@ pub async fn process_connection(
 async fn process_incoming_bi(
     stream: Option<Result<(SendStream, RecvStream), ConnectionError>>,
     open_tx: &OpeningSender,
+    progress: &TransferProgressSender,
+    registry: &RegistryContext,
     // pool: &mut HashMap<StreamId, (SendStream, RecvStream)>,
     // stream_counter: &mut StreamCounter,
     received_messages: &mut ReceivedMessages,
@@ -151,18 +372,16 @@ async fn process_incoming_bi(
     let stream = stream.ok_or_else(|| ProcessStreamError::BiStreamsClosed)?;
     let (send, recv) = stream?;
 
-    let (tx, local_rx) = mpsc::unbounded_channel();
-    let (local_tx, rx) = mpsc::unbounded_channel();
+    let (local, remote) = Transaction::pair();
 
-    open_tx.send((local_tx, local_rx))?;
+    open_tx.send(local)?;
 
     setup_message_listeners(
         send,
         recv,
-        tx,
-        rx,
-        vec![],
-        vec![],
+        remote,
+        progress,
+        registry,
         pending_messages,
         received_messages,
     );
@@ -171,12 +390,14 @@ async fn process_incoming_bi(
 }
 
 async fn process_incoming_channel(
-    channel: Option<(MessageTx, MessageRx)>,
+    channel: Option<Transaction>,
     new: &NewConnection,
+    progress: &TransferProgressSender,
+    registry: &RegistryContext,
     received_messages: &mut ReceivedMessages,
     pending_messages: &mut PendingMessages,
 ) -> Result<(), ProcessChannelError> {
-    let (tx, rx) = match channel {
+    let remote = match channel {
         Some(channel) => channel,
         None => return Err(ProcessChannelError::OpenChannelClosed),
     };
@@ -186,10 +407,9 @@ async fn process_incoming_channel(
     setup_message_listeners(
         send,
         recv,
-        tx,
-        rx,
-        vec![],
-        vec![],
+        remote,
+        progress,
+        registry,
         pending_messages,
         received_messages,
     );
@@ -200,94 +420,436 @@ async fn process_incoming_channel(
 fn setup_message_listeners(
     send: SendStream,
     recv: RecvStream,
-    tx: MessageTx,
-    rx: MessageRx,
-    pend_buffer: Vec<u8>,
-    recv_buffer: Vec<u8>,
+    Transaction { tx, rx, state }: Transaction,
+    progress: &TransferProgressSender,
+    registry: &RegistryContext,
     pending_messages: &mut PendingMessages,
     received_messages: &mut ReceivedMessages,
 ) {
-    setup_received(recv, tx, recv_buffer, received_messages);
+    let span = transaction_span(&state);
+
+    push_receive(
+        received_messages,
+        ReceiveState {
+            recv,
+            tx,
+            transfer: TransferCounter::new(&state, TransferDirection::Receiving, progress),
+            state: state.clone(),
+            buffer: vec![],
+            span: span.clone(),
+            registry: registry.clone(),
+        },
+    );
 
-    setup_pending(send, rx, pend_buffer, pending_messages);
+    push_send(
+        pending_messages,
+        SendState {
+            send,
+            rx,
+            transfer: TransferCounter::new(&state, TransferDirection::Sending, progress),
+            state,
+            buffer: vec![],
+            span,
+            registry: registry.clone(),
+        },
+    );
 }
 
-fn setup_received(
-    recv: RecvStream,
-    tx: MessageTx,
-    buffer: Vec<u8>,
-    received_messages: &mut ReceivedMessages,
-) {
-    received_messages.push(Box::pin(receive_message(ReceiveState { recv, tx, buffer })));
+/// Creates the span the remote thread's work on a transaction is done in. It records the type name of the
+/// last message sent or received, so failures carry the message they happened on.
+pub(crate) fn transaction_span(state: &TransactionState) -> Span {
+    info_span!("transaction", id = %state.id(), message = field::Empty)
 }
 
-fn setup_pending(
-    send: SendStream,
-    rx: MessageRx,
-    buffer: Vec<u8>,
-    pending_messages: &mut PendingMessages,
-) {
-    pending_messages.push(Box::pin(send_message(SendState { send, rx, buffer })));
+/// Records the type name of the message the current transaction is sending or receiving.
+pub(crate) fn record_message(msg: &dyn Message) {
+    Span::current().record("message", msg.type_name());
+}
+
+fn push_receive(received_messages: &mut ReceivedMessages, state: ReceiveState) {
+    let span = state.span.clone();
+    received_messages.push(Box::pin(
+        async move {
+            let received = receive_message(state).await;
+            match &received {
+                Ok(_)
+                | Err(RecvError::Finished | RecvError::TransactionClosed | RecvError::Expired) => {}
+                Err(err) => warn!(error = %err, "receive stream closed"),
+            }
+            received
+        }
+        .instrument(span),
+    ));
 }
 
-async fn receive_message(
+fn push_send(pending_messages: &mut PendingMessages, state: SendState) {
+    let span = state.span.clone();
+    pending_messages.waiting.push(Box::pin(
+        async move { log_sent(next_message(state).await) }.instrument(span),
+    ));
+}
+
+fn log_sent<T>(sent: Result<T, SendError>) -> Result<T, SendError> {
+    match &sent {
+        Ok(_)
+        | Err(
+            SendError::TransactionClosed
+            | SendError::TransactionAborted(_)
+            | SendError::ChannelClosed
+            | SendError::Expired,
+        ) => (),
+        Err(err) => warn!(error = %err, "send stream closed"),
+    }
+    sent
+}
+
+async fn receive_message<R: ReadStream>(
     ReceiveState {
         mut recv,
         tx,
+        state,
+        mut transfer,
         mut buffer,
-    }: ReceiveState,
-) -> Result<ReceiveState, RecvError> {
-    let mut header = [0; 12];
-    recv.read_exact(&mut header).await?;
-    if header[0..4] != *MAGIC {
-        let mut arr = [0; 4];
-        arr.copy_from_slice(&header[0..4]);
-        return Err(RecvError::InvalidData(arr));
+        span,
+        registry,
+    }: ReceiveState<R>,
+) -> Result<ReceiveState<R>, RecvError> {
+    let mut header = [0; HEADER_SIZE];
+
+    // Stop the stream if the local threads are no longer listening or the transaction expired
+    let read = select! {
+        read = recv.read_exact(&mut header) => Ok(read),
+        _ = tx.closed() => Err(None),
+        expiry = state.expired() => Err(Some(expiry)),
+    };
+
+    match read {
+        Ok(Ok(())) => (),
+        // The remote application finished the stream on a message boundary
+        Ok(Err(ReadExactError::FinishedEarly)) => {
+            state.close_remote(None);
+            return Err(RecvError::Finished);
+        }
+        Ok(Err(ReadExactError::ReadError(ReadError::Reset(code)))) => {
+            state.close_remote(Some(code.into_inner()));
+            return Err(RecvError::Reset(code));
+        }
+        Ok(Err(err)) => return Err(err.into()),
+        Err(None) => {
+            recv.stop(VarInt::from_u32(state.local_code()));
+            return Err(RecvError::TransactionClosed);
+        }
+        Err(Some(expiry)) => {
+            recv.stop(VarInt::from_u32(expiry.code()));
+            return Err(RecvError::Expired);
+        }
     }
 
-    let len = usize::from_le_bytes(header[4..12].try_into().unwrap());
-    if len > buffer.len() {
-        buffer.append(&mut vec![0; len - buffer.len()]);
+    let (is_chunk, len) = parse_header(&header)?;
+
+    let msg: MessageBox = if is_chunk {
+        // Chunks are handed to the local threads as they are, so they get a buffer of their own
+        let mut bytes = vec![0; len];
+        read_payload(&mut recv, &mut bytes, &state).await?;
+        transfer.chunk(len);
+        Box::new(TransferChunk { bytes })
+    } else {
+        if len > buffer.len() {
+            buffer.append(&mut vec![0; len - buffer.len()]);
+        }
+        let buf = &mut buffer[..len];
+        read_payload(&mut recv, buf, &state).await?;
+
+        let msg = message::deserialize_message(buf, &registry)?;
+        transfer.observe(&*msg);
+        msg
+    };
+    record_message(&*msg);
+
+    state.touch();
+    // Wait for the local threads to make room, which in turn stops reading from the stream
+    let sent = select! {
+        sent = tx.send(msg) => sent,
+        expiry = state.expired() => {
+            recv.stop(VarInt::from_u32(expiry.code()));
+            return Err(RecvError::Expired);
+        }
+    };
+    if sent.is_err() {
+        recv.stop(VarInt::from_u32(state.local_code()));
+        return Err(RecvError::TransactionClosed);
     }
-    let buf = &mut buffer[..len];
 
-    recv.read_exact(buf).await?;
+    Ok(ReceiveState {
+        recv,
+        tx,
+        state,
+        transfer,
+        buffer,
+        span,
+        registry,
+    })
+}
+
+async fn read_payload<R: ReadStream>(
+    recv: &mut R,
+    buf: &mut [u8],
+    state: &TransactionState,
+) -> Result<(), RecvError> {
+    if let Err(err) = recv.read_exact(buf).await {
+        if let ReadExactError::ReadError(ReadError::Reset(code)) = err {
+            state.close_remote(Some(code.into_inner()));
+        }
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+/// Waits for the local threads to send the next message to write, handling the messages which close the transaction.
+async fn next_message(mut state: SendState) -> Result<(SendState, MessageBox), SendError> {
+    let msg = select! {
+        msg = state.rx.recv() => msg,
+        expiry = state.state.expired() => {
+            _ = state.send.reset(VarInt::from_u32(expiry.code()));
+            return Err(SendError::Expired);
+        }
+    };
 
-    let msg = message::deserialize_message(buf)?;
+    let msg = match msg {
+        Some(m) => m,
+        // Every sender was dropped, so nothing more can be sent
+        None => {
+            state.state.finish();
+            finish_stream(&mut state.send, &state.state).await?;
+            return Err(SendError::ChannelClosed);
+        }
+    };
 
-    tx.send(msg)?;
+    if msg.is::<CloseTransaction>() {
+        finish_stream(&mut state.send, &state.state).await?;
+        return Err(SendError::TransactionClosed);
+    }
 
-    Ok(ReceiveState { recv, tx, buffer })
+    match msg.downcast::<AbortTransaction>() {
+        Ok(AbortTransaction { code }) => {
+            _ = state.send.reset(VarInt::from_u32(code));
+            Err(SendError::TransactionAborted(code))
+        }
+        Err(msg) => Ok((state, msg)),
+    }
 }
 
 async fn send_message(
     SendState {
         mut send,
-        mut rx,
+        rx,
+        state,
+        mut transfer,
         mut buffer,
+        span,
+        registry,
     }: SendState,
+    msg: MessageBox,
 ) -> Result<SendState, SendError> {
-    let msg = match rx.recv().await {
-        Some(m) => m,
-        None => return Err(SendError::ChannelClosed),
+    record_message(&*msg);
+    _ = send.set_priority(state.priority_of(&*msg));
+
+    buffer.clear();
+    match msg.downcast::<TransferChunk>() {
+        // Chunks are written as they are, without being copied into the buffer
+        Ok(TransferChunk { bytes }) => {
+            buffer.extend_from_slice(CHUNK_MAGIC);
+            buffer.extend_from_slice(&u64::to_le_bytes(bytes.len() as u64));
+            write_all(&mut send, &buffer, &state).await?;
+            write_all(&mut send, &bytes, &state).await?;
+            transfer.chunk(bytes.len());
+        }
+        Err(msg) => {
+            transfer.observe(&*msg);
+            encode_message(msg, &mut buffer, &registry)?;
+            write_all(&mut send, &buffer, &state).await?;
+        }
+    }
+    state.touch();
+
+    Ok(SendState {
+        send,
+        rx,
+        state,
+        transfer,
+        buffer,
+        span,
+        registry,
+    })
+}
+
+/// Receives every message of a transaction's stream from `recv` the way the remote thread does, until the stream
+/// ends. Returns the messages and the error which ended it, which is [`RecvError::Finished`] on a message boundary.
+#[cfg(feature = "fuzzing")]
+pub(crate) async fn receive_stream<R: ReadStream>(
+    recv: R,
+    registry: &RegistryContext,
+) -> (Vec<MessageBox>, RecvError) {
+    let (mut local, Transaction { tx, state, .. }) = Transaction::pair();
+    let (progress, _) = mpsc::unbounded_channel();
+    let mut receiving = ReceiveState {
+        recv,
+        tx,
+        transfer: TransferCounter::new(&state, TransferDirection::Receiving, &progress),
+        span: transaction_span(&state),
+        state,
+        buffer: vec![],
+        registry: registry.clone(),
     };
 
-    if msg.as_any().is::<CloseTransaction>() {
-        return Err(SendError::TransactionClosed);
+    let mut messages = vec![];
+    loop {
+        receiving = match receive_message(receiving).await {
+            Ok(receiving) => receiving,
+            Err(err) => return (messages, err),
+        };
+        // Make room for the next message
+        if let Ok(msg) = local.try_recv() {
+            messages.push(msg);
+        }
     }
+}
 
+#[cfg(feature = "fuzzing")]
+pub(crate) use multiplex::receive_frames;
+
+/// Appends `msg` to `buffer`, framed as a message header followed by the serialized message.
+fn encode_message(
+    msg: MessageBox,
+    buffer: &mut Vec<u8>,
+    registry: &RegistryContext,
+) -> Result<(), serde_yaml::Error> {
     // For clarity:
     // create a header of [MAGIC, 0usize], write the payload to the message,
     // then go back and write the payload length to the 0'd part of the header.
-    const HEADER_SIZE: usize = MAGIC.len() + mem::size_of::<usize>();
+    let start = buffer.len();
     buffer.extend_from_slice(MAGIC);
-    buffer.extend_from_slice(&usize::to_le_bytes(0));
-    message::serialize_message(msg, &mut buffer)?;
-    let message_len = buffer.len();
-    buffer[MAGIC.len()..HEADER_SIZE]
-        .copy_from_slice(&usize::to_le_bytes(message_len - HEADER_SIZE));
+    buffer.extend_from_slice(&u64::to_le_bytes(0));
+    message::serialize_message(msg, &mut *buffer, registry)?;
+    let message_len = buffer.len() - start;
+    buffer[start + MAGIC.len()..start + HEADER_SIZE]
+        .copy_from_slice(&u64::to_le_bytes((message_len - HEADER_SIZE) as u64));
 
+    Ok(())
+}
+
+/// Returns whether a header precedes a chunk rather than a message, and the length of what follows it.
+fn parse_header(header: &[u8; HEADER_SIZE]) -> Result<(bool, usize), RecvError> {
+    let is_chunk = header[0..4] == *CHUNK_MAGIC;
+    if !is_chunk && header[0..4] != *MAGIC {
+        let mut arr = [0; 4];
+        arr.copy_from_slice(&header[0..4]);
+        return Err(RecvError::InvalidData(arr));
+    }
+
+    let len = u64::from_le_bytes(header[4..12].try_into().unwrap());
+    if len > MAX_PAYLOAD_LEN as u64 {
+        return Err(RecvError::TooLarge(len));
+    }
+    Ok((is_chunk, len as usize))
+}
+
+/// Writes a single message to `send`, framed the same way as the messages of a transaction.
+///
+/// Useful for handshakes which happen before [`process_connection`] takes over the connection.
+pub async fn write_message(
+    send: &mut SendStream,
+    msg: MessageBox,
+    registry: &RegistryContext,
+) -> Result<(), SendError> {
+    let mut buffer = vec![];
+    encode_message(msg, &mut buffer, registry)?;
     send.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Reads a single message written by [`write_message`] from `recv`.
+pub async fn read_message(
+    recv: &mut RecvStream,
+    registry: &RegistryContext,
+) -> Result<MessageBox, RecvError> {
+    let mut header = [0; HEADER_SIZE];
+    recv.read_exact(&mut header).await?;
+
+    match parse_header(&header)? {
+        (false, len) => {
+            let mut buffer = vec![0; len];
+            recv.read_exact(&mut buffer).await?;
+            Ok(message::deserialize_message(&buffer, registry)?)
+        }
+        (true, _) => Err(RecvError::InvalidData(*CHUNK_MAGIC)),
+    }
+}
 
-    Ok(SendState { send, rx, buffer })
+async fn write_all(
+    send: &mut SendStream,
+    buf: &[u8],
+    state: &TransactionState,
+) -> Result<(), SendError> {
+    if let Err(err) = send.write_all(buf).await {
+        record_stopped(&err, state);
+        return Err(err.into());
+    }
+
+    Ok(())
+}
+
+async fn finish_stream(send: &mut SendStream, state: &TransactionState) -> Result<(), WriteError> {
+    match send.finish().await {
+        Ok(()) => Ok(()),
+        // The remote application already stopped receiving, so there is nothing left to finish
+        Err(err @ WriteError::Stopped(_)) => {
+            record_stopped(&err, state);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn record_stopped(err: &WriteError, state: &TransactionState) {
+    if let WriteError::Stopped(code) = err {
+        state.close_remote(Some(code.into_inner()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(magic: &[u8; 4], len: u64) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(magic);
+        header[4..].copy_from_slice(&len.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn oversized_payload_rejected() {
+        // Found by the framing fuzz target: the length was allocated before any of the payload arrived
+        for magic in [MAGIC, CHUNK_MAGIC] {
+            assert!(matches!(
+                parse_header(&header(magic, u64::MAX)),
+                Err(RecvError::TooLarge(u64::MAX))
+            ));
+            assert!(matches!(
+                parse_header(&header(magic, MAX_PAYLOAD_LEN as u64 + 1)),
+                Err(RecvError::TooLarge(_))
+            ));
+        }
+
+        assert!(matches!(
+            parse_header(&header(MAGIC, MAX_PAYLOAD_LEN as u64)),
+            Ok((false, MAX_PAYLOAD_LEN))
+        ));
+        assert!(matches!(
+            parse_header(&header(b"OBRX", 0)),
+            Err(RecvError::InvalidData(magic)) if magic == *b"OBRX"
+        ));
+    }
 }
@@ -0,0 +1,750 @@
+//! Multiplexes many transactions over a small pool of streams.
+//!
+//! Every transaction opened by this side is assigned to one of at most `streams` bi-directional
+//! streams opened by this side, round-robin. Messages are sent as frames tagged with the id of their
+//! transaction, and both directions of a transaction share the stream it was assigned to.
+//! Transactions opened by the remote application arrive on the streams it opened, so ids only need to be
+//! unique among the streams opened by one side.
+//!
+//! A shared stream takes on the priority of the last data frame written to it, so priorities are
+//! only approximate between transactions on the same stream.
+//!
+//! Frames are routed to their transaction without waiting while it has room to buffer them. Once a transaction
+//! whose local threads fall behind has buffered too many, its stream stops being read until it catches up, which
+//! holds up the other transactions on that stream but not those on other streams.
+
+use std::future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bevy::log::warn;
+use bevy::utils::tracing::Instrument;
+use bevy::utils::{HashMap, HashSet};
+use futures::stream::FuturesUnordered;
+use futures_lite::{Future, StreamExt};
+use quinn::{ConnectionError, NewConnection, ReadError, ReadExactError, RecvStream, SendStream};
+use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Mutex;
+
+use crate::error::{
+    MultiplexError, ProcessChannelError, ProcessConnectionError, ProcessStreamError, RecvError,
+    SendError,
+};
+use crate::interface::{AbortTransaction, CloseTransaction, Expiry, Transaction};
+use crate::message;
+use crate::serde::RegistryContext;
+use crate::transfer::{TransferChunk, TransferCounter, TransferDirection, TransferProgressSender};
+
+use super::{
+    MessageBox, MessageRx, MessageTx, OpeningReceiver, OpeningSender, ReadStream, MAX_PAYLOAD_LEN,
+};
+
+const MAGIC: &[u8; 4] = b"OBRM";
+/// The magic, transaction id, frame kind and payload length
+const HEADER_SIZE: usize = MAGIC.len() + 8 + 1 + 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FrameKind {
+    /// A serialized message
+    Data = 0,
+    /// The sender finished its side of the transaction
+    Finish = 1,
+    /// The sender aborted its side of the transaction. The payload is the error code.
+    Reset = 2,
+    /// The sender stopped receiving from the transaction. The payload is the error code.
+    Stop = 3,
+    /// The raw bytes of a [`TransferChunk`]
+    Chunk = 4,
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(FrameKind::Data),
+            1 => Some(FrameKind::Finish),
+            2 => Some(FrameKind::Reset),
+            3 => Some(FrameKind::Stop),
+            4 => Some(FrameKind::Chunk),
+            _ => None,
+        }
+    }
+}
+
+struct Frame {
+    id: u64,
+    kind: FrameKind,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn code(&self) -> u64 {
+        let mut code = [0; 4];
+        let len = self.payload.len().min(4);
+        code[..len].copy_from_slice(&self.payload[..len]);
+        u32::from_le_bytes(code) as u64
+    }
+}
+
+type SharedSend = Arc<Mutex<SendStream>>;
+
+/// The number of frames buffered for a transaction whose local threads fall behind. Once full, its stream
+/// stops being read until there is room again.
+const FRAME_BUFFER: usize = 32;
+
+struct Stream {
+    send: SharedSend,
+    opened_locally: bool,
+    routes: HashMap<u64, Sender<Frame>>,
+    /// The ids of the transactions the remote application has opened on this stream, including those which ended.
+    /// Each transaction writes its first frame on its own, so they may be seen out of order.
+    seen: HashSet<u64>,
+}
+
+/// A stream in the pool that transactions opened by this side are assigned to.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Slot {
+    /// The index of the stream in `streams`
+    Open(usize),
+    /// The stream is still being opened; the key of its entry in `waiting`
+    Opening(u64),
+}
+
+struct Reader {
+    index: usize,
+    recv: RecvStream,
+}
+
+type Readers =
+    FuturesUnordered<Pin<Box<dyn Future<Output = (Reader, Result<Frame, RecvError>)> + Send>>>;
+type TransactionFuture = Pin<Box<dyn Future<Output = (usize, u64)> + Send>>;
+type Transactions = FuturesUnordered<TransactionFuture>;
+type Openings = FuturesUnordered<
+    Pin<Box<dyn Future<Output = (u64, Result<(SendStream, RecvStream), ConnectionError>)> + Send>>,
+>;
+
+/// Processes incoming transactions and messages to send like [`super::process_connection`], but multiplexes
+/// transactions opened by this side over at most `max_streams` streams.
+pub(super) async fn process_connection(
+    new: NewConnection,
+    tx: &OpeningSender,
+    rx: &mut OpeningReceiver,
+    progress: &TransferProgressSender,
+    max_streams: usize,
+    registry: &RegistryContext,
+) -> Result<(), ProcessConnectionError> {
+    let NewConnection {
+        connection,
+        mut bi_streams,
+        ..
+    } = new;
+    let max_streams = max_streams.max(1);
+
+    let mut streams: Vec<Stream> = vec![];
+    let mut pool: Vec<Slot> = vec![];
+    let mut next_stream = 0;
+    let mut next_id = 0;
+    let mut next_opening = 0;
+    // The transactions assigned to each stream which is still being opened
+    let mut waiting: HashMap<u64, Vec<(u64, Transaction)>> = HashMap::default();
+    let mut readers: Readers = FuturesUnordered::new();
+    let mut transactions: Transactions = FuturesUnordered::new();
+    let mut openings: Openings = FuturesUnordered::new();
+
+    loop {
+        select! {
+            // The remote application opened a new stream
+            stream = bi_streams.next() => {
+                let stream = stream.ok_or(ProcessStreamError::BiStreamsClosed)?;
+                let (send, recv) = stream.map_err(ProcessStreamError::from)?;
+
+                let index = streams.len();
+                streams.push(Stream::new(send, false));
+                readers.push(Box::pin(read_frame(Reader { index, recv })));
+            },
+            // The local thread(s) opened a new channel
+            channel = rx.recv() => {
+                let remote = channel.ok_or(ProcessChannelError::OpenChannelClosed)?;
+
+                // Streams are opened concurrently, so a stalled open doesn't hold up the other streams
+                let slot = if pool.len() < max_streams {
+                    next_opening += 1;
+                    let key = next_opening;
+                    let connection = connection.clone();
+                    openings.push(Box::pin(async move { (key, connection.open_bi().await) }));
+                    pool.push(Slot::Opening(key));
+                    Slot::Opening(key)
+                } else {
+                    next_stream = (next_stream + 1) % pool.len();
+                    pool[next_stream]
+                };
+
+                next_id += 1;
+                match slot {
+                    Slot::Open(index) => {
+                        let (_, transaction) = streams[index].start(index, next_id, remote, progress, registry);
+                        transactions.push(transaction);
+                    }
+                    Slot::Opening(key) => waiting.entry(key).or_default().push((next_id, remote)),
+                }
+            }
+            // A stream opened by this side is ready
+            opened = openings.next(), if !openings.is_empty() => {
+                let (key, opened) = match opened {
+                    Some(opened) => opened,
+                    None => continue,
+                };
+                let (send, recv) = opened.map_err(ProcessChannelError::from)?;
+
+                let index = streams.len();
+                streams.push(Stream::new(send, true));
+                readers.push(Box::pin(read_frame(Reader { index, recv })));
+                for slot in &mut pool {
+                    if *slot == Slot::Opening(key) {
+                        *slot = Slot::Open(index);
+                    }
+                }
+
+                for (id, remote) in waiting.remove(&key).unwrap_or_default() {
+                    let (_, transaction) = streams[index].start(index, id, remote, progress, registry);
+                    transactions.push(transaction);
+                }
+            }
+            // The remote application sent a frame
+            read = readers.next(), if !readers.is_empty() => {
+                let (reader, frame) = match read {
+                    Some(read) => read,
+                    None => continue,
+                };
+                let stream = &mut streams[reader.index];
+
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        if !matches!(err, RecvError::Finished) {
+                            warn!(error = %err, "multiplexed stream closed");
+                        }
+                        // Every transaction on this stream sees the end of its stream
+                        stream.routes.clear();
+                        pool.retain(|&slot| slot != Slot::Open(reader.index));
+                        continue;
+                    }
+                };
+
+                let blocked = match stream.routes.get(&frame.id) {
+                    Some(route) => match route.try_send(frame) {
+                        Ok(()) | Err(TrySendError::Closed(_)) => None,
+                        Err(TrySendError::Full(frame)) => Some((route.clone(), frame)),
+                    },
+                    // The remote application opened a new transaction
+                    None if !stream.opened_locally
+                        && frame.kind == FrameKind::Data
+                        && stream.seen.insert(frame.id) =>
+                    {
+
+                        let (local, remote) = Transaction::pair();
+                        tx.send(local).map_err(ProcessStreamError::from)?;
+
+                        let (route, transaction) = stream.start(reader.index, frame.id, remote, progress, registry);
+                        // The buffer of a new transaction is empty
+                        _ = route.try_send(frame);
+                        transactions.push(transaction);
+                        None
+                    }
+                    // A frame for a transaction which already ended
+                    None => None,
+                };
+
+                match blocked {
+                    // The buffer of the transaction is full, so stop reading the stream until it has room.
+                    // Other streams are read meanwhile.
+                    Some((route, frame)) => readers.push(Box::pin(async move {
+                        _ = route.send(frame).await;
+                        read_frame(reader).await
+                    })),
+                    None => readers.push(Box::pin(read_frame(reader))),
+                }
+            }
+            // A transaction ended
+            ended = transactions.next(), if !transactions.is_empty() => {
+                // Transactions which failed were logged by the transaction
+                if let Some((index, id)) = ended {
+                    streams[index].routes.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+impl Stream {
+    fn new(send: SendStream, opened_locally: bool) -> Self {
+        Self {
+            send: Arc::new(Mutex::new(send)),
+            opened_locally,
+            routes: Default::default(),
+            seen: Default::default(),
+        }
+    }
+
+    /// Routes the frames of transaction `id` to a new transaction on this stream, returning the route and
+    /// the future driving the transaction.
+    fn start(
+        &mut self,
+        index: usize,
+        id: u64,
+        remote: Transaction,
+        progress: &TransferProgressSender,
+        registry: &RegistryContext,
+    ) -> (Sender<Frame>, TransactionFuture) {
+        let (frames_tx, frames_rx) = mpsc::channel(FRAME_BUFFER);
+        self.routes.insert(id, frames_tx.clone());
+        let transaction = Box::pin(run_transaction(
+            index,
+            id,
+            self.send.clone(),
+            remote,
+            frames_rx,
+            progress.clone(),
+            registry.clone(),
+        ));
+        (frames_tx, transaction)
+    }
+}
+
+async fn read_frame(mut reader: Reader) -> (Reader, Result<Frame, RecvError>) {
+    let frame = read_frame_from(&mut reader.recv).await;
+    (reader, frame)
+}
+
+async fn read_frame_from<R: ReadStream>(recv: &mut R) -> Result<Frame, RecvError> {
+    let mut header = [0; HEADER_SIZE];
+    match recv.read_exact(&mut header).await {
+        Ok(()) => (),
+        Err(ReadExactError::FinishedEarly) => return Err(RecvError::Finished),
+        Err(ReadExactError::ReadError(ReadError::Reset(code))) => {
+            return Err(RecvError::Reset(code))
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    if header[0..4] != *MAGIC {
+        let mut arr = [0; 4];
+        arr.copy_from_slice(&header[0..4]);
+        return Err(RecvError::InvalidData(arr));
+    }
+
+    let id = u64::from_le_bytes(header[4..12].try_into().unwrap());
+    let kind = FrameKind::from_u8(header[12]).ok_or(RecvError::InvalidFrameKind(header[12]))?;
+    let len = u64::from_le_bytes(header[13..21].try_into().unwrap());
+    if len > MAX_PAYLOAD_LEN as u64 {
+        return Err(RecvError::TooLarge(len));
+    }
+
+    let mut payload = vec![0; len as usize];
+    recv.read_exact(&mut payload).await?;
+
+    Ok(Frame { id, kind, payload })
+}
+
+/// Reads frames from `recv` the way a multiplexed stream is read, deserializing the messages of data frames as
+/// their transaction would, until the stream ends. Returns the error which ended it, which is
+/// [`RecvError::Finished`] on a frame boundary.
+#[cfg(feature = "fuzzing")]
+pub(crate) async fn receive_frames<R: ReadStream>(
+    mut recv: R,
+    registry: &RegistryContext,
+) -> RecvError {
+    loop {
+        let frame = match read_frame_from(&mut recv).await {
+            Ok(frame) => frame,
+            Err(err) => return err,
+        };
+        if frame.kind == FrameKind::Data {
+            if let Err(err) = message::deserialize_message(&frame.payload, registry) {
+                return err.into();
+            }
+        }
+    }
+}
+
+/// Writes a frame, using `write_payload` to append its payload to the header.
+/// If `priority` is given, the shared stream takes on the priority of the frame.
+async fn write_frame(
+    send: &SharedSend,
+    buffer: &mut Vec<u8>,
+    id: u64,
+    kind: FrameKind,
+    priority: Option<i32>,
+    write_payload: impl FnOnce(&mut Vec<u8>) -> Result<(), SendError>,
+) -> Result<(), SendError> {
+    buffer.clear();
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&id.to_le_bytes());
+    buffer.push(kind as u8);
+    buffer.extend_from_slice(&0u64.to_le_bytes());
+    write_payload(buffer)?;
+    let payload_len = (buffer.len() - HEADER_SIZE) as u64;
+    buffer[13..HEADER_SIZE].copy_from_slice(&payload_len.to_le_bytes());
+
+    // Hold the lock for the whole frame so frames of different transactions don't interleave
+    let mut send = send.lock().await;
+    if let Some(priority) = priority {
+        _ = send.set_priority(priority);
+    }
+    send.write_all(buffer).await?;
+
+    Ok(())
+}
+
+async fn write_code(
+    send: &SharedSend,
+    buffer: &mut Vec<u8>,
+    id: u64,
+    kind: FrameKind,
+    code: u32,
+) -> Result<(), SendError> {
+    write_frame(send, buffer, id, kind, None, |buffer| {
+        buffer.extend_from_slice(&code.to_le_bytes());
+        Ok(())
+    })
+    .await
+}
+
+/// Receives from the channel, or waits forever once it has been taken.
+async fn recv_local(rx: &mut Option<MessageRx>) -> Option<MessageBox> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// Completes when the local threads stop listening, or waits forever once the channel has been taken.
+async fn local_closed(tx: &Option<MessageTx>) {
+    match tx {
+        Some(tx) => tx.closed().await,
+        None => future::pending().await,
+    }
+}
+
+/// Drives both directions of a single multiplexed transaction until both are closed.
+async fn run_transaction(
+    index: usize,
+    id: u64,
+    send: SharedSend,
+    transaction: Transaction,
+    frames: Receiver<Frame>,
+    progress: TransferProgressSender,
+    registry: RegistryContext,
+) -> (usize, u64) {
+    let span = super::transaction_span(&transaction.state);
+    let result = drive_transaction(id, &send, transaction, frames, &progress, &registry)
+        .instrument(span.clone())
+        .await;
+
+    match result {
+        Ok(()) | Err(MultiplexError::Send(SendError::Expired)) => (),
+        Err(err) => span.in_scope(|| warn!(error = %err, "multiplexed transaction closed")),
+    }
+
+    (index, id)
+}
+
+async fn drive_transaction(
+    id: u64,
+    send: &SharedSend,
+    Transaction { tx, rx, state }: Transaction,
+    mut frames: Receiver<Frame>,
+    progress: &TransferProgressSender,
+    registry: &RegistryContext,
+) -> Result<(), MultiplexError> {
+    let mut tx = Some(tx);
+    let mut rx = Some(rx);
+    let mut buffer = vec![];
+    let mut sending = TransferCounter::new(&state, TransferDirection::Sending, progress);
+    let mut receiving = TransferCounter::new(&state, TransferDirection::Receiving, progress);
+
+    while tx.is_some() || rx.is_some() {
+        select! {
+            // The local thread(s) sent a new message
+            msg = recv_local(&mut rx) => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    // Every sender was dropped, so nothing more can be sent
+                    None => {
+                        state.finish();
+                        write_frame(send, &mut buffer, id, FrameKind::Finish, None, |_| Ok(())).await?;
+                        rx = None;
+                        continue;
+                    }
+                };
+
+                if msg.is::<CloseTransaction>() {
+                    write_frame(send, &mut buffer, id, FrameKind::Finish, None, |_| Ok(())).await?;
+                    rx = None;
+                    continue;
+                }
+
+                let msg = match msg.downcast::<AbortTransaction>() {
+                    Ok(AbortTransaction { code }) => {
+                        write_code(send, &mut buffer, id, FrameKind::Reset, code).await?;
+                        rx = None;
+                        continue;
+                    }
+                    Err(msg) => msg,
+                };
+
+                super::record_message(&*msg);
+                let priority = state.priority_of(&*msg);
+                match msg.downcast::<TransferChunk>() {
+                    Ok(TransferChunk { bytes }) => {
+                        write_frame(send, &mut buffer, id, FrameKind::Chunk, Some(priority), |buffer| {
+                            buffer.extend_from_slice(&bytes);
+                            Ok(())
+                        })
+                        .await?;
+                        sending.chunk(bytes.len());
+                    }
+                    Err(msg) => {
+                        sending.observe(&*msg);
+                        write_frame(send, &mut buffer, id, FrameKind::Data, Some(priority), |buffer| {
+                            Ok(message::serialize_message(msg, buffer, registry)?)
+                        })
+                        .await?;
+                    }
+                }
+                state.touch();
+            }
+            // The remote application sent a frame
+            frame = frames.recv() => {
+                let frame = match frame {
+                    Some(frame) => frame,
+                    // The stream this transaction was multiplexed over closed
+                    None => {
+                        state.close_remote(None);
+                        return Ok(());
+                    }
+                };
+
+                match frame.kind {
+                    FrameKind::Data | FrameKind::Chunk => {
+                        let msg: MessageBox = if frame.kind == FrameKind::Chunk {
+                            receiving.chunk(frame.payload.len());
+                            Box::new(TransferChunk { bytes: frame.payload })
+                        } else {
+                            let msg = message::deserialize_message(&frame.payload, registry)
+                                .map_err(RecvError::from)?;
+                            receiving.observe(&*msg);
+                            msg
+                        };
+                        super::record_message(&*msg);
+                        state.touch();
+                        if let Some(local) = &tx {
+                            // Wait for the local threads to make room. Up to `FRAME_BUFFER` frames for this
+                            // transaction are buffered meanwhile before the stream is held up.
+                            let sent = select! {
+                                sent = local.send(msg) => sent,
+                                expiry = state.expired() => {
+                                    return expire(send, &mut buffer, id, expiry, rx.is_some(), true).await;
+                                }
+                            };
+                            if sent.is_err() {
+                                write_code(send, &mut buffer, id, FrameKind::Stop, state.local_code()).await?;
+                                tx = None;
+                            }
+                        }
+                    }
+                    FrameKind::Finish => {
+                        state.close_remote(None);
+                        tx = None;
+                    }
+                    FrameKind::Reset => {
+                        state.close_remote(Some(frame.code()));
+                        tx = None;
+                    }
+                    FrameKind::Stop => {
+                        state.close_remote(Some(frame.code()));
+                        rx = None;
+                    }
+                }
+            }
+            // The local thread(s) stopped listening
+            _ = local_closed(&tx) => {
+                write_code(send, &mut buffer, id, FrameKind::Stop, state.local_code()).await?;
+                tx = None;
+            }
+            expiry = state.expired() => {
+                return expire(send, &mut buffer, id, expiry, rx.is_some(), tx.is_some()).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resets and stops whichever directions of an expired transaction are still open.
+async fn expire(
+    send: &SharedSend,
+    buffer: &mut Vec<u8>,
+    id: u64,
+    expiry: Expiry,
+    sending: bool,
+    receiving: bool,
+) -> Result<(), MultiplexError> {
+    if sending {
+        write_code(send, buffer, id, FrameKind::Reset, expiry.code()).await?;
+    }
+    if receiving {
+        write_code(send, buffer, id, FrameKind::Stop, expiry.code()).await?;
+    }
+    Err(SendError::Expired.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    use crate::config::ConnectionConfig;
+    use crate::testing::{blocking, eventually, with_peers, Ping};
+
+    fn sequence(msg: MessageBox) -> u32 {
+        msg.downcast::<Ping>().unwrap().sequence
+    }
+
+    fn data_header(len: u64) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&1u64.to_le_bytes());
+        header.push(FrameKind::Data as u8);
+        header.extend_from_slice(&len.to_le_bytes());
+        header
+    }
+
+    fn multiplexed(streams: usize) -> ConnectionConfig {
+        ConnectionConfig {
+            multiplex: Some(streams),
+            ..default()
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_frames_to_their_transaction() {
+        with_peers(multiplexed(2), |a, mut b| async move {
+            // Four transactions share two streams, and their frames interleave
+            let opened: Vec<_> = (0..4).map(|_| a.open()).collect();
+            for i in 0..10 {
+                for (t, transaction) in opened.iter().enumerate() {
+                    transaction
+                        .send(Ping {
+                            sequence: t as u32 * 100 + i,
+                        })
+                        .unwrap();
+                }
+            }
+
+            let mut accepted = vec![];
+            for _ in 0..4 {
+                let mut receiver = b.accept().await;
+                let (received, receiver) = blocking(move || {
+                    let received: Vec<_> = (0..10)
+                        .map(|_| sequence(receiver.recv().unwrap()))
+                        .collect();
+                    (received, receiver)
+                })
+                .await;
+
+                let t = received[0] / 100;
+                assert_eq!(received, (0..10).map(|i| t * 100 + i).collect::<Vec<_>>());
+                receiver.send(Ping { sequence: t }).unwrap();
+                accepted.push(receiver);
+            }
+
+            // Replies are routed back over the stream the transaction was assigned to
+            for (t, mut transaction) in opened.into_iter().enumerate() {
+                let reply = blocking(move || transaction.recv()).await;
+                assert_eq!(sequence(reply.unwrap()), t as u32);
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn later_transaction_may_send_first() {
+        with_peers(multiplexed(1), |a, mut b| async move {
+            // Both transactions share the only stream, and the second is given the higher id
+            let first = a.open();
+            let second = a.open();
+
+            second.send(Ping { sequence: 2 }).unwrap();
+            let mut second_remote = b.accept().await;
+            let (received, _second_remote) =
+                blocking(move || (second_remote.recv(), second_remote)).await;
+            assert_eq!(sequence(received.unwrap()), 2);
+
+            // The first frame of the lower id arrives after the higher id's, and still opens its transaction
+            first.send(Ping { sequence: 1 }).unwrap();
+            let mut first_remote = b.accept().await;
+            let (received, _first_remote) =
+                blocking(move || (first_remote.recv(), first_remote)).await;
+            assert_eq!(sequence(received.unwrap()), 1);
+            drop((first, second));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn stalled_transaction_holds_up_only_its_stream() {
+        // More than the receiving queue and the frame buffer of the transaction hold
+        const STALLED: u32 = 400;
+
+        with_peers(multiplexed(2), |a, mut b| async move {
+            // The first transaction is assigned to the first stream, and its messages are not taken...
+            let stalled = a.open();
+            let stalled = blocking(move || {
+                for sequence in 0..STALLED {
+                    stalled.send_blocking(Ping { sequence }).unwrap();
+                }
+                stalled
+            })
+            .await;
+            let mut stalled_remote = b.accept().await;
+            eventually(|| {
+                let metrics = stalled_remote.recv_queue_metrics();
+                metrics.len == metrics.capacity
+            })
+            .await;
+
+            // ...which doesn't hold up the second, assigned to the second stream
+            let other = a.open();
+            other.send(Ping { sequence: 0 }).unwrap();
+            let mut other_remote = b.accept().await;
+            let (first, _other_remote) =
+                blocking(move || (other_remote.recv(), other_remote)).await;
+            assert_eq!(sequence(first.unwrap()), 0);
+
+            // Once the stalled transaction catches up, the rest of its messages arrive in order
+            let received = blocking(move || {
+                (0..STALLED)
+                    .map(|_| sequence(stalled_remote.recv().unwrap()))
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            assert_eq!(received, (0..STALLED).collect::<Vec<_>>());
+            drop((stalled, other));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected() {
+        // The length of a frame's payload was allocated before any of it arrived
+        for len in [u64::MAX, MAX_PAYLOAD_LEN as u64 + 1] {
+            let frame = read_frame_from(&mut data_header(len).as_slice()).await;
+            assert!(matches!(frame, Err(RecvError::TooLarge(l)) if l == len));
+        }
+
+        // The payload of the longest frame is awaited instead, and the stream ends before it
+        let frame = read_frame_from(&mut data_header(MAX_PAYLOAD_LEN as u64).as_slice()).await;
+        assert!(matches!(frame, Err(RecvError::ReadExact(_))));
+    }
+}
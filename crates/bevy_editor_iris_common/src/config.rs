@@ -0,0 +1,156 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use quinn::{TransportConfig, VarInt};
+use tokio::runtime::Handle;
+
+use crate::discovery::DiscoveryConfig;
+use crate::transport::{CertificateFingerprint, Identity};
+
+/// Configures the connection to the remote application. The remote thread reads this resource
+/// whenever it is opened, so it must be inserted before the editor or client plugin is added to take effect.
+///
+/// ## Example:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_editor_iris_common::config::ConnectionConfig;
+/// App::new().insert_resource(ConnectionConfig {
+///     multiplex: Some(4),
+///     ..Default::default()
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// The maximum number of bi-directional streams the remote application may have open at once.
+    pub max_concurrent_bidi_streams: u32,
+    /// The interval at which keep-alive packets are sent, if any.
+    pub keep_alive_interval: Option<Duration>,
+    /// When set, transactions opened by this side are multiplexed over at most this many streams,
+    /// rather than each opening a stream of its own.
+    ///
+    /// Multiplexed streams are framed differently, so both sides must agree on whether this is set.
+    /// The number of streams may differ between the two sides.
+    pub multiplex: Option<usize>,
+    /// Where the remote thread runs.
+    pub runtime: RemoteRuntime,
+    /// Whether this side listens for or dials the remote application. Defaults to listening in the editor
+    /// and dialing in the game.
+    pub role: Option<Role>,
+    /// The address the listening side listens on instead of [`server_addr`](crate::server_addr), or instead of
+    /// every interface on the same port when [discovery](ConnectionConfig::discovery) is enabled.
+    /// Must be set to an address reachable from the dialing side, such as `0.0.0.0:5001`, when it runs on another device.
+    pub listen_addr: Option<SocketAddr>,
+    /// The address the dialing side connects to instead of [`server_addr`](crate::server_addr), such as that of a
+    /// [`NetworkSimulator`](crate::simulator::NetworkSimulator).
+    pub remote_addr: Option<SocketAddr>,
+    /// When set, games announce themselves on the local network. A dialing game waits for an editor to invite it,
+    /// unless [`remote_addr`](ConnectionConfig::remote_addr) is set, and the editor lists the games it discovers.
+    /// A listening game announces where it listens instead, and a dialing editor connects to the first one it discovers.
+    /// Disabled by default.
+    pub discovery: Option<DiscoveryConfig>,
+    /// When set, [`remote_addr`](ConnectionConfig::remote_addr) is a [relay](crate::relay), and the dialing side
+    /// joins the room with this code before the connection is used. Both sides must dial the relay.
+    pub room: Option<String>,
+    /// The certificate the listening side presents. Generated when the plugin is added if unset.
+    pub identity: Option<Identity>,
+    /// The fingerprint of the certificate of the listening side or relay, which the dialing side pins.
+    /// Not needed with [discovery](ConnectionConfig::discovery), as the invite carries it. When no fingerprint
+    /// is known, the dialing side trusts the certificate the listening side writes to `certificate.der`,
+    /// which only works when both run in the same directory.
+    pub remote_fingerprint: Option<CertificateFingerprint>,
+}
+
+/// Which application this side of the connection is part of.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Side {
+    /// The editor, which listens by default.
+    Editor,
+    /// The game, which dials by default.
+    Game,
+}
+
+impl Side {
+    /// The role this side takes unless [`ConnectionConfig::role`] says otherwise.
+    pub fn default_role(self) -> Role {
+        match self {
+            Side::Editor => Role::Listen,
+            Side::Game => Role::Dial,
+        }
+    }
+
+    /// The name the side is sent as.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Side::Editor => "editor",
+            Side::Game => "game",
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Which side of the connection listens, independently of which side is the editor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// Listen for the remote application to connect.
+    Listen,
+    /// Connect to the remote application.
+    Dial,
+}
+
+/// Where the networking of the remote thread is driven.
+///
+/// quinn relies on the IO and timer drivers of a tokio runtime, so every option needs one.
+#[derive(Clone, Debug, Default)]
+pub enum RemoteRuntime {
+    /// Spawn a dedicated thread with its own single-threaded tokio runtime.
+    #[default]
+    Dedicated,
+    /// Spawn a task on an existing multi-threaded tokio runtime, sharing its worker threads and drivers
+    /// instead of creating a thread and runtime of its own.
+    Shared(Handle),
+    /// Spawn a task on bevy's [`IoTaskPool`](bevy::tasks::IoTaskPool), using the drivers of the given tokio runtime.
+    /// The runtime must be multi-threaded, so its drivers are driven by its own threads.
+    IoTaskPool(Handle),
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_bidi_streams: 100,
+            keep_alive_interval: Some(Duration::from_secs(5)),
+            multiplex: None,
+            runtime: RemoteRuntime::Dedicated,
+            role: None,
+            listen_addr: None,
+            remote_addr: None,
+            discovery: None,
+            room: None,
+            identity: None,
+            remote_fingerprint: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The [identity](ConnectionConfig::identity) of this side, generating it if it isn't set yet.
+    pub fn identity_or_generate(&mut self) -> &Identity {
+        self.identity.get_or_insert_with(|| {
+            Identity::generate().expect("failed to generate a self-signed certificate")
+        })
+    }
+
+    /// Builds the quinn [`TransportConfig`] described by this config.
+    pub fn transport_config(&self) -> TransportConfig {
+        let mut transport = TransportConfig::default();
+        transport
+            .max_concurrent_bidi_streams(VarInt::from_u32(self.max_concurrent_bidi_streams))
+            .keep_alive_interval(self.keep_alive_interval);
+        transport
+    }
+}
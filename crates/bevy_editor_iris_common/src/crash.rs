@@ -0,0 +1,45 @@
+//! Messages for reporting a panic of the game to the editor.
+//!
+//! The game keeps a transaction opened with [`OpenCrashReporter`] for as long as it runs. When it panics, it sends
+//! [`ClientPanicked`] over that transaction and waits for the editor to answer with [`CrashReportReceived`], so the
+//! report isn't lost when the process dies.
+
+use bevy_editor_iris_derive::message;
+
+use crate::session::SessionId;
+
+/// Opens the transaction the game reports a panic over.
+#[message]
+pub struct OpenCrashReporter {
+    /// The session of the game
+    pub session: SessionId,
+}
+
+/// Sent by the game when it panics.
+#[message]
+#[derive(Clone, Debug)]
+pub struct ClientPanicked {
+    /// The panic message
+    pub message: String,
+    /// The file, line and column the game panicked at, if known
+    pub location: Option<String>,
+    /// The backtrace of the panicking thread
+    pub backtrace: String,
+    /// The number of frames the game had run when it panicked
+    pub frame_count: u64,
+}
+
+/// Sent by the editor once it has received a [`ClientPanicked`] report.
+#[message]
+pub struct CrashReportReceived;
+
+crate::protocol! {
+    /// Reporting a panic of the game to the editor.
+    pub struct CrashReportProtocol {
+        name: "iris.crash",
+        version: 1,
+        initiator: Game,
+        messages: [OpenCrashReporter],
+        requests: [ClientPanicked => CrashReportReceived],
+    }
+}
@@ -0,0 +1,410 @@
+//! Opt-in discovery of games on the local network.
+//!
+//! While discovery is enabled in its [`ConnectionConfig`](crate::config::ConnectionConfig::discovery), a game
+//! waits to be invited rather than connecting to the editor straight away. Until it is invited, it
+//! [announces](Announcement) itself over UDP every [`interval`](DiscoveryConfig::interval). The editor
+//! listens for announcements with a [`DiscoveryListener`], and connecting to a game [invites](DiscoveryListener::invite)
+//! it to connect to the editor. The invite carries the [fingerprint](CertificateFingerprint) of the certificate
+//! of the editor, which the game pins.
+//!
+//! A game which [listens](crate::config::Role::Listen) can't be invited. It announces where it listens and the
+//! fingerprint of its certificate instead, and an editor which dials connects to the first one it discovers
+//! with [`wait_for_listening_game`]. Only games announce themselves, whichever side dials.
+//!
+//! Announcements are sent to a multicast group by default. They may instead be sent to a broadcast address,
+//! or to a loopback address for testing.
+
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use tokio::time;
+
+use crate::transport::CertificateFingerprint;
+
+/// Identifies packets of the discovery protocol.
+const DISCOVERY_MAGIC: &[u8; 4] = b"OBRD";
+const ANNOUNCEMENT: u8 = 0;
+const INVITE: u8 = 1;
+
+/// The largest discovery packet which is read.
+const MAX_PACKET_SIZE: usize = 1024;
+
+/// Configures discovery. Both sides must use the same [`addr`](DiscoveryConfig::addr).
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// The address games announce themselves to and the editor listens on. May be a multicast group,
+    /// a broadcast address, or a unicast address such as a loopback address.
+    pub addr: SocketAddr,
+    /// The interval at which games announce themselves.
+    pub interval: Duration,
+    /// The name games announce themselves with. Defaults to the name of the executable.
+    pub app_name: String,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        let app_name = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_stem()?.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+
+        Self {
+            addr: (Ipv4Addr::new(239, 255, 73, 82), 5003).into(),
+            interval: Duration::from_secs(1),
+            app_name,
+        }
+    }
+}
+
+/// A game announcing that it is waiting to be invited by an editor.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Announcement {
+    /// The name of the game
+    pub app_name: String,
+    /// The process id of the game
+    pub pid: u32,
+    /// The version of bevy_editor_iris the game uses
+    pub version: String,
+    /// Where the game listens, if it listens rather than waiting to be invited
+    pub listening: Option<Listening>,
+}
+
+/// Where a game which listens for the editor can be reached.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Listening {
+    /// The port the game listens on, at the address it announced from
+    pub port: u16,
+    /// The fingerprint of the certificate the game presents
+    pub fingerprint: CertificateFingerprint,
+}
+
+/// An invite from an editor to connect to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Invite {
+    /// The address the editor listens on
+    pub addr: SocketAddr,
+    /// The fingerprint of the certificate the editor presents
+    pub fingerprint: CertificateFingerprint,
+}
+
+impl Announcement {
+    fn encode(&self) -> Vec<u8> {
+        let mut packet = header(ANNOUNCEMENT);
+        packet.extend_from_slice(&self.pid.to_le_bytes());
+        encode_str(&mut packet, &self.app_name);
+        encode_str(&mut packet, &self.version);
+        if let Some(listening) = self.listening {
+            packet.extend_from_slice(&listening.port.to_le_bytes());
+            packet.extend_from_slice(&listening.fingerprint.0);
+        }
+        packet
+    }
+
+    fn decode(mut packet: &[u8]) -> Option<Self> {
+        let pid = u32::from_le_bytes(take(&mut packet, 4)?.try_into().ok()?);
+        let app_name = decode_str(&mut packet)?;
+        let version = decode_str(&mut packet)?;
+        let listening = match packet {
+            [] => None,
+            _ => Some(Listening {
+                port: u16::from_le_bytes(take(&mut packet, 2)?.try_into().ok()?),
+                fingerprint: CertificateFingerprint(take(&mut packet, 32)?.try_into().ok()?),
+            }),
+        };
+
+        Some(Self {
+            app_name,
+            pid,
+            version,
+            listening,
+        })
+    }
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut packet = DISCOVERY_MAGIC.to_vec();
+    packet.push(kind);
+    packet
+}
+
+/// Splits the kind off a packet of the discovery protocol.
+fn kind(packet: &[u8]) -> Option<(u8, &[u8])> {
+    match packet.strip_prefix(DISCOVERY_MAGIC)? {
+        [kind, rest @ ..] => Some((*kind, rest)),
+        [] => None,
+    }
+}
+
+fn encode_str(packet: &mut Vec<u8>, s: &str) {
+    // Strings are prefixed with a one byte length, so longer ones are cut short
+    let bytes = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+    packet.push(bytes.len() as u8);
+    packet.extend_from_slice(bytes);
+}
+
+fn decode_str(packet: &mut &[u8]) -> Option<String> {
+    let len = *take(packet, 1)?.first()? as usize;
+    Some(String::from_utf8_lossy(take(packet, len)?).into_owned())
+}
+
+fn take<'p>(packet: &mut &'p [u8], len: usize) -> Option<&'p [u8]> {
+    if packet.len() < len {
+        return None;
+    }
+    let (taken, rest) = packet.split_at(len);
+    *packet = rest;
+    Some(taken)
+}
+
+/// Announces this game every [`interval`](DiscoveryConfig::interval) until an editor invites it,
+/// returning the invite.
+pub async fn wait_for_invite(config: &DiscoveryConfig) -> io::Result<Invite> {
+    announce(config, None).await
+}
+
+/// Announces that this game listens as described by `listening` every [`interval`](DiscoveryConfig::interval),
+/// until the future is dropped or fails.
+pub async fn announce_listening(
+    config: &DiscoveryConfig,
+    listening: Listening,
+) -> io::Result<Infallible> {
+    loop {
+        // An editor which listens can't connect to a game which listens, so invites are ignored
+        announce(config, Some(listening)).await?;
+    }
+}
+
+/// Announces this game until an editor invites it.
+async fn announce(config: &DiscoveryConfig, listening: Option<Listening>) -> io::Result<Invite> {
+    let bind: SocketAddr = match config.addr {
+        SocketAddr::V4(addr) if addr.ip().is_loopback() => (Ipv4Addr::LOCALHOST, 0).into(),
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = tokio::net::UdpSocket::bind(bind).await?;
+    if let SocketAddr::V4(addr) = config.addr {
+        if addr.ip().is_broadcast() {
+            socket.set_broadcast(true)?;
+        }
+    }
+
+    let announcement = Announcement {
+        app_name: config.app_name.clone(),
+        pid: std::process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        listening,
+    }
+    .encode();
+
+    let mut interval = time::interval(config.interval);
+    let mut buf = [0; MAX_PACKET_SIZE];
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                socket.send_to(&announcement, config.addr).await?;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, editor) = received?;
+                if let Some((INVITE, rest)) = kind(&buf[..len]) {
+                    if let Some(invite) = decode_invite(editor, rest) {
+                        return Ok(invite);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn decode_invite(editor: SocketAddr, mut packet: &[u8]) -> Option<Invite> {
+    let port = u16::from_le_bytes(take(&mut packet, 2)?.try_into().ok()?);
+    let fingerprint = CertificateFingerprint(take(&mut packet, 32)?.try_into().ok()?);
+
+    Some(Invite {
+        addr: SocketAddr::new(editor.ip(), port),
+        fingerprint,
+    })
+}
+
+/// Listens for the announcements of games until one which [listens](Announcement::listening) is found,
+/// returning where to connect to it as if it had invited this side.
+pub async fn wait_for_listening_game(config: &DiscoveryConfig) -> io::Result<Invite> {
+    let listener = DiscoveryListener::bind(config)?;
+
+    loop {
+        for (game, announcement) in listener.poll() {
+            if let Some(listening) = announcement.listening {
+                return Ok(Invite {
+                    addr: SocketAddr::new(game.ip(), listening.port),
+                    fingerprint: listening.fingerprint,
+                });
+            }
+        }
+        time::sleep(config.interval / 4).await;
+    }
+}
+
+/// Listens for the [announcements](Announcement) of games, and invites them to connect.
+///
+/// Never blocks, so it can be polled from a system every frame.
+///
+/// ## Example:
+/// ```no_run
+/// # use bevy_editor_iris_common::discovery::{DiscoveryConfig, DiscoveryListener};
+/// # use bevy_editor_iris_common::transport::Identity;
+/// # let identity = Identity::generate().unwrap();
+/// let listener = DiscoveryListener::bind(&DiscoveryConfig::default())?;
+///
+/// for (game, announcement) in listener.poll() {
+///     println!("Found {} ({})", announcement.app_name, announcement.pid);
+///     listener.invite(game, bevy_editor_iris_common::server_addr().port(), identity.fingerprint())?;
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct DiscoveryListener {
+    socket: UdpSocket,
+}
+
+impl DiscoveryListener {
+    /// Listens on the port of [`DiscoveryConfig::addr`], joining it if it is a multicast group.
+    pub fn bind(config: &DiscoveryConfig) -> io::Result<Self> {
+        let socket = match config.addr {
+            SocketAddr::V4(addr) if addr.ip().is_multicast() => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?;
+                socket.join_multicast_v4(addr.ip(), &Ipv4Addr::UNSPECIFIED)?;
+                socket
+            }
+            SocketAddr::V4(addr) if addr.ip().is_broadcast() => {
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))?
+            }
+            addr => UdpSocket::bind(addr)?,
+        };
+        socket.set_nonblocking(true)?;
+
+        Ok(Self { socket })
+    }
+
+    /// The address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Returns the announcements received since the last poll, along with the address each was sent from.
+    pub fn poll(&self) -> Vec<(SocketAddr, Announcement)> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        let mut announcements = vec![];
+
+        // Stops once no more packets are waiting, or on any other error
+        while let Ok((len, game)) = self.socket.recv_from(&mut buf) {
+            if let Some((ANNOUNCEMENT, rest)) = kind(&buf[..len]) {
+                if let Some(announcement) = Announcement::decode(rest) {
+                    announcements.push((game, announcement));
+                }
+            }
+        }
+
+        announcements
+    }
+
+    /// Invites the game which sent an announcement from `game` to connect to the editor listening on `iris_port`
+    /// with the certificate which has `fingerprint`.
+    pub fn invite(
+        &self,
+        game: SocketAddr,
+        iris_port: u16,
+        fingerprint: CertificateFingerprint,
+    ) -> io::Result<()> {
+        let mut packet = header(INVITE);
+        packet.extend_from_slice(&iris_port.to_le_bytes());
+        packet.extend_from_slice(&fingerprint.0);
+        self.socket.send_to(&packet, game)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discover_over_loopback() {
+        let mut config = DiscoveryConfig {
+            addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            interval: Duration::from_millis(10),
+            app_name: "game".to_string(),
+        };
+        let listener = DiscoveryListener::bind(&config).unwrap();
+        config.addr = listener.local_addr().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let fingerprint = CertificateFingerprint([7; 32]);
+        let (invite, announcement) = runtime.block_on(async {
+            let invite = async {
+                loop {
+                    if let Some((game, announcement)) = listener.poll().pop() {
+                        listener.invite(game, 5001, fingerprint).unwrap();
+                        return announcement;
+                    }
+                    time::sleep(Duration::from_millis(1)).await;
+                }
+            };
+
+            tokio::join!(wait_for_invite(&config), invite)
+        });
+
+        assert_eq!(
+            invite.unwrap(),
+            Invite {
+                addr: (Ipv4Addr::LOCALHOST, 5001).into(),
+                fingerprint,
+            }
+        );
+        assert_eq!(announcement.app_name, "game");
+        assert_eq!(announcement.pid, std::process::id());
+        assert_eq!(announcement.listening, None);
+    }
+
+    #[test]
+    fn listening_games_are_found_by_dialing_editors() {
+        let mut config = DiscoveryConfig {
+            addr: (Ipv4Addr::LOCALHOST, 0).into(),
+            interval: Duration::from_millis(10),
+            app_name: "game".to_string(),
+        };
+        // Reserve a free port for the announcements, as the editor binds it itself
+        config.addr = UdpSocket::bind(config.addr)
+            .and_then(|socket| socket.local_addr())
+            .unwrap();
+        let listening = Listening {
+            port: 5001,
+            fingerprint: CertificateFingerprint([7; 32]),
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let found = runtime.block_on(async {
+            tokio::select! {
+                found = wait_for_listening_game(&config) => found,
+                Err(err) = announce_listening(&config, listening) => Err(err),
+            }
+        });
+
+        assert_eq!(
+            found.unwrap(),
+            Invite {
+                addr: (Ipv4Addr::LOCALHOST, 5001).into(),
+                fingerprint: listening.fingerprint,
+            }
+        );
+    }
+}
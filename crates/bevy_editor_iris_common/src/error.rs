@@ -1,9 +1,11 @@
-use quinn::{ConnectError, ConnectionError, ReadExactError, WriteError};
+use quinn::{ConnectError, ConnectionError, ReadExactError, VarInt, WriteError};
 use rcgen::RcgenError;
 use thiserror::Error;
 use tokio::sync::mpsc::error::TryRecvError;
 
-use crate::asynchronous::{MessageBox, MessageRx, MessageTx};
+use crate::asynchronous::MessageBox;
+use crate::interface::Transaction;
+use crate::queue::DropPolicy;
 
 /// Error that a [transaction](crate::interface::Transaction) may use
 #[derive(Debug, Error)]
@@ -11,8 +13,97 @@ pub enum TransactionError {
     /// Transaction channel closed
     #[error("transaction channel closed")]
     ChannelClosed,
+    /// The local side already finished the transaction, so nothing more can be sent
+    #[error("transaction already finished")]
+    Finished,
+    /// The transaction passed its deadline or idle timeout
+    #[error("transaction timed out")]
+    TimedOut,
+    /// The transaction was cancelled
+    #[error("transaction cancelled")]
+    Cancelled,
+    /// No message has been received yet
+    #[error("no message is ready to be received")]
+    Empty,
+    /// The outgoing queue is full, so the message was not sent
+    #[error("the outgoing queue is full")]
+    Full,
 }
 
+/// An error that occurs while sending or receiving a [transfer](crate::transfer)
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// The transaction the transfer is sent on failed
+    #[error(transparent)]
+    Transaction(#[from] TransactionError),
+    /// Failed to read the payload being sent
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The queue the transfer would be sent or received on may discard chunks, which would corrupt the payload
+    #[error("transfers must use a queue which blocks when full, not one which uses {:?}", .0)]
+    LossyQueue(DropPolicy),
+    /// The transfer ended without every byte of the payload being received
+    #[error("the transfer ended after {} of {} bytes", .received, .total)]
+    Incomplete {
+        /// The offset into the payload that was received, including the offset the transfer started at
+        received: u64,
+        /// The length of the whole payload
+        total: u64,
+    },
+    /// The sender cancelled the transfer. A later transfer can resume from this offset.
+    #[error("the transfer was cancelled at offset {}", .0)]
+    Cancelled(u64),
+    /// A message other than the next part of a transfer was received
+    #[error("expected part of a transfer but received {}", .0)]
+    UnexpectedMessage(String),
+}
+
+/// An error that occurs while opening a [subscription](crate::session::Subscription)
+#[derive(Debug, Error)]
+pub enum SubscriptionError {
+    /// The transaction could not be opened
+    #[error(transparent)]
+    Interface(#[from] InterfaceError),
+    /// The opening message could not be sent
+    #[error(transparent)]
+    Transaction(#[from] TransactionError),
+}
+
+/// An error that occurs while joining a room on a [relay](crate::relay), or while the relay pairs a connection
+#[derive(Debug, Error)]
+pub enum RelayError {
+    /// The connection to the relay was lost
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    /// Failed to send the handshake
+    #[error(transparent)]
+    Send(#[from] SendError),
+    /// Failed to receive the handshake
+    #[error(transparent)]
+    Recv(#[from] RecvError),
+    /// A message other than the expected handshake was received
+    #[error("expected a relay handshake but received {}", .0)]
+    UnexpectedMessage(String),
+}
+
+/// Two registered messages share an [id or alias](crate::message::Message::message_id), or one is
+/// the type name of another registered type
+#[derive(Debug, Error)]
+#[error("{} is the id, an alias or the type name of both {} and {}", .name, .first, .second)]
+pub struct DuplicateMessageName {
+    /// The name both types are received by
+    pub name: String,
+    /// The type which was indexed first
+    pub first: String,
+    /// The type which was indexed second
+    pub second: String,
+}
+
+/// A [certificate fingerprint](crate::transport::CertificateFingerprint) which isn't 64 hexadecimal digits
+#[derive(Debug, Error)]
+#[error("invalid certificate fingerprint {:?}", .0)]
+pub struct InvalidFingerprint(pub String);
+
 /// Error that an [interface](crate::interface::Interface) may use
 #[derive(Debug, Error)]
 pub enum InterfaceError {
@@ -21,7 +112,7 @@ pub enum InterfaceError {
     Poison,
     /// [`send`](crate::interface::Interface::send) failed
     #[error(transparent)]
-    Send(#[from] tokio::sync::mpsc::error::SendError<(MessageTx, MessageRx)>),
+    Send(#[from] tokio::sync::mpsc::error::SendError<Transaction>),
     /// [`try_recv`](crate::interface::Interface::try_recv) failed
     #[error(transparent)]
     TryRecv(#[from] TryRecvError),
@@ -48,6 +139,12 @@ pub enum RemoteThreadError {
     /// A failure occurred while using rustls.
     #[error(transparent)]
     RustlsError(#[from] rustls::Error),
+    /// Failed to join a room on a relay.
+    #[error(transparent)]
+    Relay(#[from] RelayError),
+    /// The remote thread panicked.
+    #[error("the remote thread panicked: {}", .0)]
+    Panicked(String),
     /// A miscellaneous error.
     #[error(transparent)]
     Other(#[from] Box<dyn std::error::Error + Send>),
@@ -75,7 +172,7 @@ pub enum ProcessStreamError {
     Connection(#[from] ConnectionError),
     /// Failed to send a message to the local threads.
     #[error(transparent)]
-    Send(#[from] tokio::sync::mpsc::error::SendError<(MessageTx, MessageRx)>),
+    Send(#[from] tokio::sync::mpsc::error::SendError<Transaction>),
     /// Failed to receive a message from the remote application.
     #[error(transparent)]
     Recv(#[from] RecvError),
@@ -105,6 +202,30 @@ pub enum RecvError {
     /// data is being sent.
     #[error("received malformed message header {:?}", .0)]
     InvalidData([u8; 4]),
+    /// A multiplexed frame had an unknown kind, indicating corrupt or malicious
+    /// data is being sent.
+    #[error("received multiplexed frame of unknown kind {}", .0)]
+    InvalidFrameKind(u8),
+    /// A header announced a payload longer than [`MAX_PAYLOAD_LEN`](crate::asynchronous::MAX_PAYLOAD_LEN),
+    /// indicating corrupt or malicious data is being sent.
+    #[error(
+        "received a header announcing {} bytes, more than the limit of {}",
+        .0,
+        crate::asynchronous::MAX_PAYLOAD_LEN
+    )]
+    TooLarge(u64),
+    /// The remote application finished the stream. This indicates normal operations.
+    #[error("the remote application finished this transaction")]
+    Finished,
+    /// The remote application reset the stream with an error code.
+    #[error("the remote application aborted this transaction with code {}", .0)]
+    Reset(VarInt),
+    /// The local threads stopped receiving from this transaction. This indicates normal operations.
+    #[error("the local thread closed this transaction")]
+    TransactionClosed,
+    /// The transaction timed out or was cancelled, and the stream was stopped.
+    #[error("the transaction expired")]
+    Expired,
     /// The stream unexpectedly closed before all data could be received.
     #[error(transparent)]
     ReadExact(#[from] ReadExactError),
@@ -122,31 +243,76 @@ pub enum SendError {
     /// An error occurred while serializing the message.
     #[error(transparent)]
     SerdeYamlError(#[from] serde_yaml::Error),
-    /// The local thread sent a [signal](CloseTransaction) to close this transaction, and it was closed.
+    /// The local thread sent a [signal](crate::interface::CloseTransaction) to close this transaction, and it was closed.
     /// This error should always be recovered from, as it indicates normal operations.
     #[error("the local thread closed this transaction")]
     TransactionClosed,
+    /// The local thread sent a [signal](crate::interface::AbortTransaction) to abort this transaction, and it was reset.
+    #[error("the local thread aborted this transaction with code {}", .0)]
+    TransactionAborted(u32),
+    /// The transaction timed out or was cancelled, and the stream was reset.
+    #[error("the transaction expired")]
+    Expired,
     /// Failed to write to the remote stream.
     #[error(transparent)]
     WriteError(#[from] WriteError),
 }
 
+/// An error that ends a transaction which is multiplexed with others over a shared stream.
+#[derive(Debug, Error)]
+pub enum MultiplexError {
+    /// Failed to send a message to the remote application.
+    #[error(transparent)]
+    Send(#[from] SendError),
+    /// Failed to receive a message from the remote application.
+    #[error(transparent)]
+    Recv(#[from] RecvError),
+}
+
 /// An error that occurs while deserializing a [`Message`].
 #[derive(Debug, Error)]
 pub enum MessageDeserError {
     /// An error occurred during serialization
     #[error(transparent)]
     YamlError(#[from] serde_yaml::Error),
+    /// The message refers to an anchor with an alias, which messages are never serialized with
+    #[error("the received message contains a yaml alias, which messages may not use")]
+    YamlAlias,
     /// The message type is not registered in the TypeRegistry
     #[error("the received message {} is not registered in the TypeRegistry", .0)]
     MessageNotRegistered(String),
     /// The message does not implement FromReflect or does not reflect the trait implementation
     #[error("the received message {} does not have an accessible FromReflect implementation; make sure to use #[reflect(MessageFromReflect)]", .0)]
     MessageNotFromReflect(String),
-    /// The message failed to be converted using FromReflect
-    #[error("the received message could not be converted to a concrete type: {:#?}", .0)]
+    /// The message failed to be converted using FromReflect, and could not be filled in from its default
+    #[error("the received message {} could not be converted to a concrete type; if it is missing fields, use #[message(default)]", .0)]
     FromReflectFailed(String),
     /// The message does not implement [`Message`] or does not use #\[reflect(Message)]
     #[error("the received message {} does not have an accessible Message implementation; make sure to use #[reflect(Message)] or #[message]", .0)]
     MessageNotImpl(String),
 }
+
+/// An error that occurs while applying a [`ReflectPatch`](crate::serde::ReflectPatch).
+#[derive(Debug, Error)]
+pub enum PatchError {
+    /// The value being patched has no value at the path of an operation
+    #[error("there is no value at {}", .0)]
+    InvalidPath(String),
+    /// A value of the wrong type was applied
+    #[error("a value of type {} can't be applied to a value of type {}", .found, .expected)]
+    Mismatched {
+        /// The type of the value being patched
+        expected: String,
+        /// The type of the value in the patch
+        found: String,
+    },
+    /// An entry would be added to or removed from a map or struct, a list would shrink, or an element would be
+    /// pushed onto an empty list, but the value isn't dynamic. Bevy can only resize concrete lists by appending
+    /// to them, and the element type of an empty one is unknown, so such patches must be applied with
+    /// [`ReflectPatch::rebuild`](crate::serde::ReflectPatch::rebuild).
+    #[error("{} can't be resized through reflection", .0)]
+    CannotResize(String),
+    /// The operation isn't one of [`patch_ops`](crate::serde::patch_ops)
+    #[error("unknown patch operation {}", .0)]
+    UnknownOp(String),
+}
@@ -0,0 +1,50 @@
+//! Entry points for the fuzz targets in `fuzz/`, which feed them bytes as though they were received from the
+//! remote application. Only compiled with the `fuzzing` feature.
+//!
+//! The targets are run with [cargo-fuzz](https://github.com/rust-fuzz/cargo-fuzz) from this crate's directory,
+//! for example `cargo fuzz run deserialize_message`. Each starts from the valid messages in its `fuzz/corpus`
+//! directory. Whatever they find should be fixed with a regression test in this crate, since the corpus
+//! isn't run by `cargo test`.
+
+use bevy_editor_iris_derive::message;
+use futures_lite::future;
+
+use crate::asynchronous::{self, MessageBox};
+use crate::error::RecvError;
+use crate::message::priorities;
+use crate::serde::{RegistryContext, RemoteEntity};
+
+/// Stands in for the editor's `ComponentQuery`, so the corpus can include it without depending on the editor.
+/// It's sent with the editor's type name as its [id](crate::message::Message::message_id), so its
+/// serialized form is the same.
+#[message(
+    id = "bevy_editor_iris_editor::tabs::inspector::messages::ComponentQuery",
+    priority = priorities::INTERACTIVE
+)]
+#[derive(Clone, Debug)]
+pub struct ComponentQuery {
+    /// The entity to query
+    pub entity: RemoteEntity,
+}
+
+/// Creates a registry of the built-in messages and the types they contain, like the one
+/// [`CommonPlugin`](crate::CommonPlugin) sets up alongside bevy's own registrations, and of [`ComponentQuery`].
+pub fn registry() -> RegistryContext {
+    crate::builtin_registry(|registry| registry.register::<ComponentQuery>())
+}
+
+/// Receives `bytes` as a transaction's stream, with the function the remote thread reads every transaction with.
+///
+/// Returns the messages, and the error which ended the stream. That is [`RecvError::Finished`] if the bytes end
+/// between messages.
+pub fn receive_stream(bytes: &[u8], registry: &RegistryContext) -> (Vec<MessageBox>, RecvError) {
+    future::block_on(asynchronous::receive_stream(bytes, registry))
+}
+
+/// Receives `bytes` as a multiplexed stream, with the function the remote thread reads every frame with, and
+/// deserializes the messages of its data frames.
+///
+/// Returns the error which ended the stream. That is [`RecvError::Finished`] if the bytes end between frames.
+pub fn receive_frames(bytes: &[u8], registry: &RegistryContext) -> RecvError {
+    future::block_on(asynchronous::receive_frames(bytes, registry))
+}
@@ -1,125 +1,833 @@
 use std::any::TypeId;
+use std::fmt;
+use std::future;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bevy::ecs::system::Command;
-use bevy::prelude::Commands;
-use bevy::reflect::{FromReflect, Reflect};
+use bevy::prelude::{default, Commands};
 use bevy::utils::HashMap;
-use bevy_editor_iris_derive::{message, Message};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TryRecvError;
+use bevy_editor_iris_derive::message;
+use tokio::select;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::Notify;
+use tokio::time;
 
 use crate::asynchronous::{MessageBox, MessageRx, MessageTx, OpeningReceiver, OpeningSender};
-use crate::error::{InterfaceError, TransactionError};
-use crate::message::{Message, ReflectMessage, ReflectMessageFromReflect};
+use crate::error::{InterfaceError, TransactionError, TransferError};
+use crate::message::Message;
+use crate::queue::{self, QueueConfig, QueueMetrics};
+use crate::transfer::{self, TransferEnd, TransferReader, TransferStart};
+
+/// Stream error codes sent to the remote application when a transaction is stopped or reset.
+pub mod codes {
+    /// The transaction was closed normally.
+    pub const CLOSED: u32 = 0;
+    /// The transaction passed its deadline or was idle for too long.
+    pub const TIMEOUT: u32 = 1;
+    /// The transaction was cancelled by the local application.
+    pub const CANCELLED: u32 = 2;
+}
+
+const NO_CODE: u64 = u64::MAX;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a [transaction](Transaction) within this application. Ids are not shared with the remote application.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TransactionId(u64);
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A cloneable token which cancels every [transaction](Transaction) it is attached to when cancelled.
+///
+/// ## Example:
+/// ```
+/// # use bevy_editor_iris_common::interface::CancellationToken;
+/// let token = CancellationToken::new();
+/// let clone = token.clone();
+///
+/// clone.cancel();
+///
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<CancellationInner>);
+
+#[derive(Debug, Default)]
+struct CancellationInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    /// Create a new token which has not been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel every transaction this token is attached to.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Release);
+        self.0.notify.notify_waiters();
+    }
+
+    /// Returns `true` if this token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Completes once this token is cancelled.
+    pub(crate) async fn cancelled(&self) {
+        loop {
+            let notified = self.0.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Why a transaction was ended before it was closed by either side.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Expiry {
+    TimedOut,
+    Cancelled,
+}
+
+impl Expiry {
+    /// The error code the stream is stopped and reset with.
+    pub(crate) fn code(self) -> u32 {
+        match self {
+            Expiry::TimedOut => codes::TIMEOUT,
+            Expiry::Cancelled => codes::CANCELLED,
+        }
+    }
+
+    pub(crate) fn error(self) -> TransactionError {
+        match self {
+            Expiry::TimedOut => TransactionError::TimedOut,
+            Expiry::Cancelled => TransactionError::Cancelled,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Timeouts {
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl Timeouts {
+    /// The next instant at which the transaction times out, if any.
+    fn next(&self) -> Option<Instant> {
+        let idle = self.idle_timeout.map(|idle| self.last_activity + idle);
+        match (self.deadline, idle) {
+            (Some(deadline), Some(idle)) => Some(deadline.min(idle)),
+            (deadline, idle) => deadline.or(idle),
+        }
+    }
+}
+
+/// The state of a transaction, shared between the local threads and the remote thread.
+#[derive(Debug)]
+pub(crate) struct TransactionState {
+    id: TransactionId,
+    finished: AtomicBool,
+    remote_closed: AtomicBool,
+    local_code: AtomicU32,
+    remote_code: AtomicU64,
+    timeouts: Mutex<Timeouts>,
+    token: Mutex<CancellationToken>,
+    expiry: Mutex<Option<Expiry>>,
+    /// The priority set on the transaction, overriding the priority of its messages
+    priority: Mutex<Option<i32>>,
+    /// Notified when the transaction expires or its timeouts change
+    changed: Notify,
+}
+
+impl Default for TransactionState {
+    fn default() -> Self {
+        Self {
+            id: TransactionId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            finished: AtomicBool::new(false),
+            remote_closed: AtomicBool::new(false),
+            local_code: AtomicU32::new(codes::CLOSED),
+            remote_code: AtomicU64::new(NO_CODE),
+            timeouts: Mutex::new(Timeouts {
+                deadline: None,
+                idle_timeout: None,
+                last_activity: Instant::now(),
+            }),
+            token: default(),
+            expiry: default(),
+            priority: default(),
+            changed: default(),
+        }
+    }
+}
+
+impl TransactionState {
+    pub(crate) fn id(&self) -> TransactionId {
+        self.id
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn remote_closed(&self) -> bool {
+        self.remote_closed.load(Ordering::Acquire)
+    }
+
+    /// Marks the remote side as closed, optionally recording the error code it reset the stream with.
+    pub(crate) fn close_remote(&self, code: Option<u64>) {
+        if let Some(code) = code {
+            self.remote_code.store(code, Ordering::Release);
+        }
+        self.remote_closed.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn remote_code(&self) -> Option<u64> {
+        match self.remote_code.load(Ordering::Acquire) {
+            NO_CODE => None,
+            code => Some(code),
+        }
+    }
+
+    /// The error code to stop the remote stream with once the local side stops receiving.
+    pub(crate) fn local_code(&self) -> u32 {
+        self.local_code.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_local_code(&self, code: u32) {
+        self.local_code.store(code, Ordering::Release);
+    }
+
+    pub(crate) fn expiry(&self) -> Option<Expiry> {
+        *self.expiry.lock().unwrap()
+    }
+
+    /// Like [`expiry`](Self::expiry), but also expires the transaction if a timeout elapsed or its token was
+    /// cancelled and the remote thread hasn't noticed yet. Used by the local threads.
+    pub(crate) fn check_expiry(&self) -> Option<Expiry> {
+        if let Some(expiry) = self.expiry() {
+            return Some(expiry);
+        }
+
+        let next = self.timeouts.lock().unwrap().next();
+        if next.is_some_and(|at| at <= Instant::now()) {
+            self.expire(Expiry::TimedOut);
+        } else if self.token.lock().unwrap().is_cancelled() {
+            self.expire(Expiry::Cancelled);
+        }
+        self.expiry()
+    }
+
+    /// Expires the transaction, unless it already expired for another reason.
+    pub(crate) fn expire(&self, expiry: Expiry) {
+        self.expiry.lock().unwrap().get_or_insert(expiry);
+        self.changed.notify_waiters();
+    }
+
+    /// Records that a message was sent or received, resetting the idle timeout.
+    pub(crate) fn touch(&self) {
+        self.timeouts.lock().unwrap().last_activity = Instant::now();
+    }
+
+    fn update_timeouts(&self, f: impl FnOnce(&mut Timeouts)) {
+        f(&mut self.timeouts.lock().unwrap());
+        self.changed.notify_waiters();
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        self.update_timeouts(|timeouts| timeouts.deadline = deadline);
+    }
+
+    fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.update_timeouts(|timeouts| {
+            timeouts.idle_timeout = timeout;
+            timeouts.last_activity = Instant::now();
+        });
+    }
+
+    fn set_token(&self, token: CancellationToken) {
+        *self.token.lock().unwrap() = token;
+        self.changed.notify_waiters();
+    }
+
+    /// The priority `msg` should be sent with.
+    pub(crate) fn priority_of(&self, msg: &dyn Message) -> i32 {
+        self.priority
+            .lock()
+            .unwrap()
+            .unwrap_or_else(|| msg.priority())
+    }
+
+    fn set_priority(&self, priority: Option<i32>) {
+        *self.priority.lock().unwrap() = priority;
+    }
+
+    /// Completes once the transaction expires, either because a timeout elapsed or because
+    /// it was cancelled. Must be polled from the remote thread.
+    pub(crate) async fn expired(&self) -> Expiry {
+        loop {
+            let changed = self.changed.notified();
+            if let Some(expiry) = self.expiry() {
+                return expiry;
+            }
+
+            let next = self.timeouts.lock().unwrap().next();
+            let token = self.token.lock().unwrap().clone();
+            let timer = async move {
+                match next {
+                    Some(at) => time::sleep_until(time::Instant::from_std(at)).await,
+                    None => future::pending().await,
+                }
+            };
+
+            select! {
+                _ = changed => (),
+                _ = timer => {
+                    // The idle timeout may have been pushed back while sleeping
+                    let next = self.timeouts.lock().unwrap().next();
+                    if next.is_some_and(|at| at <= Instant::now()) {
+                        self.expire(Expiry::TimedOut);
+                    }
+                }
+                _ = token.cancelled() => self.expire(Expiry::Cancelled),
+            }
+        }
+    }
+}
 
 /// An interface to send and receive [messages](Message) to/from the remote application
+///
+/// A transaction can be closed in three ways:
+/// - [`finish`](Transaction::finish) half-closes it; nothing more will be sent, but messages can still be received.
+/// - [`close`](Transaction::close) finishes it and stops receiving.
+/// - [`abort`](Transaction::abort) resets both directions with an error code.
+///
+/// Each of these is reported to the remote application as the end of the stream.
+///
+/// A transaction can also be given a [deadline](Transaction::set_deadline), an [idle timeout](Transaction::set_idle_timeout),
+/// or a [cancellation token](CancellationToken). When one of these ends the transaction, the stream is reset with
+/// [`codes::TIMEOUT`] or [`codes::CANCELLED`], and every way of sending or receiving returns [`TransactionError::TimedOut`]
+/// or [`TransactionError::Cancelled`], even if messages were received before the transaction expired. The same applies
+/// to the [`TransactionSender`] and [`TransactionReceiver`] a transaction is [split](Transaction::split) into.
+///
+/// Messages are buffered in a bounded [queue](crate::queue) in each direction. By default, [`send`](Transaction::send)
+/// fails with [`TransactionError::Full`] while the outgoing queue is full, and [`send_blocking`](Transaction::send_blocking)
+/// waits for space; use [`set_send_queue`](Transaction::set_send_queue) to drop or coalesce messages instead.
+///
+/// Messages are sent with their [priority](Message::priority) unless the transaction has a
+/// [priority](Transaction::set_priority) of its own.
 pub struct Transaction {
-    tx: MessageTx,
-    rx: MessageRx,
+    pub(crate) tx: MessageTx,
+    pub(crate) rx: MessageRx,
+    pub(crate) state: Arc<TransactionState>,
 }
 
 /// A [cloneable](Clone) interface to send [messages](Message) to the remote application
 #[derive(Clone)]
 pub struct TransactionSender {
     tx: MessageTx,
+    state: Arc<TransactionState>,
 }
 
 /// An interface to receive [messages](Message) from the remote application
 pub struct TransactionReceiver {
     rx: MessageRx,
+    state: Arc<TransactionState>,
 }
 
 impl Transaction {
+    /// Creates the two ends of a transaction. One is kept by the local threads,
+    /// the other is handed to the remote thread.
+    pub(crate) fn pair() -> (Transaction, Transaction) {
+        let (tx, remote_rx) = queue::channel(QueueConfig::default());
+        let (remote_tx, rx) = queue::channel(QueueConfig::default());
+        let state = Arc::new(TransactionState::default());
+
+        (
+            Transaction {
+                tx,
+                rx,
+                state: state.clone(),
+            },
+            Transaction {
+                tx: remote_tx,
+                rx: remote_rx,
+                state,
+            },
+        )
+    }
+
+    /// Returns the id of this transaction, which identifies it in [`TransferProgress`](crate::transfer::TransferProgress) events.
+    #[inline]
+    pub fn id(&self) -> TransactionId {
+        self.state.id()
+    }
+
     /// Returns `true` if the sender has been closed or the receiver has been dropped.
     #[inline]
     pub fn sender_is_closed(&self) -> bool {
         self.tx.is_closed()
     }
 
+    /// Returns `true` if the local side has finished sending on this transaction.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// Returns `true` if the remote application has finished or aborted its side of this transaction.
+    /// Messages which were received before the remote side closed can still be received.
+    #[inline]
+    pub fn remote_closed(&self) -> bool {
+        self.state.remote_closed()
+    }
+
+    /// Returns the error code the remote application aborted this transaction with, if it did.
+    #[inline]
+    pub fn remote_error_code(&self) -> Option<u64> {
+        self.state.remote_code()
+    }
+
+    /// Set an instant after which the transaction is ended with [`TransactionError::TimedOut`].
+    /// `None` removes the deadline.
+    pub fn set_deadline(&self, deadline: impl Into<Option<Instant>>) {
+        self.state.set_deadline(deadline.into());
+    }
+
+    /// End the transaction with [`TransactionError::TimedOut`] if no message is sent or received for `timeout`.
+    /// `None` removes the idle timeout.
+    pub fn set_idle_timeout(&self, timeout: impl Into<Option<Duration>>) {
+        self.state.set_idle_timeout(timeout.into());
+    }
+
+    /// Attach a [`CancellationToken`] to this transaction, replacing the one it had.
+    /// Cancelling the token ends the transaction with [`TransactionError::Cancelled`].
+    pub fn cancel_with(&self, token: CancellationToken) {
+        self.state.set_token(token);
+    }
+
+    /// Cancel this transaction only, without cancelling the token attached to it.
+    pub fn cancel(&self) {
+        self.state.expire(Expiry::Cancelled);
+    }
+
+    /// Returns [`TransactionError::TimedOut`] or [`TransactionError::Cancelled`] if the transaction ended
+    /// for either of those reasons.
+    pub fn expiry(&self) -> Option<TransactionError> {
+        self.state.check_expiry().map(Expiry::error)
+    }
+
+    /// Send every message of this transaction with `priority`, rather than the [priority](Message::priority) of
+    /// each message. `None` restores the priorities of the messages.
+    pub fn set_priority(&self, priority: impl Into<Option<i32>>) {
+        self.state.set_priority(priority.into());
+    }
+
+    /// Replace the [config](QueueConfig) of the queue of messages waiting to be sent to the remote application.
+    pub fn set_send_queue(&self, config: QueueConfig) {
+        self.tx.set_config(config);
+    }
+
+    /// Replace the [config](QueueConfig) of the queue of messages received from the remote application.
+    /// While this queue is full and uses [`DropPolicy::Block`](crate::queue::DropPolicy::Block), the remote
+    /// thread stops reading from the stream, slowing down the remote application.
+    pub fn set_recv_queue(&self, config: QueueConfig) {
+        self.rx.set_config(config);
+    }
+
+    /// Returns the [metrics](QueueMetrics) of the queue of messages waiting to be sent.
+    pub fn send_queue_metrics(&self) -> QueueMetrics {
+        self.tx.metrics()
+    }
+
+    /// Returns the [metrics](QueueMetrics) of the queue of messages received but not yet taken.
+    pub fn recv_queue_metrics(&self) -> QueueMetrics {
+        self.rx.metrics()
+    }
+
+    /// Half-close the transaction. The remote application will see the end of the stream
+    /// once all previously sent messages have been received, while this side can keep receiving.
+    pub fn finish(&self) -> Result<(), TransactionError> {
+        finish(&self.tx, &self.state)
+    }
+
+    /// Finish the transaction and stop receiving from it.
+    pub fn close(self) -> Result<(), TransactionError> {
+        self.finish()
+    }
+
+    /// Reset both directions of the transaction with an application-defined error code.
+    /// Messages which have not been sent yet are discarded.
+    pub fn abort(self, code: u32) -> Result<(), TransactionError> {
+        self.state.set_local_code(code);
+        self.state.finish();
+        // Control messages are queued even when the queue is full
+        self.tx
+            .try_send(Box::new(AbortTransaction { code }))
+            .map_err(|_| TransactionError::ChannelClosed)
+    }
+
     /// Split the transaction into a sender and receiver, allowing
     /// the sender to be cloned and the functionality to be separated
     #[inline]
     pub fn split(self) -> (TransactionSender, TransactionReceiver) {
         (
-            TransactionSender { tx: self.tx },
-            TransactionReceiver { rx: self.rx },
+            TransactionSender {
+                tx: self.tx,
+                state: self.state.clone(),
+            },
+            TransactionReceiver {
+                rx: self.rx,
+                state: self.state,
+            },
         )
     }
 
-    /// Block until a [message](Message) is received
+    /// Block until a [message](Message) is received. Returns [`TransactionError::TimedOut`] or
+    /// [`TransactionError::Cancelled`] if the transaction expired, and [`TransactionError::ChannelClosed`]
+    /// once no more messages can be received otherwise.
     #[inline]
-    pub fn recv(&mut self) -> Option<MessageBox> {
-        self.rx.blocking_recv()
+    pub fn recv(&mut self) -> Result<MessageBox, TransactionError> {
+        recv(&mut self.rx, &self.state)
     }
 
-    /// Attempt to receive a [message](Message) or return
-    /// a [TryRecvError] on failure
+    /// Attempt to receive a [message](Message) without blocking. Returns [`TransactionError::Empty`] if none is
+    /// ready yet, and otherwise fails like [`recv`](Transaction::recv).
     #[inline]
-    pub fn try_recv(&mut self) -> Result<MessageBox, TryRecvError> {
-        self.rx.try_recv()
+    pub fn try_recv(&mut self) -> Result<MessageBox, TransactionError> {
+        try_recv(&mut self.rx, &self.state)
     }
 
-    /// Send a message to the remote application through this transaction.
-    /// Returns [`TransactionError::ChannelClosed`] if the transaction channel is closed.
+    /// Send a message to the remote application through this transaction without blocking. Returns
+    /// [`TransactionError::Full`] if the outgoing queue is full, and [`TransactionError::ChannelClosed`] if the
+    /// transaction channel is closed.
     #[inline]
     pub fn send<M: Message>(&self, message: M) -> Result<(), TransactionError> {
-        self.tx
-            .send(Box::new(message))
-            .map_err(|_| TransactionError::ChannelClosed)
+        send(&self.tx, &self.state, message)
+    }
+
+    /// Like [`send`](Transaction::send), but blocks until there is space in the outgoing queue.
+    /// Called from a system, this stalls the frame until the remote application catches up.
+    #[inline]
+    pub fn send_blocking<M: Message>(&self, message: M) -> Result<(), TransactionError> {
+        send_blocking(&self.tx, &self.state, message)
+    }
+
+    /// Send everything read from `reader` as a chunked [transfer](crate::transfer) described by `start`,
+    /// blocking until every chunk is queued. If `start.offset` is non-zero, `reader` should begin at that offset.
+    ///
+    /// If `token` is cancelled, the transfer ends early and the returned [`TransferEnd`] records the offset
+    /// to resume from.
+    ///
+    /// Fails with [`TransferError::LossyQueue`] unless the outgoing queue uses [`DropPolicy::Block`](queue::DropPolicy::Block),
+    /// as a discarded chunk would corrupt the payload.
+    pub fn send_transfer(
+        &self,
+        reader: impl Read,
+        start: TransferStart,
+        token: Option<&CancellationToken>,
+    ) -> Result<TransferEnd, TransferError> {
+        transfer::send_transfer(&self.tx, &self.state, reader, start, token)
+    }
+
+    /// Block until a [`TransferStart`] is received, and return a reader over the payload of the transfer.
+    ///
+    /// Fails with [`TransferError::LossyQueue`] unless the incoming queue uses [`DropPolicy::Block`](queue::DropPolicy::Block).
+    pub fn recv_transfer(&mut self) -> Result<TransferReader<'_>, TransferError> {
+        transfer::recv_transfer(&mut self.rx, &self.state)
     }
 
     /// Get an iterator over incoming messages. Stops when an empty message is encountered
     /// or the transaction stream is closed.
-    pub fn iter(&mut self) -> TransactionIterator {
-        TransactionIterator { rx: &mut self.rx }
+    pub fn iter(&mut self) -> TransactionIterator<'_> {
+        TransactionIterator {
+            rx: &mut self.rx,
+            state: &self.state,
+        }
     }
 }
 
-/// An iterator over the incoming messages of a transaction
+/// An iterator over the incoming messages of a transaction. Ends early if the transaction expired.
 pub struct TransactionIterator<'rx> {
     rx: &'rx mut MessageRx,
+    state: &'rx TransactionState,
 }
 
 impl<'rx> Iterator for TransactionIterator<'rx> {
     type Item = MessageBox;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(msg) = self.rx.try_recv() {
-            Some(msg)
-        } else {
-            None
-        }
+        try_recv(self.rx, self.state).ok()
     }
 }
 
 impl TransactionSender {
-    /// Send a message to the remote application through this transaction.
-    /// Returns [TransactionError::ChannelClosed] if the transaction channel is closed.
+    /// See [`Transaction::send`].
     #[inline]
     pub fn send<M: Message>(&self, message: M) -> Result<(), TransactionError> {
-        self.tx
-            .send(Box::new(message))
-            .map_err(|_| TransactionError::ChannelClosed)
+        send(&self.tx, &self.state, message)
+    }
+
+    /// See [`Transaction::send_blocking`].
+    #[inline]
+    pub fn send_blocking<M: Message>(&self, message: M) -> Result<(), TransactionError> {
+        send_blocking(&self.tx, &self.state, message)
+    }
+
+    /// Returns `true` if the local side has finished sending on this transaction.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// See [`Transaction::id`].
+    #[inline]
+    pub fn id(&self) -> TransactionId {
+        self.state.id()
+    }
+
+    /// See [`Transaction::send_transfer`].
+    pub fn send_transfer(
+        &self,
+        reader: impl Read,
+        start: TransferStart,
+        token: Option<&CancellationToken>,
+    ) -> Result<TransferEnd, TransferError> {
+        transfer::send_transfer(&self.tx, &self.state, reader, start, token)
+    }
+
+    /// Half-close the transaction. See [`Transaction::finish`].
+    pub fn finish(&self) -> Result<(), TransactionError> {
+        finish(&self.tx, &self.state)
+    }
+
+    /// See [`Transaction::set_priority`].
+    pub fn set_priority(&self, priority: impl Into<Option<i32>>) {
+        self.state.set_priority(priority.into());
+    }
+
+    /// See [`Transaction::set_deadline`].
+    pub fn set_deadline(&self, deadline: impl Into<Option<Instant>>) {
+        self.state.set_deadline(deadline.into());
+    }
+
+    /// See [`Transaction::set_idle_timeout`].
+    pub fn set_idle_timeout(&self, timeout: impl Into<Option<Duration>>) {
+        self.state.set_idle_timeout(timeout.into());
+    }
+
+    /// See [`Transaction::cancel_with`].
+    pub fn cancel_with(&self, token: CancellationToken) {
+        self.state.set_token(token);
+    }
+
+    /// See [`Transaction::cancel`].
+    pub fn cancel(&self) {
+        self.state.expire(Expiry::Cancelled);
+    }
+
+    /// See [`Transaction::expiry`].
+    pub fn expiry(&self) -> Option<TransactionError> {
+        self.state.check_expiry().map(Expiry::error)
+    }
+
+    /// See [`Transaction::set_send_queue`].
+    pub fn set_queue(&self, config: QueueConfig) {
+        self.tx.set_config(config);
+    }
+
+    /// See [`Transaction::send_queue_metrics`].
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.tx.metrics()
     }
 }
 
 impl TransactionReceiver {
-    /// Block until a [message](Message) is received
+    /// Returns `true` if the remote application has finished or aborted its side of this transaction.
+    #[inline]
+    pub fn remote_closed(&self) -> bool {
+        self.state.remote_closed()
+    }
+
+    /// See [`Transaction::id`].
     #[inline]
-    pub fn recv(&mut self) -> Option<MessageBox> {
-        self.rx.blocking_recv()
+    pub fn id(&self) -> TransactionId {
+        self.state.id()
+    }
+
+    /// See [`Transaction::recv_transfer`].
+    pub fn recv_transfer(&mut self) -> Result<TransferReader<'_>, TransferError> {
+        transfer::recv_transfer(&mut self.rx, &self.state)
     }
 
-    /// Attempt to receive a [message](Message) or return
-    /// a [TryRecvError] on failure
+    /// Block until a [message](Message) is received. Returns [`TransactionError::TimedOut`] or
+    /// [`TransactionError::Cancelled`] if the transaction expired, and [`TransactionError::ChannelClosed`]
+    /// once no more messages can be received otherwise.
     #[inline]
-    pub fn try_recv(&mut self) -> Result<MessageBox, TryRecvError> {
-        self.rx.try_recv()
+    pub fn recv(&mut self) -> Result<MessageBox, TransactionError> {
+        recv(&mut self.rx, &self.state)
     }
+
+    /// See [`Transaction::try_recv`].
+    #[inline]
+    pub fn try_recv(&mut self) -> Result<MessageBox, TransactionError> {
+        try_recv(&mut self.rx, &self.state)
+    }
+
+    /// See [`Transaction::iter`].
+    pub fn iter(&mut self) -> TransactionIterator<'_> {
+        TransactionIterator {
+            rx: &mut self.rx,
+            state: &self.state,
+        }
+    }
+
+    /// See [`Transaction::set_deadline`].
+    pub fn set_deadline(&self, deadline: impl Into<Option<Instant>>) {
+        self.state.set_deadline(deadline.into());
+    }
+
+    /// See [`Transaction::set_idle_timeout`].
+    pub fn set_idle_timeout(&self, timeout: impl Into<Option<Duration>>) {
+        self.state.set_idle_timeout(timeout.into());
+    }
+
+    /// See [`Transaction::cancel_with`].
+    pub fn cancel_with(&self, token: CancellationToken) {
+        self.state.set_token(token);
+    }
+
+    /// See [`Transaction::cancel`].
+    pub fn cancel(&self) {
+        self.state.expire(Expiry::Cancelled);
+    }
+
+    /// See [`Transaction::expiry`].
+    pub fn expiry(&self) -> Option<TransactionError> {
+        self.state.check_expiry().map(Expiry::error)
+    }
+
+    /// See [`Transaction::set_recv_queue`].
+    pub fn set_queue(&self, config: QueueConfig) {
+        self.rx.set_config(config);
+    }
+
+    /// See [`Transaction::recv_queue_metrics`].
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.rx.metrics()
+    }
+}
+
+pub(crate) fn send<M: Message>(
+    tx: &MessageTx,
+    state: &TransactionState,
+    message: M,
+) -> Result<(), TransactionError> {
+    send_boxed(tx, state, Box::new(message))
 }
 
-type MessageCallback = Box<dyn FnMut(MessageBox, Commands)>;
+pub(crate) fn send_boxed(
+    tx: &MessageTx,
+    state: &TransactionState,
+    message: MessageBox,
+) -> Result<(), TransactionError> {
+    check_sendable(state)?;
+
+    tx.try_send(message).map_err(|err| match err {
+        TrySendError::Full(_) => TransactionError::Full,
+        TrySendError::Closed(_) => TransactionError::ChannelClosed,
+    })
+}
+
+pub(crate) fn send_blocking<M: Message>(
+    tx: &MessageTx,
+    state: &TransactionState,
+    message: M,
+) -> Result<(), TransactionError> {
+    check_sendable(state)?;
+
+    // The remote thread closes the channel when the transaction expires, which may happen while this waits
+    tx.blocking_send(Box::new(message)).map_err(|_| {
+        state
+            .check_expiry()
+            .map_or(TransactionError::ChannelClosed, |expiry| expiry.error())
+    })
+}
+
+fn check_sendable(state: &TransactionState) -> Result<(), TransactionError> {
+    if let Some(expiry) = state.check_expiry() {
+        return Err(expiry.error());
+    }
+
+    if state.is_finished() {
+        return Err(TransactionError::Finished);
+    }
+
+    Ok(())
+}
+
+/// Once the transaction expires, the remote thread drops its end of `rx`, which wakes up a blocked receiver.
+pub(crate) fn recv(
+    rx: &mut MessageRx,
+    state: &TransactionState,
+) -> Result<MessageBox, TransactionError> {
+    if let Some(expiry) = state.check_expiry() {
+        return Err(expiry.error());
+    }
+
+    let msg = rx.blocking_recv();
+    // A message which was queued as the transaction expired is dropped too
+    if let Some(expiry) = state.expiry() {
+        return Err(expiry.error());
+    }
+    msg.ok_or(TransactionError::ChannelClosed)
+}
+
+pub(crate) fn try_recv(
+    rx: &mut MessageRx,
+    state: &TransactionState,
+) -> Result<MessageBox, TransactionError> {
+    if let Some(expiry) = state.check_expiry() {
+        return Err(expiry.error());
+    }
+
+    rx.try_recv().map_err(|err| match err {
+        TryRecvError::Empty => TransactionError::Empty,
+        TryRecvError::Disconnected => state
+            .expiry()
+            .map_or(TransactionError::ChannelClosed, Expiry::error),
+    })
+}
+
+fn finish(tx: &MessageTx, state: &TransactionState) -> Result<(), TransactionError> {
+    if state.is_finished() {
+        return Ok(());
+    }
+
+    state.finish();
+    // Control messages are queued even when the queue is full
+    tx.try_send(Box::new(CloseTransaction))
+        .map_err(|_| TransactionError::ChannelClosed)
+}
+
+type MessageCallback = Box<dyn FnMut(MessageBox, Commands) + Send>;
 
 pub(crate) struct InternalInterface {
     pub(crate) open_tx: OpeningSender,
@@ -133,7 +841,7 @@ pub(crate) struct InternalInterface {
 /// Represents the communication interface between the remote thread
 /// and local threads.
 pub struct Interface {
-    pub(crate) inner: Mutex<InternalInterface>,
+    pub(crate) inner: Arc<Mutex<InternalInterface>>,
 }
 
 impl Interface {
@@ -152,34 +860,38 @@ impl Interface {
     /// Attempts to retrieve the next [transaction](Transaction) stream. Fails if no transaction is ready yet.
     /// or if the transaction channel was disconnected
     pub(crate) fn try_recv(&mut self) -> Result<Transaction, InterfaceError> {
-        let mut lock = self.inner.get_mut().map_err(|_| InterfaceError::Poison)?;
-
-        let (tx, rx) = lock.open_rx.try_recv()?;
+        let mut lock = self.inner.lock().map_err(|_| InterfaceError::Poison)?;
 
-        Ok(Transaction { tx, rx })
+        Ok(lock.open_rx.try_recv()?)
     }
 
     /// Attempts to open a new [transaction](Transaction) stream. Fails if the transaction channel was disconnected.
     pub(crate) fn open_transaction(&mut self) -> Result<Transaction, InterfaceError> {
-        let lock = self.inner.get_mut().map_err(|_| InterfaceError::Poison)?;
+        let lock = self.inner.lock().map_err(|_| InterfaceError::Poison)?;
 
-        let (tx, remote_rx) = mpsc::unbounded_channel();
-        let (remote_tx, rx) = mpsc::unbounded_channel();
+        let (local, remote) = Transaction::pair();
 
-        lock.open_tx.send((remote_tx, remote_rx))?;
+        lock.open_tx.send(remote)?;
 
-        Ok(Transaction { tx, rx })
+        Ok(local)
     }
 
     /// Register a callback for a particular message type. This callback will be called at some point after receiving a message of that type.
-    pub fn register_callback<M: Message>(&mut self, callback: impl FnMut(M, Commands)) {
-        let callback = |msg: MessageBox, c| (callback)(msg.downcast().unwrap(), c);
+    /// Fails if the interface has been poisoned.
+    pub fn register_callback<M: Message>(
+        &mut self,
+        mut callback: impl 'static + FnMut(M, Commands) + Send,
+    ) -> Result<(), InterfaceError> {
+        let callback: MessageCallback =
+            Box::new(move |msg: MessageBox, c| (callback)(msg.downcast().unwrap(), c));
 
-        let lock = self.inner.get_mut().map_err(|_| InterfaceError::Poison)?;
+        let mut lock = self.inner.lock().map_err(|_| InterfaceError::Poison)?;
 
         let old = lock.callbacks.insert(TypeId::of::<M>(), callback);
         // TODO: Might be a better way to handle this.
         assert!(old.is_none());
+
+        Ok(())
     }
 }
 
@@ -192,6 +904,193 @@ impl Clone for Interface {
 }
 
 /// A special message built into the editor.
-/// When sent, closes a transaction on the local side.
+/// When sent, finishes the transaction's stream, which the remote application sees as the end of the stream.
+/// Prefer [`Transaction::finish`] over sending this directly.
 #[message]
 pub struct CloseTransaction;
+
+/// A special message built into the editor.
+/// When sent, resets both directions of the transaction's stream with `code`.
+/// Prefer [`Transaction::abort`] over sending this directly.
+#[message]
+pub struct AbortTransaction {
+    /// The application-defined error code sent to the remote application
+    pub code: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{blocking, eventually, with_peers, Ping};
+
+    fn sequence(msg: MessageBox) -> u32 {
+        msg.downcast::<Ping>().unwrap().sequence
+    }
+
+    #[tokio::test]
+    async fn finish_half_closes() {
+        with_peers(default(), |a, mut b| async move {
+            let mut sender = a.open();
+            sender.send(Ping { sequence: 1 }).unwrap();
+            sender.finish().unwrap();
+            assert!(matches!(
+                sender.send(Ping { sequence: 2 }),
+                Err(TransactionError::Finished)
+            ));
+
+            // The message arrives before the end of the stream, which the quinn stream was finished with
+            let mut receiver = b.accept().await;
+            let (receiver, first, end) = blocking(move || {
+                let first = receiver.recv();
+                let end = receiver.recv();
+                (receiver, first, end)
+            })
+            .await;
+            assert_eq!(sequence(first.unwrap()), 1);
+            assert!(matches!(end, Err(TransactionError::ChannelClosed)));
+            assert!(receiver.remote_closed());
+            assert_eq!(receiver.remote_error_code(), None);
+
+            // The finished side can still receive
+            receiver.send(Ping { sequence: 3 }).unwrap();
+            let reply = blocking(move || sender.recv()).await;
+            assert_eq!(sequence(reply.unwrap()), 3);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn close_stops_receiving() {
+        with_peers(default(), |a, mut b| async move {
+            let closed = a.open();
+            closed.send(Ping { sequence: 1 }).unwrap();
+            let mut receiver = b.accept().await;
+            closed.close().unwrap();
+
+            let (mut receiver, first, end) = blocking(move || {
+                let first = receiver.recv();
+                let end = receiver.recv();
+                (receiver, first, end)
+            })
+            .await;
+            assert_eq!(sequence(first.unwrap()), 1);
+            assert!(matches!(end, Err(TransactionError::ChannelClosed)));
+
+            // The closed side stopped its quinn stream, which the other side sees once it writes
+            eventually(|| {
+                _ = receiver.send(Ping { sequence: 2 });
+                receiver.remote_error_code().is_some()
+            })
+            .await;
+            assert_eq!(receiver.remote_error_code(), Some(codes::CLOSED as u64));
+            assert!(receiver.try_recv().is_err());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn abort_resets_both_directions() {
+        with_peers(default(), |a, mut b| async move {
+            let aborted = a.open();
+            aborted.send(Ping { sequence: 1 }).unwrap();
+            let mut receiver = b.accept().await;
+            let (first, mut receiver) = blocking(move || (receiver.recv(), receiver)).await;
+            assert_eq!(sequence(first.unwrap()), 1);
+
+            aborted.abort(7).unwrap();
+
+            // The quinn stream was reset with the code...
+            let (end, receiver) = blocking(move || (receiver.recv(), receiver)).await;
+            assert!(matches!(end, Err(TransactionError::ChannelClosed)));
+            assert!(receiver.remote_closed());
+            assert_eq!(receiver.remote_error_code(), Some(7));
+
+            // ...and stopped, so nothing more can be sent to the aborted side
+            eventually(|| receiver.send(Ping { sequence: 2 }).is_err()).await;
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn deadline_expires() {
+        with_peers(default(), |a, mut b| async move {
+            let mut expiring = a.open();
+            expiring.send(Ping { sequence: 1 }).unwrap();
+            expiring.set_deadline(Instant::now() + Duration::from_millis(50));
+
+            let (expired, expiring) = blocking(move || (expiring.recv(), expiring)).await;
+            assert!(matches!(expired, Err(TransactionError::TimedOut)));
+            assert!(matches!(
+                expiring.send(Ping { sequence: 2 }),
+                Err(TransactionError::TimedOut)
+            ));
+
+            // The remote application sees the stream reset with the timeout code
+            let mut receiver = b.accept().await;
+            let (first, mut receiver) = blocking(move || (receiver.recv(), receiver)).await;
+            assert_eq!(sequence(first.unwrap()), 1);
+            let (end, receiver) = blocking(move || (receiver.recv(), receiver)).await;
+            assert!(matches!(end, Err(TransactionError::ChannelClosed)));
+            assert_eq!(receiver.remote_error_code(), Some(codes::TIMEOUT as u64));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_expires_split_halves() {
+        with_peers(default(), |a, _b| async move {
+            let (sender, mut receiver) = a.open().split();
+            receiver.set_idle_timeout(Duration::from_millis(200));
+
+            // Sending a message resets the idle timeout
+            for sequence in 0..5 {
+                sender.send(Ping { sequence }).unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            assert!(receiver.expiry().is_none());
+
+            let (expired, mut receiver) = blocking(move || (receiver.recv(), receiver)).await;
+            assert!(matches!(expired, Err(TransactionError::TimedOut)));
+            assert!(matches!(
+                receiver.try_recv(),
+                Err(TransactionError::TimedOut)
+            ));
+            assert!(receiver.iter().next().is_none());
+            assert!(matches!(sender.expiry(), Some(TransactionError::TimedOut)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn token_cancels() {
+        with_peers(default(), |a, mut b| async move {
+            let token = CancellationToken::new();
+            let transaction = a.open();
+            transaction.send(Ping { sequence: 1 }).unwrap();
+            let (sender, mut receiver) = transaction.split();
+            sender.cancel_with(token.clone());
+
+            let mut remote = b.accept().await;
+            let (first, remote) = blocking(move || (remote.recv(), remote)).await;
+            assert_eq!(sequence(first.unwrap()), 1);
+            remote.send(Ping { sequence: 2 }).unwrap();
+
+            token.cancel();
+            assert!(matches!(
+                sender.send(Ping { sequence: 3 }),
+                Err(TransactionError::Cancelled)
+            ));
+            // Even a message which already arrived isn't received once the transaction is cancelled
+            assert!(matches!(
+                receiver.try_recv(),
+                Err(TransactionError::Cancelled)
+            ));
+            let expired = blocking(move || receiver.recv()).await;
+            assert!(matches!(expired, Err(TransactionError::Cancelled)));
+
+            eventually(|| remote.remote_error_code().is_some()).await;
+            assert_eq!(remote.remote_error_code(), Some(codes::CANCELLED as u64));
+        })
+        .await;
+    }
+}
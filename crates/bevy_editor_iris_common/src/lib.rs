@@ -3,52 +3,106 @@
 //!
 //! This crate mainly provides the networking infrastructure of the editor.
 //! At a high level:
-//! - Messages are represented as reflectable types which can be serialized and deserialized automatically at both ends
+//! - Messages are represented as reflectable types which can be serialized and deserialized automatically at both ends.
+//!   They are identified on the wire by their type name, or by a stable [id](message::Message::message_id) which
+//!   survives the type being moved or renamed.
+//!   Unknown fields are ignored, and messages can be versioned so that older peers' messages are migrated.
 //! - A new thread is spun up, the remote thread. The remote thread runs a tokio runtime which drives quinn, the QUIC protocol library.
-//! - Messages are sent between the remote thread and the local threads (all other threads) via channels.
+//!   It may instead run as a task on a shared tokio runtime or bevy's IO task pool, see [`config::RemoteRuntime`].
+//! - By default the editor listens and the game dials in, but either side may take either [role](config::Role),
+//!   or both may dial a [relay](relay).
+//! - Messages are sent between the remote thread and the local threads (all other threads) via bounded queues,
+//!   which either wait for space or drop messages when full.
+//! - Messages and transactions may declare a priority. Higher priority transactions are written first, so interactive
+//!   traffic isn't held up by large transfers.
+//! - Large payloads can be streamed in chunks as a transfer, with progress reported as events on both ends.
 //! - Sending a message without a StreamId creates a new "transaction", represented as a stream.
 //! - When a message is received, the corresponding StreamId is kept with it.
 //! - Sending a message with a StreamId sends it to that transaction.
+//! - A transaction can be finished (half-closed), closed, or aborted with an error code; the remote application
+//!   sees each of these as the end of the stream.
 //! - Messages that are received are distributed via bevy's event system.
+//! - If the remote thread fails, it is reopened within the same [session](session::Session), and
+//!   [subscriptions](session::Subscription) are re-established automatically.
+//! - Games may opt in to announcing themselves on the local network, so the editor can [discover](discovery) them.
+//! - The remote thread logs through `tracing`, within spans for each connection and transaction, so its output goes
+//!   through bevy's `LogPlugin` and can be filtered by target.
+//! - A game which panics [reports the crash](crash) to the editor before it dies.
+//! - The game sends a [schema](schema) of its reflected types, so the editor can show and edit types it wasn't
+//!   compiled with.
+//! - Editor features declare their messages as a [protocol](protocol), which both sides confirm they support
+//!   in a handshake before the feature uses it.
+//! - Bad network conditions can be reproduced deterministically in tests with a [simulator](simulator::NetworkSimulator).
+
+// Lets the message macros refer to this crate by its name from inside it
+extern crate self as bevy_editor_iris_common;
 
 use std::borrow::Cow;
-use std::time::Duration;
 
-use asynchronous::{OpeningReceiver, OpeningSender};
+use bevy::log::error;
 use bevy::math::Vec3A;
-use bevy::prelude::{ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem, Plugin, SystemSet};
-use futures_lite::Future;
+use bevy::prelude::{
+    CoreStage, ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem,
+    ParallelSystemDescriptorCoercion, Plugin,
+};
+use bevy::reflect::{TypeRegistry, TypeRegistryInternal};
+use config::{ConnectionConfig, Side};
+use crash::{ClientPanicked, CrashReportReceived, OpenCrashReporter};
+use logs::{LogField, OpenLogStream, RemoteLogBatch, RemoteLogRecord};
 use prelude::TransactionRegistry;
+use protocol::{ProtocolHandshake, ProtocolInfo, Protocols, ReceivedHandshakes, RequestInfo};
 use registry::RunTransactionRegistry;
+use relay::{JoinRoom, RoomPaired};
+use schema::{FieldSchema, RegistrySchema, TypeKind, TypeSchema};
+use serde::{PatchOp, ReflectPatch, RegistryContext};
+use session::{ReceivedSessionHandshakes, Session, SessionHandshake, SessionId};
+use transfer::{TransferChunk, TransferEnd, TransferProgress, TransferStart};
 
 // use self::message::distributor::{self, AppRegisterMsgExt};
-use self::error::RemoteThreadError;
-use self::message::Message;
 
 // TODO: Move these descriptions into their modules
 /// Contains asynchronous logic using tokio which powers the remote thread
 pub mod asynchronous;
+/// Contains configuration of the connection to the remote application
+pub mod config;
+pub mod crash;
+pub mod discovery;
 /// Contains this crate's error types
 pub mod error;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 /// Contains logic binding the local and remote threads together
 pub mod interface;
+pub mod logs;
 /// Contains utility macros
 pub mod macros;
 /// Contains message infrastructure and some built-in message definitions
 pub mod message;
+pub mod protocol;
+pub mod queue;
 pub mod registry;
+pub mod relay;
+pub mod schema;
 /// Contains logic related to serializing and deserializing reflected types and messages
 pub mod serde;
+pub mod session;
+pub mod simulator;
 /// Contains local-thread logic which both the editor and client depend on
 pub mod systems;
+#[cfg(test)]
+mod testing;
+pub mod transfer;
+pub mod transport;
 
 /// Contains all the most commonly used imports for easy usage.
 pub mod prelude {
     pub use super::error::{InterfaceError, TransactionError};
     pub use super::interface::{Interface, Transaction, TransactionReceiver, TransactionSender};
     pub use super::message::{IntoAny, IntoReflect, Message};
+    pub use super::protocol::{AppProtocolExt, Protocol, Protocols};
+    pub use super::queue::{DropPolicy, QueueConfig};
     pub use super::registry::{RunTransactionRegistry, TransactionRegistry};
-    pub use super::serde::{ReflectObject, RemoteEntity};
+    pub use super::serde::{ReflectObject, RegistryContext, RemoteEntity};
 }
 
 /// Contains re-exports of dependencies
@@ -61,42 +115,138 @@ pub mod deps {
     pub use tokio;
 }
 
-/// Handles common logic for both the editor and client components of the iris editor.,
+/// Items the message macros expand to, which aren't part of the public API
+#[doc(hidden)]
+pub mod __macro_exports {
+    /// bevy's derives refer to `bevy_reflect` in crates which don't depend on bevy
+    pub mod bevy_reflect_alias {
+        pub use bevy::reflect as bevy_reflect;
+    }
+}
+
+/// Handles common logic for both the editor and client components of the iris editor,
 /// including opening the remote thread and registering messages.
-pub struct CommonPlugin<
-    Run: 'static
-        + Fn(
-            OpeningSender,
-            OpeningReceiver,
-            // StreamCounter,
-        ) -> F
-        + Send
-        + Sync
-        + Copy,
-    F: 'static + Future<Output = Result<(), RemoteThreadError>>,
->(pub Run);
-
-impl<
-        Run: 'static + Fn(OpeningSender, OpeningReceiver) -> F + Send + Sync + Copy,
-        F: 'static + Future<Output = Result<(), RemoteThreadError>>,
-    > Plugin for CommonPlugin<Run, F>
-{
+///
+/// Whether the remote thread listens for or dials the remote application is decided by [`ConnectionConfig::role`],
+/// or by the [default role](Side::default_role) of the [`Side`] when it isn't set.
+pub struct CommonPlugin(pub Side);
+
+impl Plugin for CommonPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app.init_non_send_resource::<TransactionRegistry>()
-            .add_startup_system(asynchronous::open_remote_thread(self.0).exclusive_system())
-            .add_system_set(
-                SystemSet::new()
-                    .with_run_criteria(systems::run_on_timer(Duration::from_secs(1)))
-                    .with_system(systems::monitor_remote_thread(self.0).exclusive_system()),
-            )
+        let side = self.0;
+        let role = app
+            .world
+            .get_resource::<ConnectionConfig>()
+            .and_then(|config| config.role)
+            .unwrap_or_else(|| side.default_role());
+        let run_fn = move |tx, rx, progress, config, registry| {
+            transport::run(side, role, tx, rx, progress, config, registry)
+        };
+        let registry = app
+            .world
+            .get_resource_or_insert_with(TypeRegistry::default)
+            .clone();
+        register_types(&mut registry.write());
+
+        // The certificate outlives each connection, so its fingerprint can be shared before the remote thread opens
+        app.world
+            .get_resource_or_insert_with(ConnectionConfig::default)
+            .identity_or_generate();
+
+        // Protocols may have been added before this plugin
+        app.world
+            .get_resource_or_insert_with(Protocols::default)
+            .set_side(side);
+
+        let mut transaction_registry = TransactionRegistry::default();
+        let handshakes = ReceivedHandshakes::register(&mut transaction_registry);
+        let session_handshakes = ReceivedSessionHandshakes::register(&mut transaction_registry);
+
+        // Messages registered before this plugin may clash, which must not take the app down
+        let context = RegistryContext::default();
+        if let Err(err) = context.set(registry) {
+            error!(error = %err, "failed to register messages");
+        }
+
+        app.insert_resource(context)
+            .init_resource::<Session>()
+            .insert_resource(handshakes)
+            .insert_resource(session_handshakes)
+            .insert_non_send_resource(transaction_registry)
+            .add_startup_system(asynchronous::open_remote_thread(run_fn).exclusive_system())
+            .add_system(systems::monitor_remote_thread(run_fn).exclusive_system())
             .add_system(
                 registry::update_transaction_registry
                     .exclusive_system()
                     .label(RunTransactionRegistry),
             )
-            .register_type::<Cow<'static, str>>()
-            .register_type::<Vec3A>();
+            .add_system(session::reestablish_subscriptions)
+            .add_system(session::send_session_handshake)
+            .add_system(session::receive_session_handshakes.after(RunTransactionRegistry))
+            .add_system(protocol::send_handshake)
+            .add_system(protocol::receive_handshakes.after(RunTransactionRegistry))
+            .add_event::<TransferProgress>()
+            .add_system(transfer::emit_transfer_progress)
+            .add_system_to_stage(CoreStage::First, systems::sync_registry_context);
+    }
+}
+
+/// Registers the types of the built-in messages.
+pub(crate) fn register_types(registry: &mut TypeRegistryInternal) {
+    registry.register::<Cow<'static, str>>();
+    registry.register::<Vec3A>();
+    registry.register::<TransferStart>();
+    registry.register::<TransferChunk>();
+    registry.register::<TransferEnd>();
+    registry.register::<SessionId>();
+    registry.register::<SessionHandshake>();
+    registry.register::<JoinRoom>();
+    registry.register::<RoomPaired>();
+    registry.register::<LogField>();
+    registry.register::<RemoteLogRecord>();
+    registry.register::<OpenLogStream>();
+    registry.register::<RemoteLogBatch>();
+    registry.register::<OpenCrashReporter>();
+    registry.register::<ClientPanicked>();
+    registry.register::<CrashReportReceived>();
+    registry.register::<TypeKind>();
+    registry.register::<FieldSchema>();
+    registry.register::<TypeSchema>();
+    registry.register::<RegistrySchema>();
+    registry.register::<PatchOp>();
+    registry.register::<ReflectPatch>();
+    registry.register::<RequestInfo>();
+    registry.register::<ProtocolInfo>();
+    registry.register::<ProtocolHandshake>();
+}
+
+/// Creates a registry of the built-in messages and the types they contain, like the one [`CommonPlugin`] sets up
+/// alongside bevy's own registrations, and of the types `register` adds. Shared by the tests and fuzz targets.
+#[cfg(any(test, feature = "fuzzing"))]
+pub(crate) fn builtin_registry(
+    register: impl FnOnce(&mut TypeRegistryInternal),
+) -> RegistryContext {
+    let registry = TypeRegistry::default();
+    {
+        let mut registry = registry.write();
+        registry.register::<bool>();
+        registry.register::<u8>();
+        registry.register::<u16>();
+        registry.register::<u32>();
+        registry.register::<u64>();
+        registry.register::<usize>();
+        registry.register::<i32>();
+        registry.register::<i64>();
+        registry.register::<f32>();
+        registry.register::<f64>();
+        registry.register::<String>();
+        registry.register::<serde::RemoteEntity>();
+        registry.register::<interface::CloseTransaction>();
+        registry.register::<interface::AbortTransaction>();
+        register_types(&mut registry);
+        register(&mut registry);
     }
+    RegistryContext::new(registry)
 }
 
 // TODO: These won't be necessary forever
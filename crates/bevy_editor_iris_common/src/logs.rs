@@ -0,0 +1,69 @@
+//! Messages for streaming the logs of the game to the editor.
+//!
+//! The game opens a transaction with [`OpenLogStream`] and sends its log records over it in [batches](RemoteLogBatch).
+//! The editor sends each record it receives as a [`RemoteLogRecord`] event.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::reflect::{FromReflect, Reflect};
+use bevy_editor_iris_derive::message;
+
+use crate::message::priorities;
+use crate::session::SessionId;
+
+/// A field recorded with a log record.
+#[derive(Clone, Debug, FromReflect, Reflect)]
+pub struct LogField {
+    /// The name of the field
+    pub name: String,
+    /// The value of the field, formatted with [`Debug`]
+    pub value: String,
+}
+
+/// A log record of the remote application.
+#[derive(Clone, Debug, FromReflect, Reflect)]
+pub struct RemoteLogRecord {
+    /// The level of the record, such as `INFO`
+    pub level: String,
+    /// The target of the record, usually the module it was logged from
+    pub target: String,
+    /// The message of the record
+    pub message: String,
+    /// The other fields of the record
+    pub fields: Vec<LogField>,
+    /// The time the record was logged at, in microseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Opens the transaction the game streams its logs over.
+#[message]
+pub struct OpenLogStream {
+    /// The session of the game
+    pub session: SessionId,
+}
+
+/// A batch of log records.
+#[message(priority = priorities::BULK)]
+pub struct RemoteLogBatch {
+    /// The records, oldest first
+    pub records: Vec<RemoteLogRecord>,
+    /// The number of records which were dropped by rate limiting or a full queue since the last batch
+    pub dropped: u64,
+}
+
+/// The current time in microseconds since the unix epoch, as [`RemoteLogRecord::timestamp`] is recorded.
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+crate::protocol! {
+    /// Streaming the logs of the game to the editor.
+    pub struct LogsProtocol {
+        name: "iris.logs",
+        version: 1,
+        initiator: Game,
+        messages: [OpenLogStream, RemoteLogBatch],
+    }
+}
@@ -1,11 +1,27 @@
 // TODO: Probably split this off into its own crate
-// TODO: Reference versions
 
-/// Match on the type of a `Box<dyn Any>`.
+use std::any::Any;
+
+use bevy::reflect::Reflect;
+
+use crate::message::Message;
+
+/// Match on the type of a value.
+///
+/// The value may be given:
+/// - By value, as a `Box<dyn Any>` or a `Box<dyn Message>` (or anything else implementing [`TypematchValue`]).
+///   Each arm binds the unboxed value.
+/// - By reference, as `ref value` where `value` is a `&dyn Any` or a `&dyn Reflect` (or anything else
+///   implementing [`TypematchRef`]). Each arm binds a reference to the value.
+/// - By mutable reference, as `mut value` where `value` is a `&mut dyn Any` or a `&mut dyn Reflect`.
+///   Each arm binds a mutable reference to the value.
+///
+/// An arm may list several types separated by `|`, which share the body, and may have a guard after `if`.
+/// Guards see the value itself when matching by value, and a shared reference otherwise.
 ///
 /// There must be a default case to handle all non-matched types.
 ///
-/// ## Example:
+/// ## Examples
 /// ```
 /// # use std::any::Any;
 /// # use bevy_editor_iris_common::typematch;
@@ -20,114 +36,270 @@
 ///
 /// assert_eq!(value, 12);
 /// ```
+///
+/// Several types per arm, and guards:
+/// ```
+/// # use std::any::Any;
+/// # use bevy_editor_iris_common::typematch;
+/// fn describe(any: Box<dyn Any>) -> String {
+///     typematch!(any, {
+///         x: f32 | f64 if x < 0.0 => "negative float".to_string(),
+///         x: f32 | f64 => format!("float {}", x),
+///         n: u8 | u16 | u32 => format!("unsigned {}", n),
+///         default => "something else".to_string(),
+///     })
+/// }
+///
+/// assert_eq!(describe(Box::new(-1.5f32)), "negative float");
+/// assert_eq!(describe(Box::new(2.5f64)), "float 2.5");
+/// assert_eq!(describe(Box::new(7u16)), "unsigned 7");
+/// assert_eq!(describe(Box::new("seven")), "something else");
+/// ```
+///
+/// By reference, such as a reflected value:
+/// ```
+/// # use bevy::reflect::Reflect;
+/// # use bevy_editor_iris_common::typematch;
+/// fn label(value: &dyn Reflect) -> String {
+///     typematch!(ref value, {
+///         s: String if s.is_empty() => "<empty>".to_string(),
+///         s: String => s.clone(),
+///         n: u32 | i32 => n.to_string(),
+///         default => format!("<{}>", value.type_name()),
+///     })
+/// }
+///
+/// assert_eq!(label(&"iris".to_string()), "iris");
+/// assert_eq!(label(&String::new()), "<empty>");
+/// assert_eq!(label(&-3i32), "-3");
+/// assert_eq!(label(&1.5f32), "<f32>");
+/// ```
+///
+/// By mutable reference:
+/// ```
+/// # use std::any::Any;
+/// # use bevy_editor_iris_common::typematch;
+/// let mut value = 41u64;
+/// let any: &mut dyn Any = &mut value;
+///
+/// typematch!(mut any, {
+///     n: u32 | u64 if *n < 100 => *n += 1,
+///     n: u32 | u64 => *n = 0,
+///     default => (),
+/// });
+///
+/// assert_eq!(value, 42);
+/// ```
+///
+/// Received messages:
+/// ```
+/// # use bevy_editor_iris_common::asynchronous::MessageBox;
+/// # use bevy_editor_iris_common::typematch;
+/// # use bevy_editor_iris_derive::message;
+/// #[message]
+/// struct Ping {
+///     sequence: u32,
+/// }
+///
+/// #[message]
+/// struct Pong {
+///     sequence: u32,
+/// }
+///
+/// let msg: MessageBox = Box::new(Pong { sequence: 2 });
+///
+/// let sequence = typematch!(msg, {
+///     ping: Ping => ping.sequence,
+///     pong: Pong if pong.sequence > 0 => pong.sequence,
+///     default => 0,
+/// });
+///
+/// assert_eq!(sequence, 2);
+/// ```
+///
+/// Without a default case, the match doesn't compile:
+/// ```compile_fail
+/// # use std::any::Any;
+/// # use bevy_editor_iris_common::typematch;
+/// let any = Box::new(12) as Box<dyn Any>;
+///
+/// let value: i32 = typematch!(any, {
+///     int: i32 => int,
+/// });
+/// ```
 #[macro_export]
 macro_rules! typematch {
+    (ref $input:expr, { $($body:tt)* }) => {{
+        let input: &dyn ::std::any::Any = $crate::macros::TypematchRef::as_any_ref($input);
+        let output = $crate::typematch!(@arms ref input $($body)*);
+        #[allow(unreachable_code)]
+        output
+    }};
+
+    (mut $input:expr, { $($body:tt)* }) => {{
+        let input: &mut dyn ::std::any::Any = $crate::macros::TypematchRef::as_any_mut($input);
+        let output = $crate::typematch!(@arms mut input $($body)*);
+        #[allow(unreachable_code)]
+        output
+    }};
+
     ($input:expr, { $($body:tt)* }) => {{
-        let input: ::std::boxed::Box<dyn ::std::any::Any> = $input;
-        let output = $crate::typematch!(@first_unroll input {} $($body)*);
+        let input: ::std::boxed::Box<dyn ::std::any::Any> =
+            $crate::macros::TypematchValue::into_any_box($input);
+        let output = $crate::typematch!(@arms box input $($body)*);
         #[allow(unreachable_code)]
         output
     }};
 
     (
-        @first_unroll
-        $input:ident
-        {}
-        default => $body:expr $(,)?
+        @arms $mode:tt $input:ident
+        default => $default:expr $(,)?
     ) => {
-        $body
+        $default
     };
 
     (
-        @first_unroll
-        $input:ident
-        {}
-        $name:ident : $ty:ty => $body:expr,
-        default => $default_body:expr $(,)?
+        @arms $mode:tt $input:ident
+        $name:ident : $($ty:ty)|+ => $body:expr,
+        $($rest:tt)*
     ) => {
-        if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
-            let $name = *::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input).unwrap();
-            $body
-        }
-        else {
-            $default_body
-        }
+        $crate::typematch!(
+            @branch $mode $input $name [] [$($ty),+] { $body }
+            { $crate::typematch!(@arms $mode $input $($rest)*) }
+        )
     };
 
+    // Types can't be followed by `if`, so the types of guarded arms are collected token by token
     (
-        @first_unroll
-        $input:ident
-        {}
-        $name:ident : $ty:ty => $body:expr,
-        $($rest:tt)+
+        @arms $mode:tt $input:ident
+        $name:ident : $($rest:tt)+
     ) => {
-        $crate::typematch!(
-            @unroll
-            $input
-            {
-                if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
-                    let $name = *::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input).unwrap();
-                    $body
-                }
-            }
-            $($rest)*
-        )
+        $crate::typematch!(@guarded $mode $input $name [] [] $($rest)+)
+    };
+
+    (@arms $mode:tt $input:ident) => {
+        $crate::typematch!(@no_default_err)
     };
 
     (
-        @unroll
-        $input:ident
-        { $($stored:tt)+ }
-        $name:ident : $ty:ty => $body:expr,
-        default => $default_body:expr $(,)?
+        @guarded $mode:tt $input:ident $name:ident [$($tys:tt)*] [$($ty:tt)+]
+        | $($rest:tt)+
     ) => {
-        $($stored)+
-        else if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
-            let $name = *::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input).unwrap();
-            $body
-        }
-        else {
-            $default_body
-        }
+        $crate::typematch!(@guarded $mode $input $name [$($tys)* ($($ty)+)] [] $($rest)+)
     };
 
     (
-        @unroll
-        $input:ident
-        { $($stored:tt)+ }
-        $name:ident : $ty:ty => $body:expr,
-        $($rest:tt)+
+        @guarded $mode:tt $input:ident $name:ident [$($tys:tt)*] [$($ty:tt)+]
+        if $($rest:tt)+
     ) => {
-        $crate::typematch!(
-            @unroll
-            $input
-            {
-                $($stored)+
-                else if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
-                    let $name = *::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input).unwrap();
-                    $body
-                }
-            }
-            $($rest)*
-        )
+        $crate::typematch!(@guard $mode $input $name [$($tys)* ($($ty)+)] [] $($rest)+)
     };
 
     (
-        @first_unroll
-        $input:ident
-        {}
-        $name:ident : $ty:ty => $body:expr,
+        @guarded $mode:tt $input:ident $name:ident [$($tys:tt)*] [$($ty:tt)*]
+        => $($rest:tt)*
     ) => {
         $crate::typematch!(@no_default_err)
     };
 
     (
-        @unroll
-        $input:ident
-        { $($stored:tt)+ }
-        $name:ident : $ty:ty => $body:expr,
+        @guarded $mode:tt $input:ident $name:ident [$($tys:tt)*] [$($ty:tt)*]
+        $next:tt $($rest:tt)+
     ) => {
-        $crate::typematch!(@no_default_err)
+        $crate::typematch!(@guarded $mode $input $name [$($tys)*] [$($ty)* $next] $($rest)+)
+    };
+
+    (
+        @guard $mode:tt $input:ident $name:ident [$(($($ty:tt)+))+] [$($guard:tt)+]
+        => $body:expr $(, $($rest:tt)*)?
+    ) => {
+        $crate::typematch!(
+            @branch $mode $input $name [$($guard)+] [$($($ty)+),+] { $body }
+            { $crate::typematch!(@arms $mode $input $($($rest)*)?) }
+        )
+    };
+
+    (
+        @guard $mode:tt $input:ident $name:ident [$($tys:tt)+] [$($guard:tt)*]
+        $next:tt $($rest:tt)+
+    ) => {
+        $crate::typematch!(@guard $mode $input $name [$($tys)+] [$($guard)* $next] $($rest)+)
+    };
+
+    (@branch box $input:ident $name:ident [] [$($ty:ty),+] $body:tt $next:tt) => {
+        $(
+            if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
+                let $name = *::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input).unwrap();
+                $body
+            } else
+        )+
+        $next
+    };
+
+    (@branch box $input:ident $name:ident [$guard:expr] [] $body:tt $next:tt) => {
+        $next
+    };
+
+    // The value is taken out of the box for the guard, and boxed again if the guard fails
+    (@branch box $input:ident $name:ident [$guard:expr] [$ty:ty $(, $tys:ty)*] $body:tt $next:tt) => {
+        match match ::std::boxed::Box::<dyn ::std::any::Any>::downcast::<$ty>($input) {
+            ::std::result::Result::Ok(value) => match *value {
+                $name if $guard => ::std::result::Result::Ok($body),
+                $name => ::std::result::Result::Err(
+                    ::std::boxed::Box::new($name) as ::std::boxed::Box<dyn ::std::any::Any>
+                ),
+            },
+            ::std::result::Result::Err(input) => ::std::result::Result::Err(input),
+        } {
+            ::std::result::Result::Ok(output) => output,
+            ::std::result::Result::Err($input) => {
+                $crate::typematch!(@branch box $input $name [$guard] [$($tys),*] $body $next)
+            }
+        }
+    };
+
+    (@branch ref $input:ident $name:ident [] [$($ty:ty),+] $body:tt $next:tt) => {
+        $(
+            if let ::std::option::Option::Some($name) = <dyn ::std::any::Any>::downcast_ref::<$ty>($input) {
+                $body
+            } else
+        )+
+        $next
+    };
+
+    (@branch ref $input:ident $name:ident [$guard:expr] [$($ty:ty),+] $body:tt $next:tt) => {
+        $(
+            if <dyn ::std::any::Any>::downcast_ref::<$ty>($input).map_or(false, |$name| $guard) {
+                // The guard may be the only use of the binding
+                #[allow(unused_variables)]
+                let $name = <dyn ::std::any::Any>::downcast_ref::<$ty>($input).unwrap();
+                $body
+            } else
+        )+
+        $next
+    };
+
+    (@branch mut $input:ident $name:ident [] [$($ty:ty),+] $body:tt $next:tt) => {
+        $(
+            if <dyn ::std::any::Any>::is::<$ty>(&*$input) {
+                let $name = <dyn ::std::any::Any>::downcast_mut::<$ty>($input).unwrap();
+                $body
+            } else
+        )+
+        $next
+    };
+
+    // Guards only see a shared reference, since the value is borrowed mutably once the guard has passed
+    (@branch mut $input:ident $name:ident [$guard:expr] [$($ty:ty),+] $body:tt $next:tt) => {
+        $(
+            if <dyn ::std::any::Any>::downcast_ref::<$ty>(&*$input).map_or(false, |$name| $guard) {
+                // The guard may be the only use of the binding
+                #[allow(unused_variables)]
+                let $name = <dyn ::std::any::Any>::downcast_mut::<$ty>($input).unwrap();
+                $body
+            } else
+        )+
+        $next
     };
 
     (
@@ -144,14 +316,88 @@ typematch!(value, {
     };
 }
 
-// TODO: With Generic Associated Types, I can make typematch generic over references as well
-// pub trait Typematch {
-//     type Target<T>;
+/// A value which [`typematch!`] can match on by value.
+pub trait TypematchValue {
+    /// Converts the value into a `Box<dyn Any>` of the same concrete type.
+    fn into_any_box(self) -> Box<dyn Any>;
+}
 
-//     fn is<T: Any>(&self) -> bool;
+impl TypematchValue for Box<dyn Any> {
+    fn into_any_box(self) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl TypematchValue for Box<dyn Any + Send> {
+    fn into_any_box(self) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl TypematchValue for Box<dyn Message> {
+    fn into_any_box(self) -> Box<dyn Any> {
+        self.into_any()
+    }
+}
+
+/// A value which [`typematch!`] can match on by reference.
+pub trait TypematchRef {
+    /// Casts a reference to `dyn Any` of the same concrete type.
+    fn as_any_ref(&self) -> &dyn Any;
+
+    /// Casts a mutable reference to `dyn Any` of the same concrete type.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
 
-//     fn downcast<T: Any>(self) -> Self::Target<T>;
-// }
+impl TypematchRef for dyn Any {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl TypematchRef for dyn Any + Send {
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl TypematchRef for dyn Reflect {
+    fn as_any_ref(&self) -> &dyn Any {
+        self.any()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self.any_mut()
+    }
+}
+
+impl TypematchRef for dyn Message {
+    fn as_any_ref(&self) -> &dyn Any {
+        self.as_any()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self.as_mut_any()
+    }
+}
+
+impl<T: ?Sized + TypematchRef> TypematchRef for Box<T> {
+    fn as_any_ref(&self) -> &dyn Any {
+        (**self).as_any_ref()
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        (**self).as_any_mut()
+    }
+}
 
 // TODO: Tbh this should be a proc macro but ¯\_(ツ)_/¯ I'll make it a standalone crate later
 /// A state machine macro to make a state machine more ergonomic to write. The state is represented as an enum.
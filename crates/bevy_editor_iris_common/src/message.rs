@@ -1,10 +1,19 @@
-use std::any::Any;
+use std::any::{Any, TypeId};
 use std::io;
+use std::str;
 
-use bevy::reflect::{FromReflect, FromType, Reflect};
+use bevy::log::warn;
+use bevy::prelude::default;
+use bevy::reflect::{
+    DynamicStruct, DynamicTupleStruct, FromReflect, FromType, Reflect, ReflectRef,
+    TypeRegistration, TypeRegistryInternal,
+};
+use bevy::utils::HashMap;
+use yaml_rust::scanner::{Scanner, Token, TokenType};
 
-use crate::error::MessageDeserError;
-use crate::serde;
+use crate::error::{DuplicateMessageName, MessageDeserError};
+use crate::schema::SchemaDeserializer;
+use crate::serde::RegistryContext;
 
 // TODO: This may end up in bevy alongside `Reflect`
 /// Blanket impl to cast a type to [`Any`].
@@ -60,9 +69,73 @@ impl<T: Reflect> IntoReflect for T {
     }
 }
 
+/// Common [message priorities](Message::priority). Any `i32` may be used; higher priorities are sent first.
+pub mod priorities {
+    /// Small messages which the user is waiting on, such as selection changes.
+    pub const INTERACTIVE: i32 = 100;
+    /// The priority of messages which don't declare one.
+    pub const DEFAULT: i32 = 0;
+    /// Large transfers, such as scene dumps, which may be delayed in favor of other messages.
+    pub const BULK: i32 = -100;
+}
+
 /// A trait that marks a type as being sendable as a message
 /// to the remote application.
-pub trait Message: Reflect + IntoAny + IntoReflect {}
+pub trait Message: Reflect + IntoAny + IntoReflect {
+    /// The priority this message is sent with, unless its transaction has a
+    /// [priority](crate::interface::Transaction::set_priority) of its own. Messages with a higher priority are
+    /// written before messages of other transactions with a lower priority.
+    ///
+    /// Set with `#[message(priority = ...)]`.
+    fn priority(&self) -> i32 {
+        priorities::DEFAULT
+    }
+
+    /// The stable id this message is sent with instead of its Rust type name, so the type can be moved or renamed
+    /// without breaking compatibility with other builds. Ids must be unique among registered messages.
+    ///
+    /// Set with `#[message(id = "...")]`.
+    fn message_id() -> Option<&'static str>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// The other names this message is recognized by when received, such as the id or type name it was sent
+    /// with by older builds.
+    ///
+    /// Set with `#[message(alias = "...")]`.
+    fn aliases() -> &'static [&'static str]
+    where
+        Self: Sized,
+    {
+        &[]
+    }
+
+    /// The version of this message's fields, which is sent along with it. Raise it when the fields change in a way
+    /// [defaults](ReflectMessageDefault) can't make up for, and [migrate](Message::migrate) older versions.
+    ///
+    /// Set with `#[message(version = ...)]`.
+    fn version() -> u32
+    where
+        Self: Sized,
+    {
+        1
+    }
+
+    /// Converts the fields of this message as received from a peer with an older `version` into the fields
+    /// of the current version.
+    ///
+    /// Set with `#[message(migrate = ...)]`, given a function with the same signature.
+    fn migrate(version: u32, value: Box<dyn Reflect>) -> Box<dyn Reflect>
+    where
+        Self: Sized,
+    {
+        _ = version;
+        value
+    }
+}
 
 impl dyn Message {
     /// Returns `true` if this message is of type `T` and `false` otherwise.
@@ -92,12 +165,41 @@ impl std::fmt::Debug for dyn Message {
 /// Allows casting a `dyn Reflect` to a `dyn Message`, if the `dyn Reflect` is the correct type.
 #[derive(Clone)]
 pub struct ReflectMessage {
+    id: Option<&'static str>,
+    aliases: &'static [&'static str],
+    version: u32,
+    migrate: fn(u32, Box<dyn Reflect>) -> Box<dyn Reflect>,
     get_func: fn(&dyn Reflect) -> Option<&dyn Message>,
     get_mut_func: fn(&mut dyn Reflect) -> Option<&mut dyn Message>,
     get_boxed_func: fn(Box<dyn Reflect>) -> Result<Box<dyn Message>, Box<dyn Reflect>>,
 }
 
 impl ReflectMessage {
+    /// See [`Message::message_id`]
+    pub fn id(&self) -> Option<&'static str> {
+        self.id
+    }
+
+    /// See [`Message::aliases`]
+    pub fn aliases(&self) -> &'static [&'static str] {
+        self.aliases
+    }
+
+    /// See [`Message::version`]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// See [`Message::migrate`]
+    pub fn migrate(&self, version: u32, value: Box<dyn Reflect>) -> Box<dyn Reflect> {
+        (self.migrate)(version, value)
+    }
+
+    /// Returns `true` if a message received as `name` is of this type, by its id or one of its aliases.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.id == Some(name) || self.aliases.contains(&name)
+    }
+
     /// Converts a `&dyn Reflect` to a `&dyn Message`
     pub fn get<'a>(&self, reflect_value: &'a dyn Reflect) -> Option<&'a dyn Message> {
         (self.get_func)(reflect_value)
@@ -120,6 +222,10 @@ impl ReflectMessage {
 impl<T: Message + Reflect> FromType<T> for ReflectMessage {
     fn from_type() -> Self {
         Self {
+            id: T::message_id(),
+            aliases: T::aliases(),
+            version: T::version(),
+            migrate: T::migrate,
             get_func: |reflect_value| {
                 reflect_value
                     .downcast_ref::<T>()
@@ -177,44 +283,240 @@ impl<T: Reflect + FromReflect> FromType<T> for ReflectMessageFromReflect {
     }
 }
 
+/// Contains the [`Default`] implementation of a message, which fields missing from messages sent by older builds are
+/// filled in from. Used as a temporary stopgap while waiting for bevy to add `ReflectDefault`.
+///
+/// Reflected by `#[message(default)]`.
+#[derive(Clone)]
+pub struct ReflectMessageDefault {
+    default: fn() -> Box<dyn Reflect>,
+}
+
+impl ReflectMessageDefault {
+    /// See [`Default`]
+    pub fn default(&self) -> Box<dyn Reflect> {
+        (self.default)()
+    }
+}
+
+impl<T: Reflect + Default> FromType<T> for ReflectMessageDefault {
+    fn from_type() -> Self {
+        Self {
+            default: || Box::new(T::default()),
+        }
+    }
+}
+
 /// Attempt to serialize a message into a yaml byte writer.
+///
+/// The message is sent as its [id](Message::message_id) if it has one, or as its type name otherwise.
+/// Versions after the first are appended to the name, as in `iris.inspector.query@2`.
 pub fn serialize_message<M: ?Sized + Message>(
     msg: Box<M>,
     writer: impl io::Write,
+    registry: &RegistryContext,
 ) -> serde_yaml::Result<()> {
-    serde::with_type_registry(|reg| {
-        let reg = reg.unwrap().read();
+    registry.read(|reg| {
+        let message = reg
+            .get((*msg).as_any().type_id())
+            .and_then(|registration| registration.data::<ReflectMessage>());
+        let id = message.and_then(ReflectMessage::id);
+        let version = message.map_or(1, ReflectMessage::version);
+
+        let name = match (id, version) {
+            (None, 1) => None,
+            (Some(id), 1) => Some(id.to_string()),
+            (id, version) => Some(format!(
+                "{}@{}",
+                id.unwrap_or_else(|| msg.type_name()),
+                version
+            )),
+        };
+
+        match name {
+            Some(name) => {
+                let renamed = with_name(msg.as_reflect(), name);
+                let refl = bevy::reflect::serde::ReflectSerializer::new(&*renamed, reg);
+                serde_yaml::to_writer(writer, &refl)
+            }
+            None => {
+                let refl = bevy::reflect::serde::ReflectSerializer::new(msg.as_reflect(), reg);
+                serde_yaml::to_writer(writer, &refl)
+            }
+        }
+    })
+}
+
+/// Clones `value` into a dynamic value which is serialized with `name` as its type name.
+/// Only structs and tuple structs can be renamed, which every message is.
+fn with_name(value: &dyn Reflect, name: String) -> Box<dyn Reflect> {
+    let mut dynamic = value.clone_value();
+    if let Some(value) = dynamic.downcast_mut::<DynamicStruct>() {
+        value.set_name(name);
+    } else if let Some(value) = dynamic.downcast_mut::<DynamicTupleStruct>() {
+        value.set_name(name);
+    }
+    dynamic
+}
 
-        let refl = bevy::reflect::serde::ReflectSerializer::new(msg.as_reflect(), &*reg);
+/// Indexes every registered message by its [id](Message::message_id) and [aliases](Message::aliases).
+///
+/// Fails if two messages share an id or alias, or if one is the type name of another registered type,
+/// since a message received by that name couldn't be told apart.
+pub(crate) fn index_messages(
+    reg: &TypeRegistryInternal,
+) -> Result<HashMap<String, TypeId>, DuplicateMessageName> {
+    let mut index: HashMap<String, TypeId> = default();
+
+    for registration in reg.iter() {
+        let message = match registration.data::<ReflectMessage>() {
+            Some(message) => message,
+            None => continue,
+        };
+
+        for &name in message.id.iter().chain(message.aliases) {
+            let other = index
+                .get(name)
+                .copied()
+                .or_else(|| reg.get_with_name(name).map(TypeRegistration::type_id));
+
+            match other {
+                Some(other) if other != registration.type_id() => {
+                    return Err(DuplicateMessageName {
+                        name: name.to_string(),
+                        first: reg.get(other).map_or("", |other| other.name()).to_string(),
+                        second: registration.name().to_string(),
+                    });
+                }
+                _ => {
+                    index.insert(name.to_string(), registration.type_id());
+                }
+            }
+        }
+    }
 
-        serde_yaml::to_writer(writer, &refl)
-    })
+    Ok(index)
 }
 
-/// Attempt to deserialize a [`Message`] from a yaml byte slice
-pub fn deserialize_message(buf: &[u8]) -> Result<Box<dyn Message>, MessageDeserError> {
-    serde::with_type_registry(|reg| {
-        let reg = reg.unwrap().read();
+/// Finds the registration of a message received as `name`, which is either its type name,
+/// its [id](Message::message_id) or one of its [aliases](Message::aliases).
+fn find_message<'a>(
+    reg: &'a TypeRegistryInternal,
+    registry: &RegistryContext,
+    name: &str,
+) -> Option<&'a TypeRegistration> {
+    match registry.message_named(name) {
+        Some(type_id) => reg.get(type_id),
+        None => reg.get_with_name(name),
+    }
+}
 
-        let deser = bevy::reflect::serde::ReflectDeserializer::new(&reg);
+/// Splits the version off a message name, as appended by [`serialize_message`].
+fn split_version(name: &str) -> (&str, u32) {
+    name.rsplit_once('@')
+        .and_then(|(name, version)| Some((name, version.parse().ok()?)))
+        .unwrap_or((name, 1))
+}
 
-        let dynamic = serde_yaml::seed::from_slice_seed(buf, deser)?;
+/// Warns about the fields of `received` which `msg` doesn't have, such as fields added by newer builds.
+fn warn_unknown_fields(received: &dyn Reflect, msg: &dyn Reflect) {
+    if let (ReflectRef::Struct(received), ReflectRef::Struct(msg)) =
+        (received.reflect_ref(), msg.reflect_ref())
+    {
+        for i in 0..received.field_len() {
+            let field = received.name_at(i).unwrap();
+            if msg.field(field).is_none() {
+                warn!(message = msg.type_name(), field, "ignoring unknown field");
+            }
+        }
+    }
+}
 
-        let registration = reg
-            .get_with_name(dynamic.type_name())
-            .ok_or_else(|| MessageDeserError::MessageNotRegistered(dynamic.type_name().into()))?;
+/// Whether the yaml in `buf` refers back to an anchor with an alias.
+fn has_alias(buf: &[u8]) -> bool {
+    // Invalid utf-8 is left for serde_yaml to report
+    let yaml = match str::from_utf8(buf) {
+        Ok(yaml) => yaml,
+        Err(_) => return false,
+    };
+    Scanner::new(yaml.chars()).any(|Token(_, token)| matches!(token, TokenType::Alias(_)))
+}
 
-        let from_reflect = registration
-            .data::<ReflectMessageFromReflect>()
-            .ok_or_else(|| MessageDeserError::MessageNotFromReflect(dynamic.type_name().into()))?;
+/// Copies `received`, taking the fields it's missing from `default`, including those of nested structs.
+fn fill_missing(received: &dyn Reflect, default: &dyn Reflect) -> Box<dyn Reflect> {
+    let (received, default) = match (received.reflect_ref(), default.reflect_ref()) {
+        (ReflectRef::Struct(received), ReflectRef::Struct(default)) => (received, default),
+        _ => return received.clone_value(),
+    };
+
+    let mut filled = DynamicStruct::default();
+    filled.set_name(default.type_name().to_string());
+    for (i, field) in default.iter_fields().enumerate() {
+        let name = default.name_at(i).unwrap();
+        let field = match received.field(name) {
+            Some(received) => fill_missing(received, field),
+            None => field.clone_value(),
+        };
+        filled.insert_boxed(name, field);
+    }
+    Box::new(filled)
+}
+
+/// Attempt to deserialize a [`Message`] from a yaml byte slice
+///
+/// Messages from older versions are [migrated](Message::migrate). Fields which are unknown are ignored,
+/// and fields which are missing are filled in from the message's [default](ReflectMessageDefault) if it has one.
+///
+/// Messages may not contain yaml aliases, since serde_yaml deserializes an alias's anchor again everywhere
+/// it is used, so a few hundred bytes of them can take gigabytes of memory.
+pub fn deserialize_message(
+    buf: &[u8],
+    registry: &RegistryContext,
+) -> Result<Box<dyn Message>, MessageDeserError> {
+    if has_alias(buf) {
+        return Err(MessageDeserError::YamlAlias);
+    }
+
+    registry.read(|reg| {
+        let schemas = registry.schemas();
 
-        let msg = from_reflect.from_reflect(&*dynamic).ok_or_else(|| {
-            MessageDeserError::FromReflectFailed(String::from_utf8_lossy(&buf).to_string())
-        })?;
+        let deser = SchemaDeserializer::new(reg, &schemas);
+
+        let mut dynamic = serde_yaml::seed::from_slice_seed(buf, deser)?;
+        let received_name = dynamic.type_name().to_string();
+        let (name, version) = split_version(&received_name);
+
+        let registration = find_message(reg, registry, name)
+            .ok_or_else(|| MessageDeserError::MessageNotRegistered(name.into()))?;
 
         let reflect_msg = registration
             .data::<ReflectMessage>()
-            .ok_or_else(|| MessageDeserError::MessageNotImpl(dynamic.type_name().into()))?;
+            .ok_or_else(|| MessageDeserError::MessageNotImpl(name.into()))?;
+
+        if version < reflect_msg.version() {
+            dynamic = reflect_msg.migrate(version, dynamic);
+        }
+
+        let from_reflect = registration
+            .data::<ReflectMessageFromReflect>()
+            .ok_or_else(|| MessageDeserError::MessageNotFromReflect(name.into()))?;
+
+        let msg = match from_reflect.from_reflect(&*dynamic) {
+            Some(msg) => msg,
+            None => {
+                let default = registration
+                    .data::<ReflectMessageDefault>()
+                    .ok_or_else(|| MessageDeserError::FromReflectFailed(name.into()))?;
+
+                // Applying a field of the wrong type would panic, so the missing fields are filled in instead,
+                // leaving FromReflect to find fields of the wrong type
+                let filled = fill_missing(&*dynamic, &*default.default());
+                from_reflect
+                    .from_reflect(&*filled)
+                    .ok_or_else(|| MessageDeserError::FromReflectFailed(name.into()))?
+            }
+        };
+        warn_unknown_fields(&*dynamic, &*msg);
 
         let msg = reflect_msg.get_boxed(msg).unwrap();
 
@@ -222,3 +524,177 @@ pub fn deserialize_message(buf: &[u8]) -> Result<Box<dyn Message>, MessageDeserE
         Ok::<_, MessageDeserError>(msg)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::reflect::serde::ReflectSerializer;
+    use bevy::reflect::TypeRegistry;
+    use bevy_editor_iris_derive::message;
+
+    use super::*;
+
+    #[message(id = "iris.test.ping", alias = "iris.test.old_ping")]
+    struct Ping {
+        sequence: u32,
+    }
+
+    /// Version 1 named `sequence` `seq`, and had no label.
+    #[message(version = 2, migrate = migrate_pong, default)]
+    #[derive(Default)]
+    struct Pong {
+        sequence: u32,
+        label: String,
+    }
+
+    fn migrate_pong(_version: u32, value: Box<dyn Reflect>) -> Box<dyn Reflect> {
+        let old = match value.reflect_ref() {
+            ReflectRef::Struct(old) => old,
+            _ => return value.clone_value(),
+        };
+
+        let mut new = DynamicStruct::default();
+        new.set_name(old.type_name().to_string());
+        for (i, field) in old.iter_fields().enumerate() {
+            let name = match old.name_at(i).unwrap() {
+                "seq" => "sequence",
+                name => name,
+            };
+            new.insert_boxed(name, field.clone_value());
+        }
+        Box::new(new)
+    }
+
+    fn registry() -> RegistryContext {
+        let registry = TypeRegistry::default();
+        {
+            let mut registry = registry.write();
+            registry.register::<u32>();
+            registry.register::<String>();
+            registry.register::<Ping>();
+            registry.register::<Pong>();
+        }
+        RegistryContext::new(registry)
+    }
+
+    #[test]
+    fn message_sent_by_id() {
+        let registry = registry();
+
+        let mut buf = vec![];
+        serialize_message(Box::new(Ping { sequence: 3 }), &mut buf, &registry).unwrap();
+        let yaml = String::from_utf8(buf).unwrap();
+        assert!(yaml.contains("iris.test.ping"));
+        assert!(!yaml.contains("tests::Ping"));
+
+        let msg = deserialize_message(yaml.as_bytes(), &registry).unwrap();
+        assert_eq!(msg.downcast::<Ping>().unwrap().sequence, 3);
+
+        // An older build which still sends the old id
+        let old = yaml.replace("iris.test.ping", "iris.test.old_ping");
+        assert!(deserialize_message(old.as_bytes(), &registry)
+            .unwrap()
+            .is::<Ping>());
+    }
+
+    #[test]
+    fn duplicate_names_rejected() {
+        #[message(id = "iris.test.old_ping")]
+        struct Renamed {
+            sequence: u32,
+        }
+
+        #[message(id = "bevy_editor_iris_common::message::tests::Pong")]
+        struct Impostor {
+            sequence: u32,
+        }
+
+        let types = registry().registry();
+        types.write().register::<Renamed>();
+        let err = RegistryContext::default().set(types).unwrap_err();
+        assert_eq!(err.name, "iris.test.old_ping");
+
+        let types = registry().registry();
+        types.write().register::<Impostor>();
+        let err = RegistryContext::default().set(types).unwrap_err();
+        assert_eq!(err.name, std::any::type_name::<Pong>());
+    }
+
+    #[test]
+    fn older_message_migrated() {
+        let registry = registry();
+
+        let mut old = DynamicStruct::default();
+        old.set_name(std::any::type_name::<Pong>().to_string());
+        old.insert("seq", 3u32);
+        old.insert("removed", 1u32);
+
+        let yaml =
+            serde_yaml::to_string(&ReflectSerializer::new(&old, &registry.registry().read()))
+                .unwrap();
+
+        let pong = deserialize_message(yaml.as_bytes(), &registry)
+            .unwrap()
+            .downcast::<Pong>()
+            .unwrap();
+        assert_eq!(pong.sequence, 3);
+        assert_eq!(pong.label, "");
+    }
+
+    #[test]
+    fn mismatched_field_rejected() {
+        let registry = registry();
+
+        let mut wrong = DynamicStruct::default();
+        wrong.set_name(std::any::type_name::<Pong>().to_string());
+        wrong.insert("label", 3u32);
+
+        let yaml =
+            serde_yaml::to_string(&ReflectSerializer::new(&wrong, &registry.registry().read()))
+                .unwrap();
+
+        assert!(matches!(
+            deserialize_message(yaml.as_bytes(), &registry),
+            Err(MessageDeserError::FromReflectFailed(_))
+        ));
+    }
+
+    #[test]
+    fn aliases_rejected() {
+        // Found by the deserialize_message fuzz target: each level doubles the values the last one expands to,
+        // so this would build 2^32 of them
+        let mut yaml =
+            String::from("type: iris.test.ping\nstruct:\n  l0: &l0 {type: u32, value: 0}\n");
+        for i in 1..=32 {
+            yaml += &format!(
+                "  l{}: &l{} {{type: alloc::vec::Vec<u32>, list: [*l{}, *l{}]}}\n",
+                i,
+                i,
+                i - 1,
+                i - 1
+            );
+        }
+
+        assert!(matches!(
+            deserialize_message(yaml.as_bytes(), &registry()),
+            Err(MessageDeserError::YamlAlias)
+        ));
+    }
+
+    #[test]
+    fn alias_characters_in_strings_accepted() {
+        let registry = registry();
+
+        let mut buf = vec![];
+        let pong = Pong {
+            sequence: 1,
+            label: "*l0 &l0".to_string(),
+        };
+        serialize_message(Box::new(pong), &mut buf, &registry).unwrap();
+
+        let pong = deserialize_message(&buf, &registry)
+            .unwrap()
+            .downcast::<Pong>()
+            .unwrap();
+        assert_eq!(pong.label, "*l0 &l0");
+    }
+}
@@ -0,0 +1,456 @@
+//! Protocols group the messages of an editor feature, so both peers can confirm they support the feature before
+//! using it.
+//!
+//! A protocol is declared once with [`protocol!`](crate::protocol!), in a crate both the editor and the game depend
+//! on, and added on both sides with [`add_protocol`](AppProtocolExt::add_protocol). Each side sends a
+//! [`ProtocolHandshake`] listing the protocols it supports whenever it connects, and features check
+//! [`Protocols::is_confirmed`] (or run with [`protocol_confirmed`]) before they open their transactions.
+
+use std::any::{type_name, TypeId};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+use bevy::ecs::schedule::ShouldRun;
+use bevy::log::warn;
+use bevy::prelude::{App, Local, Res, ResMut};
+use bevy::reflect::{FromReflect, Reflect};
+use bevy::utils::HashMap;
+use bevy_editor_iris_derive::message;
+
+use crate::asynchronous::MessageBox;
+use crate::config::Side;
+use crate::interface::{Interface, Transaction};
+use crate::message::{priorities, Message};
+use crate::registry::TransactionRegistry;
+use crate::session::{Session, Subscription};
+
+/// A named group of messages which an editor feature exchanges. Declared with [`protocol!`](crate::protocol!).
+pub trait Protocol: 'static {
+    /// The name the protocol is recognized by on both sides, such as `"iris.logs"`
+    const NAME: &'static str;
+    /// Increased whenever the protocol changes in a way older peers can't handle
+    const VERSION: u32;
+    /// The side which opens the transactions of the protocol. Transactions the other side opens with the
+    /// protocol's messages are closed.
+    const INITIATOR: Side;
+
+    /// Registers the messages of the protocol.
+    fn register(app: &mut App);
+
+    /// The types of the messages of the protocol, including its requests and responses.
+    fn message_types() -> Vec<TypeId>;
+
+    /// Describes the protocol, as it is sent in the [`ProtocolHandshake`].
+    fn info() -> ProtocolInfo;
+}
+
+/// The name a message is sent by, its [id](Message::message_id) or else its type name.
+pub fn message_name<M: Message>() -> String {
+    M::message_id().unwrap_or_else(type_name::<M>).to_string()
+}
+
+/// A request of a protocol, and the message the other side answers it with.
+#[derive(Clone, Debug, Eq, FromReflect, PartialEq, Reflect)]
+pub struct RequestInfo {
+    /// The [name](message_name) of the request
+    pub request: String,
+    /// The [name](message_name) of the response
+    pub response: String,
+}
+
+/// Describes a [`Protocol`]. Both peers must give a protocol the same name and version for it to be confirmed.
+#[derive(Clone, Debug, Eq, FromReflect, PartialEq, Reflect)]
+pub struct ProtocolInfo {
+    /// The [name](Protocol::NAME) of the protocol
+    pub name: String,
+    /// The [version](Protocol::VERSION) of the protocol
+    pub version: u32,
+    /// The [side](Side::as_str) which initiates the protocol, which can only change along with the version
+    pub initiator: String,
+    /// The [names](message_name) of the messages of the protocol, sorted
+    pub messages: Vec<String>,
+    /// The request/response pairs of the protocol
+    pub requests: Vec<RequestInfo>,
+}
+
+/// Lists the protocols a peer supports. Sent by both sides as the first message of a transaction, whenever
+/// they connect or add a protocol.
+#[message(priority = priorities::INTERACTIVE)]
+#[derive(Clone, Debug, Default)]
+pub struct ProtocolHandshake {
+    /// The protocols the peer supports
+    pub protocols: Vec<ProtocolInfo>,
+}
+
+/// Whether a [`Protocol`] can be used with the remote application.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProtocolStatus {
+    /// The remote application hasn't sent its handshake yet
+    Pending,
+    /// Both sides support the protocol
+    Confirmed,
+    /// One of the sides doesn't support the protocol
+    Unsupported,
+    /// Both sides support a protocol of this name, but at different versions
+    Incompatible,
+}
+
+/// The protocols supported by this application and the remote application.
+#[derive(Debug, Default)]
+pub struct Protocols {
+    local: HashMap<String, ProtocolInfo>,
+    remote: Option<HashMap<String, ProtocolInfo>>,
+    /// The protocol each message belongs to, and the side which initiates it
+    initiators: HashMap<TypeId, (&'static str, Side)>,
+    /// This application's side, once [`CommonPlugin`](crate::CommonPlugin) is added
+    side: Option<Side>,
+    revision: u64,
+    generation: u64,
+}
+
+impl Protocols {
+    /// Adds a protocol this application supports. Prefer [`add_protocol`](AppProtocolExt::add_protocol),
+    /// which registers its messages too.
+    pub fn add<P: Protocol>(&mut self) {
+        self.local.insert(P::NAME.to_string(), P::info());
+        for type_id in P::message_types() {
+            self.initiators.insert(type_id, (P::NAME, P::INITIATOR));
+        }
+        self.revision += 1;
+    }
+
+    pub(crate) fn set_side(&mut self, side: Side) {
+        self.side = Some(side);
+    }
+
+    /// The protocol of a transaction the remote application opened with a message of type `first_message`,
+    /// if only this application may open that protocol's transactions.
+    pub(crate) fn rejected_by_initiator(&self, first_message: TypeId) -> Option<&'static str> {
+        match self.initiators.get(&first_message) {
+            Some(&(name, initiator)) if Some(initiator) == self.side => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Whether `P` can be used with the remote application.
+    pub fn status<P: Protocol>(&self) -> ProtocolStatus {
+        self.status_of(P::NAME)
+    }
+
+    /// Whether the protocol named `name` can be used with the remote application.
+    pub fn status_of(&self, name: &str) -> ProtocolStatus {
+        let local = match self.local.get(name) {
+            Some(local) => local,
+            None => return ProtocolStatus::Unsupported,
+        };
+
+        match self.remote.as_ref().map(|remote| remote.get(name)) {
+            None => ProtocolStatus::Pending,
+            Some(None) => ProtocolStatus::Unsupported,
+            Some(Some(remote)) if remote.version == local.version => ProtocolStatus::Confirmed,
+            Some(Some(_)) => ProtocolStatus::Incompatible,
+        }
+    }
+
+    /// Returns `true` if both sides support `P`.
+    pub fn is_confirmed<P: Protocol>(&self) -> bool {
+        self.status::<P>() == ProtocolStatus::Confirmed
+    }
+
+    /// The protocols this application supports.
+    pub fn local(&self) -> impl Iterator<Item = &ProtocolInfo> {
+        self.local.values()
+    }
+
+    /// The protocols the remote application supports, once it has sent its handshake.
+    pub fn remote(&self) -> Option<impl Iterator<Item = &ProtocolInfo>> {
+        self.remote.as_ref().map(|remote| remote.values())
+    }
+
+    fn handshake(&self) -> ProtocolHandshake {
+        ProtocolHandshake {
+            protocols: self.local.values().cloned().collect(),
+        }
+    }
+
+    fn set_remote(&mut self, handshake: ProtocolHandshake) {
+        self.remote = Some(
+            handshake
+                .protocols
+                .into_iter()
+                .map(|info| (info.name.clone(), info))
+                .collect(),
+        );
+    }
+}
+
+/// Adds [`Protocol`]s to an app.
+pub trait AppProtocolExt {
+    /// Registers the messages of `P` and announces it to the remote application. Must be called on both
+    /// sides for the protocol to be [confirmed](ProtocolStatus::Confirmed).
+    fn add_protocol<P: Protocol>(&mut self) -> &mut Self;
+}
+
+impl AppProtocolExt for App {
+    fn add_protocol<P: Protocol>(&mut self) -> &mut Self {
+        P::register(self);
+        self.world
+            .get_resource_or_insert_with(Protocols::default)
+            .add::<P>();
+        self
+    }
+}
+
+/// A run criterion which runs the system once both sides have confirmed `P`.
+pub fn protocol_confirmed<P: Protocol>(protocols: Res<Protocols>) -> ShouldRun {
+    match protocols.is_confirmed::<P>() {
+        true => ShouldRun::Yes,
+        false => ShouldRun::No,
+    }
+}
+
+/// The handshakes sent by the remote application, as they arrive from the transaction registry.
+pub(crate) struct ReceivedHandshakes(Mutex<Receiver<(Transaction, MessageBox)>>);
+
+impl ReceivedHandshakes {
+    pub(crate) fn register(registry: &mut TransactionRegistry) -> Self {
+        let (tx, rx) = mpsc::channel();
+        registry.register::<ProtocolHandshake>(tx);
+        Self(Mutex::new(rx))
+    }
+}
+
+/// Sends the [`ProtocolHandshake`], again whenever the remote thread is reopened or a protocol is added.
+pub(crate) fn send_handshake(
+    protocols: Res<Protocols>,
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
+    mut subscription: Local<Option<(u64, Subscription)>>,
+) {
+    let stale = subscription
+        .as_ref()
+        .is_none_or(|(revision, _)| *revision != protocols.revision);
+    if stale {
+        let handshake = protocols.handshake();
+        *subscription = Some((
+            protocols.revision,
+            Subscription::new(move |_| handshake.clone()),
+        ));
+    }
+
+    _ = subscription
+        .as_mut()
+        .unwrap()
+        .1
+        .update(&mut interface, &session);
+}
+
+/// Keeps the protocols of the remote application up to date with its latest handshake, and forgets them
+/// when the remote thread is reopened until the new handshake arrives.
+pub(crate) fn receive_handshakes(
+    handshakes: Res<ReceivedHandshakes>,
+    mut protocols: ResMut<Protocols>,
+    session: Res<Session>,
+) {
+    if protocols.generation != session.generation() {
+        protocols.generation = session.generation();
+        protocols.remote = None;
+    }
+
+    for (_, msg) in handshakes.0.lock().unwrap().try_iter() {
+        let handshake = match msg.downcast::<ProtocolHandshake>() {
+            Ok(handshake) => handshake,
+            Err(_) => continue,
+        };
+        protocols.set_remote(handshake);
+
+        for info in protocols.local.values() {
+            let problem = match protocols.status_of(&info.name) {
+                ProtocolStatus::Unsupported => "doesn't support",
+                ProtocolStatus::Incompatible => "has an incompatible version of",
+                _ => continue,
+            };
+            warn!(protocol = %info.name, "the remote application {} this protocol", problem);
+        }
+    }
+}
+
+/// Declares a [`Protocol`]: its messages, which side initiates it and its request/response pairs.
+///
+/// Messages of requests and responses don't need to be repeated in `messages`.
+///
+/// ## Example:
+/// ```
+/// # use bevy_editor_iris_common::protocol;
+/// # use bevy_editor_iris_derive::message;
+/// #[message]
+/// struct Ping {
+///     sequence: u32,
+/// }
+///
+/// #[message]
+/// struct Pong {
+///     sequence: u32,
+/// }
+///
+/// #[message]
+/// struct Goodbye {
+///     reason: String,
+/// }
+///
+/// protocol! {
+///     /// Checks whether the game is still responsive
+///     pub struct PingProtocol {
+///         name: "example.ping",
+///         version: 1,
+///         initiator: Editor,
+///         messages: [Goodbye],
+///         requests: [Ping => Pong],
+///     }
+/// }
+///
+/// // On both sides
+/// # use bevy::prelude::App;
+/// # use bevy_editor_iris_common::protocol::AppProtocolExt;
+/// App::new().add_protocol::<PingProtocol>();
+/// ```
+#[macro_export]
+macro_rules! protocol {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $ident:ident {
+            name: $name:expr,
+            version: $version:expr,
+            initiator: $initiator:ident,
+            messages: [$($message:ty),* $(,)?]
+            $(, requests: [$($request:ty => $response:ty),* $(,)?])?
+            $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $ident;
+
+        impl $crate::protocol::Protocol for $ident {
+            const NAME: &'static str = $name;
+            const VERSION: u32 = $version;
+            const INITIATOR: $crate::config::Side = $crate::config::Side::$initiator;
+
+            fn register(app: &mut $crate::deps::bevy::prelude::App) {
+                $(app.register_type::<$message>();)*
+                $($(
+                    app.register_type::<$request>();
+                    app.register_type::<$response>();
+                )*)?
+            }
+
+            fn message_types() -> ::std::vec::Vec<::std::any::TypeId> {
+                #[allow(unused_mut)]
+                let mut types: ::std::vec::Vec<::std::any::TypeId> =
+                    ::std::vec![$(::std::any::TypeId::of::<$message>()),*];
+                $($(
+                    types.push(::std::any::TypeId::of::<$request>());
+                    types.push(::std::any::TypeId::of::<$response>());
+                )*)?
+                types
+            }
+
+            fn info() -> $crate::protocol::ProtocolInfo {
+                #[allow(unused_mut)]
+                let mut messages: ::std::vec::Vec<::std::string::String> =
+                    ::std::vec![$($crate::protocol::message_name::<$message>()),*];
+                #[allow(unused_mut)]
+                let mut requests: ::std::vec::Vec<$crate::protocol::RequestInfo> = ::std::vec::Vec::new();
+                $($(
+                    let request = $crate::protocol::RequestInfo {
+                        request: $crate::protocol::message_name::<$request>(),
+                        response: $crate::protocol::message_name::<$response>(),
+                    };
+                    messages.push(request.request.clone());
+                    messages.push(request.response.clone());
+                    requests.push(request);
+                )*)?
+                messages.sort();
+                messages.dedup();
+
+                $crate::protocol::ProtocolInfo {
+                    name: ::std::string::ToString::to_string(<Self as $crate::protocol::Protocol>::NAME),
+                    version: <Self as $crate::protocol::Protocol>::VERSION,
+                    initiator: ::std::string::ToString::to_string(
+                        <Self as $crate::protocol::Protocol>::INITIATOR.as_str(),
+                    ),
+                    messages,
+                    requests,
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::{CrashReportProtocol, OpenCrashReporter};
+    use crate::logs::LogsProtocol;
+
+    #[test]
+    fn protocols_confirmed_by_both_sides() {
+        let mut editor = Protocols::default();
+        editor.add::<LogsProtocol>();
+        editor.add::<CrashReportProtocol>();
+
+        let mut game = Protocols::default();
+        game.add::<LogsProtocol>();
+
+        assert_eq!(editor.status::<LogsProtocol>(), ProtocolStatus::Pending);
+
+        editor.set_remote(game.handshake());
+        game.set_remote(editor.handshake());
+
+        assert!(editor.is_confirmed::<LogsProtocol>());
+        assert!(game.is_confirmed::<LogsProtocol>());
+        assert_eq!(
+            editor.status::<CrashReportProtocol>(),
+            ProtocolStatus::Unsupported
+        );
+        assert_eq!(
+            game.status::<CrashReportProtocol>(),
+            ProtocolStatus::Unsupported
+        );
+
+        // A game built against an older version of the protocol
+        let mut old = game.handshake();
+        old.protocols[0].version = 0;
+        editor.set_remote(old);
+        assert_eq!(
+            editor.status::<LogsProtocol>(),
+            ProtocolStatus::Incompatible
+        );
+    }
+
+    #[test]
+    fn transactions_opened_by_the_wrong_side_rejected() {
+        let mut game = Protocols::default();
+        game.add::<CrashReportProtocol>();
+        game.set_side(Side::Game);
+
+        let mut editor = Protocols::default();
+        editor.add::<CrashReportProtocol>();
+        editor.set_side(Side::Editor);
+
+        let open = TypeId::of::<OpenCrashReporter>();
+        assert_eq!(game.rejected_by_initiator(open), Some("iris.crash"));
+        assert_eq!(editor.rejected_by_initiator(open), None);
+    }
+
+    #[test]
+    fn requests_are_messages() {
+        let info = CrashReportProtocol::info();
+        assert_eq!(info.name, "iris.crash");
+        assert_eq!(info.initiator, "game");
+        assert_eq!(info.requests.len(), 1);
+        for request in &info.requests {
+            assert!(info.messages.contains(&request.request));
+            assert!(info.messages.contains(&request.response));
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! Bounded queues of [messages](Message) between the local threads and the remote thread.
+//!
+//! Every [transaction](crate::interface::Transaction) has one queue in each direction. When a queue is full,
+//! its [`DropPolicy`] decides whether the sender waits for space or a message is discarded. This lets
+//! high-rate producers, such as scene diffs, degrade gracefully when the connection stalls instead
+//! of buffering an unbounded number of messages. [`QueueMetrics`] report how full a queue is and how many
+//! messages it has discarded.
+//!
+//! [`CloseTransaction`] and [`AbortTransaction`] are never discarded and don't count towards the capacity of the queue,
+//! so they are queued even when it is full.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::Notify;
+
+use crate::asynchronous::MessageBox;
+use crate::interface::{AbortTransaction, CloseTransaction};
+use crate::message::Message;
+
+/// What a queue does with a new message when it is full.
+#[derive(Clone, Copy)]
+pub enum DropPolicy {
+    /// Refuse the message until there is space for it. [`Transaction::send`](crate::interface::Transaction::send)
+    /// fails, while [`Transaction::send_blocking`](crate::interface::Transaction::send_blocking) waits.
+    Block,
+    /// Discard the oldest queued message to make space. If the capacity is zero, the new message is discarded instead.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Replace the queued message with the same key, wherever it is in the queue. Messages without a key
+    /// are never replaced. If the queue is full and nothing can be replaced, a message is discarded
+    /// as with [`DropPolicy::DropOldest`].
+    ///
+    /// Coalescing happens whether or not the queue is full, so only the latest message for each key is ever queued.
+    Coalesce(fn(&dyn Message) -> Option<u64>),
+}
+
+impl std::fmt::Debug for DropPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DropPolicy::Block => f.write_str("Block"),
+            DropPolicy::DropOldest => f.write_str("DropOldest"),
+            DropPolicy::DropNewest => f.write_str("DropNewest"),
+            DropPolicy::Coalesce(_) => f.write_str("Coalesce"),
+        }
+    }
+}
+
+/// Configures the [queue](self) of one direction of a transaction.
+///
+/// ## Example:
+/// ```
+/// # use bevy_editor_iris_common::queue::{DropPolicy, QueueConfig};
+/// // Only the latest 16 messages are kept if the remote application falls behind
+/// let config = QueueConfig {
+///     capacity: 16,
+///     policy: DropPolicy::DropOldest,
+/// };
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct QueueConfig {
+    /// The maximum number of messages the queue holds, not counting control messages.
+    pub capacity: usize,
+    /// What to do with a new message when the queue is full.
+    pub policy: DropPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: DropPolicy::Block,
+        }
+    }
+}
+
+/// A snapshot of the depth of a queue and how many messages it has discarded.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueueMetrics {
+    /// The number of messages currently queued.
+    pub len: usize,
+    /// The maximum number of messages the queue holds.
+    pub capacity: usize,
+    /// The most messages that have been queued at once.
+    pub high_water_mark: usize,
+    /// The number of messages discarded by [`DropPolicy::DropOldest`], [`DropPolicy::DropNewest`]
+    /// or [`DropPolicy::Coalesce`] because the queue was full.
+    pub dropped: u64,
+    /// The number of messages replaced by a newer message with the same key.
+    pub coalesced: u64,
+}
+
+struct Inner {
+    queue: VecDeque<MessageBox>,
+    /// The number of control messages in `queue`, which don't count towards its capacity
+    controls: usize,
+    config: QueueConfig,
+    senders: usize,
+    receiver_closed: bool,
+    high_water_mark: usize,
+    dropped: u64,
+    coalesced: u64,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    /// Notified when a message is queued or the last sender is dropped
+    pushed: Notify,
+    /// Notified when a message is taken, the config changes or the receiver is dropped
+    popped: Notify,
+}
+
+enum Push {
+    Queued,
+    Full(MessageBox),
+    Closed(MessageBox),
+}
+
+/// Creates a bounded queue of messages, returning the sending and receiving halves.
+pub(crate) fn channel(config: QueueConfig) -> (MessageTx, MessageRx) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            queue: VecDeque::new(),
+            controls: 0,
+            config,
+            senders: 1,
+            receiver_closed: false,
+            high_water_mark: 0,
+            dropped: 0,
+            coalesced: 0,
+        }),
+        pushed: Notify::new(),
+        popped: Notify::new(),
+    });
+
+    (
+        MessageTx {
+            shared: shared.clone(),
+        },
+        MessageRx { shared },
+    )
+}
+
+fn is_control(msg: &dyn Message) -> bool {
+    msg.is::<CloseTransaction>() || msg.is::<AbortTransaction>()
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    fn push(&self, msg: MessageBox) -> Push {
+        let mut guard = self.lock();
+        let inner = &mut *guard;
+        if inner.receiver_closed {
+            return Push::Closed(msg);
+        }
+
+        if !is_control(&*msg) {
+            if let DropPolicy::Coalesce(key_of) = inner.config.policy {
+                if let Some(key) = key_of(&*msg) {
+                    let queued = inner.queue.iter_mut().find(|queued| {
+                        !is_control(queued.as_ref()) && key_of(queued.as_ref()) == Some(key)
+                    });
+                    if let Some(queued) = queued {
+                        *queued = msg;
+                        inner.coalesced += 1;
+                        return Push::Queued;
+                    }
+                }
+            }
+
+            if inner.queue.len() - inner.controls >= inner.config.capacity {
+                match inner.config.policy {
+                    DropPolicy::Block => return Push::Full(msg),
+                    DropPolicy::DropNewest => {
+                        inner.dropped += 1;
+                        return Push::Queued;
+                    }
+                    DropPolicy::DropOldest | DropPolicy::Coalesce(_) => {
+                        let oldest = inner
+                            .queue
+                            .iter()
+                            .position(|queued| !is_control(queued.as_ref()));
+                        inner.dropped += 1;
+                        match oldest {
+                            Some(oldest) => _ = inner.queue.remove(oldest),
+                            // Nothing can be queued with a capacity of zero
+                            None => return Push::Queued,
+                        }
+                    }
+                }
+            }
+        }
+
+        if is_control(&*msg) {
+            inner.controls += 1;
+        }
+        inner.queue.push_back(msg);
+        inner.high_water_mark = inner.high_water_mark.max(inner.queue.len());
+        drop(guard);
+
+        self.pushed.notify_waiters();
+        Push::Queued
+    }
+
+    /// Takes the next message, or `Err(true)` if the queue is empty and every sender was dropped.
+    fn pop(&self) -> Result<MessageBox, bool> {
+        let mut inner = self.lock();
+        match inner.queue.pop_front() {
+            Some(msg) => {
+                if is_control(&*msg) {
+                    inner.controls -= 1;
+                }
+                drop(inner);
+                self.popped.notify_waiters();
+                Ok(msg)
+            }
+            None => Err(inner.senders == 0),
+        }
+    }
+
+    fn metrics(&self) -> QueueMetrics {
+        let inner = self.lock();
+        QueueMetrics {
+            len: inner.queue.len(),
+            capacity: inner.config.capacity,
+            high_water_mark: inner.high_water_mark,
+            dropped: inner.dropped,
+            coalesced: inner.coalesced,
+        }
+    }
+
+    fn set_config(&self, config: QueueConfig) {
+        self.lock().config = config;
+        // Blocked senders may have room now
+        self.popped.notify_waiters();
+    }
+}
+
+/// A [cloneable](Clone) sender of [messages](MessageBox) into a bounded queue
+pub struct MessageTx {
+    shared: Arc<Shared>,
+}
+
+/// A receiver of [messages](MessageBox) from a bounded queue
+pub struct MessageRx {
+    shared: Arc<Shared>,
+}
+
+impl MessageTx {
+    /// Queue a message, waiting for space if the queue is full and uses [`DropPolicy::Block`].
+    /// Fails if the receiver was dropped.
+    pub async fn send(&self, mut msg: MessageBox) -> Result<(), SendError<MessageBox>> {
+        loop {
+            let popped = self.shared.popped.notified();
+            match self.shared.push(msg) {
+                Push::Queued => return Ok(()),
+                Push::Closed(msg) => return Err(SendError(msg)),
+                Push::Full(full) => msg = full,
+            }
+            popped.await;
+        }
+    }
+
+    /// Queue a message without waiting. Fails with [`TrySendError::Full`] if the queue is full and uses
+    /// [`DropPolicy::Block`], and with [`TrySendError::Closed`] if the receiver was dropped.
+    pub fn try_send(&self, msg: MessageBox) -> Result<(), TrySendError<MessageBox>> {
+        match self.shared.push(msg) {
+            Push::Queued => Ok(()),
+            Push::Full(msg) => Err(TrySendError::Full(msg)),
+            Push::Closed(msg) => Err(TrySendError::Closed(msg)),
+        }
+    }
+
+    /// Like [`send`](Self::send), but blocks the current thread while waiting for space.
+    /// Must not be called from the remote thread, nor from a system unless stalling the frame is acceptable.
+    pub fn blocking_send(&self, msg: MessageBox) -> Result<(), SendError<MessageBox>> {
+        futures::executor::block_on(self.send(msg))
+    }
+
+    /// Returns `true` if the receiver was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().receiver_closed
+    }
+
+    /// Completes once the receiver is dropped.
+    pub async fn closed(&self) {
+        loop {
+            let popped = self.shared.popped.notified();
+            if self.is_closed() {
+                return;
+            }
+            popped.await;
+        }
+    }
+
+    /// Once the receiver has been dropped, takes every message it never received, in the order they were sent.
+    /// [`CloseTransaction`] and [`AbortTransaction`] are left out. Returns nothing while the receiver is alive.
+    pub fn take_unsent(&self) -> Vec<MessageBox> {
+        let mut inner = self.shared.lock();
+        if !inner.receiver_closed {
+            return vec![];
+        }
+
+        inner.controls = 0;
+        inner
+            .queue
+            .drain(..)
+            .filter(|msg| !is_control(&**msg))
+            .collect()
+    }
+
+    /// Returns the current [metrics](QueueMetrics) of the queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+
+    /// Replace the [config](QueueConfig) of the queue. Messages already queued are kept,
+    /// even if there are more than the new capacity.
+    pub fn set_config(&self, config: QueueConfig) {
+        self.shared.set_config(config);
+    }
+
+    /// Returns the current [config](QueueConfig) of the queue.
+    pub(crate) fn config(&self) -> QueueConfig {
+        self.shared.lock().config
+    }
+}
+
+impl Clone for MessageTx {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for MessageTx {
+    fn drop(&mut self) {
+        let mut inner = self.shared.lock();
+        inner.senders -= 1;
+        let last = inner.senders == 0;
+        drop(inner);
+
+        if last {
+            self.shared.pushed.notify_waiters();
+        }
+    }
+}
+
+impl MessageRx {
+    /// Wait for the next message. Returns `None` once the queue is empty and every sender was dropped.
+    pub async fn recv(&mut self) -> Option<MessageBox> {
+        loop {
+            let pushed = self.shared.pushed.notified();
+            match self.shared.pop() {
+                Ok(msg) => return Some(msg),
+                Err(true) => return None,
+                Err(false) => pushed.await,
+            }
+        }
+    }
+
+    /// Like [`recv`](Self::recv), but blocks the current thread while waiting.
+    /// Must not be called from the remote thread.
+    pub fn blocking_recv(&mut self) -> Option<MessageBox> {
+        futures::executor::block_on(self.recv())
+    }
+
+    /// Attempt to take the next message without waiting.
+    pub fn try_recv(&mut self) -> Result<MessageBox, TryRecvError> {
+        self.shared.pop().map_err(|disconnected| {
+            if disconnected {
+                TryRecvError::Disconnected
+            } else {
+                TryRecvError::Empty
+            }
+        })
+    }
+
+    /// Returns the current [metrics](QueueMetrics) of the queue.
+    pub fn metrics(&self) -> QueueMetrics {
+        self.shared.metrics()
+    }
+
+    /// Replace the [config](QueueConfig) of the queue. See [`MessageTx::set_config`].
+    pub fn set_config(&self, config: QueueConfig) {
+        self.shared.set_config(config);
+    }
+
+    /// Returns the current [config](QueueConfig) of the queue.
+    pub(crate) fn config(&self) -> QueueConfig {
+        self.shared.lock().config
+    }
+}
+
+impl Drop for MessageRx {
+    fn drop(&mut self) {
+        // Queued messages are kept so the senders can take them back with `take_unsent`
+        self.shared.lock().receiver_closed = true;
+        self.shared.popped.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Ping;
+
+    fn ping(sequence: u32) -> MessageBox {
+        Box::new(Ping { sequence })
+    }
+
+    fn sequence_of(msg: &dyn Message) -> Option<u32> {
+        msg.as_any()
+            .downcast_ref::<Ping>()
+            .map(|ping| ping.sequence)
+    }
+
+    /// Takes every queued message, returning the sequences of the pings.
+    fn drain(rx: &mut MessageRx) -> Vec<u32> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| sequence_of(&*msg))
+            .collect()
+    }
+
+    fn queue(capacity: usize, policy: DropPolicy) -> (MessageTx, MessageRx) {
+        channel(QueueConfig { capacity, policy })
+    }
+
+    #[tokio::test]
+    async fn block_refuses_until_there_is_space() {
+        let (tx, mut rx) = queue(2, DropPolicy::Block);
+        tx.try_send(ping(1)).unwrap();
+        tx.try_send(ping(2)).unwrap();
+        assert!(matches!(tx.try_send(ping(3)), Err(TrySendError::Full(_))));
+        // Control messages are queued regardless
+        tx.try_send(Box::new(CloseTransaction)).unwrap();
+
+        // A waiting sender is woken once a message is taken
+        let (sent, first) = tokio::join!(tx.send(ping(3)), rx.recv());
+        sent.unwrap();
+        assert_eq!(sequence_of(&*first.unwrap()), Some(1));
+        assert_eq!(drain(&mut rx), [2, 3]);
+        assert_eq!(tx.metrics().dropped, 0);
+    }
+
+    #[test]
+    fn drop_oldest_discards_the_oldest() {
+        let (tx, mut rx) = queue(2, DropPolicy::DropOldest);
+        for sequence in 1..=4 {
+            tx.try_send(ping(sequence)).unwrap();
+        }
+
+        assert_eq!(tx.metrics().dropped, 2);
+        assert_eq!(drain(&mut rx), [3, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_never_discards_control_messages() {
+        let (tx, mut rx) = queue(1, DropPolicy::DropOldest);
+        tx.try_send(Box::new(CloseTransaction)).unwrap();
+        tx.try_send(Box::new(AbortTransaction { code: 0 })).unwrap();
+        for sequence in 1..=3 {
+            tx.try_send(ping(sequence)).unwrap();
+        }
+
+        // The control messages don't take up space, and only the pings which were discarded are counted
+        assert_eq!(tx.metrics().len, 3);
+        assert_eq!(tx.metrics().dropped, 2);
+        assert_eq!(drain(&mut rx), [3]);
+
+        // With no space at all, the new message is discarded instead
+        let (tx, mut rx) = queue(0, DropPolicy::DropOldest);
+        tx.try_send(Box::new(CloseTransaction)).unwrap();
+        tx.try_send(ping(1)).unwrap();
+        assert_eq!(tx.metrics().len, 1);
+        assert_eq!(tx.metrics().dropped, 1);
+        assert!(drain(&mut rx).is_empty());
+    }
+
+    #[test]
+    fn drop_newest_discards_the_new_message() {
+        let (tx, mut rx) = queue(2, DropPolicy::DropNewest);
+        for sequence in 1..=4 {
+            tx.try_send(ping(sequence)).unwrap();
+        }
+
+        assert_eq!(tx.metrics().dropped, 2);
+        assert_eq!(drain(&mut rx), [1, 2]);
+    }
+
+    #[test]
+    fn coalesce_replaces_messages_with_the_same_key() {
+        // Pings below 10 are keyed by whether they are odd, the rest have no key
+        let (tx, mut rx) = queue(
+            2,
+            DropPolicy::Coalesce(|msg| {
+                sequence_of(msg)
+                    .filter(|&sequence| sequence < 10)
+                    .map(|sequence| (sequence % 2) as u64)
+            }),
+        );
+        tx.try_send(ping(1)).unwrap();
+        tx.try_send(ping(2)).unwrap();
+        // Replaced in place, keeping the order of the queue
+        tx.try_send(ping(3)).unwrap();
+        assert_eq!(tx.metrics().coalesced, 1);
+        assert_eq!(tx.metrics().dropped, 0);
+
+        // Nothing to replace in a full queue, so the oldest is discarded
+        tx.try_send(ping(10)).unwrap();
+        assert_eq!(tx.metrics().dropped, 1);
+        assert_eq!(drain(&mut rx), [2, 10]);
+    }
+
+    #[test]
+    fn metrics_track_the_queue() {
+        let (tx, mut rx) = queue(4, DropPolicy::Block);
+        for sequence in 1..=3 {
+            tx.try_send(ping(sequence)).unwrap();
+        }
+        rx.try_recv().unwrap();
+        rx.try_recv().unwrap();
+        tx.try_send(ping(4)).unwrap();
+
+        assert_eq!(
+            rx.metrics(),
+            QueueMetrics {
+                len: 2,
+                capacity: 4,
+                high_water_mark: 3,
+                dropped: 0,
+                coalesced: 0,
+            }
+        );
+
+        // Once the receiver is dropped, the messages it never took can be taken back
+        tx.try_send(Box::new(CloseTransaction)).unwrap();
+        drop(rx);
+        let unsent = tx.take_unsent();
+        assert_eq!(unsent.len(), 2);
+        assert_eq!(sequence_of(&*unsent[0]), Some(3));
+        assert_eq!(sequence_of(&*unsent[1]), Some(4));
+        assert!(matches!(tx.try_send(ping(5)), Err(TrySendError::Closed(_))));
+    }
+}
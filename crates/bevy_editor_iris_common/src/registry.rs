@@ -9,19 +9,21 @@
 use std::any::TypeId;
 use std::sync::mpsc::Sender;
 
+use bevy::log::warn;
 use bevy::prelude::{SystemLabel, World};
 use bevy::utils::HashMap;
 
-use crate::asynchronous::{MessageBox, MessageRx, MessageTx};
-use crate::interface::Interface;
+use crate::asynchronous::MessageBox;
+use crate::interface::{Interface, Transaction};
 use crate::message::Message;
+use crate::protocol::Protocols;
 
 /// A registry of channels to send incoming streams to, based on
 /// the [TypeId] of their first [message](Message).
 #[derive(Default)]
 pub struct TransactionRegistry {
-    map: HashMap<TypeId, Sender<(MessageTx, MessageRx, MessageBox)>>,
-    pool: HashMap<TypeId, Vec<(MessageTx, MessageRx, MessageBox)>>,
+    map: HashMap<TypeId, Sender<(Transaction, MessageBox)>>,
+    pool: HashMap<TypeId, Vec<(Transaction, MessageBox)>>,
 }
 
 impl TransactionRegistry {
@@ -36,8 +38,8 @@ impl TransactionRegistry {
     /// needed to allow the previous consumer to continue functioning.
     pub fn register<M: Message>(
         &mut self,
-        sender: Sender<(MessageTx, MessageRx, MessageBox)>,
-    ) -> Option<Sender<(MessageTx, MessageRx, MessageBox)>> {
+        sender: Sender<(Transaction, MessageBox)>,
+    ) -> Option<Sender<(Transaction, MessageBox)>> {
         match self.pool.remove(&TypeId::of::<M>()) {
             Some(pool) => {
                 for stream in pool {
@@ -51,7 +53,7 @@ impl TransactionRegistry {
     }
 
     /// Get the pool corresponding to streams with a first message of type M.
-    pub fn pool<M: Message>(&mut self) -> Option<&mut Vec<(MessageTx, MessageRx, MessageBox)>> {
+    pub fn pool<M: Message>(&mut self) -> Option<&mut Vec<(Transaction, MessageBox)>> {
         self.pool.get_mut(&TypeId::of::<M>())
     }
 }
@@ -64,27 +66,38 @@ pub(crate) fn update_transaction_registry(world: &mut World) {
     let mut registry: TransactionRegistry = world.remove_non_send_resource().unwrap();
     let interface: Interface = world.remove_resource().unwrap();
 
+    let protocols = world.get_resource::<Protocols>();
+
     let mut lock = match interface.inner.lock() {
         Ok(i) => i,
         Err(_) => return,
     };
 
-    while let Ok((tx, mut rx)) = lock.open_rx.try_recv() {
+    while let Ok(mut transaction) = lock.open_rx.try_recv() {
         // According to quinn, streams will not be picked up by the recipient until they're used.
         // This should mean that this will never block for a significant amount of time.
         // TODO: Verify this assumption
-        let first_msg = match rx.blocking_recv() {
-            Some(msg) => msg,
-            None => continue,
+        let first_msg = match transaction.recv() {
+            Ok(msg) => msg,
+            Err(_) => continue,
         };
         let id = first_msg.as_any().type_id();
 
+        if let Some(protocol) = protocols.and_then(|protocols| protocols.rejected_by_initiator(id))
+        {
+            warn!(
+                protocol,
+                "closed a transaction opened by the side which doesn't initiate its protocol"
+            );
+            continue;
+        }
+
         match registry.map.get(&id) {
-            Some(entry) => _ = entry.send((tx, rx, first_msg)),
+            Some(entry) => _ = entry.send((transaction, first_msg)),
             None => match registry.pool.get_mut(&id) {
-                Some(pool) => pool.push((tx, rx, first_msg)),
+                Some(pool) => pool.push((transaction, first_msg)),
                 None => {
-                    registry.pool.insert(id, vec![(tx, rx, first_msg)]);
+                    registry.pool.insert(id, vec![(transaction, first_msg)]);
                 }
             },
         }
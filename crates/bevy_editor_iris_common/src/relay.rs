@@ -0,0 +1,321 @@
+//! Connecting through a relay, for when neither side can accept incoming connections.
+//!
+//! Both sides [dial](crate::config::Role::Dial) the relay and join the same room by its code. The relay pairs the
+//! two connections in a room and forwards every stream between them, so once [`join_room`] returns the connection
+//! behaves as if it was made directly to the remote application.
+//!
+//! The handshake is a [`JoinRoom`] message on the first stream of the connection, which the relay answers with
+//! [`RoomPaired`] once the remote application has joined too. The relay itself is run by [`serve`].
+//!
+//! The relay terminates QUIC, so both sides must pin the [fingerprint](crate::transport::CertificateFingerprint)
+//! of the certificate of the relay with [`ConnectionConfig::remote_fingerprint`](crate::config::ConnectionConfig::remote_fingerprint).
+
+use std::sync::{Arc, Mutex};
+
+use bevy::log::{debug, info, warn};
+use bevy::reflect::TypeRegistry;
+use bevy::utils::HashMap;
+use bevy_editor_iris_derive::message;
+use futures_lite::StreamExt;
+use quinn::{
+    Connecting, Connection, Incoming, NewConnection, ReadError, RecvStream, SendStream, VarInt,
+    WriteError,
+};
+use tokio::select;
+use tokio::sync::oneshot;
+
+use crate::asynchronous;
+use crate::error::RelayError;
+use crate::serde::RegistryContext;
+
+/// The error code connections are closed with when the other side of the pair leaves.
+const PEER_LEFT: u32 = 0;
+
+/// Asks the relay to pair this connection with the other one in the room.
+#[message]
+#[derive(Clone, Debug)]
+pub struct JoinRoom {
+    /// The room code both sides agreed on
+    pub room: String,
+}
+
+/// Sent by the relay once both sides have joined the room.
+#[message]
+#[derive(Clone, Debug)]
+pub struct RoomPaired {
+    /// The room code which was joined
+    pub room: String,
+}
+
+/// Joins `room` on the relay at the other end of `connection`, waiting until the remote application joins too.
+pub async fn join_room(
+    connection: &Connection,
+    room: &str,
+    registry: &RegistryContext,
+) -> Result<(), RelayError> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+
+    asynchronous::write_message(
+        &mut send,
+        Box::new(JoinRoom {
+            room: room.to_string(),
+        }),
+        registry,
+    )
+    .await?;
+
+    let reply = asynchronous::read_message(&mut recv, registry).await?;
+    if !reply.is::<RoomPaired>() {
+        return Err(RelayError::UnexpectedMessage(reply.type_name().to_string()));
+    }
+
+    _ = send.finish().await;
+
+    Ok(())
+}
+
+/// The connections waiting for the other side to join, by room code.
+type Rooms = Arc<Mutex<HashMap<String, oneshot::Sender<Peer>>>>;
+
+/// A connection which has joined a room, and the stream it joined on.
+struct Peer {
+    new: NewConnection,
+    send: SendStream,
+}
+
+/// A registry of the handshake messages and the types they contain, which is all a relay needs to
+/// deserialize.
+pub fn registry() -> RegistryContext {
+    let registry = TypeRegistry::default();
+    {
+        let mut registry = registry.write();
+        registry.register::<String>();
+        registry.register::<JoinRoom>();
+        registry.register::<RoomPaired>();
+    }
+    RegistryContext::new(registry)
+}
+
+/// Relays the connections from `incoming`, pairing them by the room they join. `registry` must have
+/// [`JoinRoom`] and [`RoomPaired`] registered, such as the one built by [`registry`].
+pub async fn serve(mut incoming: Incoming, registry: RegistryContext) {
+    let rooms = Rooms::default();
+
+    while let Some(connecting) = incoming.next().await {
+        let rooms = rooms.clone();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = join(connecting, rooms, registry).await {
+                warn!(error = %err, "failed to join a room");
+            }
+        });
+    }
+}
+
+/// Waits for a new connection to join a room, then pairs it with the connection already waiting
+/// in the room, or waits for one to join.
+async fn join(
+    connecting: Connecting,
+    rooms: Rooms,
+    registry: RegistryContext,
+) -> Result<(), RelayError> {
+    let mut new = connecting.await?;
+    let (send, mut recv) = match new.bi_streams.next().await {
+        Some(stream) => stream?,
+        None => return Ok(()),
+    };
+
+    let room = match asynchronous::read_message(&mut recv, &registry)
+        .await?
+        .downcast::<JoinRoom>()
+    {
+        Ok(join) => join.room,
+        Err(msg) => return Err(RelayError::UnexpectedMessage(msg.type_name().to_string())),
+    };
+
+    let mut peer = Peer { new, send };
+    let waiting = loop {
+        let waiting = rooms.lock().unwrap().remove(&room);
+        match waiting {
+            Some(waiting) => match waiting.send(peer) {
+                // The connection waiting in the room takes over
+                Ok(()) => return Ok(()),
+                // The connection waiting in the room left, so try again
+                Err(returned) => peer = returned,
+            },
+            None => {
+                let (tx, rx) = oneshot::channel();
+                rooms.lock().unwrap().insert(room.clone(), tx);
+                break rx;
+            }
+        }
+    };
+
+    debug!(%room, "waiting for the other side of the room");
+
+    // The waiting side never opens streams of its own, so the next stream is only ever the connection closing
+    let other = select! {
+        other = waiting => match other {
+            Ok(other) => other,
+            Err(_) => return Ok(()),
+        },
+        _ = peer.new.bi_streams.next() => return Ok(()),
+    };
+
+    info!(%room, "paired room");
+
+    pair(&room, peer, other, &registry).await?;
+
+    info!(%room, "closed room");
+
+    Ok(())
+}
+
+async fn pair(room: &str, a: Peer, b: Peer, registry: &RegistryContext) -> Result<(), RelayError> {
+    let mut connections = vec![];
+    for Peer { new, mut send } in [a, b] {
+        asynchronous::write_message(
+            &mut send,
+            Box::new(RoomPaired {
+                room: room.to_string(),
+            }),
+            registry,
+        )
+        .await?;
+        _ = send.finish().await;
+        connections.push(new);
+    }
+
+    let b = connections.pop().unwrap();
+    let a = connections.pop().unwrap();
+    forward(a, b).await;
+
+    Ok(())
+}
+
+/// Forwards every stream opened by one connection to the other, until either closes.
+async fn forward(mut a: NewConnection, mut b: NewConnection) {
+    loop {
+        select! {
+            stream = a.bi_streams.next() => match stream {
+                Some(Ok(stream)) => {
+                    tokio::spawn(bridge(stream, b.connection.clone()));
+                }
+                _ => break,
+            },
+            stream = b.bi_streams.next() => match stream {
+                Some(Ok(stream)) => {
+                    tokio::spawn(bridge(stream, a.connection.clone()));
+                }
+                _ => break,
+            },
+        }
+    }
+
+    a.connection
+        .close(VarInt::from_u32(PEER_LEFT), b"peer left");
+    b.connection
+        .close(VarInt::from_u32(PEER_LEFT), b"peer left");
+}
+
+/// Opens a stream to `to` and copies both directions of `stream` to it.
+async fn bridge((send, recv): (SendStream, RecvStream), to: Connection) {
+    let (to_send, to_recv) = match to.open_bi().await {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+
+    tokio::join!(pipe(recv, to_send), pipe(to_recv, send));
+}
+
+/// Copies one direction of a stream, passing finishes, resets and stops along.
+async fn pipe(mut recv: RecvStream, mut send: SendStream) {
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        match recv.read(&mut buf).await {
+            Ok(Some(len)) => {
+                if let Err(err) = send.write_all(&buf[..len]).await {
+                    if let WriteError::Stopped(code) = err {
+                        _ = recv.stop(code);
+                    }
+                    return;
+                }
+            }
+            Ok(None) => {
+                _ = send.finish().await;
+                return;
+            }
+            Err(ReadError::Reset(code)) => {
+                _ = send.reset(code);
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+    use quinn::Endpoint;
+
+    use crate::config::{ConnectionConfig, Role, Side};
+    use crate::testing::{self, blocking, Ping, TIMEOUT};
+    use crate::transport::{self, Identity};
+
+    #[tokio::test]
+    async fn relay_pairs_editor_and_game() {
+        let registry = testing::registry();
+        let identity = Identity::generate().unwrap();
+        let server_config = transport::server_config(&identity, &default()).unwrap();
+        let (relay, incoming) = Endpoint::server(server_config, testing::localhost()).unwrap();
+        tokio::spawn(serve(incoming, super::registry()));
+
+        let config = ConnectionConfig {
+            role: Some(Role::Dial),
+            remote_addr: Some(relay.local_addr().unwrap()),
+            room: Some("test".to_string()),
+            remote_fingerprint: Some(identity.fingerprint()),
+            ..default()
+        };
+        let dial = |side, remote: testing::Remote| {
+            transport::dial(
+                side,
+                remote.tx,
+                remote.rx,
+                remote.progress,
+                config.clone(),
+                registry.clone(),
+            )
+        };
+
+        let (editor, editor_remote) = testing::peer();
+        let (mut game, game_remote) = testing::peer();
+
+        let test = async {
+            let mut local = editor.open();
+            local.send(Ping { sequence: 1 }).unwrap();
+
+            let remote = game.accept().await;
+            let (remote, msg) = blocking(move || {
+                let mut remote = remote;
+                let msg = remote.recv().unwrap();
+                (remote, msg)
+            })
+            .await;
+            assert_eq!(msg.downcast::<Ping>().unwrap().sequence, 1);
+
+            remote.send(Ping { sequence: 2 }).unwrap();
+            let reply = blocking(move || local.recv().unwrap()).await;
+            assert_eq!(reply.downcast::<Ping>().unwrap().sequence, 2);
+        };
+
+        select! {
+            result = dial(Side::Editor, editor_remote) => panic!("the editor stopped: {:?}", result),
+            result = dial(Side::Game, game_remote) => panic!("the game stopped: {:?}", result),
+            result = tokio::time::timeout(TIMEOUT, test) => result.expect("the test timed out"),
+        }
+    }
+}
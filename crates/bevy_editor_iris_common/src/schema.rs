@@ -0,0 +1,735 @@
+//! Schemas of the types registered in the remote application, so the editor can handle types it wasn't compiled with.
+//!
+//! The game describes its reflected types in a [`RegistrySchema`], which the editor adds to its
+//! [`RegistryContext`](crate::serde::RegistryContext). Values of types which aren't registered locally are then
+//! deserialized into `DynamicStruct`, `DynamicTupleStruct`, `DynamicList` and similar trees, which can be shown and
+//! edited like any other reflected value. Value types which are only known from the schema, such as a custom id type,
+//! are kept as an [`UnknownValue`] and sent back exactly as they were received.
+
+use std::any::Any;
+use std::fmt;
+
+use bevy::reflect::serde::Serializable;
+use bevy::reflect::{
+    DynamicList, DynamicMap, DynamicStruct, DynamicTuple, DynamicTupleStruct, FromReflect, Reflect,
+    ReflectDeserialize, ReflectMut, ReflectRef, TypeRegistryInternal,
+};
+use bevy::utils::HashMap;
+use bevy_editor_iris_derive::message;
+use serde::de::{self, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::message::priorities;
+
+/// The kind of a type, matching the variants of [`ReflectRef`].
+#[derive(Clone, Copy, Debug, Deserialize, Eq, FromReflect, PartialEq, Reflect, Serialize)]
+#[reflect_value(PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeKind {
+    /// A struct with named fields
+    Struct,
+    /// A struct with unnamed fields
+    TupleStruct,
+    /// A tuple
+    Tuple,
+    /// A list, such as a `Vec`
+    List,
+    /// A map, such as a `HashMap`
+    Map,
+    /// A value which is serialized as a whole, such as an `f32` or a `String`
+    Value,
+}
+
+/// A field of a struct, tuple struct or tuple.
+#[derive(Clone, Debug, FromReflect, PartialEq, Reflect)]
+pub struct FieldSchema {
+    /// The name of the field, or its index for tuple structs and tuples
+    pub name: String,
+    /// The type name of the field
+    pub type_name: String,
+}
+
+/// Describes a reflected type of the remote application.
+#[derive(Clone, Debug, FromReflect, Reflect)]
+pub struct TypeSchema {
+    /// The type name
+    pub type_name: String,
+    /// The kind of the type, which values of it received from the remote application must have
+    pub kind: TypeKind,
+    /// The fields of a struct, tuple struct or tuple
+    pub fields: Vec<FieldSchema>,
+    /// The element type of a list, or the key and value types of a map, if any instance had elements
+    pub elements: Vec<String>,
+}
+
+/// Describes the reflected types of the remote application.
+#[message(priority = priorities::BULK)]
+#[derive(Clone, Debug, Default)]
+pub struct RegistrySchema {
+    /// The types, in no particular order
+    pub types: Vec<TypeSchema>,
+}
+
+crate::protocol! {
+    /// Sending the [`RegistrySchema`] of the game to the editor.
+    pub struct SchemaProtocol {
+        name: "iris.schema",
+        version: 1,
+        initiator: Game,
+        messages: [RegistrySchema],
+    }
+}
+
+/// Builds a [`RegistrySchema`].
+///
+/// Bevy can't describe the fields of a type without an instance of it, so types are added by
+/// [walking values](SchemaBuilder::add_value), which also adds the types of their fields.
+#[derive(Default)]
+pub struct SchemaBuilder {
+    types: HashMap<String, TypeSchema>,
+}
+
+impl SchemaBuilder {
+    /// Adds the type of `value`, and the types nested in it, unless they were already added.
+    pub fn add_value(&mut self, value: &dyn Reflect) {
+        if self.types.contains_key(value.type_name()) {
+            return;
+        }
+
+        let mut fields = Vec::new();
+        let mut elements = Vec::new();
+        let mut nested = Vec::new();
+        let kind = match value.reflect_ref() {
+            ReflectRef::Struct(value) => {
+                for (i, field) in value.iter_fields().enumerate() {
+                    fields.push(field_schema(value.name_at(i).unwrap(), field));
+                    nested.push(field);
+                }
+                TypeKind::Struct
+            }
+            ReflectRef::TupleStruct(value) => {
+                for (i, field) in value.iter_fields().enumerate() {
+                    fields.push(field_schema(&i.to_string(), field));
+                    nested.push(field);
+                }
+                TypeKind::TupleStruct
+            }
+            ReflectRef::Tuple(value) => {
+                for (i, field) in value.iter_fields().enumerate() {
+                    fields.push(field_schema(&i.to_string(), field));
+                    nested.push(field);
+                }
+                TypeKind::Tuple
+            }
+            ReflectRef::List(value) => {
+                if let Some(first) = value.get(0) {
+                    elements.push(first.type_name().to_string());
+                }
+                nested.extend(value.iter());
+                TypeKind::List
+            }
+            ReflectRef::Map(value) => {
+                if let Some((key, first)) = value.get_at(0) {
+                    elements.push(key.type_name().to_string());
+                    elements.push(first.type_name().to_string());
+                }
+                for (key, value) in value.iter() {
+                    nested.push(key);
+                    nested.push(value);
+                }
+                TypeKind::Map
+            }
+            ReflectRef::Value(_) => TypeKind::Value,
+        };
+
+        self.types.insert(
+            value.type_name().to_string(),
+            TypeSchema {
+                type_name: value.type_name().to_string(),
+                kind,
+                fields,
+                elements,
+            },
+        );
+
+        for value in nested {
+            self.add_value(value);
+        }
+    }
+
+    /// Adds a [value](TypeKind::Value) type by name, unless it was already added.
+    pub fn add_value_type(&mut self, type_name: &str) {
+        if !self.types.contains_key(type_name) {
+            self.types.insert(
+                type_name.to_string(),
+                TypeSchema {
+                    type_name: type_name.to_string(),
+                    kind: TypeKind::Value,
+                    fields: Vec::new(),
+                    elements: Vec::new(),
+                },
+            );
+        }
+    }
+
+    /// The number of types added so far.
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    /// Whether no types were added yet.
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    /// The schema of the types added so far.
+    pub fn schema(&self) -> RegistrySchema {
+        RegistrySchema {
+            types: self.types.values().cloned().collect(),
+        }
+    }
+
+    /// Finishes the schema.
+    pub fn finish(self) -> RegistrySchema {
+        RegistrySchema {
+            types: self.types.into_iter().map(|(_, schema)| schema).collect(),
+        }
+    }
+}
+
+fn field_schema(name: &str, field: &dyn Reflect) -> FieldSchema {
+    FieldSchema {
+        name: name.to_string(),
+        type_name: field.type_name().to_string(),
+    }
+}
+
+/// A value of a type which isn't registered locally, but is known from the remote application's
+/// [schema](RegistrySchema). The value is kept as it was serialized, so it is sent back unchanged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnknownValue {
+    type_name: String,
+    value: serde_yaml::Value,
+}
+
+impl UnknownValue {
+    /// Creates a value of the remote type `type_name`.
+    pub fn new(type_name: impl Into<String>, value: serde_yaml::Value) -> Self {
+        Self {
+            type_name: type_name.into(),
+            value,
+        }
+    }
+
+    /// The serialized value.
+    pub fn value(&self) -> &serde_yaml::Value {
+        &self.value
+    }
+
+    /// The serialized value, for editing. It must still deserialize as the remote type.
+    pub fn value_mut(&mut self) -> &mut serde_yaml::Value {
+        &mut self.value
+    }
+}
+
+/// Formats the value as yaml.
+impl fmt::Display for UnknownValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yaml = serde_yaml::to_string(&self.value).map_err(|_| fmt::Error)?;
+        f.write_str(yaml.trim_start_matches("---").trim())
+    }
+}
+
+unsafe impl Reflect for UnknownValue {
+    fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    fn any(&self) -> &dyn Any {
+        self
+    }
+
+    fn any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn apply(&mut self, value: &dyn Reflect) {
+        match value.downcast_ref::<Self>() {
+            Some(value) => *self = value.clone(),
+            None => panic!("Value is not {}.", self.type_name),
+        }
+    }
+
+    fn set(&mut self, value: Box<dyn Reflect>) -> Result<(), Box<dyn Reflect>> {
+        *self = value.take()?;
+        Ok(())
+    }
+
+    fn reflect_ref(&self) -> ReflectRef<'_> {
+        ReflectRef::Value(self)
+    }
+
+    fn reflect_mut(&mut self) -> ReflectMut<'_> {
+        ReflectMut::Value(self)
+    }
+
+    fn clone_value(&self) -> Box<dyn Reflect> {
+        Box::new(self.clone())
+    }
+
+    fn reflect_hash(&self) -> Option<u64> {
+        None
+    }
+
+    fn reflect_partial_eq(&self, value: &dyn Reflect) -> Option<bool> {
+        value.downcast_ref::<Self>().map(|value| value == self)
+    }
+
+    fn serializable(&self) -> Option<Serializable<'_>> {
+        Some(Serializable::Borrowed(&self.value))
+    }
+}
+
+/// The names of the fields bevy serializes reflected values with.
+mod type_fields {
+    pub const TYPE: &str = "type";
+    pub const MAP: &str = "map";
+    pub const STRUCT: &str = "struct";
+    pub const TUPLE_STRUCT: &str = "tuple_struct";
+    pub const TUPLE: &str = "tuple";
+    pub const LIST: &str = "list";
+    pub const VALUE: &str = "value";
+}
+
+/// Deserializes reflected values like bevy's `ReflectDeserializer`, but value types which aren't registered are
+/// deserialized as an [`UnknownValue`] if they are known from the remote application's schema.
+#[derive(Clone, Copy)]
+pub struct SchemaDeserializer<'a> {
+    registry: &'a TypeRegistryInternal,
+    schemas: &'a HashMap<String, TypeSchema>,
+}
+
+impl<'a> SchemaDeserializer<'a> {
+    /// Creates a deserializer which looks up types in `registry`, then in `schemas`.
+    pub fn new(
+        registry: &'a TypeRegistryInternal,
+        schemas: &'a HashMap<String, TypeSchema>,
+    ) -> Self {
+        Self { registry, schemas }
+    }
+}
+
+impl<'a> SchemaDeserializer<'a> {
+    /// Fails if `type_name` isn't registered locally, and the remote application's schema describes it
+    /// as another kind of type than the value received.
+    fn check_kind<E: de::Error>(&self, type_name: &str, kind: TypeKind) -> Result<(), E> {
+        if self.registry.get_with_name(type_name).is_some() {
+            return Ok(());
+        }
+
+        match self.schemas.get(type_name) {
+            Some(schema) if schema.kind != kind => Err(E::custom(format_args!(
+                "{} is a {:?}, but was received as a {:?}",
+                type_name, schema.kind, kind
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for SchemaDeserializer<'a> {
+    type Value = Box<dyn Reflect>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(ReflectVisitor(self))
+    }
+}
+
+struct ReflectVisitor<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> Visitor<'de> for ReflectVisitor<'a> {
+    type Value = Box<dyn Reflect>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("reflect value")
+    }
+
+    fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+    where
+        V: MapAccess<'de>,
+    {
+        let seed = self.0;
+        let mut type_name: Option<String> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                type_fields::TYPE => type_name = Some(map.next_value()?),
+                type_fields::STRUCT => {
+                    let type_name = take_name(&mut type_name)?;
+                    seed.check_kind(&type_name, TypeKind::Struct)?;
+                    let mut value = map.next_value_seed(StructDeserializer(seed))?;
+                    value.set_name(type_name);
+                    return Ok(Box::new(value));
+                }
+                type_fields::TUPLE_STRUCT => {
+                    let type_name = take_name(&mut type_name)?;
+                    seed.check_kind(&type_name, TypeKind::TupleStruct)?;
+                    let mut value = map.next_value_seed(TupleStructDeserializer(seed))?;
+                    value.set_name(type_name);
+                    return Ok(Box::new(value));
+                }
+                type_fields::TUPLE => {
+                    let type_name = take_name(&mut type_name)?;
+                    seed.check_kind(&type_name, TypeKind::Tuple)?;
+                    let mut value = map.next_value_seed(TupleDeserializer(seed))?;
+                    value.set_name(type_name);
+                    return Ok(Box::new(value));
+                }
+                type_fields::LIST => {
+                    let type_name = take_name(&mut type_name)?;
+                    seed.check_kind(&type_name, TypeKind::List)?;
+                    let mut value = map.next_value_seed(ListDeserializer(seed))?;
+                    value.set_name(type_name);
+                    return Ok(Box::new(value));
+                }
+                type_fields::MAP => {
+                    let type_name = take_name(&mut type_name)?;
+                    seed.check_kind(&type_name, TypeKind::Map)?;
+                    let mut value = map.next_value_seed(MapDeserializer(seed))?;
+                    value.set_name(type_name);
+                    return Ok(Box::new(value));
+                }
+                type_fields::VALUE => {
+                    let type_name = take_name(&mut type_name)?;
+                    return match seed.registry.get_with_name(&type_name) {
+                        Some(registration) => {
+                            let deserialize = registration
+                                .data::<ReflectDeserialize>()
+                                .ok_or_else(|| {
+                                    de::Error::custom(format_args!(
+                                        "The TypeRegistration for {} doesn't have DeserializeReflect",
+                                        type_name
+                                    ))
+                                })?;
+                            map.next_value_seed(ValueDeserializer(deserialize))
+                        }
+                        None if seed.schemas.contains_key(&type_name) => {
+                            seed.check_kind(&type_name, TypeKind::Value)?;
+                            let value = map.next_value::<serde_yaml::Value>()?;
+                            Ok(Box::new(UnknownValue::new(type_name, value)))
+                        }
+                        None => Err(de::Error::custom(format_args!(
+                            "No registration or schema found for {}",
+                            type_name
+                        ))),
+                    };
+                }
+                _ => return Err(de::Error::unknown_field(key.as_str(), &[])),
+            }
+        }
+
+        Err(de::Error::custom(
+            "Maps in this location must have the 'type' field and one of the following fields: 'map', 'seq', 'value'",
+        ))
+    }
+}
+
+fn take_name<E: de::Error>(type_name: &mut Option<String>) -> Result<String, E> {
+    type_name
+        .take()
+        .ok_or_else(|| E::missing_field(type_fields::TYPE))
+}
+
+struct ValueDeserializer<'a>(&'a ReflectDeserialize);
+
+impl<'a, 'de> DeserializeSeed<'de> for ValueDeserializer<'a> {
+    type Value = Box<dyn Reflect>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        self.0.deserialize(deserializer)
+    }
+}
+
+struct StructDeserializer<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> DeserializeSeed<'de> for StructDeserializer<'a> {
+    type Value = DynamicStruct;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for StructDeserializer<'a> {
+    type Value = DynamicStruct;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("struct value")
+    }
+
+    fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+    where
+        V: MapAccess<'de>,
+    {
+        let mut value = DynamicStruct::default();
+        while let Some(key) = map.next_key::<String>()? {
+            let field = map.next_value_seed(self.0)?;
+            value.insert_boxed(&key, field);
+        }
+        Ok(value)
+    }
+}
+
+struct TupleStructDeserializer<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> DeserializeSeed<'de> for TupleStructDeserializer<'a> {
+    type Value = DynamicTupleStruct;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for TupleStructDeserializer<'a> {
+    type Value = DynamicTupleStruct;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("tuple struct value")
+    }
+
+    fn visit_seq<V>(self, mut seq: V) -> Result<Self::Value, V::Error>
+    where
+        V: SeqAccess<'de>,
+    {
+        let mut value = DynamicTupleStruct::default();
+        while let Some(field) = seq.next_element_seed(self.0)? {
+            value.insert_boxed(field);
+        }
+        Ok(value)
+    }
+}
+
+struct TupleDeserializer<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> DeserializeSeed<'de> for TupleDeserializer<'a> {
+    type Value = DynamicTuple;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for TupleDeserializer<'a> {
+    type Value = DynamicTuple;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("tuple value")
+    }
+
+    fn visit_seq<V>(self, mut seq: V) -> Result<Self::Value, V::Error>
+    where
+        V: SeqAccess<'de>,
+    {
+        let mut value = DynamicTuple::default();
+        while let Some(field) = seq.next_element_seed(self.0)? {
+            value.insert_boxed(field);
+        }
+        Ok(value)
+    }
+}
+
+struct ListDeserializer<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> DeserializeSeed<'de> for ListDeserializer<'a> {
+    type Value = DynamicList;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for ListDeserializer<'a> {
+    type Value = DynamicList;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("list value")
+    }
+
+    fn visit_seq<V>(self, mut seq: V) -> Result<Self::Value, V::Error>
+    where
+        V: SeqAccess<'de>,
+    {
+        let mut value = DynamicList::default();
+        while let Some(element) = seq.next_element_seed(self.0)? {
+            value.push_box(element);
+        }
+        Ok(value)
+    }
+}
+
+struct MapDeserializer<'a>(SchemaDeserializer<'a>);
+
+impl<'a, 'de> DeserializeSeed<'de> for MapDeserializer<'a> {
+    type Value = DynamicMap;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'a, 'de> Visitor<'de> for MapDeserializer<'a> {
+    type Value = DynamicMap;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("map value")
+    }
+
+    fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+    where
+        V: MapAccess<'de>,
+    {
+        let mut value = DynamicMap::default();
+        while let Some(key) = map.next_key_seed(self.0)? {
+            let element = map.next_value_seed(self.0)?;
+            value.insert_boxed(key, element);
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::reflect::serde::ReflectSerializer;
+    use bevy::reflect::TypeRegistry;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Reflect, Serialize)]
+    #[reflect_value(PartialEq, Serialize, Deserialize)]
+    struct Score(u32);
+
+    #[derive(Reflect)]
+    struct Team(u32);
+
+    #[derive(Reflect)]
+    struct Player {
+        name: String,
+        score: Score,
+        tags: Vec<String>,
+        team: Team,
+        stats: HashMap<String, u32>,
+    }
+
+    #[test]
+    fn unknown_types_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let game = TypeRegistry::default();
+        {
+            let mut game = game.write();
+            game.register::<String>();
+            game.register::<Score>();
+            game.register::<u32>();
+            game.register::<Team>();
+            game.register::<Player>();
+        }
+
+        let player = Player {
+            name: "Iris".to_string(),
+            score: Score(3),
+            tags: vec!["red".to_string()],
+            team: Team(1),
+            stats: HashMap::from_iter([("wins".to_string(), 2)]),
+        };
+        let ser = serde_yaml::to_string(&ReflectSerializer::new(&player, &game.read()))?;
+
+        let mut builder = SchemaBuilder::default();
+        builder.add_value(&player);
+        let schema = builder.finish();
+        let schemas: HashMap<_, _> = schema
+            .types
+            .into_iter()
+            .map(|schema| (schema.type_name.clone(), schema))
+            .collect();
+        let player_schema = &schemas[std::any::type_name::<Player>()];
+        assert_eq!(player_schema.kind, TypeKind::Struct);
+        let field = |name: &str, type_name: &str| FieldSchema {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+        };
+        assert_eq!(
+            player_schema.fields,
+            [
+                field("name", std::any::type_name::<String>()),
+                field("score", std::any::type_name::<Score>()),
+                field("tags", std::any::type_name::<Vec<String>>()),
+                field("team", std::any::type_name::<Team>()),
+                field("stats", std::any::type_name::<HashMap<String, u32>>()),
+            ]
+        );
+        assert!(player_schema.elements.is_empty());
+
+        let team_schema = &schemas[std::any::type_name::<Team>()];
+        assert_eq!(team_schema.kind, TypeKind::TupleStruct);
+        assert_eq!(
+            team_schema.fields,
+            [field("0", std::any::type_name::<u32>())]
+        );
+        assert_eq!(
+            schemas[std::any::type_name::<Vec<String>>()].elements,
+            [std::any::type_name::<String>()]
+        );
+        assert_eq!(
+            schemas[std::any::type_name::<HashMap<String, u32>>()].elements,
+            [
+                std::any::type_name::<String>(),
+                std::any::type_name::<u32>()
+            ]
+        );
+        assert_eq!(
+            schemas[std::any::type_name::<Score>()].kind,
+            TypeKind::Value
+        );
+
+        // The editor only knows about `String`
+        let editor = TypeRegistry::default();
+        editor.write().register::<String>();
+        let editor = editor.read();
+
+        let no_schemas = HashMap::default();
+        let deser = SchemaDeserializer::new(&editor, &no_schemas);
+        assert!(serde_yaml::seed::from_str_seed(&ser, deser).is_err());
+
+        let deser = SchemaDeserializer::new(&editor, &schemas);
+        let value = serde_yaml::seed::from_str_seed(&ser, deser)?;
+        assert_eq!(value.type_name(), std::any::type_name::<Player>());
+
+        let reser = serde_yaml::to_string(&ReflectSerializer::new(&*value, &editor))?;
+        assert_eq!(ser, reser);
+
+        // A score is a value in the schema, so it can't be received as a struct
+        let score = format!("type: {}\nstruct: {{}}\n", std::any::type_name::<Score>());
+        let deser = SchemaDeserializer::new(&editor, &schemas);
+        assert!(serde_yaml::seed::from_str_seed(&score, deser).is_err());
+
+        Ok(())
+    }
+}
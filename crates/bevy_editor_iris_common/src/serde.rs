@@ -1,11 +1,20 @@
+use std::any::TypeId;
 use std::borrow::{Borrow, BorrowMut};
-use std::cell::RefCell;
 use std::hash::Hash;
+use std::sync::{Arc, RwLock};
 
-use bevy::prelude::{default, Entity};
-use bevy::reflect::{FromReflect, Reflect, TypeRegistry};
-use serde::de::DeserializeSeed;
-use serde::{Deserialize, Serialize, Serializer};
+use bevy::prelude::Entity;
+use bevy::reflect::{FromReflect, Reflect, TypeRegistry, TypeRegistryInternal};
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::error::DuplicateMessageName;
+use crate::message;
+use crate::schema::{RegistrySchema, TypeSchema};
+
+mod diff;
+
+pub use self::diff::{patch_ops, reflect_diff, PatchOp, ReflectPatch};
 
 /// A serializable representation of an entity in the client, for use in the editor.
 /// This prevents confusion with whether an entity represents an entity in the editor or
@@ -47,51 +56,102 @@ impl From<Entity> for RemoteEntity {
     }
 }
 
-// TODO: There should be a built-in solution to this in bevy in the future. 6/10/2022
-thread_local!(static TYPE_REGISTRY: RefCell<Option<TypeRegistry>> = default());
+/// A handle to the type registry messages are (de)serialized with, shared between the local threads and the
+/// remote thread, along with the [schemas](crate::schema) of the remote application's types.
+///
+/// Every clone of a context shares the same registry, so types registered on it at runtime are seen by the remote
+/// thread, and [`set`](RegistryContext::set) replaces the registry for every clone. [`CommonPlugin`](crate::CommonPlugin)
+/// keeps the context in sync with the world's [`TypeRegistry`] resource.
+///
+/// Messages are found by their [ids and aliases](crate::message::Message::message_id) through an index which is
+/// built when the registry is given to the context. Messages registered on the registry afterwards are only found
+/// by their type name until it is [set](RegistryContext::set) again, which [`CommonPlugin`](crate::CommonPlugin)
+/// does whenever types are registered on the world's [`TypeRegistry`].
+#[derive(Clone, Default)]
+pub struct RegistryContext {
+    registry: Arc<RwLock<TypeRegistry>>,
+    messages: Arc<RwLock<HashMap<String, TypeId>>>,
+    schemas: Arc<RwLock<Arc<HashMap<String, TypeSchema>>>>,
+}
+
+impl RegistryContext {
+    /// Create a context sharing the given registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if two registered messages share an id or alias, see [`set`](RegistryContext::set).
+    pub fn new(registry: TypeRegistry) -> Self {
+        let context = Self::default();
+        if let Err(err) = context.set(registry) {
+            panic!("failed to register messages: {}", err);
+        }
+        context
+    }
+
+    /// The current registry.
+    pub fn registry(&self) -> TypeRegistry {
+        self.registry.read().unwrap().clone()
+    }
 
-/// Runs a closure with the given type registry available in thread local storage.
-/// Useful for de/serializing [`ReflectObject`].
-pub fn with_type_registry_context<F: FnOnce() -> R, R>(
-    registry: TypeRegistry,
-    f: F,
-) -> (TypeRegistry, R) {
-    let old_reg = replace_type_registry(registry);
+    /// Replaces the registry for every clone of this context, indexing its messages.
+    ///
+    /// Fails if two messages share an id or alias, or if one is the type name of another registered type,
+    /// in which case the registry isn't replaced.
+    pub fn set(&self, registry: TypeRegistry) -> Result<(), DuplicateMessageName> {
+        let messages = message::index_messages(&registry.read())?;
+        *self.registry.write().unwrap() = registry;
+        *self.messages.write().unwrap() = messages;
+        Ok(())
+    }
 
-    let r = f();
+    /// The type of the message with the id or alias `name`.
+    pub(crate) fn message_named(&self, name: &str) -> Option<TypeId> {
+        self.messages.read().unwrap().get(name).copied()
+    }
 
-    let registry = match old_reg {
-        Some(old) => {
-            replace_type_registry(old).expect("Type registry unexpectedly removed from TLS")
+    /// Adds the types of the remote application, so values of types which aren't registered locally
+    /// can be deserialized. Types which were already known are replaced.
+    pub fn add_schema(&self, schema: RegistrySchema) {
+        let mut schemas = self.schemas.write().unwrap();
+        let schemas = Arc::make_mut(&mut schemas);
+        for schema in schema.types {
+            schemas.insert(schema.type_name.clone(), schema);
         }
-        None => take_type_registry().expect("Type registry unexpectedly removed from TLS"),
-    };
+    }
 
-    (registry, r)
-}
+    /// The schema of a type of the remote application, if it is known.
+    pub fn schema(&self, type_name: &str) -> Option<TypeSchema> {
+        self.schemas.read().unwrap().get(type_name).cloned()
+    }
 
-/// Runs a closure, giving it the type registry in thread local storage.
-/// Combine with [`replace_type_registry`] and [`take_type_registry`].
-pub fn with_type_registry<F: FnOnce(Option<&TypeRegistry>) -> R, R>(f: F) -> R {
-    TYPE_REGISTRY.with(|registry| f(registry.borrow().as_ref()))
-}
+    /// The schemas of the remote application's types, as of now.
+    pub fn schemas(&self) -> Arc<HashMap<String, TypeSchema>> {
+        self.schemas.read().unwrap().clone()
+    }
 
-/// Swaps the type registry in thread local storage with the given registry.
-/// Useful for setting up a call to [`with_type_registry`].
-pub fn replace_type_registry(registry: TypeRegistry) -> Option<TypeRegistry> {
-    TYPE_REGISTRY.with(|cell| cell.borrow_mut().replace(registry))
+    /// Runs a closure with the current registry locked for reading, which every value (de)serialized within it
+    /// should be given, rather than locking the registry again.
+    pub fn read<F: FnOnce(&TypeRegistryInternal) -> R, R>(&self, f: F) -> R {
+        let registry = self.registry.read().unwrap();
+        let reg = registry.read();
+        f(&reg)
+    }
 }
 
-/// Takes the type regsitry from thread local storage.
-/// Useful for retrieving the type registry after a call to [`with_type_registry`].
-pub fn take_type_registry() -> Option<TypeRegistry> {
-    TYPE_REGISTRY.with(|cell| cell.take())
+impl From<TypeRegistry> for RegistryContext {
+    fn from(registry: TypeRegistry) -> Self {
+        Self::new(registry)
+    }
 }
 
 // TODO: There should be a native solution to this in bevy in the future, and ReflectObject can be entirely removed.
 // 6/17/2022
 /// Represents a serializable reflected object. Usually the underlying type is a `Dynamic***` type, but
 /// represents a type which may or may not be available in the editor.
+///
+/// A `ReflectObject` is (de)serialized as the value it holds, with bevy's
+/// [`ReflectSerializer`](bevy::reflect::serde::ReflectSerializer) and a
+/// [`SchemaDeserializer`](crate::schema::SchemaDeserializer), since both need the registry.
 #[derive(Debug)]
 pub struct ReflectObject(Box<dyn Reflect>);
 
@@ -151,7 +211,7 @@ unsafe impl Reflect for ReflectObject {
     }
 
     fn serializable(&self) -> Option<bevy::reflect::serde::Serializable> {
-        Some(bevy::reflect::serde::Serializable::Borrowed(self))
+        self.0.serializable()
     }
 }
 
@@ -186,40 +246,12 @@ impl From<ReflectObject> for Box<dyn Reflect> {
     }
 }
 
-impl Serialize for ReflectObject {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        with_type_registry(|reg| {
-            let reg = reg.expect("Type registry must be placed in TLS to perform serialization");
-            let lock = reg.internal.read();
-
-            bevy::reflect::serde::ReflectSerializer::new(self.0.as_ref(), &lock)
-                .serialize(serializer)
-        })
-    }
-}
-
-impl<'de> Deserialize<'de> for ReflectObject {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        with_type_registry(|reg| {
-            let reg = reg.expect("Type registry must be placed in TLS to perform serialization");
-            let lock = reg.internal.read();
-
-            bevy::reflect::serde::ReflectDeserializer::new(&lock)
-                .deserialize(deserializer)
-                .map(|b| Self(b))
-        })
-    }
-}
-
 #[test]
 fn reflect_object_serialization() -> Result<(), Box<dyn std::error::Error>> {
     use bevy::math::Vec4;
+    use bevy::reflect::serde::ReflectSerializer;
+
+    use crate::schema::SchemaDeserializer;
 
     let registry = TypeRegistry::default();
 
@@ -244,17 +276,20 @@ fn reflect_object_serialization() -> Result<(), Box<dyn std::error::Error>> {
         registry.register::<TestStruct>();
     }
 
-    let _ = replace_type_registry(registry);
+    let context = RegistryContext::new(registry);
 
     let reflect: Box<dyn Reflect> = Box::new(test.clone());
 
     let object: ReflectObject = reflect.into();
 
-    let ser = serde_yaml::to_string(&object)?;
+    let ser = context.read(|reg| serde_yaml::to_string(&ReflectSerializer::new(&object, reg)))?;
 
     println!("{ser}");
 
-    let deser: ReflectObject = serde_yaml::from_str(&ser)?;
+    let schemas = context.schemas();
+    let deser: ReflectObject = context
+        .read(|reg| serde_yaml::seed::from_str_seed(&ser, SchemaDeserializer::new(reg, &schemas)))?
+        .into();
     let reflect: Box<dyn Reflect> = deser.into();
     let mut deser_test: TestStruct = TestStruct::default();
     deser_test.apply(reflect.as_ref());
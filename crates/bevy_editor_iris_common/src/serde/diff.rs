@@ -0,0 +1,557 @@
+use std::borrow::Borrow;
+use std::fmt::Write;
+
+use bevy::reflect::{DynamicList, DynamicMap, FromReflect, List, Reflect, ReflectMut, ReflectRef};
+use bevy_editor_iris_derive::message;
+
+use crate::error::PatchError;
+
+use super::ReflectObject;
+
+/// The [operations](PatchOp::op) a [`ReflectPatch`] is made of.
+pub mod patch_ops {
+    /// Applies `value` to the value at the path. If the path ends at a new list index, map key or
+    /// struct field, `value` is inserted there instead.
+    pub const SET: &str = "set";
+    /// Removes the entry with the key `value` from the map at the path.
+    pub const REMOVE: &str = "remove";
+    /// Shortens the list at the path to the length `value`, a `u64`.
+    pub const TRUNCATE: &str = "truncate";
+}
+
+/// A change to a single value within a reflected value.
+#[derive(Clone, Debug, FromReflect, Reflect)]
+pub struct PatchOp {
+    /// The path from the patched value to the changed value. Each segment is the name of a struct field
+    /// as a `String`, the index of a tuple, tuple struct or list element as a `u64`, or the key of a map entry.
+    pub path: Vec<ReflectObject>,
+    /// The operation, one of [`patch_ops`]
+    pub op: String,
+    /// The operand of the operation
+    pub value: ReflectObject,
+}
+
+/// The changes between two versions of a reflected value, as computed by [`reflect_diff`].
+///
+/// A patch can be applied to a [`ReflectObject`] or to a live component. Map entries and struct fields can
+/// only be added or removed, and lists only shortened, in dynamic values such as the contents of a
+/// `ReflectObject`, since bevy can't resize concrete values through reflection. Concrete values can be
+/// [rebuilt](ReflectPatch::rebuild) instead.
+#[message]
+#[derive(Clone, Debug, Default)]
+pub struct ReflectPatch {
+    /// The operations, applied in order
+    pub ops: Vec<PatchOp>,
+}
+
+impl ReflectPatch {
+    /// Whether the patch changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Applies the patch to `target`, which should be equal to the old value the patch was computed from.
+    ///
+    /// Operations are applied in order, so `target` is left partially patched if one fails.
+    pub fn apply(&self, target: &mut dyn Reflect) -> Result<(), PatchError> {
+        for op in &self.ops {
+            apply_op(target, op)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the patch to a dynamic copy of `target`, then replaces `target` with the patched copy.
+    ///
+    /// Unlike [`apply`](ReflectPatch::apply), this can resize the lists, maps and structs within a concrete value,
+    /// and `target` is left unchanged if the patch fails.
+    pub fn rebuild<T: FromReflect>(&self, target: &mut T) -> Result<(), PatchError> {
+        let mut patched = ReflectObject::from(target.clone_value());
+        self.apply(&mut patched)?;
+        *target = T::from_reflect(&patched).ok_or_else(|| PatchError::Mismatched {
+            expected: target.type_name().to_string(),
+            found: patched.type_name().to_string(),
+        })?;
+        Ok(())
+    }
+}
+
+/// Computes the patch which turns `old` into `new`.
+///
+/// Structs, tuple structs, tuples, lists and maps are compared field by field, so only the values which changed
+/// are included. Values of different types, and other values which aren't equal, are replaced as a whole.
+///
+/// ## Example:
+/// ```
+/// # use bevy::reflect::Reflect;
+/// # use bevy_editor_iris_common::serde::reflect_diff;
+/// #[derive(Clone, Debug, PartialEq, Reflect)]
+/// struct Health {
+///     current: u32,
+///     max: u32,
+/// }
+///
+/// let old = Health { current: 10, max: 10 };
+/// let new = Health { current: 4, max: 10 };
+///
+/// let patch = reflect_diff(&old, &new);
+/// assert_eq!(patch.ops.len(), 1);
+///
+/// let mut patched = old.clone();
+/// patch.apply(&mut patched).unwrap();
+/// assert_eq!(patched, new);
+/// ```
+pub fn reflect_diff(old: &dyn Reflect, new: &dyn Reflect) -> ReflectPatch {
+    let mut patch = ReflectPatch::default();
+    diff_into(&mut Vec::new(), old, new, &mut patch.ops);
+    patch
+}
+
+fn diff_into(
+    path: &mut Vec<ReflectObject>,
+    old: &dyn Reflect,
+    new: &dyn Reflect,
+    ops: &mut Vec<PatchOp>,
+) {
+    if old.type_name() != new.type_name() {
+        return push_op(ops, path, patch_ops::SET, new.clone_value());
+    }
+
+    match (old.reflect_ref(), new.reflect_ref()) {
+        (ReflectRef::Struct(old), ReflectRef::Struct(new)) => {
+            // Fields can only be removed by replacing the struct
+            let mut old_names = old
+                .iter_fields()
+                .enumerate()
+                .map(|(i, _)| old.name_at(i).unwrap());
+            if old_names.any(|name| new.field(name).is_none()) {
+                return push_op(ops, path, patch_ops::SET, new.clone_value());
+            }
+
+            for (i, new_field) in new.iter_fields().enumerate() {
+                let name = new.name_at(i).unwrap();
+                path.push(Box::new(name.to_string()).into());
+                match old.field(name) {
+                    Some(old_field) => diff_into(path, old_field, new_field, ops),
+                    None => push_op(ops, path, patch_ops::SET, new_field.clone_value()),
+                }
+                path.pop();
+            }
+        }
+        (ReflectRef::TupleStruct(old), ReflectRef::TupleStruct(new)) => {
+            if old.field_len() != new.field_len() {
+                return push_op(ops, path, patch_ops::SET, new.clone_value());
+            }
+            for (i, (old, new)) in old.iter_fields().zip(new.iter_fields()).enumerate() {
+                path.push(Box::new(i as u64).into());
+                diff_into(path, old, new, ops);
+                path.pop();
+            }
+        }
+        (ReflectRef::Tuple(old), ReflectRef::Tuple(new)) => {
+            if old.field_len() != new.field_len() {
+                return push_op(ops, path, patch_ops::SET, new.clone_value());
+            }
+            for (i, (old, new)) in old.iter_fields().zip(new.iter_fields()).enumerate() {
+                path.push(Box::new(i as u64).into());
+                diff_into(path, old, new, ops);
+                path.pop();
+            }
+        }
+        (ReflectRef::List(old), ReflectRef::List(new)) => {
+            for (i, new_element) in new.iter().enumerate() {
+                path.push(Box::new(i as u64).into());
+                match old.get(i) {
+                    Some(old_element) => diff_into(path, old_element, new_element, ops),
+                    None => push_op(ops, path, patch_ops::SET, new_element.clone_value()),
+                }
+                path.pop();
+            }
+            if new.len() < old.len() {
+                push_op(ops, path, patch_ops::TRUNCATE, Box::new(new.len() as u64));
+            }
+        }
+        (ReflectRef::Map(old), ReflectRef::Map(new)) => {
+            for (key, new_value) in new.iter() {
+                path.push(key.clone_value().into());
+                match old.get(key) {
+                    Some(old_value) => diff_into(path, old_value, new_value, ops),
+                    None => push_op(ops, path, patch_ops::SET, new_value.clone_value()),
+                }
+                path.pop();
+            }
+            for (key, _) in old.iter() {
+                if new.get(key).is_none() {
+                    push_op(ops, path, patch_ops::REMOVE, key.clone_value());
+                }
+            }
+        }
+        (ReflectRef::Value(old), ReflectRef::Value(new)) => {
+            if old.reflect_partial_eq(new) != Some(true) {
+                push_op(ops, path, patch_ops::SET, new.clone_value());
+            }
+        }
+        _ => push_op(ops, path, patch_ops::SET, new.clone_value()),
+    }
+}
+
+fn push_op(ops: &mut Vec<PatchOp>, path: &[ReflectObject], op: &str, value: Box<dyn Reflect>) {
+    ops.push(PatchOp {
+        path: path.to_vec(),
+        op: op.to_string(),
+        value: value.into(),
+    });
+}
+
+fn apply_op(target: &mut dyn Reflect, op: &PatchOp) -> Result<(), PatchError> {
+    let value: &dyn Reflect = op.value.borrow();
+    match op.op.as_str() {
+        patch_ops::SET => match op.path.split_last() {
+            Some((last, parents)) => {
+                let parent = resolve_mut(target, parents, &op.path)?;
+                set_child(parent, last.borrow(), value, &op.path)
+            }
+            None => apply_value(target, value),
+        },
+        patch_ops::REMOVE => {
+            let target = resolve_mut(target, &op.path, &op.path)?;
+            let resized = match target.reflect_ref() {
+                ReflectRef::Map(map) => {
+                    let mut resized = DynamicMap::default();
+                    resized.set_name(map.type_name().to_string());
+                    for (key, element) in map.iter() {
+                        if key.reflect_partial_eq(value) != Some(true) {
+                            resized.insert_boxed(key.clone_value(), element.clone_value());
+                        }
+                    }
+                    resized
+                }
+                _ => return Err(PatchError::InvalidPath(display_path(&op.path))),
+            };
+            set_resized(target, Box::new(resized))
+        }
+        patch_ops::TRUNCATE => {
+            let target = resolve_mut(target, &op.path, &op.path)?;
+            let len = match value.downcast_ref::<u64>() {
+                Some(len) => *len as usize,
+                None => return Err(mismatched(target, value)),
+            };
+            let resized = match target.reflect_ref() {
+                ReflectRef::List(list) => {
+                    let mut resized = DynamicList::default();
+                    resized.set_name(list.type_name().to_string());
+                    for element in list.iter().take(len) {
+                        resized.push_box(element.clone_value());
+                    }
+                    resized
+                }
+                _ => return Err(PatchError::InvalidPath(display_path(&op.path))),
+            };
+            set_resized(target, Box::new(resized))
+        }
+        op => Err(PatchError::UnknownOp(op.to_string())),
+    }
+}
+
+/// Follows `segments` from `value`. `path` is the full path of the operation, for errors.
+fn resolve_mut<'a>(
+    mut value: &'a mut dyn Reflect,
+    segments: &[ReflectObject],
+    path: &[ReflectObject],
+) -> Result<&'a mut dyn Reflect, PatchError> {
+    for segment in segments {
+        value = child_mut(value, segment.borrow())
+            .ok_or_else(|| PatchError::InvalidPath(display_path(path)))?;
+    }
+    Ok(value)
+}
+
+fn child_mut<'a>(value: &'a mut dyn Reflect, segment: &dyn Reflect) -> Option<&'a mut dyn Reflect> {
+    match value.reflect_mut() {
+        ReflectMut::Struct(value) => value.field_mut(segment.downcast_ref::<String>()?),
+        ReflectMut::TupleStruct(value) => value.field_mut(index(segment)?),
+        ReflectMut::Tuple(value) => value.field_mut(index(segment)?),
+        ReflectMut::List(value) => value.get_mut(index(segment)?),
+        ReflectMut::Map(value) => value.get_mut(segment),
+        ReflectMut::Value(_) => None,
+    }
+}
+
+fn index(segment: &dyn Reflect) -> Option<usize> {
+    segment.downcast_ref::<u64>().map(|index| *index as usize)
+}
+
+/// Applies `value` to the child of `parent` at `segment`, or inserts it if there is no such child.
+fn set_child(
+    parent: &mut dyn Reflect,
+    segment: &dyn Reflect,
+    value: &dyn Reflect,
+    path: &[ReflectObject],
+) -> Result<(), PatchError> {
+    if child_mut(parent, segment).is_some() {
+        return apply_value(child_mut(parent, segment).unwrap(), value);
+    }
+
+    let resized: Box<dyn Reflect> = match parent.reflect_mut() {
+        ReflectMut::List(list) if index(segment) == Some(list.len()) => {
+            check_push(list, value)?;
+            list.push(value.clone_value());
+            return Ok(());
+        }
+        ReflectMut::Map(map) => {
+            let mut resized = map.clone_dynamic();
+            resized.insert_boxed(segment.clone_value(), value.clone_value());
+            Box::new(resized)
+        }
+        ReflectMut::Struct(value_struct) => match segment.downcast_ref::<String>() {
+            Some(name) => {
+                let mut resized = value_struct.clone_dynamic();
+                resized.insert_boxed(name, value.clone_value());
+                Box::new(resized)
+            }
+            None => return Err(PatchError::InvalidPath(display_path(path))),
+        },
+        _ => return Err(PatchError::InvalidPath(display_path(path))),
+    };
+    set_resized(parent, resized)
+}
+
+fn apply_value(target: &mut dyn Reflect, value: &dyn Reflect) -> Result<(), PatchError> {
+    // Replacing a dynamic value keeps it from holding onto entries the new value doesn't have
+    if target.set(value.clone_value()).is_ok() {
+        return Ok(());
+    }
+
+    check_apply(target, value)?;
+    target.apply(value);
+    Ok(())
+}
+
+/// Checks that `value` can be applied to `target`, which bevy panics on rather than failing if their types
+/// don't match.
+fn check_apply(target: &dyn Reflect, value: &dyn Reflect) -> Result<(), PatchError> {
+    match (target.reflect_ref(), value.reflect_ref()) {
+        (ReflectRef::Struct(target), ReflectRef::Struct(value)) => {
+            for (i, field) in value.iter_fields().enumerate() {
+                if let Some(target) = target.field(value.name_at(i).unwrap()) {
+                    check_apply(target, field)?;
+                }
+            }
+        }
+        (ReflectRef::TupleStruct(target), ReflectRef::TupleStruct(value)) => {
+            for (target, field) in target.iter_fields().zip(value.iter_fields()) {
+                check_apply(target, field)?;
+            }
+        }
+        (ReflectRef::Tuple(target), ReflectRef::Tuple(value)) => {
+            for (target, field) in target.iter_fields().zip(value.iter_fields()) {
+                check_apply(target, field)?;
+            }
+        }
+        (ReflectRef::List(target), ReflectRef::List(value)) => {
+            for (i, element) in value.iter().enumerate() {
+                match target.get(i) {
+                    Some(target) => check_apply(target, element)?,
+                    None => check_push(target, element)?,
+                }
+            }
+        }
+        (ReflectRef::Map(target), ReflectRef::Map(value)) => {
+            for (key, element) in value.iter() {
+                if let Some(target) = target.get(key) {
+                    check_apply(target, element)?;
+                }
+            }
+        }
+        (ReflectRef::Value(target), ReflectRef::Value(value))
+            if target.any().type_id() == value.any().type_id() => {}
+        _ => return Err(mismatched(target, value)),
+    }
+    Ok(())
+}
+
+/// Checks that `value` can be pushed onto `list`. An empty concrete list has no element to compare `value`
+/// with, and its element type can't be found through reflection, so pushes onto it are rejected.
+fn check_push(list: &dyn List, value: &dyn Reflect) -> Result<(), PatchError> {
+    match list.get(0) {
+        Some(element) if element.type_name() == value.type_name() => check_apply(element, value),
+        Some(element) => Err(mismatched(element, value)),
+        // Converting a dynamic list back to its concrete type checks the pushed elements
+        None if list.any().is::<DynamicList>() => Ok(()),
+        None => Err(PatchError::CannotResize(list.type_name().to_string())),
+    }
+}
+
+/// Replaces `target` with a resized copy of it, which only works if `target` is dynamic.
+fn set_resized(target: &mut dyn Reflect, resized: Box<dyn Reflect>) -> Result<(), PatchError> {
+    let type_name = target.type_name().to_string();
+    target
+        .set(resized)
+        .map_err(|_| PatchError::CannotResize(type_name))
+}
+
+fn mismatched(target: &dyn Reflect, value: &dyn Reflect) -> PatchError {
+    PatchError::Mismatched {
+        expected: target.type_name().to_string(),
+        found: value.type_name().to_string(),
+    }
+}
+
+/// Formats a path like a Rust expression, such as `.items[2]`, with map keys in braces.
+fn display_path(path: &[ReflectObject]) -> String {
+    let mut display = String::new();
+    for segment in path {
+        let segment: &dyn Reflect = segment.borrow();
+        if let Some(name) = segment.downcast_ref::<String>() {
+            _ = write!(display, ".{}", name);
+        } else if let Some(index) = segment.downcast_ref::<u64>() {
+            _ = write!(display, "[{}]", index);
+        } else {
+            _ = write!(display, "{{{:?}}}", segment);
+        }
+    }
+    display
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::reflect::TypeRegistry;
+    use bevy::utils::HashMap;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::message::{deserialize_message, serialize_message};
+    use crate::serde::RegistryContext;
+
+    #[derive(Clone, Debug, Default, FromReflect, PartialEq, Reflect)]
+    struct Inner {
+        value: u32,
+        label: String,
+    }
+
+    #[derive(Clone, Debug, Default, FromReflect, PartialEq, Reflect)]
+    struct Pair(u32, Inner);
+
+    #[derive(Clone, Debug, Default, FromReflect, PartialEq, Reflect)]
+    struct Sample {
+        inner: Inner,
+        pair: Pair,
+        tuple: (u32, String),
+        list: Vec<Inner>,
+        map: HashMap<String, u32>,
+    }
+
+    // Values are drawn from small ranges so that pairs of samples share most of their fields
+    fn inner() -> impl Strategy<Value = Inner> {
+        (0..3u32, "[abc]").prop_map(|(value, label)| Inner { value, label })
+    }
+
+    fn sample() -> impl Strategy<Value = Sample> {
+        (
+            inner(),
+            (0..3u32, inner()),
+            (0..3u32, "[xy]"),
+            vec(inner(), 0..4),
+            vec(("[klm]", 0..3u32), 0..4),
+        )
+            .prop_map(|(inner, (value, pair), tuple, list, map)| Sample {
+                inner,
+                pair: Pair(value, pair),
+                tuple,
+                list,
+                map: map.into_iter().collect(),
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn apply_diff_round_trip((old, new) in (sample(), sample())) {
+            let patch = reflect_diff(&old, &new);
+
+            prop_assert!(reflect_diff(&new, &new).is_empty());
+
+            let mut object = ReflectObject::from(old.clone_value());
+            patch.apply(&mut object).unwrap();
+            prop_assert_eq!(object.reflect_partial_eq(&new), Some(true));
+
+            let mut live = old.clone();
+            patch.rebuild(&mut live).unwrap();
+            prop_assert_eq!(live, new);
+        }
+
+        #[test]
+        fn patch_sent_as_message((old, new) in (sample(), sample())) {
+            let registry = registry();
+
+            let mut buf = Vec::new();
+            serialize_message(Box::new(reflect_diff(&old, &new)), &mut buf, &registry).unwrap();
+            let patch = deserialize_message(&buf, &registry)
+                .unwrap()
+                .downcast::<ReflectPatch>()
+                .unwrap_or_else(|_| panic!("expected a ReflectPatch"));
+
+            let mut object = ReflectObject::from(old.clone_value());
+            patch.apply(&mut object).unwrap();
+            prop_assert_eq!(object.reflect_partial_eq(&new), Some(true));
+        }
+    }
+
+    fn registry() -> RegistryContext {
+        let registry = TypeRegistry::default();
+        {
+            let mut registry = registry.write();
+            registry.register::<u32>();
+            registry.register::<u64>();
+            registry.register::<String>();
+            registry.register::<PatchOp>();
+            registry.register::<ReflectPatch>();
+        }
+        RegistryContext::new(registry)
+    }
+
+    #[test]
+    fn mismatched_values_rejected() {
+        let set = |path: Vec<ReflectObject>| ReflectPatch {
+            ops: vec![PatchOp {
+                path,
+                op: patch_ops::SET.to_string(),
+                value: ReflectObject::from(Box::new(3u32)),
+            }],
+        };
+
+        let mut live = Sample::default();
+        let field = set(vec![Box::new("inner".to_string()).into()]);
+        assert!(matches!(
+            field.apply(&mut live),
+            Err(PatchError::Mismatched { .. })
+        ));
+
+        let element = set(vec![
+            Box::new("list".to_string()).into(),
+            Box::new(0u64).into(),
+        ]);
+        assert!(matches!(
+            element.apply(&mut live),
+            Err(PatchError::CannotResize(_))
+        ));
+        assert_eq!(live, Sample::default());
+    }
+
+    #[test]
+    fn push_onto_empty_list_needs_rebuild() {
+        let new = Sample {
+            list: vec![Inner::default()],
+            ..Sample::default()
+        };
+        let patch = reflect_diff(&Sample::default(), &new);
+
+        let mut live = Sample::default();
+        assert!(matches!(
+            patch.apply(&mut live),
+            Err(PatchError::CannotResize(_))
+        ));
+
+        patch.rebuild(&mut live).unwrap();
+        assert_eq!(live, new);
+    }
+}
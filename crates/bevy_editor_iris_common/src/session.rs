@@ -0,0 +1,473 @@
+//! A [session](Session) outlives the connection to the remote application. When the remote thread is reopened,
+//! every [transaction](Transaction) opened before goes dead, but the session keeps its id and counts the reconnect.
+//!
+//! Each side sends a [`SessionHandshake`] carrying its [`SessionId`] whenever it connects, so the other side can
+//! tell a [returning](RemoteSession::returning) session from a new one.
+//!
+//! Long-lived transactions, such as the inspector stream, scene diffs or logs, can be opened as a [`Subscription`],
+//! which is opened again automatically after a reconnect. Messages that were still waiting to be sent on the
+//! dead transaction are either replayed on the new one or reported as lost.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::log::{info, warn};
+use bevy::prelude::{Local, Res, ResMut};
+use bevy::reflect::{FromReflect, Reflect};
+use bevy_editor_iris_derive::message;
+
+use crate::asynchronous::MessageBox;
+use crate::error::SubscriptionError;
+use crate::interface::{self, Interface, Transaction};
+use crate::message::Message;
+use crate::queue::QueueConfig;
+use crate::registry::TransactionRegistry;
+
+/// Identifies a [`Session`]. Stays the same across reconnects, so it can be sent to the remote application
+/// to let it recognize a returning session.
+#[derive(Clone, Copy, Debug, Eq, FromReflect, Hash, PartialEq, Reflect)]
+#[reflect(Hash, PartialEq)]
+pub struct SessionId(u64);
+
+/// The logical session with the remote application, which survives the remote thread being reopened.
+#[derive(Debug)]
+pub struct Session {
+    id: SessionId,
+    generation: u64,
+    remote: Option<RemoteSession>,
+    last_remote: Option<SessionId>,
+    /// The subscriptions which have been opened, to be re-established on reconnect
+    subscriptions: Mutex<Vec<Weak<Mutex<SubscriptionState>>>>,
+}
+
+/// The session of the remote application, as announced by its [`SessionHandshake`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RemoteSession {
+    /// The id of the remote session
+    pub id: SessionId,
+    /// The [generation](Session::generation) of the remote session
+    pub generation: u64,
+    /// Whether the remote session is the same one which was connected before this side reconnected
+    pub returning: bool,
+}
+
+/// Announces the [`Session`] of the sending side. Sent whenever the remote thread is opened.
+#[message]
+#[derive(Clone, Debug)]
+pub struct SessionHandshake {
+    /// The id of the session
+    pub session: SessionId,
+    /// The generation of the session
+    pub generation: u64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+
+        Self {
+            id: SessionId(nanos ^ (u64::from(std::process::id()) << 32)),
+            generation: 0,
+            remote: None,
+            last_remote: None,
+            subscriptions: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Session {
+    /// The id of this session.
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// The number of times the remote thread has been opened during this session. Transactions opened
+    /// during an earlier generation are dead.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The session of the remote application, once its [`SessionHandshake`] of the current generation has arrived.
+    pub fn remote(&self) -> Option<RemoteSession> {
+        self.remote
+    }
+
+    pub(crate) fn reconnect(&mut self) {
+        self.generation += 1;
+        self.remote = None;
+    }
+
+    fn set_remote(&mut self, handshake: &SessionHandshake) {
+        let returning = self.last_remote == Some(handshake.session);
+        self.last_remote = Some(handshake.session);
+        self.remote = Some(RemoteSession {
+            id: handshake.session,
+            generation: handshake.generation,
+            returning,
+        });
+    }
+
+    fn track(&self, subscription: &Arc<Mutex<SubscriptionState>>) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|tracked| tracked.strong_count() > 0);
+        subscriptions.push(Arc::downgrade(subscription));
+    }
+
+    /// Re-establishes every subscription which was opened before the remote thread was reopened.
+    /// Subscriptions which fail are retried on the next call.
+    pub(crate) fn reestablish_subscriptions(&self, interface: &mut Interface) {
+        let subscriptions: Vec<_> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+
+        for subscription in subscriptions {
+            let mut state = subscription.lock().unwrap();
+            if state.generation == self.generation {
+                continue;
+            }
+
+            match state.reestablish(interface, self) {
+                Ok(reestablished) if reestablished.lost > 0 => {
+                    warn!(
+                        lost = reestablished.lost,
+                        "subscription re-established, unsent messages were lost"
+                    )
+                }
+                Ok(reestablished) => {
+                    info!(
+                        replayed = reestablished.replayed,
+                        "subscription re-established"
+                    )
+                }
+                Err(err) => warn!(error = %err, "failed to re-establish a subscription"),
+            }
+        }
+    }
+}
+
+/// What happened to the messages which were waiting to be sent when a [`Subscription`] was re-established.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Reestablished {
+    /// The number of messages sent again on the new transaction
+    pub replayed: usize,
+    /// The number of messages which were dropped
+    pub lost: usize,
+}
+
+/// A long-lived [transaction](Transaction) which is opened again whenever the remote thread is reopened.
+///
+/// The subscription is first opened by [`update`](Subscription::update), which sends the message built by the
+/// opening function as the first message of the new transaction. From then on, it is re-established by the
+/// [`CommonPlugin`](crate::CommonPlugin) as soon as the remote thread is reopened, whether or not `update` is called.
+/// The opening function is given the [`Session`], so the message can carry the [`SessionId`] if the remote
+/// application needs to recognize a returning session.
+///
+/// Clones of a subscription share its transaction. It is no longer re-established once every clone is dropped.
+///
+/// ## Example:
+/// ```
+/// # use bevy::prelude::*;
+/// # use bevy_editor_iris_common::interface::Interface;
+/// # use bevy_editor_iris_common::session::{Session, SessionId, Subscription};
+/// # use bevy_editor_iris_derive::message;
+/// #[message]
+/// struct SubscribeToLogs {
+///     session: SessionId,
+/// }
+///
+/// fn logs(
+///     mut subscription: Local<Option<Subscription>>,
+///     mut interface: ResMut<Interface>,
+///     session: Res<Session>,
+/// ) {
+///     let subscription = subscription.get_or_insert_with(|| {
+///         Subscription::new(|session| SubscribeToLogs { session: session.id() }).replay_unsent(true)
+///     });
+///
+///     if subscription.update(&mut *interface, &session).is_err() {
+///         return;
+///     }
+///
+///     if let Some(mut transaction) = subscription.transaction() {
+///         for msg in transaction.iter() {
+///             // ...
+///         }
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Subscription(Arc<Mutex<SubscriptionState>>);
+
+struct SubscriptionState {
+    open: Box<dyn Fn(&Session) -> MessageBox + Send + Sync>,
+    replay: bool,
+    send_queue: Option<QueueConfig>,
+    transaction: Option<Transaction>,
+    generation: u64,
+    /// Re-established since `update` last returned
+    reestablished: Option<Reestablished>,
+}
+
+impl std::fmt::Debug for SubscriptionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriptionState")
+            .field("replay", &self.replay)
+            .field("send_queue", &self.send_queue)
+            .field("generation", &self.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The [transaction](Transaction) of a [`Subscription`], which is locked while the guard is held.
+pub struct SubscriptionTransaction<'s>(MutexGuard<'s, SubscriptionState>);
+
+impl Subscription {
+    /// Create a subscription which is opened with the message built by `open`. It is not opened
+    /// until [`update`](Subscription::update) is called.
+    pub fn new<M: Message>(open: impl 'static + Fn(&Session) -> M + Send + Sync) -> Self {
+        Self(Arc::new(Mutex::new(SubscriptionState {
+            open: Box::new(move |session| Box::new(open(session))),
+            replay: false,
+            send_queue: None,
+            transaction: None,
+            generation: 0,
+            reestablished: None,
+        })))
+    }
+
+    /// Whether messages which were waiting to be sent when the connection was lost are sent again
+    /// on the new transaction. By default they are dropped and reported as lost.
+    pub fn replay_unsent(self, replay: bool) -> Self {
+        self.lock().replay = replay;
+        self
+    }
+
+    /// The [config](QueueConfig) of the queue of messages waiting to be sent, applied to the transaction
+    /// each time it is opened. By default the queue of a new transaction is used.
+    pub fn send_queue(self, config: QueueConfig) -> Self {
+        self.lock().send_queue = Some(config);
+        self
+    }
+
+    /// Opens the subscription if it isn't open yet, or opens it again if the remote thread was reopened since
+    /// it was last opened. Returns what happened to the unsent messages of the old transaction if it was
+    /// re-established since the last call.
+    ///
+    /// Messages which were received on the old transaction but not yet taken are dropped with it.
+    pub fn update(
+        &self,
+        interface: &mut Interface,
+        session: &Session,
+    ) -> Result<Option<Reestablished>, SubscriptionError> {
+        let mut state = self.lock();
+        if state.transaction.is_none() {
+            state.reestablish(interface, session)?;
+            session.track(&self.0);
+        } else if state.generation != session.generation() {
+            state.reestablish(interface, session)?;
+        }
+
+        Ok(state.reestablished.take())
+    }
+
+    /// Returns `true` once the subscription has been opened.
+    pub fn is_open(&self) -> bool {
+        self.lock().transaction.is_some()
+    }
+
+    /// The transaction of the current generation, if the subscription has been opened.
+    pub fn transaction(&self) -> Option<SubscriptionTransaction<'_>> {
+        SubscriptionTransaction::new(self.lock())
+    }
+
+    /// Like [`transaction`](Subscription::transaction), but returns `None` rather than waiting if the
+    /// subscription is in use on another thread.
+    pub fn try_transaction(&self) -> Option<SubscriptionTransaction<'_>> {
+        let state = match self.0.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        SubscriptionTransaction::new(state)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SubscriptionState> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SubscriptionState {
+    /// Opens a new transaction, moving the unsent messages of the old one over if it should replay them.
+    fn reestablish(
+        &mut self,
+        interface: &mut Interface,
+        session: &Session,
+    ) -> Result<Reestablished, SubscriptionError> {
+        let transaction = interface.open_transaction()?;
+        if let Some(config) = self.send_queue {
+            transaction.set_send_queue(config);
+        }
+        interface::send_boxed(&transaction.tx, &transaction.state, (self.open)(session))?;
+
+        let mut reestablished = Reestablished::default();
+        if let Some(old) = self.transaction.take() {
+            for msg in old.tx.take_unsent() {
+                if self.replay
+                    && interface::send_boxed(&transaction.tx, &transaction.state, msg).is_ok()
+                {
+                    reestablished.replayed += 1;
+                } else {
+                    reestablished.lost += 1;
+                }
+            }
+
+            let pending = self
+                .reestablished
+                .get_or_insert_with(Reestablished::default);
+            pending.replayed += reestablished.replayed;
+            pending.lost += reestablished.lost;
+        }
+
+        self.transaction = Some(transaction);
+        self.generation = session.generation();
+
+        Ok(reestablished)
+    }
+}
+
+impl<'s> SubscriptionTransaction<'s> {
+    fn new(state: MutexGuard<'s, SubscriptionState>) -> Option<Self> {
+        state.transaction.as_ref()?;
+        Some(Self(state))
+    }
+}
+
+impl<'s> Deref for SubscriptionTransaction<'s> {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Transaction {
+        self.0.transaction.as_ref().unwrap()
+    }
+}
+
+impl<'s> DerefMut for SubscriptionTransaction<'s> {
+    fn deref_mut(&mut self) -> &mut Transaction {
+        self.0.transaction.as_mut().unwrap()
+    }
+}
+
+/// Receives the [`SessionHandshake`]s of the remote application from the [`TransactionRegistry`].
+pub(crate) struct ReceivedSessionHandshakes(Mutex<Receiver<(Transaction, MessageBox)>>);
+
+impl ReceivedSessionHandshakes {
+    pub(crate) fn register(registry: &mut TransactionRegistry) -> Self {
+        let (tx, rx) = mpsc::channel();
+        registry.register::<SessionHandshake>(tx);
+        Self(Mutex::new(rx))
+    }
+}
+
+/// Re-establishes the open subscriptions as soon as the remote thread is reopened.
+pub(crate) fn reestablish_subscriptions(mut interface: ResMut<Interface>, session: Res<Session>) {
+    session.reestablish_subscriptions(&mut interface);
+}
+
+/// Sends the [`SessionHandshake`], again whenever the remote thread is reopened.
+pub(crate) fn send_session_handshake(
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
+    mut subscription: Local<Option<Subscription>>,
+) {
+    let subscription = subscription.get_or_insert_with(|| {
+        Subscription::new(|session| SessionHandshake {
+            session: session.id(),
+            generation: session.generation(),
+        })
+    });
+    _ = subscription.update(&mut interface, &session);
+}
+
+/// Records the session of the remote application from its latest [`SessionHandshake`].
+pub(crate) fn receive_session_handshakes(
+    handshakes: Res<ReceivedSessionHandshakes>,
+    mut session: ResMut<Session>,
+) {
+    for (_, msg) in handshakes.0.lock().unwrap().try_iter() {
+        if let Ok(handshake) = msg.downcast::<SessionHandshake>() {
+            session.set_remote(&handshake);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use crate::testing::Ping;
+
+    /// An interface whose transactions are taken from the returned receiver, as the remote thread would.
+    fn interface() -> (Interface, UnboundedReceiver<Transaction>) {
+        let (open_tx, remote_rx) = mpsc::unbounded_channel();
+        let (_, open_rx) = mpsc::unbounded_channel();
+        (Interface::new(open_tx, open_rx), remote_rx)
+    }
+
+    fn sequence(msg: MessageBox) -> u32 {
+        msg.downcast::<Ping>().unwrap().sequence
+    }
+
+    #[test]
+    fn subscriptions_are_reestablished_on_reconnect() {
+        let mut session = Session::default();
+        let subscription = Subscription::new(|session| Ping {
+            sequence: session.generation() as u32,
+        })
+        .replay_unsent(true);
+
+        let (mut first, mut first_remote) = interface();
+        assert_eq!(subscription.update(&mut first, &session).unwrap(), None);
+        let mut remote = first_remote.try_recv().unwrap();
+        assert_eq!(sequence(remote.try_recv().unwrap()), 0);
+
+        // The connection is lost with two messages still waiting to be sent
+        for sequence in 1..=2 {
+            subscription
+                .transaction()
+                .unwrap()
+                .send(Ping { sequence })
+                .unwrap();
+        }
+        drop((remote, first_remote));
+
+        let (mut second, mut second_remote) = interface();
+        session.reconnect();
+        session.reestablish_subscriptions(&mut second);
+
+        let mut remote = second_remote.try_recv().unwrap();
+        let received: Vec<_> = std::iter::from_fn(|| remote.try_recv().ok())
+            .map(sequence)
+            .collect();
+        assert_eq!(received, [1, 1, 2]);
+
+        // The system already re-established it, so `update` only reports what happened
+        assert_eq!(
+            subscription.update(&mut second, &session).unwrap(),
+            Some(Reestablished {
+                replayed: 2,
+                lost: 0
+            })
+        );
+        assert!(second_remote.try_recv().is_err());
+    }
+}
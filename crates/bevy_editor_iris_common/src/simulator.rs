@@ -0,0 +1,392 @@
+//! A deterministic simulator of bad network conditions, for testing.
+//!
+//! The [`NetworkSimulator`] is a UDP relay which sits between the two applications, below QUIC. The client connects
+//! to the relay instead of the server (see [`ConnectionConfig::remote_addr`](crate::config::ConnectionConfig::remote_addr)),
+//! and every datagram in either direction is subjected to the current [`NetworkConditions`]. Because it sits under quinn,
+//! [`process_connection`](crate::asynchronous::process_connection) and everything above it runs exactly as in production.
+//!
+//! Every random decision is drawn from a generator seeded by [`NetworkSimulator::start`], so the same seed and the same
+//! sequence of datagrams always produce the same losses and delays.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bevy::log::{error, warn};
+use bevy::utils::HashMap;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::time::{self, Instant};
+
+use crate::interface::CancellationToken;
+use crate::transport;
+
+/// The largest datagram the relay forwards.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// The conditions a [`NetworkSimulator`] subjects datagrams to. Applied to both directions independently.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkConditions {
+    /// The delay added to every datagram.
+    pub latency: Duration,
+    /// The maximum amount by which the delay of a datagram randomly differs from `latency`.
+    pub jitter: Duration,
+    /// The probability, from `0.0` to `1.0`, that a datagram is dropped.
+    pub loss: f64,
+    /// The probability, from `0.0` to `1.0`, that a datagram is held back long enough for the
+    /// datagrams after it to overtake it.
+    pub reorder: f64,
+    /// The maximum number of bytes per second forwarded in each direction, if limited.
+    pub bandwidth: Option<u64>,
+    /// Drops every datagram, as if the link was cut. The connection times out unless this is unset in time.
+    pub disconnected: bool,
+}
+
+/// A deterministic pseudo-random number generator (SplitMix64).
+#[derive(Clone, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Direction {
+    ToServer = 0,
+    ToClient = 1,
+}
+
+/// Decides the fate of each datagram.
+#[derive(Debug)]
+struct Link {
+    conditions: NetworkConditions,
+    rng: Rng,
+    /// The instant each direction finishes sending the datagrams accepted so far, when bandwidth is limited
+    busy_until: [Option<Instant>; 2],
+}
+
+impl Link {
+    /// The instant a datagram of `len` bytes sent at `now` should be delivered, or `None` if it is dropped.
+    fn schedule(&mut self, direction: Direction, len: usize, now: Instant) -> Option<Instant> {
+        let conditions = &self.conditions;
+        if conditions.disconnected || self.rng.next_f64() < conditions.loss {
+            return None;
+        }
+
+        let mut delay = conditions.latency;
+        if !conditions.jitter.is_zero() {
+            let offset = conditions.jitter.mul_f64(self.rng.next_f64());
+            delay = if self.rng.next_f64() < 0.5 {
+                conditions.latency.saturating_sub(offset)
+            } else {
+                conditions.latency + offset
+            };
+        }
+        if self.rng.next_f64() < conditions.reorder {
+            // Held back by a full latency (at least a millisecond), so later datagrams arrive first
+            delay += conditions.latency.max(Duration::from_millis(1));
+        }
+
+        let mut sent = now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            let busy_until = &mut self.busy_until[direction as usize];
+            let start = busy_until.map_or(now, |busy| busy.max(now));
+            sent = start + Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+            *busy_until = Some(sent);
+        }
+
+        Some(sent + delay)
+    }
+}
+
+struct Shared {
+    link: Mutex<Link>,
+    client_socket: UdpSocket,
+    server: SocketAddr,
+    shutdown: CancellationToken,
+}
+
+/// A UDP relay which forwards datagrams between a client and a server under simulated [`NetworkConditions`].
+/// Stops relaying when dropped.
+///
+/// Must be started from within a multi-threaded tokio runtime, or a runtime which keeps running for as long as the
+/// simulator is used.
+///
+/// ## Example:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use bevy_editor_iris_common::config::ConnectionConfig;
+/// # use bevy_editor_iris_common::simulator::{NetworkConditions, NetworkSimulator};
+/// # async fn run() -> std::io::Result<()> {
+/// let simulator = NetworkSimulator::start(
+///     "127.0.0.1:0".parse().unwrap(),
+///     bevy_editor_iris_common::server_addr(),
+///     NetworkConditions {
+///         latency: Duration::from_millis(100),
+///         loss: 0.05,
+///         ..Default::default()
+///     },
+///     42,
+/// )
+/// .await?;
+///
+/// // Connect the client through the simulator
+/// let config = ConnectionConfig {
+///     remote_addr: Some(simulator.local_addr()?),
+///     ..Default::default()
+/// };
+///
+/// // Later, cut the link to exercise reconnecting
+/// simulator.disconnect();
+/// # Ok(())
+/// # }
+/// ```
+pub struct NetworkSimulator {
+    shared: Arc<Shared>,
+}
+
+impl NetworkSimulator {
+    /// Binds the relay to `bind` and starts forwarding datagrams between it and `server`, drawing
+    /// every random decision from a generator seeded with `seed`.
+    pub async fn start(
+        bind: SocketAddr,
+        server: SocketAddr,
+        conditions: NetworkConditions,
+        seed: u64,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            link: Mutex::new(Link {
+                conditions,
+                rng: Rng(seed),
+                busy_until: [None; 2],
+            }),
+            client_socket: UdpSocket::bind(bind).await?,
+            server,
+            shutdown: CancellationToken::new(),
+        });
+
+        tokio::spawn(relay_clients(shared.clone()));
+
+        Ok(Self { shared })
+    }
+
+    /// The address clients should connect to instead of the server.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.client_socket.local_addr()
+    }
+
+    /// Returns the current conditions.
+    pub fn conditions(&self) -> NetworkConditions {
+        self.shared.link.lock().unwrap().conditions.clone()
+    }
+
+    /// Replaces the conditions. Datagrams which are already delayed keep the delay they were given.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        self.shared.link.lock().unwrap().conditions = conditions;
+    }
+
+    /// Drops every datagram until [`reconnect`](NetworkSimulator::reconnect) is called.
+    pub fn disconnect(&self) {
+        self.shared.link.lock().unwrap().conditions.disconnected = true;
+    }
+
+    /// Stops dropping every datagram after a [`disconnect`](NetworkSimulator::disconnect).
+    pub fn reconnect(&self) {
+        self.shared.link.lock().unwrap().conditions.disconnected = false;
+    }
+}
+
+impl Drop for NetworkSimulator {
+    fn drop(&mut self) {
+        self.shared.shutdown.cancel();
+    }
+}
+
+/// Forwards datagrams from clients to the server, giving each client a socket of its own so the
+/// server can tell them apart.
+async fn relay_clients(shared: Arc<Shared>) {
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::default();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (len, client) = select! {
+            received = shared.client_socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(err) => {
+                    error!(error = %err, "network simulator stopped");
+                    return;
+                }
+            },
+            _ = shared.shutdown.cancelled() => return,
+        };
+
+        let upstream = match upstreams.get(&client) {
+            Some(upstream) => upstream.clone(),
+            None => {
+                let unspecified = transport::unspecified_addr(shared.server);
+                let upstream = match UdpSocket::bind(unspecified).await {
+                    Ok(upstream) => Arc::new(upstream),
+                    Err(err) => {
+                        warn!(error = %err, %client, "network simulator failed to relay a new client");
+                        continue;
+                    }
+                };
+
+                tokio::spawn(relay_server(shared.clone(), upstream.clone(), client));
+                upstreams.insert(client, upstream.clone());
+                upstream
+            }
+        };
+
+        let datagram = buf[..len].to_vec();
+        let server = shared.server;
+        forward(
+            &shared,
+            Direction::ToServer,
+            datagram,
+            move |datagram| async move {
+                _ = upstream.send_to(&datagram, server).await;
+            },
+        );
+    }
+}
+
+/// Forwards datagrams from the server back to one client.
+async fn relay_server(shared: Arc<Shared>, upstream: Arc<UdpSocket>, client: SocketAddr) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let len = select! {
+            received = upstream.recv(&mut buf) => match received {
+                Ok(len) => len,
+                Err(_) => return,
+            },
+            _ = shared.shutdown.cancelled() => return,
+        };
+
+        let datagram = buf[..len].to_vec();
+        let relay = shared.clone();
+        forward(
+            &shared,
+            Direction::ToClient,
+            datagram,
+            move |datagram| async move {
+                _ = relay.client_socket.send_to(&datagram, client).await;
+            },
+        );
+    }
+}
+
+/// Delivers `datagram` with `send` once the link allows, or drops it.
+fn forward<F: std::future::Future<Output = ()> + Send + 'static>(
+    shared: &Shared,
+    direction: Direction,
+    datagram: Vec<u8>,
+    send: impl FnOnce(Vec<u8>) -> F,
+) {
+    let now = Instant::now();
+    let deliver_at = match shared
+        .link
+        .lock()
+        .unwrap()
+        .schedule(direction, datagram.len(), now)
+    {
+        Some(deliver_at) => deliver_at,
+        None => return,
+    };
+
+    let send = send(datagram);
+    tokio::spawn(async move {
+        time::sleep_until(deliver_at).await;
+        send.await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    use crate::asynchronous::MessageBox;
+    use crate::testing::{self, blocking, Ping};
+
+    fn link(seed: u64) -> Link {
+        Link {
+            conditions: NetworkConditions {
+                latency: Duration::from_millis(50),
+                jitter: Duration::from_millis(20),
+                loss: 0.3,
+                reorder: 0.1,
+                bandwidth: Some(10_000),
+                disconnected: false,
+            },
+            rng: Rng(seed),
+            busy_until: [None; 2],
+        }
+    }
+
+    #[test]
+    fn same_seed_same_schedule() {
+        let now = Instant::now();
+        let schedule = |seed| {
+            let mut link = link(seed);
+            (0..100)
+                .map(|i| link.schedule(Direction::ToServer, 1200, now + Duration::from_millis(i)))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(schedule(7), schedule(7));
+        assert_ne!(schedule(7), schedule(8));
+    }
+
+    fn sequence(msg: MessageBox) -> u32 {
+        msg.downcast::<Ping>().unwrap().sequence
+    }
+
+    #[tokio::test]
+    async fn process_connection_survives_a_bad_network() {
+        let conditions = NetworkConditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+            loss: 0.05,
+            reorder: 0.1,
+            ..default()
+        };
+
+        testing::with_simulated_peers(default(), conditions, 3, |a, mut b| async move {
+            let local = a.open();
+            let mut local = blocking(move || {
+                for sequence in 0..100 {
+                    local.send_blocking(Ping { sequence }).unwrap();
+                }
+                local
+            })
+            .await;
+
+            let mut remote = b.accept().await;
+            let received = blocking(move || {
+                let received: Vec<_> = (0..100).map(|_| sequence(remote.recv().unwrap())).collect();
+                remote.send(Ping { sequence: 100 }).unwrap();
+                received
+            })
+            .await;
+            assert_eq!(received, (0..100).collect::<Vec<_>>());
+
+            let reply = blocking(move || sequence(local.recv().unwrap())).await;
+            assert_eq!(reply, 100);
+        })
+        .await;
+    }
+}
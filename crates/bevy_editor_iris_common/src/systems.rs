@@ -1,12 +1,18 @@
 use std::time::Duration;
 
 use bevy::ecs::schedule::ShouldRun;
-use bevy::prelude::{Res, Time, World};
+use bevy::log::{error, info};
+use bevy::prelude::{Local, Res, Time, World};
+use bevy::reflect::TypeRegistry;
 use futures_lite::Future;
+use tokio::sync::oneshot::error::TryRecvError;
 
 use crate::asynchronous::{self, OpeningReceiver, OpeningSender, RemoteThread};
+use crate::config::ConnectionConfig;
 use crate::error::RemoteThreadError;
 use crate::interface::Interface;
+use crate::serde::RegistryContext;
+use crate::transfer::TransferProgressSender;
 
 /// Creates a run criteria for running a system on an interval of `duration`.
 ///
@@ -14,6 +20,7 @@ use crate::interface::Interface;
 /// ```
 /// # use std::time::Duration;
 /// # use bevy::prelude::*;
+/// # use bevy_editor_iris_common::systems::run_on_timer;
 /// #
 /// # fn my_system() {}
 /// #
@@ -48,37 +55,127 @@ pub fn run_on_timer(duration: Duration) -> impl FnMut(Res<Time>) -> ShouldRun {
 }
 
 /// Monitors the remote thread until it closes; when it does, uses the given run function to
-/// reopen it if the closure was unexpected.
-pub fn monitor_remote_thread<F: 'static + Future<Output = Result<(), RemoteThreadError>>>(
+/// reopen it if the closure was unexpected. Errors and panics are noticed on the next frame after they happen.
+pub fn monitor_remote_thread<F: 'static + Future<Output = Result<(), RemoteThreadError>> + Send>(
     run_fn: impl 'static
         + Fn(
             OpeningSender,
             OpeningReceiver,
-            // StreamCounter,
+            TransferProgressSender,
+            ConnectionConfig,
+            RegistryContext,
         ) -> F
         + Send
         + Sync
         + Copy,
 ) -> impl 'static + Fn(&mut World) {
     move |world| {
-        let RemoteThread(thread) = world
-            .remove_resource::<RemoteThread>()
-            .expect("RemoteThread resource unexpectedly removed");
+        // The remote thread closed normally and was not reopened
+        let mut thread = match world.get_resource_mut::<RemoteThread>() {
+            Some(thread) => thread,
+            None => return,
+        };
 
-        if !thread.is_finished() {
-            world.insert_resource(RemoteThread(thread));
-        } else {
-            match thread.join() {
-                Ok(Ok(())) => {
-                    eprintln!("Remote thread closed normally. Not reopening.");
-                    return;
-                }
-                Ok(Err(err)) => eprintln!("Remote thread closed with error {err:?}! Reopening."),
-                Err(_) => eprintln!("Remote thread closed with an unknown error! Reopening."),
+        match thread.0.try_recv() {
+            Err(TryRecvError::Empty) => return,
+            Ok(Ok(())) => {
+                info!("remote thread closed normally, not reopening");
+                world.remove_resource::<RemoteThread>();
+                return;
+            }
+            Ok(Err(err)) => error!(error = %err, "remote thread closed, reopening"),
+            Err(TryRecvError::Closed) => {
+                error!("remote thread closed with an unknown error, reopening")
             }
-
-            _ = world.remove_resource::<Interface>();
-            asynchronous::open_remote_thread(run_fn)(world);
         }
+
+        _ = world.remove_resource::<Interface>();
+        asynchronous::open_remote_thread(run_fn)(world);
+    }
+}
+
+/// Shares the world's [`TypeRegistry`] with the remote thread through the [`RegistryContext`],
+/// re-indexing its messages when the resource is replaced or types are registered on it.
+///
+/// If two messages share an id or alias, the error is logged and the remote thread keeps the previous index.
+pub fn sync_registry_context(
+    registry: Res<TypeRegistry>,
+    context: Res<RegistryContext>,
+    mut registered: Local<usize>,
+) {
+    // Registering through `TypeRegistry::write` doesn't change the resource, so the number of types is watched too
+    let count = registry.read().iter().count();
+    if !registry.is_changed() && count == *registered {
+        return;
+    }
+    *registered = count;
+
+    if let Err(err) = context.set(registry.clone()) {
+        error!(error = %err, "failed to register messages, keeping the previous ones");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::any::TypeId;
+
+    use bevy::app::App;
+    use bevy::ecs::schedule::{Stage, SystemStage};
+    use bevy_editor_iris_derive::message;
+
+    use super::*;
+    use crate::config::Side;
+    use crate::CommonPlugin;
+
+    #[message(id = "iris.test.late")]
+    struct Late {
+        sequence: u32,
+    }
+
+    #[message(id = "iris.test.late")]
+    struct Clashing {
+        sequence: u32,
+    }
+
+    #[test]
+    fn registry_context_follows_registrations() {
+        let registry = TypeRegistry::default();
+        registry.write().register::<u32>();
+
+        let mut world = World::new();
+        world.insert_resource(registry.clone());
+        world.insert_resource(RegistryContext::default());
+        let mut stage = SystemStage::single(sync_registry_context);
+        stage.run(&mut world);
+
+        // Registered without changing the resource
+        registry.write().register::<Late>();
+        stage.run(&mut world);
+        let context = world.get_resource::<RegistryContext>().unwrap().clone();
+        assert_eq!(
+            context.message_named("iris.test.late"),
+            Some(TypeId::of::<Late>())
+        );
+
+        // The clash is logged instead of panicking, and the previous index is kept
+        registry.write().register::<Clashing>();
+        stage.run(&mut world);
+        assert_eq!(
+            context.message_named("iris.test.late"),
+            Some(TypeId::of::<Late>())
+        );
+    }
+
+    #[test]
+    fn clashing_messages_are_logged_by_the_plugin() {
+        let mut app = App::new();
+        let registry = TypeRegistry::default();
+        registry.write().register::<Late>();
+        registry.write().register::<Clashing>();
+        app.insert_resource(registry);
+
+        app.add_plugin(CommonPlugin(Side::Game));
+        let context = app.world.get_resource::<RegistryContext>().unwrap();
+        assert_eq!(context.message_named("iris.test.late"), None);
     }
 }
@@ -0,0 +1,233 @@
+//! Helpers for tests which run both ends of a connection in one process, over a QUIC connection on localhost,
+//! optionally through a [`NetworkSimulator`].
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy_editor_iris_derive::message;
+use futures::future;
+use futures_lite::StreamExt;
+use quinn::{Endpoint, NewConnection};
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time;
+
+use crate::asynchronous::{self, OpeningReceiver, OpeningSender};
+use crate::config::ConnectionConfig;
+use crate::interface::Transaction;
+use crate::serde::RegistryContext;
+use crate::simulator::{NetworkConditions, NetworkSimulator};
+use crate::transfer::{TransferProgress, TransferProgressSender};
+use crate::transport::{self, Identity};
+
+/// How long a test waits for something to happen before failing.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A message for tests to send.
+#[message]
+#[derive(Clone, Debug)]
+pub(crate) struct Ping {
+    pub(crate) sequence: u32,
+}
+
+/// A registry of the built-in messages and [`Ping`].
+pub(crate) fn registry() -> RegistryContext {
+    crate::builtin_registry(|registry| registry.register::<Ping>())
+}
+
+/// One end of a connection, as seen by the local threads.
+pub(crate) struct Peer {
+    open_tx: OpeningSender,
+    open_rx: OpeningReceiver,
+    progress_rx: UnboundedReceiver<TransferProgress>,
+}
+
+impl Peer {
+    /// Opens a transaction. The other end sees it once the first message arrives.
+    pub(crate) fn open(&self) -> Transaction {
+        let (local, remote) = Transaction::pair();
+        self.open_tx.send(remote).unwrap();
+        local
+    }
+
+    /// Waits for the other end to open a transaction.
+    pub(crate) async fn accept(&mut self) -> Transaction {
+        time::timeout(TIMEOUT, self.open_rx.recv())
+            .await
+            .expect("timed out waiting for a transaction")
+            .expect("the remote thread stopped")
+    }
+
+    /// Takes the progress of transfers reported so far.
+    pub(crate) fn progress(&mut self) -> Vec<TransferProgress> {
+        std::iter::from_fn(|| self.progress_rx.try_recv().ok()).collect()
+    }
+}
+
+/// The ends of the channels of a [`Peer`] which the remote thread holds.
+pub(crate) struct Remote {
+    pub(crate) tx: OpeningSender,
+    pub(crate) rx: OpeningReceiver,
+    pub(crate) progress: TransferProgressSender,
+}
+
+/// Creates a peer, along with the channels to pass to the remote thread which serves it.
+pub(crate) fn peer() -> (Peer, Remote) {
+    let (open_tx, remote_rx) = mpsc::unbounded_channel();
+    let (remote_tx, open_rx) = mpsc::unbounded_channel();
+    let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+
+    let peer = Peer {
+        open_tx,
+        open_rx,
+        progress_rx,
+    };
+    let remote = Remote {
+        tx: remote_tx,
+        rx: remote_rx,
+        progress: progress_tx,
+    };
+    (peer, remote)
+}
+
+/// Connects two endpoints on localhost, returning the connection of the listening side first.
+pub(crate) async fn connect(config: &ConnectionConfig) -> (NewConnection, NewConnection) {
+    connect_via(config, |server| async move { server }).await
+}
+
+/// Connects two endpoints on localhost through a [`NetworkSimulator`] under `conditions`, returning
+/// the connection of the listening side first. The connections stall once the simulator is dropped.
+pub(crate) async fn connect_simulated(
+    config: &ConnectionConfig,
+    conditions: NetworkConditions,
+    seed: u64,
+) -> (NewConnection, NewConnection, NetworkSimulator) {
+    let mut simulator = None;
+    let started = &mut simulator;
+    let (accepted, connected) = connect_via(config, |server| async move {
+        let simulator = NetworkSimulator::start(localhost(), server, conditions, seed)
+            .await
+            .unwrap();
+        let addr = simulator.local_addr().unwrap();
+        *started = Some(simulator);
+        addr
+    })
+    .await;
+    (accepted, connected, simulator.unwrap())
+}
+
+/// Any port on the loopback address.
+pub(crate) fn localhost() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+/// Connects the client to the address `route` returns for the server.
+async fn connect_via<F, Fut>(config: &ConnectionConfig, route: F) -> (NewConnection, NewConnection)
+where
+    F: FnOnce(SocketAddr) -> Fut,
+    Fut: Future<Output = SocketAddr>,
+{
+    let identity = Identity::generate().unwrap();
+    let mut client_config = transport::pinned_client_config(identity.fingerprint());
+    client_config.transport = Arc::new(config.transport_config());
+
+    let server_config = transport::server_config(&identity, config).unwrap();
+    let (server, mut incoming) = Endpoint::server(server_config, localhost()).unwrap();
+    let client = Endpoint::client(localhost()).unwrap();
+    let remote = route(server.local_addr().unwrap()).await;
+
+    let connecting = client
+        .connect_with(client_config, remote, "localhost")
+        .unwrap();
+    let (accepted, connected) = tokio::join!(
+        async { incoming.next().await.unwrap().await.unwrap() },
+        async { connecting.await.unwrap() },
+    );
+    (accepted, connected)
+}
+
+/// Runs `test` with both ends of a connection, each processed by
+/// [`process_connection`](asynchronous::process_connection) as the remote thread would.
+pub(crate) async fn with_peers<F, Fut>(config: ConnectionConfig, test: F) -> Fut::Output
+where
+    F: FnOnce(Peer, Peer) -> Fut,
+    Fut: Future,
+{
+    let connections = connect(&config).await;
+    with_connections(config, connections, test).await
+}
+
+/// Like [`with_peers`], but runs the connection through a [`NetworkSimulator`] under `conditions`.
+pub(crate) async fn with_simulated_peers<F, Fut>(
+    config: ConnectionConfig,
+    conditions: NetworkConditions,
+    seed: u64,
+    test: F,
+) -> Fut::Output
+where
+    F: FnOnce(Peer, Peer) -> Fut,
+    Fut: Future,
+{
+    let (a, b, _simulator) = connect_simulated(&config, conditions, seed).await;
+    with_connections(config, (a, b), test).await
+}
+
+async fn with_connections<F, Fut>(
+    config: ConnectionConfig,
+    (a, b): (NewConnection, NewConnection),
+    test: F,
+) -> Fut::Output
+where
+    F: FnOnce(Peer, Peer) -> Fut,
+    Fut: Future,
+{
+    let registry = registry();
+
+    let (a_peer, mut a_remote) = peer();
+    let (b_peer, mut b_remote) = peer();
+
+    // The test may drop a peer it has no use for, which must not stop the remote thread serving it
+    let _senders = (a_peer.open_tx.clone(), b_peer.open_tx.clone());
+    let (a_tx, a_forward) = forward(a_remote.tx);
+    let (b_tx, b_forward) = forward(b_remote.tx);
+
+    select! {
+        result = asynchronous::process_connection(a, &a_tx, &mut a_remote.rx, &a_remote.progress, &config, &registry) => {
+            panic!("the first connection ended: {:?}", result)
+        }
+        result = asynchronous::process_connection(b, &b_tx, &mut b_remote.rx, &b_remote.progress, &config, &registry) => {
+            panic!("the second connection ended: {:?}", result)
+        }
+        _ = future::join(a_forward, b_forward) => unreachable!("the remote threads hold the forwarded channels"),
+        output = time::timeout(TIMEOUT, test(a_peer, b_peer)) => output.expect("the test timed out"),
+    }
+}
+
+/// Forwards the transactions the remote thread opens to `tx`. Once the peer has been dropped they're held
+/// unanswered instead, as the peer would have left them.
+fn forward(tx: OpeningSender) -> (OpeningSender, impl Future<Output = ()>) {
+    let (forward_tx, mut forward_rx) = mpsc::unbounded_channel();
+    let forward = async move {
+        let mut unanswered = Vec::new();
+        while let Some(transaction) = forward_rx.recv().await {
+            if let Err(err) = tx.send(transaction) {
+                unanswered.push(err.0);
+            }
+        }
+    };
+    (forward_tx, forward)
+}
+
+/// Runs `f` on a blocking thread, as the blocking methods of a [`Transaction`] must be called from outside the runtime.
+pub(crate) async fn blocking<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    tokio::task::spawn_blocking(f).await.unwrap()
+}
+
+/// Waits until `condition` holds.
+pub(crate) async fn eventually(mut condition: impl FnMut() -> bool) {
+    while !condition() {
+        time::sleep(Duration::from_millis(5)).await;
+    }
+}
@@ -0,0 +1,596 @@
+//! Chunked transfers of large payloads, such as full-world snapshots and assets.
+//!
+//! A transfer is a [`TransferStart`], any number of [`TransferChunk`]s and a [`TransferEnd`], sent on a
+//! [transaction](Transaction) like any other messages. Chunks are written to the stream as raw bytes rather
+//! than being serialized, and the remote thread only ever holds one chunk of a transfer at a time, so together
+//! with the bounded [queues](crate::queue) of the transaction, memory use stays bounded however large the payload is.
+//!
+//! While a transfer is sent or received, the remote thread reports a [`TransferProgress`] event after every chunk.
+//!
+//! A transfer can be cancelled with a [`CancellationToken`]. The [`TransferEnd`] records how many bytes were
+//! sent, and a later transfer can resume from there by starting at that offset.
+
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use bevy::prelude::{EventWriter, Res};
+use bevy_editor_iris_derive::message;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::asynchronous::MessageBox;
+use crate::error::TransferError;
+use crate::interface::{self, CancellationToken, Transaction, TransactionId, TransactionState};
+use crate::message::Message;
+use crate::queue::{DropPolicy, MessageRx, MessageTx};
+
+/// The size of the chunks [`Transaction::send_transfer`] reads and sends.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Begins a transfer of `total` bytes, the first `offset` of which were sent by an earlier, cancelled transfer.
+#[message]
+#[derive(Clone, Debug)]
+pub struct TransferStart {
+    /// The length of the whole payload
+    pub total: u64,
+    /// The number of bytes of the payload which are skipped by this transfer
+    pub offset: u64,
+}
+
+/// A piece of the payload of a transfer. Written to the stream without being serialized.
+#[message]
+pub struct TransferChunk {
+    /// The bytes of the payload
+    pub bytes: Vec<u8>,
+}
+
+/// Ends a transfer.
+#[message]
+#[derive(Clone, Debug)]
+pub struct TransferEnd {
+    /// The offset into the payload the transfer ended at. A cancelled transfer can be resumed from here.
+    pub bytes: u64,
+    /// Whether the transfer was cancelled before the whole payload was sent
+    pub cancelled: bool,
+}
+
+/// Whether a [`TransferProgress`] event is for a transfer to or from the remote application.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+    /// The transfer is being sent to the remote application
+    Sending,
+    /// The transfer is being received from the remote application
+    Receiving,
+}
+
+/// An event sent after every chunk of a transfer is written to or read from the stream.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferProgress {
+    /// The transaction the transfer is sent on
+    pub transaction: TransactionId,
+    /// Whether the transfer is being sent or received
+    pub direction: TransferDirection,
+    /// The offset into the payload the transfer has reached, including the offset it started at
+    pub bytes: u64,
+    /// The length of the whole payload
+    pub total: u64,
+}
+
+/// A channel the remote thread reports the [progress](TransferProgress) of transfers to.
+pub type TransferProgressSender = UnboundedSender<TransferProgress>;
+
+/// Tracks the progress of the transfer in one direction of a transaction on the remote thread.
+pub(crate) struct TransferCounter {
+    transaction: TransactionId,
+    direction: TransferDirection,
+    progress: Option<(u64, u64)>,
+    sender: TransferProgressSender,
+}
+
+impl TransferCounter {
+    pub(crate) fn new(
+        state: &TransactionState,
+        direction: TransferDirection,
+        sender: &TransferProgressSender,
+    ) -> Self {
+        Self {
+            transaction: state.id(),
+            direction,
+            progress: None,
+            sender: sender.clone(),
+        }
+    }
+
+    /// Starts or ends the transfer if `msg` is a [`TransferStart`] or [`TransferEnd`].
+    pub(crate) fn observe(&mut self, msg: &dyn Message) {
+        if let Some(start) = msg.as_any().downcast_ref::<TransferStart>() {
+            self.progress = Some((start.offset, start.total));
+        } else if msg.is::<TransferEnd>() {
+            self.progress = None;
+        }
+    }
+
+    /// Records that a chunk of `len` bytes was written or read, and reports the progress.
+    pub(crate) fn chunk(&mut self, len: usize) {
+        let (bytes, total) = match &mut self.progress {
+            Some((bytes, total)) => {
+                *bytes += len as u64;
+                (*bytes, *total)
+            }
+            // Chunks sent outside of a transfer have nothing to report progress against
+            None => return,
+        };
+
+        let progress = TransferProgress {
+            transaction: self.transaction,
+            direction: self.direction,
+            bytes,
+            total,
+        };
+        _ = self.sender.send(progress);
+    }
+}
+
+/// Receives the [progress](TransferProgress) reported by the remote thread.
+pub(crate) struct TransferProgressReceiver(pub(crate) Mutex<UnboundedReceiver<TransferProgress>>);
+
+/// Sends the [progress](TransferProgress) reported by the remote thread as events.
+pub(crate) fn emit_transfer_progress(
+    receiver: Option<Res<TransferProgressReceiver>>,
+    mut events: EventWriter<TransferProgress>,
+) {
+    let receiver = match receiver {
+        Some(receiver) => receiver,
+        None => return,
+    };
+    let mut receiver = receiver.0.lock().unwrap();
+
+    while let Ok(progress) = receiver.try_recv() {
+        events.send(progress);
+    }
+}
+
+pub(crate) fn send_transfer(
+    tx: &MessageTx,
+    state: &TransactionState,
+    mut reader: impl Read,
+    start: TransferStart,
+    token: Option<&CancellationToken>,
+) -> Result<TransferEnd, TransferError> {
+    match tx.config().policy {
+        DropPolicy::Block => (),
+        policy => return Err(TransferError::LossyQueue(policy)),
+    }
+
+    let mut bytes = start.offset;
+    interface::send_blocking(tx, state, start)?;
+
+    loop {
+        if token.is_some_and(CancellationToken::is_cancelled) {
+            let end = TransferEnd {
+                bytes,
+                cancelled: true,
+            };
+            interface::send_blocking(tx, state, end.clone())?;
+            return Ok(end);
+        }
+
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                // Let the remote application know where to resume from
+                interface::send_blocking(
+                    tx,
+                    state,
+                    TransferEnd {
+                        bytes,
+                        cancelled: true,
+                    },
+                )?;
+                return Err(err.into());
+            }
+        };
+        chunk.truncate(read);
+
+        interface::send_blocking(tx, state, TransferChunk { bytes: chunk })?;
+        bytes += read as u64;
+    }
+
+    let end = TransferEnd {
+        bytes,
+        cancelled: false,
+    };
+    interface::send_blocking(tx, state, end.clone())?;
+    Ok(end)
+}
+
+/// Reads the payload of a transfer from a [transaction](Transaction) as it is received.
+///
+/// Reading returns an error of kind [`ConnectionAborted`](io::ErrorKind::ConnectionAborted) if the transfer was cancelled,
+/// in which case [`bytes`](TransferReader::bytes) is the offset a later transfer can resume from. It returns an error
+/// of kind [`UnexpectedEof`](io::ErrorKind::UnexpectedEof) if the transfer ended before the whole payload was received.
+///
+/// ## Example:
+/// ```no_run
+/// # use std::io::Read;
+/// # use bevy_editor_iris_common::interface::Transaction;
+/// # fn recv_snapshot(transaction: &mut Transaction) -> Result<(), Box<dyn std::error::Error>> {
+/// let mut snapshot = vec![];
+/// transaction.recv_transfer()?.read_to_end(&mut snapshot)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TransferReader<'t> {
+    rx: &'t mut MessageRx,
+    state: &'t TransactionState,
+    start: TransferStart,
+    bytes: u64,
+    chunk: Vec<u8>,
+    read: usize,
+    end: Option<TransferEnd>,
+}
+
+impl<'t> TransferReader<'t> {
+    /// Reads the transfer begun by `start`, which was received on `transaction`.
+    pub fn new(transaction: &'t mut Transaction, start: TransferStart) -> Self {
+        Self::from_parts(&mut transaction.rx, &transaction.state, start)
+    }
+
+    pub(crate) fn from_parts(
+        rx: &'t mut MessageRx,
+        state: &'t TransactionState,
+        start: TransferStart,
+    ) -> Self {
+        Self {
+            rx,
+            state,
+            bytes: start.offset,
+            start,
+            chunk: vec![],
+            read: 0,
+            end: None,
+        }
+    }
+
+    /// The [`TransferStart`] which began this transfer.
+    pub fn start(&self) -> &TransferStart {
+        &self.start
+    }
+
+    /// The offset into the payload that has been read, including the offset the transfer started at.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The [`TransferEnd`] which ended this transfer, once it has been reached.
+    pub fn end(&self) -> Option<&TransferEnd> {
+        self.end.as_ref()
+    }
+
+    fn next_chunk(&mut self) -> Result<bool, TransferError> {
+        let msg = interface::recv(self.rx, self.state)?;
+
+        let msg = match msg.downcast::<TransferChunk>() {
+            Ok(TransferChunk { bytes }) => {
+                self.chunk = bytes;
+                self.read = 0;
+                return Ok(true);
+            }
+            Err(msg) => msg,
+        };
+
+        match msg.downcast::<TransferEnd>() {
+            Ok(end) => {
+                let result = self.check_end(&end);
+                self.end = Some(end);
+                result.map(|()| false)
+            }
+            Err(msg) => Err(unexpected(msg)),
+        }
+    }
+
+    /// Fails if the transfer was cancelled, or if it ended without every byte of the payload being received.
+    fn check_end(&self, end: &TransferEnd) -> Result<(), TransferError> {
+        if end.cancelled {
+            return Err(TransferError::Cancelled(end.bytes));
+        }
+        if self.bytes != end.bytes || end.bytes != self.start.total {
+            return Err(TransferError::Incomplete {
+                received: self.bytes,
+                total: self.start.total,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<'t> Read for TransferReader<'t> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.chunk.len() {
+            if let Some(end) = &self.end {
+                return self.check_end(end).map(|()| 0).map_err(io_error);
+            }
+
+            match self.next_chunk() {
+                Ok(true) => (),
+                Ok(false) => return Ok(0),
+                Err(err) => return Err(io_error(err)),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.read);
+        buf[..len].copy_from_slice(&self.chunk[self.read..self.read + len]);
+        self.read += len;
+        self.bytes += len as u64;
+
+        Ok(len)
+    }
+}
+
+fn io_error(err: TransferError) -> io::Error {
+    let kind = match err {
+        TransferError::Cancelled(_) => io::ErrorKind::ConnectionAborted,
+        TransferError::Incomplete { .. } => io::ErrorKind::UnexpectedEof,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err)
+}
+
+fn unexpected(msg: MessageBox) -> TransferError {
+    TransferError::UnexpectedMessage(msg.type_name().to_string())
+}
+
+pub(crate) fn recv_transfer<'t>(
+    rx: &'t mut MessageRx,
+    state: &'t TransactionState,
+) -> Result<TransferReader<'t>, TransferError> {
+    match rx.config().policy {
+        DropPolicy::Block => (),
+        policy => return Err(TransferError::LossyQueue(policy)),
+    }
+
+    let msg = interface::recv(rx, state)?;
+
+    match msg.downcast::<TransferStart>() {
+        Ok(start) => Ok(TransferReader::from_parts(rx, state, start)),
+        Err(msg) => Err(unexpected(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    use crate::queue::QueueConfig;
+    use crate::testing::{blocking, eventually, with_peers};
+
+    /// Reads from `payload`, cancelling `token` once `limit` bytes have been read.
+    struct CancellingReader<'a> {
+        payload: &'a [u8],
+        limit: usize,
+        token: CancellationToken,
+    }
+
+    impl Read for CancellingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit).min(self.payload.len());
+            buf[..len].copy_from_slice(&self.payload[..len]);
+            self.payload = &self.payload[len..];
+            self.limit -= len;
+            if self.limit == 0 {
+                self.token.cancel();
+            }
+            Ok(len)
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_round_trip_reports_progress() {
+        with_peers(default(), |mut a, mut b| async move {
+            let payload: Vec<_> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+            let total = payload.len() as u64;
+
+            let sender = a.open();
+            let id = sender.id();
+            let sent = payload.clone();
+            let (end, _sender) = blocking(move || {
+                let start = TransferStart { total, offset: 0 };
+                (sender.send_transfer(&sent[..], start, None), sender)
+            })
+            .await;
+            let end = end.unwrap();
+            assert_eq!(end.bytes, total);
+            assert!(!end.cancelled);
+
+            let mut receiver = b.accept().await;
+            let received_id = receiver.id();
+            let received = blocking(move || {
+                let mut received = vec![];
+                receiver
+                    .recv_transfer()
+                    .unwrap()
+                    .read_to_end(&mut received)
+                    .unwrap();
+                received
+            })
+            .await;
+            assert!(received == payload);
+
+            // Progress is reported after every chunk, in both directions
+            let chunks = [CHUNK_SIZE as u64, CHUNK_SIZE as u64 * 2, total];
+            let mut sending = vec![];
+            eventually(|| {
+                sending.extend(a.progress());
+                sending.len() == chunks.len()
+            })
+            .await;
+            let receiving = b.progress();
+            for (progress, id, direction) in [
+                (&sending, id, TransferDirection::Sending),
+                (&receiving, received_id, TransferDirection::Receiving),
+            ] {
+                assert!(progress.iter().all(|progress| progress.transaction == id
+                    && progress.direction == direction
+                    && progress.total == total));
+                let bytes: Vec<_> = progress.iter().map(|progress| progress.bytes).collect();
+                assert_eq!(bytes, chunks);
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_transfer_resumes_from_offset() {
+        with_peers(default(), |a, mut b| async move {
+            let payload: Vec<_> = (0..CHUNK_SIZE * 3).map(|i| i as u8).collect();
+            let total = payload.len() as u64;
+
+            let sender = a.open();
+            let sent = payload.clone();
+            let (end, sender) = blocking(move || {
+                let token = CancellationToken::new();
+                let reader = CancellingReader {
+                    payload: &sent,
+                    limit: CHUNK_SIZE,
+                    token: token.clone(),
+                };
+                let start = TransferStart { total, offset: 0 };
+                (sender.send_transfer(reader, start, Some(&token)), sender)
+            })
+            .await;
+            let end = end.unwrap();
+            assert_eq!(end.bytes, CHUNK_SIZE as u64);
+            assert!(end.cancelled);
+
+            let receiver = b.accept().await;
+            let (received, offset, receiver) = blocking(move || {
+                let mut receiver = receiver;
+                let mut received = vec![];
+                let mut reader = receiver.recv_transfer().unwrap();
+                let err = reader.read_to_end(&mut received).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+                // `Read::bytes` takes precedence over the inherent method on a value
+                let offset = TransferReader::bytes(&reader);
+                assert_eq!(reader.end().map(|end| end.bytes), Some(offset));
+                (received, offset, receiver)
+            })
+            .await;
+            assert_eq!(offset, CHUNK_SIZE as u64);
+            assert!(received[..] == payload[..CHUNK_SIZE]);
+
+            // Resume on the same transaction, skipping what was already received
+            let sent = payload.clone();
+            let (end, _sender) = blocking(move || {
+                let start = TransferStart { total, offset };
+                let rest = &sent[offset as usize..];
+                (sender.send_transfer(rest, start, None), sender)
+            })
+            .await;
+            let end = end.unwrap();
+            assert_eq!(end.bytes, total);
+            assert!(!end.cancelled);
+
+            let received = blocking(move || {
+                let mut receiver = receiver;
+                let mut received = received;
+                let mut reader = receiver.recv_transfer().unwrap();
+                assert_eq!(reader.start().offset, offset);
+                reader.read_to_end(&mut received).unwrap();
+                assert_eq!(TransferReader::bytes(&reader), total);
+                received
+            })
+            .await;
+            assert!(received == payload);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn transfer_on_lossy_queue_is_rejected() {
+        with_peers(default(), |a, _b| async move {
+            let sender = a.open();
+            sender.set_send_queue(QueueConfig {
+                capacity: 1,
+                policy: DropPolicy::DropOldest,
+            });
+
+            let payload = vec![0; CHUNK_SIZE * 2];
+            let start = TransferStart {
+                total: payload.len() as u64,
+                offset: 0,
+            };
+            let result = sender.send_transfer(&payload[..], start, None);
+            assert!(matches!(
+                result,
+                Err(TransferError::LossyQueue(DropPolicy::DropOldest))
+            ));
+            // Nothing was queued, so no chunk could be discarded
+            assert_eq!(sender.send_queue_metrics().high_water_mark, 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn transfer_with_lost_chunks_fails() {
+        with_peers(default(), |a, mut b| async move {
+            let payload = vec![7; CHUNK_SIZE * 3];
+            let total = payload.len() as u64;
+
+            let sender = a.open();
+            let (end, _sender) = blocking(move || {
+                let start = TransferStart { total, offset: 0 };
+                (sender.send_transfer(&payload[..], start, None), sender)
+            })
+            .await;
+            assert!(!end.unwrap().cancelled);
+
+            let receiver = b.accept().await;
+            let err = blocking(move || {
+                let mut receiver = receiver;
+
+                // A lossy queue could discard chunks, so nothing is received from it
+                receiver.set_recv_queue(QueueConfig {
+                    capacity: 16,
+                    policy: DropPolicy::DropNewest,
+                });
+                assert!(matches!(
+                    receiver.recv_transfer(),
+                    Err(TransferError::LossyQueue(DropPolicy::DropNewest))
+                ));
+                receiver.set_recv_queue(QueueConfig::default());
+
+                // Lose a chunk the way a lossy queue would
+                let start = receiver
+                    .recv()
+                    .unwrap()
+                    .downcast::<TransferStart>()
+                    .unwrap();
+                assert!(receiver.recv().unwrap().is::<TransferChunk>());
+                let mut reader = TransferReader::new(&mut receiver, start);
+                let mut received = vec![];
+                let err = reader.read_to_end(&mut received).unwrap_err();
+                assert_eq!(received.len(), CHUNK_SIZE * 2);
+                // Reading again reports the same error rather than the end of the payload
+                assert!(reader.read(&mut [0; 16]).is_err());
+                err
+            })
+            .await;
+
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<TransferError>()
+                .unwrap();
+            assert!(matches!(
+                *err,
+                TransferError::Incomplete { received, total: expected }
+                    if received == CHUNK_SIZE as u64 * 2 && expected == total
+            ));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,372 @@
+//! Establishes the connection to the remote application. Which side listens and which dials is decided by its
+//! [`Role`], independently of which [`Side`] it is, so a game on a device the editor can't be reached
+//! from can listen for the editor instead. With [discovery](crate::discovery), only games announce themselves,
+//! whichever side listens.
+//!
+//! The listening side presents a self-signed certificate, its [`Identity`]. The dialing side trusts it by pinning
+//! its [fingerprint](CertificateFingerprint), which it receives in the invite when [discovery](crate::discovery)
+//! is used, or from [`ConnectionConfig::remote_fingerprint`]. When neither provides one, it falls back to reading
+//! the certificate the listening side writes to `certificate.der`, which only works on the same machine.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bevy::log::{debug, info, info_span};
+use bevy::utils::tracing::{Instrument, Span};
+use futures_lite::StreamExt;
+use quinn::{ClientConfig, Connection, Endpoint, Incoming, ServerConfig};
+use rcgen::RcgenError;
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio::select;
+
+use crate::asynchronous::{self, OpeningReceiver, OpeningSender};
+use crate::config::{ConnectionConfig, Role, Side};
+use crate::discovery::{self, Listening};
+use crate::error::{InvalidFingerprint, RemoteThreadError};
+use crate::relay;
+use crate::serde::RegistryContext;
+use crate::transfer::TransferProgressSender;
+
+/// The file the listening side writes its certificate to.
+const CERTIFICATE_PATH: &str = "certificate.der";
+
+/// The SHA-256 fingerprint of a certificate. Displayed and parsed as 64 hexadecimal digits.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct CertificateFingerprint(pub [u8; 32]);
+
+impl CertificateFingerprint {
+    /// The fingerprint of `cert`.
+    pub fn of(cert: &Certificate) -> Self {
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(digest(&SHA256, &cert.0).as_ref());
+        Self(fingerprint)
+    }
+}
+
+impl Display for CertificateFingerprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl FromStr for CertificateFingerprint {
+    type Err = InvalidFingerprint;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidFingerprint(s.to_string());
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(invalid());
+        }
+
+        let mut fingerprint = [0; 32];
+        for (byte, digits) in fingerprint.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+/// The self-signed certificate and private key the listening side presents.
+///
+/// Generated once when the plugin is added, so the [fingerprint](Identity::fingerprint) stays the same
+/// across reconnects and can be shared with the dialing side before the remote thread is opened.
+#[derive(Clone)]
+pub struct Identity {
+    cert: Certificate,
+    key: PrivateKey,
+}
+
+impl Identity {
+    /// Generates a new self-signed certificate for `localhost`.
+    pub fn generate() -> Result<Self, RcgenError> {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        Ok(Self {
+            key: PrivateKey(cert.serialize_private_key_der()),
+            cert: Certificate(cert.serialize_der()?),
+        })
+    }
+
+    /// The certificate presented to the dialing side.
+    pub fn certificate(&self) -> &Certificate {
+        &self.cert
+    }
+
+    /// The fingerprint the dialing side pins.
+    pub fn fingerprint(&self) -> CertificateFingerprint {
+        CertificateFingerprint::of(&self.cert)
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // The private key is left out of logs
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs the remote thread of `side` for the given role.
+pub async fn run(
+    side: Side,
+    role: Role,
+    tx: OpeningSender,
+    rx: OpeningReceiver,
+    progress: TransferProgressSender,
+    config: ConnectionConfig,
+    registry: RegistryContext,
+) -> Result<(), RemoteThreadError> {
+    match role {
+        Role::Listen => listen(side, tx, rx, progress, config, registry).await,
+        Role::Dial => dial(side, tx, rx, progress, config, registry).await,
+    }
+}
+
+/// Listens on [`ConnectionConfig::listen_addr`] and processes each connection in turn. A game announces where
+/// it listens while it waits for a connection, if [discovery](ConnectionConfig::discovery) is enabled.
+pub async fn listen(
+    side: Side,
+    tx: OpeningSender,
+    mut rx: OpeningReceiver,
+    progress: TransferProgressSender,
+    config: ConnectionConfig,
+    registry: RegistryContext,
+) -> Result<(), RemoteThreadError> {
+    let listen_addr = config
+        .listen_addr
+        .unwrap_or_else(|| default_listen_addr(&config));
+    let identity = match &config.identity {
+        Some(identity) => identity.clone(),
+        None => Identity::generate()?,
+    };
+    let (endpoint, mut incoming) = listen_endpoint(listen_addr, &identity, &config)?;
+
+    info!(addr = %listen_addr, fingerprint = %identity.fingerprint(), "accepting connections");
+
+    let listening = Listening {
+        port: endpoint.local_addr()?.port(),
+        fingerprint: identity.fingerprint(),
+    };
+    let announce = match (side, &config.discovery) {
+        (Side::Game, Some(discovery)) => Some(discovery),
+        _ => None,
+    };
+
+    loop {
+        let conn = match announce {
+            Some(discovery) => select! {
+                conn = incoming.next() => conn,
+                Err(err) = discovery::announce_listening(discovery, listening) => return Err(err.into()),
+            },
+            None => incoming.next().await,
+        };
+        let conn = match conn {
+            Some(conn) => conn,
+            None => break,
+        };
+        debug!(remote = %conn.remote_address(), "connection incoming");
+
+        let new = conn.await?;
+        let span = connection_span(&new.connection);
+        span.in_scope(|| info!("received a connection"));
+
+        asynchronous::process_connection(new, &tx, &mut rx, &progress, &config, &registry)
+            .instrument(span)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Dials [`ConnectionConfig::remote_addr`] and processes the connection. If [discovery](ConnectionConfig::discovery)
+/// is enabled instead, a game waits to be invited, and an editor waits for a game which listens.
+pub async fn dial(
+    side: Side,
+    tx: OpeningSender,
+    mut rx: OpeningReceiver,
+    progress: TransferProgressSender,
+    config: ConnectionConfig,
+    registry: RegistryContext,
+) -> Result<(), RemoteThreadError> {
+    let (remote_addr, fingerprint) = match (config.remote_addr, &config.discovery) {
+        (Some(addr), _) => (addr, config.remote_fingerprint),
+        (None, Some(discovery)) => {
+            let invite = match side {
+                Side::Game => {
+                    info!(addr = %discovery.addr, "waiting for an editor to invite us");
+                    discovery::wait_for_invite(discovery).await?
+                }
+                Side::Editor => {
+                    info!(addr = %discovery.addr, "waiting for a listening game to announce itself");
+                    discovery::wait_for_listening_game(discovery).await?
+                }
+            };
+            (invite.addr, Some(invite.fingerprint))
+        }
+        (None, None) => (crate::server_addr(), config.remote_fingerprint),
+    };
+
+    // Any interface may lead to the remote application, and the port doesn't matter to it
+    let endpoint = Endpoint::client(unspecified_addr(remote_addr))?;
+
+    info!(remote = %remote_addr, "attempting connection");
+
+    let new = endpoint
+        .connect_with(
+            client_config(&config, fingerprint)?,
+            remote_addr,
+            "localhost",
+        )?
+        .await?;
+    let span = connection_span(&new.connection);
+
+    if let Some(room) = &config.room {
+        span.in_scope(
+            || info!(room = %room, "waiting for the remote application to join the room"),
+        );
+        relay::join_room(&new.connection, room, &registry).await?;
+    }
+
+    span.in_scope(|| info!("acquired connection"));
+
+    asynchronous::process_connection(new, &tx, &mut rx, &progress, &config, &registry)
+        .instrument(span)
+        .await?;
+
+    Ok(())
+}
+
+/// Creates the span a connection is processed in.
+fn connection_span(connection: &Connection) -> Span {
+    info_span!(
+        "connection",
+        id = connection.stable_id(),
+        remote = %connection.remote_address()
+    )
+}
+
+/// The address to listen on when [`ConnectionConfig::listen_addr`] isn't set. Discovered games are on another device
+/// more often than not, so the listening side must be reachable from every interface when discovery is enabled.
+fn default_listen_addr(config: &ConnectionConfig) -> SocketAddr {
+    match config.discovery {
+        Some(_) => (Ipv4Addr::UNSPECIFIED, crate::server_addr().port()).into(),
+        None => crate::server_addr(),
+    }
+}
+
+/// The unspecified address of the same family as `addr`, with any port.
+pub(crate) fn unspecified_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// Creates an endpoint listening on `addr` and presenting `identity`, writing its certificate for a dialing side
+/// on the same machine to read.
+pub fn listen_endpoint(
+    addr: SocketAddr,
+    identity: &Identity,
+    config: &ConnectionConfig,
+) -> Result<(Endpoint, Incoming), RemoteThreadError> {
+    fs::write(CERTIFICATE_PATH, &identity.cert.0)?;
+    let server_config = server_config(identity, config)?;
+
+    Ok(Endpoint::server(server_config, addr)?)
+}
+
+pub(crate) fn server_config(
+    identity: &Identity,
+    connection: &ConnectionConfig,
+) -> Result<ServerConfig, rustls::Error> {
+    ServerConfig::with_single_cert(vec![identity.cert.clone()], identity.key.clone()).map(
+        |mut config| {
+            config.transport = Arc::new(connection.transport_config());
+            config
+        },
+    )
+}
+
+/// Builds the config which trusts the certificate with `fingerprint`, or the certificate in `certificate.der`
+/// if there is none.
+fn client_config(
+    connection: &ConnectionConfig,
+    fingerprint: Option<CertificateFingerprint>,
+) -> Result<ClientConfig, RemoteThreadError> {
+    let mut config = match fingerprint {
+        Some(fingerprint) => pinned_client_config(fingerprint),
+        None => {
+            let cert = Certificate(fs::read(CERTIFICATE_PATH)?);
+
+            let mut store = RootCertStore::empty();
+            store.add(&cert).unwrap();
+
+            ClientConfig::with_root_certificates(store)
+        }
+    };
+    config.transport = Arc::new(connection.transport_config());
+    Ok(config)
+}
+
+/// Builds a config which only trusts the certificate with `fingerprint`.
+pub(crate) fn pinned_client_config(fingerprint: CertificateFingerprint) -> ClientConfig {
+    // The same as the defaults of quinn, other than the verifier
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate(fingerprint)))
+        .with_no_client_auth();
+    crypto.enable_early_data = true;
+
+    ClientConfig::new(Arc::new(crypto))
+}
+
+/// Trusts the one certificate with the given fingerprint. The handshake still proves that the other side
+/// holds its private key.
+struct PinnedCertificate(CertificateFingerprint);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if CertificateFingerprint::of(end_entity) == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(format!(
+                "expected a certificate with fingerprint {}",
+                self.0
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_round_trips_through_hex() {
+        let fingerprint = Identity::generate().unwrap().fingerprint();
+        let hex = fingerprint.to_string();
+
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<CertificateFingerprint>().unwrap(), fingerprint);
+        assert!(hex[1..].parse::<CertificateFingerprint>().is_err());
+        assert!("zz".repeat(32).parse::<CertificateFingerprint>().is_err());
+    }
+}
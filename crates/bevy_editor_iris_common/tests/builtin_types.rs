@@ -0,0 +1,298 @@
+//! Round trips the built-in bevy types which games send to the editor through a [`ReflectObject`] in a message,
+//! and pins the messages they're sent in with the golden files in `tests/golden`.
+//!
+//! Run with `IRIS_BLESS=1` to rewrite the golden files after an intended change to the wire format.
+
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+
+use bevy::app::App;
+use bevy::asset::{AddAsset, AssetPlugin, Handle, HandleId};
+use bevy::core::Name;
+use bevy::ecs::entity::Entity;
+use bevy::ecs::reflect::ReflectComponent;
+use bevy::ecs::world::{FromWorld, World};
+use bevy::hierarchy::{Children, HierarchyPlugin, Parent, PreviousParent};
+use bevy::math::{Quat, Vec3, Vec3A};
+use bevy::reflect::{Reflect, TypeRegistry, TypeUuid};
+use bevy::render::color::Color;
+use bevy::render::mesh::Mesh;
+use bevy::render::view::{ComputedVisibility, Visibility};
+use bevy::transform::components::{GlobalTransform, Transform};
+use bevy::transform::TransformPlugin;
+use bevy::MinimalPlugins;
+use bevy_editor_iris_common::config::Side;
+use bevy_editor_iris_common::message::{deserialize_message, serialize_message};
+use bevy_editor_iris_common::serde::{ReflectObject, RegistryContext};
+use bevy_editor_iris_common::CommonPlugin;
+use bevy_editor_iris_derive::message;
+
+const ENTITY_GENERATION: &str =
+    "bevy serializes an Entity as its index alone, so its generation is lost";
+
+/// The types which don't survive the round trip, and why. Remove a type once it does.
+const KNOWN_FAILURES: &[(&str, &str)] = &[
+    (
+        "bevy_hierarchy::components::children::Children",
+        ENTITY_GENERATION,
+    ),
+    (
+        "bevy_hierarchy::components::parent::Parent",
+        ENTITY_GENERATION,
+    ),
+    (
+        "bevy_hierarchy::components::parent::PreviousParent",
+        ENTITY_GENERATION,
+    ),
+];
+
+/// Carries a value with nothing around it, so each golden file pins only the format of the value.
+#[message(id = "iris.test.carrier")]
+#[derive(Clone, Debug)]
+struct Carrier {
+    value: ReflectObject,
+}
+
+/// A value which is sent to the editor, and the golden file the message it's sent in is compared to.
+struct Sample {
+    golden: &'static str,
+    value: Box<dyn Reflect>,
+    /// A field whose value differs in every process, which is left out of the golden file
+    unstable_field: Option<&'static str>,
+}
+
+impl Sample {
+    fn new(golden: &'static str, value: impl Reflect) -> Self {
+        Self {
+            golden,
+            value: Box::new(value),
+            unstable_field: None,
+        }
+    }
+
+    fn with_unstable_field(mut self, field: &'static str) -> Self {
+        self.unstable_field = Some(field);
+        self
+    }
+}
+
+/// An app with the plugins of `DefaultPlugins` which run headless, and the registrations of those which don't.
+fn app() -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin)
+        .add_plugin(AssetPlugin)
+        // Registered by the render plugins, which need a GPU
+        .add_asset::<Mesh>()
+        .register_type::<Visibility>()
+        .register_type::<ComputedVisibility>()
+        .register_type::<Color>()
+        .register_type::<Carrier>()
+        .add_plugin(CommonPlugin(Side::Editor));
+    app
+}
+
+/// An entity whose index was freed and reused once, like most entities of a game which despawns any.
+fn reused_entity(index: u32) -> Entity {
+    Entity::from_bits((1 << 32) | index as u64)
+}
+
+/// A value of every component registered by [`app`], and of the other types which are sent on their own.
+/// Values differ from their type's default where they can, so that applying them is tested too.
+fn samples(world: &mut World) -> Vec<Sample> {
+    let transform = Transform {
+        translation: Vec3::new(1.0, 2.0, 3.0),
+        rotation: Quat::from_xyzw(0.0, 0.6, 0.0, 0.8),
+        scale: Vec3::new(2.0, 2.0, 2.0),
+    };
+
+    // Its entity can't be set directly
+    let mut previous_parent = PreviousParent::from_world(world);
+    previous_parent.apply(&Parent(reused_entity(5)));
+
+    vec![
+        Sample::new("transform", transform),
+        Sample::new("global_transform", GlobalTransform::from(transform)),
+        Sample::new("name", Name::new("Player")).with_unstable_field("hash"),
+        Sample::new("visibility", Visibility { is_visible: false }),
+        Sample::new(
+            "computed_visibility",
+            ComputedVisibility { is_visible: true },
+        ),
+        Sample::new(
+            "handle_mesh",
+            Handle::<Mesh>::weak(HandleId::Id(Mesh::TYPE_UUID, 42)),
+        ),
+        Sample::new("parent", Parent(reused_entity(3))),
+        Sample::new("previous_parent", previous_parent),
+        Sample::new(
+            "children",
+            Children::with(&[reused_entity(4), reused_entity(7)]),
+        ),
+        Sample::new("color", Color::rgba(0.25, 0.5, 0.75, 1.0)),
+        Sample::new("vec3a", Vec3A::new(1.0, 2.0, 3.0)),
+        Sample::new("cow_str", Cow::<'static, str>::Borrowed("iris")),
+    ]
+}
+
+/// Whether the golden files are being rewritten.
+fn blessing() -> bool {
+    env::var_os("IRIS_BLESS").is_some()
+}
+
+/// Compares `yaml` to the golden file `name`, or rewrites the golden file if blessing.
+fn check_golden(name: &str, yaml: &str) -> Result<(), String> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.yaml", name));
+
+    if blessing() {
+        fs::write(&path, yaml)
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+        return Ok(());
+    }
+
+    match fs::read_to_string(&path) {
+        Ok(golden) if golden == yaml => Ok(()),
+        Ok(golden) => Err(format!(
+            "was sent as\n{}\nrather than as in {}\n{}",
+            yaml,
+            path.display(),
+            golden
+        )),
+        Err(_) => Err(format!(
+            "has no golden file at {}; run with IRIS_BLESS=1 to write it",
+            path.display()
+        )),
+    }
+}
+
+/// Removes `field` from the value in the [`Carrier`] serialized as `yaml`.
+fn strip_field(yaml: &str, field: &str) -> Result<String, String> {
+    let mut carrier: serde_yaml::Value =
+        serde_yaml::from_str(yaml).map_err(|err| format!("failed to parse: {}", err))?;
+    let removed = carrier
+        .get_mut("struct")
+        .and_then(|carrier| carrier.get_mut("value"))
+        .and_then(|value| value.get_mut("struct"))
+        .and_then(|fields| fields.as_mapping_mut())
+        .and_then(|fields| fields.remove(&field.into()));
+    if removed.is_none() {
+        return Err(format!("has no field {} to leave out", field));
+    }
+    serde_yaml::to_string(&carrier).map_err(|err| format!("failed to serialize: {}", err))
+}
+
+/// Sends `sample` in a [`Carrier`], and checks the value received is the same.
+fn round_trip(
+    world: &mut World,
+    registry: &RegistryContext,
+    sample: &Sample,
+) -> Result<(), String> {
+    let carrier = Carrier {
+        value: ReflectObject::from(sample.value.clone_value()),
+    };
+
+    let mut buf = vec![];
+    serialize_message(Box::new(carrier), &mut buf, registry)
+        .map_err(|err| format!("failed to serialize: {}", err))?;
+    let yaml = String::from_utf8(buf).unwrap();
+    match sample.unstable_field {
+        Some(field) => check_golden(sample.golden, &strip_field(&yaml, field)?)?,
+        None => check_golden(sample.golden, &yaml)?,
+    }
+
+    let carrier = deserialize_message(yaml.as_bytes(), registry)
+        .map_err(|err| format!("failed to deserialize: {}", err))?
+        .downcast::<Carrier>()
+        .unwrap();
+    let received: Box<dyn Reflect> = carrier.value.into();
+
+    let component = registry
+        .registry()
+        .read()
+        .get_with_name(sample.value.type_name())
+        .and_then(|registration| registration.data::<ReflectComponent>())
+        .cloned();
+
+    let equal = match component {
+        // Inserted into a new entity, the way received components are
+        Some(component) => {
+            let entity = world.spawn().id();
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                component.add_component(world, entity, &*received)
+            }))
+            .map_err(|_| "applying the received value panicked".to_string())?;
+
+            let equal = sample
+                .value
+                .reflect_partial_eq(component.reflect_component(world, entity).unwrap());
+            world.despawn(entity);
+            equal
+        }
+        None => sample.value.reflect_partial_eq(&*received),
+    };
+
+    match equal {
+        Some(true) => Ok(()),
+        Some(false) => Err("the value received differs from the value sent".to_string()),
+        None => Err("the value received can't be compared to the value sent".to_string()),
+    }
+}
+
+#[test]
+fn builtin_types_round_trip() {
+    let mut app = app();
+    let registry = RegistryContext::new(app.world.resource::<TypeRegistry>().clone());
+    let samples = samples(&mut app.world);
+
+    let mut failures = vec![];
+    for sample in &samples {
+        if let Err(reason) = round_trip(&mut app.world, &registry, sample) {
+            failures.push((sample.value.type_name().to_string(), reason));
+        }
+    }
+
+    for registration in registry.registry().read().iter() {
+        let sampled = samples
+            .iter()
+            .any(|sample| sample.value.type_name() == registration.name());
+        if registration.data::<ReflectComponent>().is_some() && !sampled {
+            failures.push((
+                registration.name().to_string(),
+                "is a registered component without a sample".to_string(),
+            ));
+        }
+    }
+
+    let listing = failures
+        .iter()
+        .map(|(name, reason)| format!("{}: {}", name, reason))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let is_known = |name: &str| KNOWN_FAILURES.iter().any(|(known, _)| *known == name);
+    assert!(
+        failures.iter().all(|(name, _)| is_known(name)),
+        "types which fail the round trip:\n{}",
+        listing
+    );
+
+    // Blessing rewrites the golden files, so a known failure may pass this time
+    if blessing() {
+        return;
+    }
+    let fixed: Vec<_> = KNOWN_FAILURES
+        .iter()
+        .filter(|(known, _)| !failures.iter().any(|(name, _)| name == known))
+        .map(|(known, _)| known)
+        .collect();
+    assert!(
+        fixed.is_empty(),
+        "these types now round trip, so remove them from KNOWN_FAILURES: {:?}",
+        fixed
+    );
+}
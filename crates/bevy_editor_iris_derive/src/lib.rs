@@ -1,27 +1,387 @@
 #![deny(missing_docs)]
 //! Procedural macros for bevy_editor_derive.
 
+use bevy_macro_utils::BevyManifest;
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::ext::IdentExt;
+use syn::parse::{ParseStream, Parser};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Expr, Ident, LitStr, Path, Token,
+};
 
 /// Derives the Message trait automatically.
-#[proc_macro_derive(Message)]
+///
+/// Takes the same parameters as [`macro@message`] in an `#[iris(...)]` attribute, apart from `default`.
+/// The type must also be reflected with `#[reflect(Message, MessageFromReflect)]`, with `ReflectMessage` and
+/// `ReflectMessageFromReflect` imported, which `#[message]` does for you.
+#[proc_macro_derive(Message, attributes(iris))]
 pub fn derive_message(input: TokenStream) -> TokenStream {
-    let DeriveInput { ident, .. } = parse_macro_input!(input);
-    quote! {
-        impl Message for #ident {}
-    }
-    .into()
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_derive(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
 }
 
 /// Derives and reflects all necessary traits to use a type as a message.
+///
+/// The following parameters may be given, separated by commas:
+/// - `priority = ...` implements `Message::priority`.
+/// - `id = "..."` implements `Message::message_id`, the stable id the message is sent with instead of its type name.
+/// - `alias = "..."` adds to `Message::aliases`, the other names the message is recognized by when received.
+///   May be repeated.
+/// - `version = ...` implements `Message::version`.
+/// - `migrate = ...` implements `Message::migrate` with the given function.
+/// - `default` reflects `MessageDefault`, so missing fields are filled in from the message's `Default` implementation.
+/// - `crate = ...` is the path to `bevy_editor_iris_common`, for crates which depend on it under another name.
+///   Defaults to `::bevy_editor_iris_common`.
+///
+/// Generic messages are supported. Their type parameters need a `FromReflect` bound, which must be declared in
+/// the parameter list since bevy ignores where clauses on tuple structs.
+///
+/// Enums are reflected as values, which bevy serializes as a whole, so they must also derive `Clone`, and serde's
+/// `Serialize` and `Deserialize`. A value can't be renamed on the wire, so enum messages only take `priority`.
+///
+/// ## Example:
+/// ```
+/// use bevy::reflect::FromReflect;
+/// use bevy_editor_iris_common as common;
+/// use bevy_editor_iris_derive::message;
+/// use common::message::{priorities, Message};
+///
+/// #[message(crate = common, priority = priorities::INTERACTIVE)]
+/// pub struct Ping<T: FromReflect> {
+///     pub payload: T,
+/// }
+///
+/// let ping = Ping { payload: 3u32 };
+/// assert_eq!(ping.priority(), priorities::INTERACTIVE);
+/// ```
 #[proc_macro_attribute]
-pub fn message(_params: TokenStream, item: TokenStream) -> TokenStream {
-    let item: proc_macro2::TokenStream = item.into();
-    TokenStream::from(quote! {
-        #[derive(Reflect, FromReflect, Message)]
-        #[reflect(Message, MessageFromReflect)]
-        #item
+pub fn message(params: TokenStream, item: TokenStream) -> TokenStream {
+    let params = match parse_params.parse(params) {
+        Ok(params) => params,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    let input = parse_macro_input!(item as DeriveInput);
+
+    expand_message(&input, &params)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_message(input: &DeriveInput, params: &MessageParams) -> syn::Result<TokenStream2> {
+    check_shape(input, params)?;
+
+    let krate = params.krate();
+    let mut trait_data = vec![
+        ("Message", quote!(#krate::message::ReflectMessage)),
+        (
+            "MessageFromReflect",
+            quote!(#krate::message::ReflectMessageFromReflect),
+        ),
+    ];
+    let reflect_attr = match input.data {
+        Data::Enum(_) => {
+            trait_data.push((
+                "Deserialize",
+                quote!(#krate::deps::bevy::reflect::ReflectDeserialize),
+            ));
+            let names = trait_data_names(input, &trait_data);
+            quote! {
+                #[reflect_value(#(#names,)* Serialize)]
+            }
+        }
+        _ => {
+            if params.default.is_some() {
+                trait_data.push((
+                    "MessageDefault",
+                    quote!(#krate::message::ReflectMessageDefault),
+                ));
+            }
+            let names = trait_data_names(input, &trait_data);
+            quote! {
+                #[reflect(#(#names),*)]
+            }
+        }
+    };
+    let aliases = trait_data_aliases(input, &trait_data);
+    let bevy_reflect = bevy_reflect_import(&krate);
+    let message_impl = impl_message(input, params);
+
+    Ok(quote! {
+        #[derive(#krate::deps::bevy::reflect::Reflect, #krate::deps::bevy::reflect::FromReflect)]
+        #reflect_attr
+        #input
+
+        #aliases
+        #bevy_reflect
+        // bevy's derive calls `Reflect` methods on the message and its fields, which needs the trait in scope
+        #[allow(unused_imports)]
+        use #krate::deps::bevy::reflect::Reflect as _;
+        const _: () = {
+            #message_impl
+        };
+    })
+}
+
+fn expand_derive(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let params = params_from_attrs(&input.attrs)?;
+    if let Some(default) = params.default {
+        return Err(syn::Error::new(
+            default,
+            "`default` is only supported by #[message]; reflect `MessageDefault` instead",
+        ));
+    }
+    check_shape(input, &params)?;
+
+    let message_impl = impl_message(input, &params);
+
+    Ok(quote! {
+        const _: () = {
+            #message_impl
+        };
     })
 }
+
+/// Rejects the types which can't be messages, with an explanation.
+fn check_shape(input: &DeriveInput, params: &MessageParams) -> syn::Result<()> {
+    if let Some(lifetime) = input.generics.lifetimes().next() {
+        return Err(syn::Error::new(
+            lifetime.span(),
+            "messages are sent between threads, so they can't borrow; remove the lifetime parameter",
+        ));
+    }
+
+    match &input.data {
+        Data::Union(data) => Err(syn::Error::new(
+            data.union_token.span,
+            "unions can't be reflected, so they can't be messages",
+        )),
+        Data::Enum(_) => {
+            let unsupported = [
+                params.id.as_ref().map(|id| ("id", id.span())),
+                params.aliases.first().map(|alias| ("alias", alias.span())),
+                params
+                    .version
+                    .as_ref()
+                    .map(|version| ("version", version.span())),
+                params
+                    .migrate
+                    .as_ref()
+                    .map(|migrate| ("migrate", migrate.span())),
+                params.default.map(|default| ("default", default)),
+            ];
+
+            match unsupported.into_iter().flatten().next() {
+                Some((name, span)) => Err(syn::Error::new(
+                    span,
+                    format!(
+                        "enum messages are reflected as values, which are always sent by their type name, \
+                         so `{}` isn't supported",
+                        name
+                    ),
+                )),
+                None => Ok(()),
+            }
+        }
+        Data::Struct(_) => Ok(()),
+    }
+}
+
+/// The names `#[reflect(...)]` is given for the trait data of a message. bevy only accepts identifiers there,
+/// and looks the trait data up as `Reflect` followed by the identifier, so the names are unique to the message
+/// and [aliased](trait_data_aliases) to the trait data, rather than importing it into the caller's module.
+fn trait_data_names(input: &DeriveInput, trait_data: &[(&str, TokenStream2)]) -> Vec<Ident> {
+    trait_data
+        .iter()
+        .map(|(name, _)| format_ident!("__{}_{}", input.ident, name))
+        .collect()
+}
+
+fn trait_data_aliases(input: &DeriveInput, trait_data: &[(&str, TokenStream2)]) -> TokenStream2 {
+    let aliases = trait_data_names(input, trait_data)
+        .into_iter()
+        .map(|name| format_ident!("Reflect{}", name));
+    let paths = trait_data.iter().map(|(_, path)| path);
+
+    quote! {
+        #(
+            #[doc(hidden)]
+            #[allow(non_camel_case_types)]
+            type #aliases = #paths;
+        )*
+    }
+}
+
+/// bevy's derives refer to `bevy_reflect` unless the crate depends on bevy itself, and a crate which depends
+/// on neither only has it through `bevy_editor_iris_common`. The derives look it up in the caller's module,
+/// so it's imported there, through a glob so that every message in a module can import it.
+fn bevy_reflect_import(krate: &Path) -> Option<TokenStream2> {
+    BevyManifest::default()
+        .maybe_get_path("bevy_reflect")
+        .is_none()
+        .then(|| {
+            quote! {
+                #[allow(unused_imports)]
+                use #krate::__macro_exports::bevy_reflect_alias::*;
+            }
+        })
+}
+
+fn impl_message(input: &DeriveInput, params: &MessageParams) -> TokenStream2 {
+    let krate = params.krate();
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let priority = params.priority.as_ref().map(|priority| {
+        quote! {
+            fn priority(&self) -> i32 {
+                #priority
+            }
+        }
+    });
+    let id = params.id.as_ref().map(|id| {
+        quote! {
+            fn message_id() -> ::std::option::Option<&'static str> {
+                ::std::option::Option::Some(#id)
+            }
+        }
+    });
+    let version = params.version.as_ref().map(|version| {
+        quote! {
+            fn version() -> u32 {
+                #version
+            }
+        }
+    });
+    let migrate = params.migrate.as_ref().map(|migrate| {
+        quote! {
+            fn migrate(
+                version: u32,
+                value: ::std::boxed::Box<dyn #krate::deps::bevy::reflect::Reflect>,
+            ) -> ::std::boxed::Box<dyn #krate::deps::bevy::reflect::Reflect> {
+                (#migrate)(version, value)
+            }
+        }
+    });
+    let aliases = (!params.aliases.is_empty()).then(|| {
+        let aliases = &params.aliases;
+        quote! {
+            fn aliases() -> &'static [&'static str] {
+                &[#(#aliases),*]
+            }
+        }
+    });
+
+    quote! {
+        impl #impl_generics #krate::message::Message for #ident #ty_generics #where_clause {
+            #priority
+            #id
+            #aliases
+            #version
+            #migrate
+        }
+    }
+}
+
+#[derive(Default)]
+struct MessageParams {
+    krate: Option<Path>,
+    priority: Option<Expr>,
+    id: Option<LitStr>,
+    aliases: Vec<LitStr>,
+    version: Option<Expr>,
+    migrate: Option<Expr>,
+    default: Option<Span>,
+}
+
+impl MessageParams {
+    fn krate(&self) -> Path {
+        self.krate
+            .clone()
+            .unwrap_or_else(|| parse_quote!(::bevy_editor_iris_common))
+    }
+}
+
+fn parse_params(input: ParseStream) -> syn::Result<MessageParams> {
+    let mut params = MessageParams::default();
+    parse_params_into(input, &mut params)?;
+    Ok(params)
+}
+
+/// Parses the parameters of every `#[iris(...)]` attribute.
+fn params_from_attrs(attrs: &[Attribute]) -> syn::Result<MessageParams> {
+    let mut params = MessageParams::default();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("iris")) {
+        attr.parse_args_with(|input: ParseStream| parse_params_into(input, &mut params))?;
+    }
+    Ok(params)
+}
+
+/// Parses an `id` or `alias`, which can't contain `@` since it separates the version from the name on the wire.
+fn parse_message_name(input: ParseStream) -> syn::Result<LitStr> {
+    let name: LitStr = input.parse()?;
+    if name.value().contains('@') {
+        return Err(syn::Error::new(
+            name.span(),
+            "message ids and aliases can't contain `@`, which separates the version they're sent with",
+        ));
+    }
+    Ok(name)
+}
+
+fn parse_params_into(input: ParseStream, params: &mut MessageParams) -> syn::Result<()> {
+    fn set<T>(slot: &mut Option<T>, name: &Ident, value: T) -> syn::Result<()> {
+        if slot.is_some() {
+            return Err(syn::Error::new(
+                name.span(),
+                format!("duplicate message parameter `{}`", name),
+            ));
+        }
+        *slot = Some(value);
+        Ok(())
+    }
+
+    while !input.is_empty() {
+        // `crate` is a keyword
+        let name = Ident::parse_any(input)?;
+        if name == "default" {
+            set(&mut params.default, &name, name.span())?;
+        } else {
+            input.parse::<Token![=]>()?;
+
+            if name == "crate" {
+                set(&mut params.krate, &name, input.parse()?)?;
+            } else if name == "priority" {
+                set(&mut params.priority, &name, input.parse()?)?;
+            } else if name == "id" {
+                set(&mut params.id, &name, parse_message_name(input)?)?;
+            } else if name == "alias" {
+                params.aliases.push(parse_message_name(input)?);
+            } else if name == "version" {
+                set(&mut params.version, &name, input.parse()?)?;
+            } else if name == "migrate" {
+                set(&mut params.migrate, &name, input.parse()?)?;
+            } else {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!(
+                        "unknown message parameter `{}`; expected one of `crate`, `priority`, `id`, `alias`, \
+                         `version`, `migrate` or `default`",
+                        name
+                    ),
+                ));
+            }
+        }
+
+        if input.is_empty() {
+            break;
+        }
+        input.parse::<Token![,]>()?;
+    }
+
+    Ok(())
+}
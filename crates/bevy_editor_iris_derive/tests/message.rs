@@ -0,0 +1,114 @@
+//! Messages declared outside of `bevy_editor_iris_common`, the way games and the editor declare them.
+
+use bevy::reflect::{FromReflect, TypeRegistry};
+use bevy_editor_iris_common::message::{
+    deserialize_message, priorities, serialize_message, Message,
+};
+use bevy_editor_iris_common::serde::RegistryContext;
+use bevy_editor_iris_derive::message;
+use serde::{Deserialize, Serialize};
+
+/// Renamed, to check that the macros don't assume the crate's name
+mod iris {
+    pub use bevy_editor_iris_common::*;
+}
+
+#[message(priority = priorities::INTERACTIVE)]
+struct Envelope<T: FromReflect> {
+    sequence: u32,
+    payload: T,
+}
+
+#[message(priority = priorities::BULK)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Command {
+    Pause,
+    Step(u32),
+}
+
+#[message(crate = iris, id = "iris.test.renamed")]
+struct Renamed {
+    label: String,
+}
+
+/// Declared without importing anything, to check that the macros don't rely on the caller's imports
+mod bare {
+    #[bevy_editor_iris_derive::message]
+    pub struct Bare {
+        pub label: String,
+    }
+}
+
+fn registry() -> RegistryContext {
+    let registry = TypeRegistry::default();
+    {
+        let mut registry = registry.write();
+        registry.register::<u32>();
+        registry.register::<String>();
+        registry.register::<Envelope<String>>();
+        registry.register::<Command>();
+        registry.register::<Renamed>();
+        registry.register::<bare::Bare>();
+    }
+    RegistryContext::new(registry)
+}
+
+fn round_trip<M: Message>(message: M, registry: &RegistryContext) -> Box<dyn Message> {
+    let mut buf = vec![];
+    serialize_message(Box::new(message), &mut buf, registry).unwrap();
+    deserialize_message(&buf, registry).unwrap()
+}
+
+#[test]
+fn generic_message() {
+    let registry = registry();
+    let envelope = Envelope {
+        sequence: 3,
+        payload: "hello".to_string(),
+    };
+    assert_eq!(envelope.priority(), priorities::INTERACTIVE);
+
+    let envelope = round_trip(envelope, &registry)
+        .downcast::<Envelope<String>>()
+        .unwrap_or_else(|_| panic!("expected an Envelope<String>"));
+    assert_eq!(envelope.sequence, 3);
+    assert_eq!(envelope.payload, "hello");
+}
+
+#[test]
+fn enum_message() {
+    let registry = registry();
+    assert_eq!(Command::Pause.priority(), priorities::BULK);
+
+    let command = round_trip(Command::Step(2), &registry)
+        .downcast::<Command>()
+        .unwrap_or_else(|_| panic!("expected a Command"));
+    assert_eq!(command, Command::Step(2));
+}
+
+#[test]
+fn crate_path() {
+    let registry = registry();
+    assert_eq!(Renamed::message_id(), Some("iris.test.renamed"));
+
+    let renamed = round_trip(
+        Renamed {
+            label: "moved".to_string(),
+        },
+        &registry,
+    );
+    assert_eq!(renamed.downcast::<Renamed>().ok().unwrap().label, "moved");
+}
+
+#[test]
+fn message_without_imports() {
+    let registry = registry();
+
+    let bare = round_trip(
+        bare::Bare {
+            label: "alone".to_string(),
+        },
+        &registry,
+    );
+    assert_eq!(bare.downcast::<bare::Bare>().ok().unwrap().label, "alone");
+}
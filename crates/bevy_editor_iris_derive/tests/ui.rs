@@ -0,0 +1,6 @@
+//! Checks that misused message macros fail with a helpful error.
+
+#[test]
+fn ui() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,9 @@
+use bevy_editor_iris_derive::Message;
+
+#[derive(Message)]
+#[iris(default)]
+struct Ping {
+    sequence: u32,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use bevy_editor_iris_derive::message;
+
+#[message(version = 2, version = 3)]
+struct Ping {
+    sequence: u32,
+}
+
+fn main() {}
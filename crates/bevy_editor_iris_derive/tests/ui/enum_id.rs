@@ -0,0 +1,10 @@
+use bevy_editor_iris_derive::message;
+use serde::{Deserialize, Serialize};
+
+#[message(id = "iris.test.command")]
+#[derive(Clone, Serialize, Deserialize)]
+enum Command {
+    Pause,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use bevy_editor_iris_derive::message;
+
+#[message]
+struct Borrowed<'a> {
+    name: &'a str,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use bevy_editor_iris_derive::message;
+
+#[message]
+union Bits {
+    int: u32,
+    float: f32,
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use bevy_editor_iris_derive::message;
+
+#[message(priorty = 1)]
+struct Ping {
+    sequence: u32,
+}
+
+fn main() {}
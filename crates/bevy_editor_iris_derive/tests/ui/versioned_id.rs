@@ -0,0 +1,8 @@
+use bevy_editor_iris_derive::message;
+
+#[message(id = "iris.test.ping", alias = "iris.test.ping@2")]
+struct Ping {
+    sequence: u32,
+}
+
+fn main() {}
@@ -1,9 +1,13 @@
 use common::deps::bevy::prelude::{App, Plugin};
 use common::deps::bevy::DefaultPlugins;
+use logs::LogsPlugin;
+use schema::SchemaPlugin;
 use server::ServerPlugin;
 use tabs::TabPlugin;
 use ui::UiPlugin;
 
+pub mod logs;
+pub mod schema;
 pub mod server;
 pub mod tabs;
 pub mod ui;
@@ -44,6 +48,8 @@ impl Plugin for EditorPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(DefaultPlugins)
             .add_plugin(ServerPlugin)
+            .add_plugin(LogsPlugin)
+            .add_plugin(SchemaPlugin)
             .add_plugin(UiPlugin)
             .add_plugin(TabPlugin);
     }
@@ -0,0 +1,76 @@
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+use common::asynchronous::MessageBox;
+use common::deps::bevy::prelude::{App, EventWriter, Local, Plugin, Res};
+use common::interface::Transaction;
+use common::logs::{self, LogsProtocol, OpenLogStream, RemoteLogBatch, RemoteLogRecord};
+use common::prelude::{AppProtocolExt, TransactionRegistry};
+
+/// Receives the logs streamed by the game, and sends each record as a [`RemoteLogRecord`] event.
+pub struct LogsPlugin;
+
+impl Plugin for LogsPlugin {
+    fn build(&self, app: &mut App) {
+        let (tx, rx) = mpsc::channel();
+        app.world
+            .get_non_send_resource_mut::<TransactionRegistry>()
+            .expect("LogsPlugin must be added after the ServerPlugin")
+            .register::<OpenLogStream>(tx);
+
+        app.add_protocol::<LogsProtocol>()
+            .add_event::<RemoteLogRecord>()
+            .insert_resource(LogStreams(Mutex::new(rx)))
+            .add_system(receive_logs);
+    }
+}
+
+/// The log streams opened by the game, as they arrive from the transaction registry.
+struct LogStreams(Mutex<Receiver<(Transaction, MessageBox)>>);
+
+fn receive_logs(
+    streams: Res<LogStreams>,
+    mut open: Local<Vec<Transaction>>,
+    mut events: EventWriter<RemoteLogRecord>,
+) {
+    open.extend(
+        streams
+            .0
+            .lock()
+            .unwrap()
+            .try_iter()
+            .map(|(transaction, _)| transaction),
+    );
+
+    // A game opens a new stream when it reconnects, so streams which closed are forgotten once drained
+    for mut transaction in std::mem::take(&mut *open) {
+        let closed = transaction.remote_closed();
+        for msg in transaction.iter() {
+            let batch = match msg.downcast::<RemoteLogBatch>() {
+                Ok(batch) => batch,
+                Err(_) => continue,
+            };
+
+            if batch.dropped > 0 {
+                events.send(RemoteLogRecord {
+                    level: "WARN".to_string(),
+                    target: "bevy_editor_iris".to_string(),
+                    message: format!(
+                        "{} log records were dropped by rate limiting or a full queue",
+                        batch.dropped
+                    ),
+                    fields: vec![],
+                    timestamp: batch
+                        .records
+                        .first()
+                        .map_or_else(logs::timestamp_now, |record| record.timestamp),
+                });
+            }
+            events.send_batch(batch.records.into_iter());
+        }
+
+        if !closed {
+            open.push(transaction);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+use common::asynchronous::MessageBox;
+use common::deps::bevy::prelude::{App, Plugin, Res};
+use common::interface::Transaction;
+use common::prelude::{AppProtocolExt, RegistryContext, TransactionRegistry};
+use common::schema::{RegistrySchema, SchemaProtocol};
+
+/// Adds the [schemas](RegistrySchema) sent by the game to the [`RegistryContext`], so components of types
+/// the editor wasn't compiled with can be deserialized.
+pub struct SchemaPlugin;
+
+impl Plugin for SchemaPlugin {
+    fn build(&self, app: &mut App) {
+        let (tx, rx) = mpsc::channel();
+        app.world
+            .get_non_send_resource_mut::<TransactionRegistry>()
+            .expect("SchemaPlugin must be added after the ServerPlugin")
+            .register::<RegistrySchema>(tx);
+
+        app.add_protocol::<SchemaProtocol>()
+            .insert_resource(ReceivedSchemas(Mutex::new(rx)))
+            .add_system(receive_schemas);
+    }
+}
+
+/// The schemas sent by the game, as they arrive from the transaction registry.
+struct ReceivedSchemas(Mutex<Receiver<(Transaction, MessageBox)>>);
+
+fn receive_schemas(schemas: Res<ReceivedSchemas>, context: Res<RegistryContext>) {
+    for (_, msg) in schemas.0.lock().unwrap().try_iter() {
+        if let Ok(schema) = msg.downcast::<RegistrySchema>() {
+            context.add_schema(schema);
+        }
+    }
+}
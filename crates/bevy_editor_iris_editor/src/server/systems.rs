@@ -1,45 +1,14 @@
 use std::sync::mpsc::{Receiver, Sender};
 
-use common::asynchronous::{self, OpeningReceiver, OpeningSender};
 use common::deps::bevy::prelude::{EventReader, Local, Res, ResMut};
 use common::deps::bevy::reflect::Reflect;
 use common::deps::bevy::utils::HashMap;
-use common::deps::futures_lite::StreamExt;
-use common::deps::quinn::Endpoint;
 use common::deps::tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use common::error::RemoteThreadError;
 use common::message::Message;
 use common::serde::RemoteEntity;
 
-use crate::server;
-
 use super::EntityCache;
 
-pub async fn run_server(
-    tx: OpeningSender,
-    mut rx: OpeningReceiver,
-) -> Result<(), RemoteThreadError> {
-    let (cert, key) = server::generate_self_signed_cert()?;
-    std::fs::write("certificate.der", cert.clone())?;
-    let server_config = server::server_config(cert, key)?;
-
-    let (_endpoint, mut incoming) = Endpoint::server(server_config, common::server_addr())?;
-
-    println!("Accepting connections!");
-
-    while let Some(conn) = incoming.next().await {
-        println!("Waiting for connection...");
-
-        let new = conn.await?;
-
-        println!("Received a connection!");
-
-        asynchronous::process_connection(new, &tx, &mut rx).await?;
-    }
-
-    Ok(())
-}
-
 // TODO: If the editor crashes, all scene diffs are lost and future scene diffs will not restore the whole state.
 // Add mechanism to refresh by sending the entire scene in this event.
 // TODO: If the client crashes, all scene diffs are invalid and future scene diffs will overwrite invalid state.
@@ -0,0 +1,6 @@
+pub use resources::CrashReports;
+pub use tab::CrashesTab;
+
+mod resources;
+mod systems;
+mod tab;
@@ -0,0 +1,76 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use common::asynchronous::MessageBox;
+use common::crash::{ClientPanicked, CrashReportReceived, OpenCrashReporter};
+use common::deps::bevy::log::error;
+use common::deps::bevy::utils::HashMap;
+use common::interface::Transaction;
+use common::session::SessionId;
+
+/// The last crash report of each game which connected to the editor, by [session](SessionId).
+pub struct CrashReports {
+    reports: HashMap<SessionId, ClientPanicked>,
+    reporters: Vec<(SessionId, Transaction)>,
+    incoming: Mutex<Receiver<(Transaction, MessageBox)>>,
+}
+
+impl CrashReports {
+    pub(crate) fn new() -> (Self, Sender<(Transaction, MessageBox)>) {
+        let (tx, rx) = mpsc::channel();
+        let reports = Self {
+            reports: HashMap::default(),
+            reporters: vec![],
+            incoming: Mutex::new(rx),
+        };
+        (reports, tx)
+    }
+
+    /// The last crash report of the game with the given session, if it has crashed.
+    pub fn get(&self, session: SessionId) -> Option<&ClientPanicked> {
+        self.reports.get(&session)
+    }
+
+    /// The last crash report of each game which has crashed.
+    pub fn iter(&self) -> impl Iterator<Item = (&SessionId, &ClientPanicked)> {
+        self.reports.iter()
+    }
+
+    /// Forgets the crash report of the game with the given session.
+    pub fn clear(&mut self, session: SessionId) {
+        self.reports.remove(&session);
+    }
+
+    /// Receives new crash reporters and the reports sent over them, acknowledging each report.
+    pub(crate) fn update(&mut self) {
+        let incoming = self.incoming.get_mut().unwrap();
+        for (transaction, msg) in incoming.try_iter() {
+            if let Ok(open) = msg.downcast::<OpenCrashReporter>() {
+                self.reporters.push((open.session, transaction));
+            }
+        }
+
+        for (session, mut transaction) in std::mem::take(&mut self.reporters) {
+            let closed = transaction.remote_closed();
+            let reports: Vec<_> = transaction
+                .iter()
+                .filter_map(|msg| msg.downcast::<ClientPanicked>().ok())
+                .collect();
+            for report in reports {
+                error!(
+                    location = report.location.as_deref().unwrap_or("unknown"),
+                    frame = report.frame_count,
+                    "game panicked: {}",
+                    report.message
+                );
+                // The game holds off dying until the report is acknowledged
+                _ = transaction.send(CrashReportReceived);
+                self.reports.insert(session, report);
+            }
+
+            if !closed {
+                self.reporters.push((session, transaction));
+            }
+        }
+    }
+}
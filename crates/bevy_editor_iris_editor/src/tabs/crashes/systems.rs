@@ -0,0 +1,7 @@
+use common::deps::bevy::prelude::ResMut;
+
+use super::CrashReports;
+
+pub(crate) fn receive_crash_reports(mut reports: ResMut<CrashReports>) {
+    reports.update();
+}
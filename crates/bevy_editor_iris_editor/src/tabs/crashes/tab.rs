@@ -0,0 +1,60 @@
+use bevy_egui::egui;
+use common::crash::{CrashReportProtocol, OpenCrashReporter};
+use common::deps::bevy::prelude::{App, World};
+use common::prelude::{AppProtocolExt, TransactionRegistry};
+
+use crate::tabs::EditorTab;
+
+use super::{systems, CrashReports};
+
+#[derive(Default)]
+pub struct CrashesTab;
+
+impl EditorTab for CrashesTab {
+    fn name(&self) -> egui::RichText {
+        "Crashes".into()
+    }
+
+    fn display(&mut self, ui: &mut egui::Ui, world: &mut World) {
+        let mut reports = match world.get_resource_mut::<CrashReports>() {
+            Some(reports) => reports,
+            None => return,
+        };
+
+        let mut clear = None;
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (session, report) in reports.iter() {
+                ui.heading(format!("{:?}", session));
+                ui.label(format!(
+                    "panicked at {}",
+                    report.location.as_deref().unwrap_or("an unknown location")
+                ));
+                ui.label(format!("after {} frames", report.frame_count));
+                ui.label(egui::RichText::new(&report.message).strong());
+                ui.collapsing("Backtrace", |ui| {
+                    ui.monospace(&report.backtrace);
+                });
+                if ui.button("Clear").clicked() {
+                    clear = Some(*session);
+                }
+                ui.separator();
+            }
+        });
+
+        if let Some(session) = clear {
+            reports.clear(session);
+        }
+    }
+
+    fn on_register(&mut self, app: &mut App) {
+        let (reports, tx) = CrashReports::new();
+        app.world
+            .get_non_send_resource_mut::<TransactionRegistry>()
+            .expect("TabPlugin must be added after the ServerPlugin")
+            .register::<OpenCrashReporter>(tx);
+
+        app.add_protocol::<CrashReportProtocol>()
+            .insert_resource(reports)
+            .add_system(systems::receive_crash_reports);
+    }
+}
@@ -0,0 +1,6 @@
+pub use resources::DiscoveredGames;
+pub use tab::GamesTab;
+
+mod resources;
+mod systems;
+mod tab;
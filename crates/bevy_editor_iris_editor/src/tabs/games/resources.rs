@@ -0,0 +1,63 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use common::deps::bevy::utils::HashMap;
+use common::discovery::{Announcement, DiscoveryConfig, DiscoveryListener};
+use common::transport::CertificateFingerprint;
+
+/// The games which have announced themselves recently, keyed by the address they announced from.
+pub struct DiscoveredGames {
+    listener: DiscoveryListener,
+    games: HashMap<SocketAddr, (Announcement, Instant)>,
+    timeout: Duration,
+    iris_port: u16,
+    fingerprint: CertificateFingerprint,
+}
+
+impl DiscoveredGames {
+    /// Listens for games, which are invited to connect to the editor listening on `iris_port` with the
+    /// certificate which has `fingerprint`.
+    pub fn bind(
+        config: &DiscoveryConfig,
+        iris_port: u16,
+        fingerprint: CertificateFingerprint,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: DiscoveryListener::bind(config)?,
+            games: HashMap::default(),
+            // Games which miss a few announcements in a row have been invited or have stopped
+            timeout: config.interval * 3,
+            iris_port,
+            fingerprint,
+        })
+    }
+
+    pub fn games(&self) -> impl Iterator<Item = (&SocketAddr, &Announcement)> {
+        self.games
+            .iter()
+            .map(|(game, (announcement, _))| (game, announcement))
+    }
+
+    /// Invites `game` to connect to this editor, and forgets it until it announces itself again.
+    pub fn connect(&mut self, game: SocketAddr) -> io::Result<()> {
+        self.listener
+            .invite(game, self.iris_port, self.fingerprint)?;
+        self.games.remove(&game);
+        Ok(())
+    }
+
+    pub(crate) fn update(&mut self) {
+        let now = Instant::now();
+        for (game, announcement) in self.listener.poll() {
+            // Games which listen can't be invited, only dialed by an editor which dials
+            if announcement.listening.is_none() {
+                self.games.insert(game, (announcement, now));
+            }
+        }
+
+        let timeout = self.timeout;
+        self.games
+            .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < timeout);
+    }
+}
@@ -0,0 +1,7 @@
+use common::deps::bevy::prelude::ResMut;
+
+use super::DiscoveredGames;
+
+pub(crate) fn discover_games(mut games: ResMut<DiscoveredGames>) {
+    games.update();
+}
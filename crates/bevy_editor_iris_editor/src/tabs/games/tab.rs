@@ -0,0 +1,77 @@
+use bevy_egui::egui;
+use common::config::{ConnectionConfig, Role};
+use common::deps::bevy::log::warn;
+use common::deps::bevy::prelude::{App, World};
+
+use crate::tabs::EditorTab;
+
+use super::{systems, DiscoveredGames};
+
+#[derive(Default)]
+pub struct GamesTab;
+
+impl EditorTab for GamesTab {
+    fn name(&self) -> egui::RichText {
+        "Games".into()
+    }
+
+    fn display(&mut self, ui: &mut egui::Ui, world: &mut World) {
+        let mut games = match world.get_resource_mut::<DiscoveredGames>() {
+            Some(games) => games,
+            None => {
+                ui.label("Discovery is disabled. Set `ConnectionConfig::discovery` to discover games on the local network.");
+                return;
+            }
+        };
+
+        let mut connect = None;
+        egui::Grid::new("Discovered Games")
+            .striped(true)
+            .show(ui, |ui| {
+                for (game, announcement) in games.games() {
+                    ui.label(announcement.app_name.as_str());
+                    ui.label(format!("pid {}", announcement.pid));
+                    ui.label(format!("v{}", announcement.version));
+                    ui.label(game.to_string());
+                    if ui.button("Connect").clicked() {
+                        connect = Some(*game);
+                    }
+                    ui.end_row();
+                }
+            });
+
+        if let Some(game) = connect {
+            if let Err(err) = games.connect(game) {
+                warn!(error = %err, %game, "failed to invite game");
+            }
+        }
+    }
+
+    fn on_register(&mut self, app: &mut App) {
+        let mut config = match app.world.get_resource_mut::<ConnectionConfig>() {
+            Some(config) => config,
+            None => return,
+        };
+        let discovery = match &config.discovery {
+            // Games can only be invited to connect to an editor which listens. An editor which dials
+            // connects to the first game it discovers which listens instead
+            Some(discovery) if config.role.unwrap_or(Role::Listen) == Role::Listen => {
+                discovery.clone()
+            }
+            _ => return,
+        };
+        let fingerprint = config.identity_or_generate().fingerprint();
+        let iris_port = config
+            .listen_addr
+            .unwrap_or_else(common::server_addr)
+            .port();
+
+        match DiscoveredGames::bind(&discovery, iris_port, fingerprint) {
+            Ok(games) => {
+                app.insert_resource(games)
+                    .add_system(systems::discover_games);
+            }
+            Err(err) => warn!(error = %err, "failed to listen for games"),
+        }
+    }
+}
@@ -1,16 +1,14 @@
-use crate::deps::common::deps::bevy::reflect as bevy_reflect;
-use bevy_reflect::{FromReflect, Reflect};
-use common::message::{Message, ReflectMessage, ReflectMessageFromReflect};
+use common::message::priorities;
 use common::serde::RemoteEntity;
-use derive::{message, Message};
+use derive::message;
 
-#[message]
+#[message(crate = common, priority = priorities::INTERACTIVE)]
 pub struct ComponentQuery {
     pub entity: RemoteEntity,
 }
 
 /// Tells the editor that the following entity data the client sends will be the data of `entity`.
-#[message]
+#[message(crate = common)]
 pub struct SendingEntityData {
     pub entity: RemoteEntity,
 }
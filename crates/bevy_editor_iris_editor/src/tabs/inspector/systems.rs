@@ -1,131 +1,76 @@
 use common::deps::bevy::prelude::{Local, Res, ResMut};
-use common::deps::tokio::sync::mpsc::error::TryRecvError;
-use common::error::TransactionError;
-use common::interface::{Interface, Transaction};
+use common::interface::Interface;
 use common::serde::RemoteEntity;
-use common::{state_machine, typematch};
+use common::session::{Session, Subscription};
+use common::typematch;
 
 use super::messages::{ComponentQuery, SendingEntityData};
 use super::InspectorCache;
 
-struct SelectedEntity(RemoteEntity);
-
+/// The components of the selected entity are queried on a [`Subscription`], so the query is sent
+/// again on a new transaction when the game reconnects.
 pub(crate) enum StreamState {
-    NoConnection,
-    Connection(Transaction),
-    WaitingForConfirmation(Transaction),
-    Selected(Transaction, RemoteEntity),
-}
-
-pub(crate) fn setup_message_callbacks(interface: ResMut<Interface>) {
-    interface.register_callback::<SendingEntityData>(|msg, commands| commands.add(|world| {
-        let cache = world.resource_mut::<InspectorCache>();
-        if msg.entity == cache.selected() {
-            world.resource_mut::<SelectedEntity>().0 = msg.entity;
-        }
-    }));
+    NoSelection,
+    WaitingForConfirmation(Subscription, RemoteEntity),
+    Selected(Subscription, RemoteEntity),
 }
 
 pub(crate) fn collect_selected_components(
-    mut cache: ResMut<InspectorCache>,
-    interface: Res<Interface>,
+    cache: Res<InspectorCache>,
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
     mut local_state: Local<Option<StreamState>>,
 ) {
-    let state = local_state.take().unwrap();
+    let mut state = local_state.take().unwrap_or(StreamState::NoSelection);
+
+    // A new selection is queried on a subscription of its own
+    let entity = match &state {
+        StreamState::NoSelection => None,
+        StreamState::WaitingForConfirmation(_, entity) | StreamState::Selected(_, entity) => {
+            Some(*entity)
+        }
+    };
+    if entity != *cache.selected() {
+        state = match *cache.selected() {
+            Some(entity) => StreamState::WaitingForConfirmation(
+                Subscription::new(move |_| ComponentQuery { entity }),
+                entity,
+            ),
+            None => StreamState::NoSelection,
+        };
+    }
 
-    // let state = state_machine!(
-    //     extern state = StreamState,
-    //     run state => {
-    //         NoConnection => match interface.open_transaction() {
-    //             Ok(trans) => Connection(trans),
-    //             Err(_) => break NoConnection,
-    //         },
-    //         Connection(trans) => match cache.selected() {
-    //             &Some(entity) => match trans.send(ComponentQuery { entity }) {
-    //                 Ok(()) => WaitingForConfirmation(trans),
-    //                 Err(TransactionError::ChannelClosed) => break NoConnection,
-    //             },
-    //             None => break Connection(trans),
-    //         },
-    //         WaitingForConfirmation(mut trans) => {
-    //             if trans.sender_is_closed() {
-    //                 NoConnection
-    //             } else {
-    //                 let mut selection = None;
-    //                 for msg in trans.iter() {
-    //                     typematch!(msg.into_any(), {
-    //                         msg: SendingEntityData => {
-    //                             if &Some(msg.entity) == cache.selected() {
-    //                                 selection = Some(msg.entity);
-    //                                 break;
-    //                             }
-    //                         },
-    //                         default => (),
-    //                     });
-    //                 }
-    //                 match selection {
-    //                     Some(entity) => break Selected(trans, entity),
-    //                     None => break WaitingForConfirmation(trans),
-    //                 }
-    //             }
-    //         },
-    //         Selected(trans, selected) => {
-    //             for msg in trans.iter() {
-    //                 typematch!(msg.into_any(), {
-    //                     default => ()
-    //                 });
-    //             }
-    //         },
-    //     }
-    // );
+    let (subscription, entity, mut confirmed) = match state {
+        StreamState::NoSelection => {
+            *local_state = Some(StreamState::NoSelection);
+            return;
+        }
+        StreamState::WaitingForConfirmation(subscription, entity) => (subscription, entity, false),
+        StreamState::Selected(subscription, entity) => (subscription, entity, true),
+    };
 
-    *local_state = Some(state);
+    match subscription.update(&mut interface, &session) {
+        // The query was sent again on a new transaction, which the game confirms again
+        Ok(Some(_)) => confirmed = false,
+        Ok(None) => (),
+        Err(_) => {
+            *local_state = Some(StreamState::WaitingForConfirmation(subscription, entity));
+            return;
+        }
+    }
+
+    if let Some(mut transaction) = subscription.transaction() {
+        for msg in transaction.iter() {
+            typematch!(msg, {
+                data: SendingEntityData if data.entity == entity => confirmed = true,
+                default => (),
+            });
+        }
+    }
 
-    // loop {
-    //     let (mut trans, state) = stream.take().unwrap();
-    //     match state {
-    //         StreamState::Nothing => match cache.selected() {
-    //             &Some(entity) => match trans.send(ComponentQuery { entity }) {
-    //                 Ok(()) => *stream = Some((trans, StreamState::Waiting)),
-    //                 Err(TransactionError::ChannelClosed) => {
-    //                     *stream = None;
-    //                     return;
-    //                 }
-    //             },
-    //             None => return,
-    //         },
-    //         StreamState::Waiting => 'waiting: loop {
-    //             match trans.try_recv() {
-    //                 Ok(msg) => {
-    //                     typematch!(msg.into_any(), {
-    //                         msg: SendingEntityData => {
-    //                             if &Some(msg.entity) == cache.selected() {
-    //                                 *stream = Some((trans, StreamState::Selected(msg.entity)));
-    //                                 break 'waiting;
-    //                             }
-    //                         },
-    //                         default => continue,
-    //                     });
-    //                 }
-    //                 Err(TryRecvError::Empty) => return,
-    //                 Err(TryRecvError::Disconnected) => {
-    //                     *stream = None;
-    //                     return;
-    //                 }
-    //             }
-    //         },
-    //         StreamState::Selected(selected) => match trans.try_recv() {
-    //             Ok(msg) => {
-    //                 typematch!(msg.into_any(), {
-    //                     default => continue,
-    //                 })
-    //             }
-    //             Err(TryRecvError::Empty) => return,
-    //             Err(TryRecvError::Disconnected) => {
-    //                 *stream = None;
-    //                 return;
-    //             }
-    //         },
-    //     }
-    // }
+    *local_state = Some(if confirmed {
+        StreamState::Selected(subscription, entity)
+    } else {
+        StreamState::WaitingForConfirmation(subscription, entity)
+    });
 }
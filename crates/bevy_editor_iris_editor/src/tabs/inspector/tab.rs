@@ -1,4 +1,3 @@
-use std::any::{self, TypeId};
 use std::borrow::Cow;
 
 use bevy_egui::egui::{self, Ui};
@@ -7,6 +6,8 @@ use common::deps::bevy::core::Name;
 use common::deps::bevy::math::{Mat3, Mat4, Quat, Vec2, Vec3, Vec3A, Vec4};
 use common::deps::bevy::prelude::{Color, World};
 use common::deps::bevy::reflect::{Reflect, ReflectRef};
+use common::schema::UnknownValue;
+use common::typematch;
 
 use crate::tabs::EditorTab;
 
@@ -117,87 +118,29 @@ fn display_component(ui: &mut Ui, component: &dyn Reflect) {
             }
         }
         // TODO: ReflectDebug should make this not awful
-        // TODO: typematch_ref to make this less awful?
-        ReflectRef::Value(comp) => match comp.type_id() {
-            id if id == TypeId::of::<bool>() => {
-                ui.label(comp.downcast_ref::<bool>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<u8>() => {
-                ui.label(comp.downcast_ref::<u8>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<u16>() => {
-                ui.label(comp.downcast_ref::<u16>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<u32>() => {
-                ui.label(comp.downcast_ref::<u32>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<u64>() => {
-                ui.label(comp.downcast_ref::<u64>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<usize>() => {
-                ui.label(comp.downcast_ref::<usize>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<i8>() => {
-                ui.label(comp.downcast_ref::<i8>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<i16>() => {
-                ui.label(comp.downcast_ref::<i16>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<i32>() => {
-                ui.label(comp.downcast_ref::<i32>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<i64>() => {
-                ui.label(comp.downcast_ref::<i64>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<isize>() => {
-                ui.label(comp.downcast_ref::<isize>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<f32>() => {
-                ui.label(comp.downcast_ref::<f32>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<f64>() => {
-                ui.label(comp.downcast_ref::<f64>().unwrap().to_string());
-            }
-            id if id == TypeId::of::<String>() => {
-                ui.label(comp.downcast_ref::<String>().unwrap());
-            }
-            id if id == TypeId::of::<Cow<'static, str>>() => {
-                ui.label(match comp.downcast_ref::<Cow<'static, str>>().unwrap() {
-                    Cow::Borrowed(str) => *str,
-                    Cow::Owned(str) => str,
-                });
-            }
-            id if id == TypeId::of::<HandleId>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<HandleId>().unwrap()));
-            }
-            // TODO: This should be a ReflectEnum rather than ReflectValue in 0.8,
+        ReflectRef::Value(comp) => typematch!(ref comp, {
+            value: bool | u8 | u16 | u32 | u64 | usize | i8 | i16 | i32 | i64 | isize | f32 | f64 => {
+                ui.label(value.to_string());
+            },
+            value: String => {
+                ui.label(value);
+            },
+            value: Cow<'static, str> => {
+                ui.label(&**value);
+            },
+            // TODO: Color should be a ReflectEnum rather than ReflectValue in 0.8,
             // so it won't belong here anymore
-            id if id == TypeId::of::<Color>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Color>().unwrap()));
-            }
-            // TODO: These values will become structs in 0.8 (except for quat, which will be unrecognized because it's readonly (somehow))
-            id if id == TypeId::of::<Vec2>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Vec2>().unwrap()));
-            }
-            id if id == TypeId::of::<Vec3>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Vec3>().unwrap()));
-            }
-            id if id == TypeId::of::<Vec3A>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Vec3A>().unwrap()));
-            }
-            id if id == TypeId::of::<Vec4>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Vec4>().unwrap()));
-            }
-            id if id == TypeId::of::<Quat>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Quat>().unwrap()));
-            }
-            id if id == TypeId::of::<Mat3>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Mat3>().unwrap()));
-            }
-            id if id == TypeId::of::<Mat4>() => {
-                ui.label(format!("{:?}", comp.downcast_ref::<Mat4>().unwrap()));
-            }
-            _ => _ = ui.label(format!("Unrecognized value of type {}", comp.type_name())),
-        },
+            // TODO: The math values will become structs in 0.8 (except for quat, which will be unrecognized because it's readonly (somehow))
+            value: HandleId | Color | Vec2 | Vec3 | Vec3A | Vec4 | Quat | Mat3 | Mat4 => {
+                ui.label(format!("{:?}", value));
+            },
+            // Types the editor wasn't compiled with, known from the game's schema
+            value: UnknownValue => {
+                ui.label(value.to_string());
+            },
+            default => {
+                ui.label(format!("Unrecognized value of type {}", comp.type_name()));
+            },
+        }),
     }
 }
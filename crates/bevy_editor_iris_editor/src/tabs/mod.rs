@@ -6,9 +6,13 @@ use common::deps::bevy::prelude::{default, App, FromWorld, Plugin, World};
 use common::deps::bevy::reflect::{self as bevy_reflect, Reflect};
 use common::deps::bevy::utils::HashMap;
 
+mod crashes;
+mod games;
 mod inspector;
 mod resources;
 
+pub use crashes::{CrashReports, CrashesTab};
+pub use games::{DiscoveredGames, GamesTab};
 pub use inspector::InspectorTab;
 pub use resources::SelectedTab;
 
@@ -22,6 +26,14 @@ impl Plugin for TabPlugin {
         inspector.on_register(app);
         registry.push(inspector);
 
+        let mut games = GamesTab;
+        games.on_register(app);
+        registry.push(games);
+
+        let mut crashes = CrashesTab;
+        crashes.on_register(app);
+        registry.push(crashes);
+
         app.insert_resource(registry)
             .insert_resource(SelectedTab(TypeId::of::<InspectorTab>()));
     }
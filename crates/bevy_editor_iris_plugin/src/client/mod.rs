@@ -1,13 +1,11 @@
-use std::fs;
 use std::time::Duration;
 
+use common::config::Side;
 use common::deps::bevy::ecs as bevy_ecs;
 use common::deps::bevy::prelude::{
     App, CoreStage, ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem, Plugin, StartupStage,
     SystemLabel, SystemSet,
 };
-use common::deps::quinn::ClientConfig;
-use common::deps::rustls::{Certificate, RootCertStore};
 use common::systems as common_systems;
 use common::CommonPlugin;
 
@@ -17,11 +15,12 @@ pub use self::systems::SceneDiffDenylist;
 mod interface;
 mod systems;
 
+/// Connects the game to the editor. The game dials the editor unless [`ConnectionConfig::role`](common::config::ConnectionConfig::role) says otherwise.
 pub struct ClientPlugin;
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(CommonPlugin(systems::run_client))
+        app.add_plugin(CommonPlugin(Side::Game))
             // .add_system_set_to_stage(
             //     CoreStage::PostUpdate,
             //     SystemSet::new()
@@ -39,12 +38,3 @@ impl Plugin for ClientPlugin {
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, SystemLabel)]
 pub struct BuildDenylist;
-
-fn client_config() -> ClientConfig {
-    let cert = Certificate(fs::read("certificate.der").unwrap());
-
-    let mut store = RootCertStore::empty();
-    store.add(&cert).unwrap();
-
-    ClientConfig::with_root_certificates(store)
-}
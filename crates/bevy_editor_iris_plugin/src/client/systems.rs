@@ -1,7 +1,6 @@
 use std::any::TypeId;
 use std::sync::mpsc::{Receiver, Sender};
 
-use common::asynchronous::{self, OpeningReceiver, OpeningSender};
 use common::deps::bevy::ecs::archetype::ArchetypeId;
 use common::deps::bevy::ecs::component::{ComponentId, ComponentTicks, StorageType};
 use common::deps::bevy::pbr::CubemapVisibleEntities;
@@ -10,33 +9,10 @@ use common::deps::bevy::reflect::TypeRegistry;
 use common::deps::bevy::render::primitives::{CubemapFrusta, Frustum};
 use common::deps::bevy::render::view::VisibleEntities;
 use common::deps::bevy::utils::{HashMap, HashSet};
-use common::deps::quinn::Endpoint;
-use common::error::RemoteThreadError;
 use common::interface::Interface;
 // use common::message::messages::SceneDiff;
 use common::serde::ReflectObject;
 
-use super::client_config;
-
-pub async fn run_client(
-    tx: OpeningSender,
-    mut rx: OpeningReceiver,
-) -> Result<(), RemoteThreadError> {
-    let endpoint = Endpoint::client(common::client_addr())?;
-
-    println!("Attempting connection!");
-
-    let new = endpoint
-        .connect_with(client_config(), common::server_addr(), "localhost")?
-        .await?;
-
-    println!("Acquired connection to editor!");
-
-    asynchronous::process_connection(new, &tx, &mut rx).await?;
-
-    Ok(())
-}
-
 #[derive(Default)]
 struct SceneDiffState {
     map: HashMap<Entity, Vec<ReflectObject>>,
@@ -0,0 +1,205 @@
+use std::panic::{self, PanicHookInfo};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use backtrace::Backtrace;
+use common::crash::{ClientPanicked, CrashReportProtocol, CrashReportReceived, OpenCrashReporter};
+use common::deps::bevy::prelude::{App, CoreStage, Plugin, Res, ResMut};
+use common::deps::tokio::runtime::Handle;
+use common::interface::Interface;
+use common::protocol::{AppProtocolExt, Protocols};
+use common::session::{Session, Subscription};
+
+/// Configures how a panic of the game is reported to the editor.
+#[derive(Clone, Debug)]
+pub struct CrashReportConfig {
+    /// How long a panicking game waits for the editor to acknowledge its report before it is allowed to die.
+    pub ack_timeout: Duration,
+}
+
+impl Default for CrashReportConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Installs a panic hook which reports the panic to the editor as a [`ClientPanicked`] message,
+/// and blocks until the editor has received it. The previous panic hook still runs first.
+///
+/// Panics on the threads of a tokio runtime aren't reported, as the report can't be sent
+/// synchronously from within a runtime. When a report isn't delivered, for that or any other reason,
+/// a line saying so is printed to stderr instead.
+pub struct CrashReportPlugin;
+
+impl Plugin for CrashReportPlugin {
+    fn build(&self, app: &mut App) {
+        let config = app
+            .world
+            .get_resource_or_insert_with(CrashReportConfig::default)
+            .clone();
+
+        let reporter = CrashReporter {
+            subscription: Subscription::new(|session| OpenCrashReporter {
+                session: session.id(),
+            }),
+            frames: Arc::new(AtomicU64::new(0)),
+        };
+
+        let hook_reporter = reporter.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            hook_reporter.report(info, config.ack_timeout);
+        }));
+
+        app.add_protocol::<CrashReportProtocol>()
+            .insert_resource(reporter)
+            .add_system_to_stage(CoreStage::First, count_frames)
+            .add_system(open_crash_reporter);
+    }
+}
+
+/// Shared between the panic hook and the systems which keep the crash reporter open.
+#[derive(Clone)]
+struct CrashReporter {
+    subscription: Subscription,
+    frames: Arc<AtomicU64>,
+}
+
+impl CrashReporter {
+    fn report(&self, info: &PanicHookInfo, ack_timeout: Duration) {
+        let report = ClientPanicked {
+            message: panic_message(info),
+            location: info.location().map(ToString::to_string),
+            backtrace: format!("{:?}", Backtrace::new()),
+            frame_count: self.frames.load(Ordering::Relaxed),
+        };
+
+        if let Err(reason) = self.send_report(report.clone(), ack_timeout) {
+            // The editor never hears of this panic, so at least leave a line saying so
+            eprintln!(
+                "bevy_editor_iris: the crash report wasn't delivered to the editor, as {}: '{}' at {}",
+                reason,
+                report.message,
+                report.location.as_deref().unwrap_or("an unknown location"),
+            );
+        }
+    }
+
+    /// Sends `report` and blocks until the editor acknowledges it, returning why it wasn't acknowledged otherwise.
+    fn send_report(
+        &self,
+        report: ClientPanicked,
+        ack_timeout: Duration,
+    ) -> Result<(), &'static str> {
+        // Blocking on the channels of the remote thread from within a runtime would panic again
+        if Handle::try_current().is_ok() {
+            return Err("the game panicked on a thread of a tokio runtime");
+        }
+
+        // The panic may have happened while the subscription was locked
+        let mut transaction = self
+            .subscription
+            .try_transaction()
+            .ok_or("the crash reporter isn't open or is in use")?;
+
+        // The deadline ends the wait if the editor never answers
+        transaction.set_deadline(Instant::now() + ack_timeout);
+        transaction
+            .send(report)
+            .map_err(|_| "the report couldn't be queued")?;
+        while let Ok(msg) = transaction.recv() {
+            if msg.is::<CrashReportReceived>() {
+                return Ok(());
+            }
+        }
+        Err("the editor didn't acknowledge it in time")
+    }
+}
+
+fn panic_message(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_string(),
+        },
+    }
+}
+
+fn count_frames(reporter: Res<CrashReporter>) {
+    reporter.frames.fetch_add(1, Ordering::Relaxed);
+}
+
+fn open_crash_reporter(
+    reporter: Res<CrashReporter>,
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
+    protocols: Res<Protocols>,
+) {
+    if !protocols.is_confirmed::<CrashReportProtocol>() {
+        return;
+    }
+
+    _ = reporter.subscription.update(&mut interface, &session);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use common::deps::tokio::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn report_waits_for_the_ack() {
+        let (open_tx, mut remote_rx) = mpsc::unbounded_channel();
+        let (_, open_rx) = mpsc::unbounded_channel();
+        let mut interface = Interface::new(open_tx, open_rx);
+        let session = Session::default();
+
+        let reporter = CrashReporter {
+            subscription: Subscription::new(|session| OpenCrashReporter {
+                session: session.id(),
+            }),
+            frames: Arc::new(AtomicU64::new(42)),
+        };
+        reporter
+            .subscription
+            .update(&mut interface, &session)
+            .unwrap();
+
+        // The editor, which acknowledges the report a while after it arrives
+        let mut editor = remote_rx.try_recv().unwrap();
+        let delay = Duration::from_millis(100);
+        let editor = thread::spawn(move || {
+            assert!(editor.recv().unwrap().is::<OpenCrashReporter>());
+            let report = editor.recv().unwrap().downcast::<ClientPanicked>().unwrap();
+            thread::sleep(delay);
+            editor.send(CrashReportReceived).unwrap();
+            report
+        });
+
+        let started = Instant::now();
+        let report = ClientPanicked {
+            message: "oh no".to_string(),
+            location: None,
+            backtrace: String::new(),
+            frame_count: 42,
+        };
+        assert_eq!(
+            reporter.send_report(report, Duration::from_secs(10)),
+            Ok(())
+        );
+        assert!(started.elapsed() >= delay);
+
+        let report = editor.join().unwrap();
+        assert_eq!(report.message, "oh no");
+        assert_eq!(report.frame_count, 42);
+    }
+}
@@ -1,18 +1,40 @@
 use client::ClientPlugin;
 use common::deps::bevy::prelude::{App, Plugin};
+use crash::CrashReportPlugin;
+use logs::RemoteLogPlugin;
+use schema::SchemaPlugin;
 use tabs::TabPlugin;
 
 pub mod client;
+pub mod crash;
+pub mod logs;
+pub mod schema;
 pub mod tabs;
 
 pub mod deps {
     pub use common;
 }
 
+/// Connects the game to the editor.
+///
+/// The logs of the game are streamed to the editor by a [`RemoteLogPlugin`], which replaces bevy's `LogPlugin`.
+/// Disable the `LogPlugin` to stream them, otherwise they are only logged locally.
+///
+/// ## Example:
+/// ```ignore
+/// App::new()
+///     .add_plugins_with(DefaultPlugins, |group| group.disable::<LogPlugin>())
+///     .add_plugin(IrisClientPlugin)
+///     .run();
+/// ```
 pub struct IrisClientPlugin;
 
 impl Plugin for IrisClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ClientPlugin).add_plugin(TabPlugin);
+        app.add_plugin(ClientPlugin)
+            .add_plugin(RemoteLogPlugin)
+            .add_plugin(CrashReportPlugin)
+            .add_plugin(SchemaPlugin)
+            .add_plugin(TabPlugin);
     }
 }
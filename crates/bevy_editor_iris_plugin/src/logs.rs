@@ -0,0 +1,288 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::deps::bevy::log::{warn, Level, LogSettings};
+use common::deps::bevy::prelude::{App, Local, Plugin, Res, ResMut, SystemSet};
+use common::deps::bevy::utils::tracing::field::{Field, Visit};
+use common::deps::bevy::utils::tracing::{self, Event, Subscriber};
+use common::interface::Interface;
+use common::logs::{self, LogField, LogsProtocol, OpenLogStream, RemoteLogBatch, RemoteLogRecord};
+use common::protocol::{AppProtocolExt, Protocols};
+use common::queue::{DropPolicy, QueueConfig};
+use common::session::{Session, Subscription};
+use common::systems::run_on_timer;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+/// Configures how the logs of the game are streamed to the editor.
+#[derive(Clone, Debug)]
+pub struct RemoteLogConfig {
+    /// The least severe level which is streamed. Records are also filtered by [`LogSettings`].
+    pub min_level: Level,
+    /// The interval at which batches of records are sent.
+    pub flush_interval: Duration,
+    /// The maximum number of records in a batch.
+    pub max_batch: usize,
+    /// The maximum number of records streamed per second. Records beyond this are dropped and counted.
+    pub max_per_second: u32,
+    /// The maximum number of records queued until they can be sent, such as while the editor isn't connected.
+    /// Records beyond this are dropped and counted.
+    pub max_queued: usize,
+}
+
+impl Default for RemoteLogConfig {
+    fn default() -> Self {
+        Self {
+            min_level: Level::INFO,
+            flush_interval: Duration::from_millis(250),
+            max_batch: 256,
+            max_per_second: 1000,
+            max_queued: 4096,
+        }
+    }
+}
+
+/// Replaces bevy's `LogPlugin`, installing the same subscriber along with a [`RemoteLogLayer`] which
+/// streams the logs of the game to the editor. Added by [`IrisClientPlugin`](crate::IrisClientPlugin).
+///
+/// Only one global subscriber can be installed, so bevy's `LogPlugin` must be disabled. If another subscriber
+/// is already installed, a warning is logged through it and the logs aren't streamed.
+pub struct RemoteLogPlugin;
+
+impl Plugin for RemoteLogPlugin {
+    fn build(&self, app: &mut App) {
+        let config = app
+            .world
+            .get_resource_or_insert_with(RemoteLogConfig::default)
+            .clone();
+        let settings = app.world.get_resource_or_insert_with(LogSettings::default);
+        let default_filter = format!("{},{}", settings.level, settings.filter);
+
+        let filter = EnvFilter::try_from_default_env()
+            .or_else(|_| EnvFilter::try_new(&default_filter))
+            .unwrap();
+
+        let (layer, receiver) = RemoteLogLayer::new(&config);
+        let subscriber = Registry::default()
+            .with(filter)
+            .with(tracing_subscriber::fmt::Layer::default())
+            .with(layer);
+
+        if tracing::subscriber::set_global_default(subscriber).is_err() {
+            warn!("a global tracing subscriber is already set, so logs won't be streamed to the editor. Disable bevy's LogPlugin to stream them");
+            return;
+        }
+
+        app.add_protocol::<LogsProtocol>()
+            .insert_resource(receiver)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_on_timer(config.flush_interval))
+                    .with_system(send_logs),
+            );
+    }
+}
+
+/// A tracing layer which queues log records to be sent to the editor.
+pub struct RemoteLogLayer {
+    min_level: Level,
+    sender: Mutex<SyncSender<RemoteLogRecord>>,
+    limiter: Mutex<RateLimiter>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Receives the records queued by the [`RemoteLogLayer`].
+pub struct RemoteLogReceiver {
+    receiver: Mutex<Receiver<RemoteLogRecord>>,
+    dropped: Arc<AtomicU64>,
+}
+
+struct RateLimiter {
+    max_per_second: u32,
+    tokens: u32,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.refilled) >= Duration::from_secs(1) {
+            self.tokens = self.max_per_second;
+            self.refilled = now;
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
+
+impl RemoteLogLayer {
+    /// Creates a layer, and the receiver of the records it queues.
+    pub fn new(config: &RemoteLogConfig) -> (Self, RemoteLogReceiver) {
+        let (sender, receiver) = mpsc::sync_channel(config.max_queued);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let layer = Self {
+            min_level: config.min_level,
+            sender: Mutex::new(sender),
+            limiter: Mutex::new(RateLimiter {
+                max_per_second: config.max_per_second,
+                tokens: config.max_per_second,
+                refilled: Instant::now(),
+            }),
+            dropped: dropped.clone(),
+        };
+
+        let receiver = RemoteLogReceiver {
+            receiver: Mutex::new(receiver),
+            dropped,
+        };
+        (layer, receiver)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RemoteLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // More verbose levels compare greater
+        if *metadata.level() > self.min_level {
+            return;
+        }
+        // Iris logs its own networking, and streaming those records would feed back into more of them
+        if metadata.target().starts_with("bevy_editor_iris") {
+            return;
+        }
+
+        if !self.limiter.lock().unwrap().try_take() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+
+        let record = RemoteLogRecord {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+            timestamp: logs::timestamp_now(),
+        };
+        // The queue is only drained while the editor is connected, so it must not grow without bound
+        if let Err(TrySendError::Full(_)) = self.sender.lock().unwrap().try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct RecordVisitor {
+    message: String,
+    fields: Vec<LogField>,
+}
+
+impl Visit for RecordVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push(LogField {
+                name: field.name().to_string(),
+                value: format!("{:?}", value),
+            });
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields.push(LogField {
+                name: field.name().to_string(),
+                value: value.to_string(),
+            });
+        }
+    }
+}
+
+/// Sends the queued log records to the editor in batches.
+pub(crate) fn send_logs(
+    receiver: Res<RemoteLogReceiver>,
+    config: Res<RemoteLogConfig>,
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
+    protocols: Res<Protocols>,
+    mut subscription: Local<Option<Subscription>>,
+) {
+    if !protocols.is_confirmed::<LogsProtocol>() {
+        return;
+    }
+
+    let subscription = subscription.get_or_insert_with(|| {
+        Subscription::new(|session| OpenLogStream {
+            session: session.id(),
+        })
+        // Logs must never hold up the game, so batches which don't fit are counted as dropped instead
+        .send_queue(QueueConfig {
+            capacity: 16,
+            policy: DropPolicy::Block,
+        })
+    });
+
+    if subscription.update(&mut interface, &session).is_err() {
+        return;
+    }
+    let transaction = match subscription.transaction() {
+        Some(transaction) => transaction,
+        None => return,
+    };
+
+    let mut records: Vec<_> = receiver.receiver.lock().unwrap().try_iter().collect();
+    let mut dropped = receiver.dropped.swap(0, Ordering::Relaxed);
+
+    while !records.is_empty() || dropped > 0 {
+        let rest = records.split_off(records.len().min(config.max_batch));
+        let batch = RemoteLogBatch {
+            records: std::mem::replace(&mut records, rest),
+            dropped: std::mem::take(&mut dropped),
+        };
+        let unsent = (batch.records.len() + records.len()) as u64 + batch.dropped;
+        if transaction.send(batch).is_err() {
+            // The editor learns of them with the next batch that is sent
+            receiver.dropped.fetch_add(unsent, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::deps::bevy::log::info;
+
+    use super::*;
+
+    #[test]
+    fn full_queue_counts_dropped_records() {
+        let config = RemoteLogConfig {
+            max_queued: 2,
+            ..Default::default()
+        };
+        let (layer, receiver) = RemoteLogLayer::new(&config);
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for i in 0..5 {
+                info!(target: "game", "record {}", i);
+            }
+        });
+
+        let records: Vec<_> = receiver.receiver.lock().unwrap().try_iter().collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].message, "record 0");
+        assert_eq!(receiver.dropped.load(Ordering::Relaxed), 3);
+    }
+}
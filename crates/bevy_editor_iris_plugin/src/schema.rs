@@ -0,0 +1,147 @@
+use std::mem;
+
+use common::deps::bevy::ecs::archetype::ArchetypeId;
+use common::deps::bevy::prelude::{
+    App, CoreStage, ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem, Local, Plugin,
+    ReflectComponent, Res, ResMut, StartupStage, World,
+};
+use common::deps::bevy::reflect::{ReflectDeserialize, TypeRegistry};
+use common::interface::Interface;
+use common::protocol::{AppProtocolExt, Protocols};
+use common::schema::{RegistrySchema, SchemaBuilder, SchemaProtocol};
+use common::session::{Session, Subscription};
+
+use crate::client::{BuildDenylist, SceneDiffDenylist};
+
+/// Sends a [`RegistrySchema`] of the game's reflected types to the editor, so it can show and edit
+/// types it wasn't compiled with.
+///
+/// The schema is built once every startup system has run, from the components of the entities which exist
+/// by then, and rebuilt whenever entities with other combinations of components appear. Replace the
+/// [`RegistrySchema`] resource to send a new one.
+pub struct SchemaPlugin;
+
+impl Plugin for SchemaPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_protocol::<SchemaProtocol>()
+            .add_startup_system_to_stage(
+                StartupStage::PostStartup,
+                build_registry_schema
+                    .exclusive_system()
+                    .after(BuildDenylist),
+            )
+            .add_system_to_stage(CoreStage::Last, update_registry_schema.exclusive_system())
+            .add_system(send_registry_schema);
+    }
+}
+
+/// The types in the [`RegistrySchema`], and the archetypes their instances were taken from.
+#[derive(Default)]
+pub(crate) struct SchemaSamples {
+    builder: SchemaBuilder,
+    /// The archetypes before this index were sampled, or are in `unsampled`
+    seen: usize,
+    /// Archetypes which had no entities when they were seen
+    unsampled: Vec<ArchetypeId>,
+}
+
+/// Builds the [`RegistrySchema`] resource from the registered value types, and from an instance of each
+/// registered component, since bevy can't describe the fields of a type without one.
+pub(crate) fn build_registry_schema(world: &mut World) {
+    let mut samples = SchemaSamples::default();
+    for registration in world.resource::<TypeRegistry>().read().iter() {
+        if registration.data::<ReflectDeserialize>().is_some() {
+            samples.builder.add_value_type(registration.name());
+        }
+    }
+
+    sample_archetypes(world, &mut samples);
+    world.insert_resource(samples.builder.schema());
+    world.insert_resource(samples);
+}
+
+/// Samples the archetypes created since the [`RegistrySchema`] was built, replacing it if their components
+/// have types it didn't describe yet.
+pub(crate) fn update_registry_schema(world: &mut World) {
+    let mut samples = match world.remove_resource::<SchemaSamples>() {
+        Some(samples) => samples,
+        None => return,
+    };
+
+    if world.archetypes().len() > samples.seen || !samples.unsampled.is_empty() {
+        let known = samples.builder.len();
+        sample_archetypes(world, &mut samples);
+        if samples.builder.len() > known {
+            world.insert_resource(samples.builder.schema());
+        }
+    }
+
+    world.insert_resource(samples);
+}
+
+/// Adds the components of an entity of each archetype which wasn't sampled yet.
+fn sample_archetypes(world: &World, samples: &mut SchemaSamples) {
+    let registry = world.resource::<TypeRegistry>().read();
+    let denylist = world.get_resource::<SceneDiffDenylist>();
+    let archetypes = world.archetypes();
+
+    let mut pending = mem::take(&mut samples.unsampled);
+    pending.extend((samples.seen..archetypes.len()).map(ArchetypeId::new));
+    samples.seen = archetypes.len();
+
+    for id in pending {
+        if matches!(id, ArchetypeId::EMPTY | ArchetypeId::RESOURCE) {
+            continue;
+        }
+        let archetype = &archetypes[id];
+        let entity = match archetype.entities().first() {
+            Some(entity) => *entity,
+            None => {
+                samples.unsampled.push(id);
+                continue;
+            }
+        };
+
+        for component_id in archetype.components() {
+            if denylist.is_some_and(|denylist| denylist.contains(&component_id)) {
+                continue;
+            }
+
+            let reflect_component = world
+                .components()
+                .get_info(component_id)
+                .and_then(|info| info.type_id())
+                .and_then(|type_id| registry.get(type_id))
+                .and_then(|registration| registration.data::<ReflectComponent>());
+            if let Some(component) =
+                reflect_component.and_then(|reflect| reflect.reflect_component(world, entity))
+            {
+                samples.builder.add_value(component);
+            }
+        }
+    }
+}
+
+/// Sends the [`RegistrySchema`] to the editor, again whenever the game reconnects or the schema is replaced.
+pub(crate) fn send_registry_schema(
+    schema: Option<Res<RegistrySchema>>,
+    mut interface: ResMut<Interface>,
+    session: Res<Session>,
+    protocols: Res<Protocols>,
+    mut subscription: Local<Option<Subscription>>,
+) {
+    let schema = match schema {
+        Some(schema) if protocols.is_confirmed::<SchemaProtocol>() => schema,
+        _ => return,
+    };
+
+    if schema.is_changed() || subscription.is_none() {
+        let schema = schema.clone();
+        *subscription = Some(Subscription::new(move |_| schema.clone()));
+    }
+
+    _ = subscription
+        .as_mut()
+        .unwrap()
+        .update(&mut interface, &session);
+}
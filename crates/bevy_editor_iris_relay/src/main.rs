@@ -0,0 +1,69 @@
+//! `iris-relay [address]`
+//!
+//! A relay which both the editor and the game dial, for when neither side can accept incoming connections.
+//! Connections are paired by the room code they join with (see [`common::relay`]), and every stream opened
+//! by one side of a pair is forwarded to the other.
+//!
+//! Logs through bevy's `LogPlugin`, so the output can be filtered with `RUST_LOG`.
+//!
+//! To try it on one machine, run the relay, then an editor and a game which both set [`ConnectionConfig::role`]
+//! to [`Role::Dial`], [`ConnectionConfig::remote_addr`] to the address of the relay, and [`ConnectionConfig::room`]
+//! to the same code. The relay logs the fingerprint of its certificate when it starts, which both sides must set as
+//! their [`ConnectionConfig::remote_fingerprint`] to trust it.
+//!
+//! [`ConnectionConfig::role`]: common::config::ConnectionConfig::role
+//! [`Role::Dial`]: common::config::Role::Dial
+//! [`ConnectionConfig::remote_addr`]: common::config::ConnectionConfig::remote_addr
+//! [`ConnectionConfig::room`]: common::config::ConnectionConfig::room
+//! [`ConnectionConfig::remote_fingerprint`]: common::config::ConnectionConfig::remote_fingerprint
+
+use std::net::SocketAddr;
+
+use common::config::ConnectionConfig;
+use common::deps::bevy::app::App;
+use common::deps::bevy::log::{error, info, LogPlugin};
+use common::deps::tokio;
+use common::error::RemoteThreadError;
+use common::relay;
+use common::serde::RegistryContext;
+use common::transport::{self, Identity};
+
+/// The address the relay listens on when none is given.
+const DEFAULT_ADDR: &str = "0.0.0.0:5004";
+
+fn main() {
+    // Only sets up the global subscriber, the relay doesn't run an app
+    App::new().add_plugin(LogPlugin);
+
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .as_deref()
+        .unwrap_or(DEFAULT_ADDR)
+        .parse()
+        .expect("expected the address to listen on, such as 0.0.0.0:5004");
+
+    // The handshake is framed like any other message, so it is deserialized with a type registry
+    let registry = relay::registry();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    if let Err(err) = runtime.block_on(run(addr, registry)) {
+        error!(error = %err, "relay closed");
+        std::process::exit(1);
+    }
+}
+
+async fn run(addr: SocketAddr, registry: RegistryContext) -> Result<(), RemoteThreadError> {
+    let identity = Identity::generate()?;
+    let (_endpoint, incoming) =
+        transport::listen_endpoint(addr, &identity, &ConnectionConfig::default())?;
+
+    info!(%addr, fingerprint = %identity.fingerprint(), "relaying");
+
+    relay::serve(incoming, registry).await;
+
+    Ok(())
+}
@@ -1,9 +1,11 @@
 use ::bevy_editor_iris::prelude::*;
+use bevy::log::LogPlugin;
 use bevy::prelude::*;
 
 fn main() {
     App::new()
-        .add_plugins(DefaultPlugins)
+        // The client plugin logs in its place, streaming the logs to the editor
+        .add_plugins_with(DefaultPlugins, |group| group.disable::<LogPlugin>())
         .add_plugin(IrisClientPlugin)
         .add_startup_system(setup)
         .run()